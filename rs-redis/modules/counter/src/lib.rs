@@ -0,0 +1,207 @@
+// a sample rs-redis module, loaded with MODULE LOAD path/to/libcounter.so
+//
+// COUNTER.INCRBY key n    adds n to the counter at key, creating it at 0
+// COUNTER.GET key         the counter at key, or nil
+// COUNTER.ADD a b         a + b, touches no keys
+//
+// counters are a module data type, so they are saved and loaded with the
+// snapshot. passing "no-type" registers only COUNTER.ADD, without the data
+// type, so the module can be unloaded again
+
+use std::ffi::{c_char, c_int, c_void};
+use std::sync::OnceLock;
+
+const MODULE_OK: c_int = 0;
+const MODULE_ERR: c_int = 1;
+
+// mirrors of the host's ABI, see src/module.rs in rs-redis
+#[repr(C)]
+pub struct ModuleString {
+    ptr: *const u8,
+    len: usize,
+}
+
+type LoadContext = c_void;
+type CallContext = c_void;
+type SaveBuffer = c_void;
+
+type CommandFunc = extern "C" fn(*mut CallContext, *const ModuleString, c_int) -> c_int;
+type UpdateFunc = extern "C" fn(*mut *mut c_void, *mut c_void) -> c_int;
+
+#[repr(C)]
+pub struct TypeMethods {
+    free: extern "C" fn(*mut c_void),
+    copy: extern "C" fn(*const c_void) -> *mut c_void,
+    save: extern "C" fn(*const c_void, *mut SaveBuffer),
+    load: extern "C" fn(*const u8, usize) -> *mut c_void,
+}
+
+#[repr(C)]
+pub struct ModuleApi {
+    version: c_int,
+    init: extern "C" fn(*mut LoadContext, *const c_char, c_int) -> c_int,
+    create_command: extern "C" fn(*mut LoadContext, *const c_char, CommandFunc, c_int, *const c_char, c_int, c_int, c_int) -> c_int,
+    create_data_type: extern "C" fn(*mut LoadContext, *const c_char, *const TypeMethods) -> c_int,
+    reply_simple_string: extern "C" fn(*mut CallContext, *const c_char) -> c_int,
+    reply_error: extern "C" fn(*mut CallContext, *const c_char) -> c_int,
+    reply_integer: extern "C" fn(*mut CallContext, i64) -> c_int,
+    reply_bulk: extern "C" fn(*mut CallContext, *const u8, usize) -> c_int,
+    reply_null: extern "C" fn(*mut CallContext) -> c_int,
+    update_value: extern "C" fn(*mut CallContext, *const u8, usize, *const c_char, UpdateFunc, *mut c_void) -> c_int,
+    save_bytes: extern "C" fn(*mut SaveBuffer, *const u8, usize),
+}
+
+// the api table is a static in the host, so it is the same for every server
+// in the process that loads this module
+struct Api(&'static ModuleApi);
+
+unsafe impl Send for Api {}
+unsafe impl Sync for Api {}
+
+static API: OnceLock<Api> = OnceLock::new();
+
+static COUNTER_METHODS: TypeMethods = TypeMethods {
+    free: counter_free,
+    copy: counter_copy,
+    save: counter_save,
+    load: counter_load,
+};
+
+fn api() -> &'static ModuleApi {
+    API.get().expect("module is not loaded").0
+}
+
+fn arg(argv: *const ModuleString, i: usize) -> &'static [u8] {
+    unsafe {
+        let s = &*argv.add(i);
+        std::slice::from_raw_parts(s.ptr, s.len)
+    }
+}
+
+/// # Safety
+///
+/// called by the host with a valid api table, load context and argv of argc entries
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rs_redis_module_onload(api: *const ModuleApi, ctx: *mut LoadContext, argv: *const ModuleString, argc: c_int) -> c_int {
+    let api = match unsafe { api.as_ref() } {
+        Some(api) if api.version >= 1 => api,
+        _ => return MODULE_ERR,
+    };
+
+    if (api.init)(ctx, c"counter".as_ptr(), 1) != MODULE_OK {
+        return MODULE_ERR;
+    }
+
+    let with_type = !(0..argc as usize).any(|i| arg(argv, i) == b"no-type");
+
+    if with_type
+        && ((api.create_data_type)(ctx, c"counter".as_ptr(), &COUNTER_METHODS) != MODULE_OK
+            || (api.create_command)(ctx, c"counter.incrby".as_ptr(), incrby_command, 3, c"write deny-oom".as_ptr(), 1, 1, 1) != MODULE_OK
+            || (api.create_command)(ctx, c"counter.get".as_ptr(), get_command, 2, c"readonly fast".as_ptr(), 1, 1, 1) != MODULE_OK)
+    {
+        return MODULE_ERR;
+    }
+
+    if (api.create_command)(ctx, c"counter.add".as_ptr(), add_command, 3, c"fast".as_ptr(), 0, 0, 0) != MODULE_OK {
+        return MODULE_ERR;
+    }
+
+    let _ = API.set(Api(api));
+    MODULE_OK
+}
+
+extern "C" fn incrby_command(call: *mut CallContext, argv: *const ModuleString, _argc: c_int) -> c_int {
+    let api = api();
+    let key = arg(argv, 1);
+
+    let mut by = match std::str::from_utf8(arg(argv, 2)).ok().and_then(|s| s.parse::<i64>().ok()) {
+        Some(by) => by,
+        None => return (api.reply_error)(call, c"ERR value is not an integer or out of range".as_ptr()),
+    };
+
+    // by comes back holding the new value
+    let status = (api.update_value)(call, key.as_ptr(), key.len(), c"counter".as_ptr(), add, &mut by as *mut i64 as *mut c_void);
+    if status != MODULE_OK {
+        return (api.reply_error)(call, c"WRONGTYPE Operation against a key holding the wrong kind of value".as_ptr());
+    }
+
+    (api.reply_integer)(call, by)
+}
+
+extern "C" fn add(slot: *mut *mut c_void, privdata: *mut c_void) -> c_int {
+    unsafe {
+        let by = &mut *(privdata as *mut i64);
+        if (*slot).is_null() {
+            *slot = Box::into_raw(Box::new(0_i64)) as *mut c_void;
+        }
+
+        let counter = &mut *(*slot as *mut i64);
+        *counter += *by;
+        *by = *counter;
+    }
+
+    MODULE_OK
+}
+
+extern "C" fn get_command(call: *mut CallContext, argv: *const ModuleString, _argc: c_int) -> c_int {
+    let api = api();
+    let key = arg(argv, 1);
+
+    let mut value: Option<i64> = None;
+    let status = (api.update_value)(call, key.as_ptr(), key.len(), c"counter".as_ptr(), read, &mut value as *mut Option<i64> as *mut c_void);
+    if status != MODULE_OK {
+        return (api.reply_error)(call, c"WRONGTYPE Operation against a key holding the wrong kind of value".as_ptr());
+    }
+
+    match value {
+        Some(i) => (api.reply_integer)(call, i),
+        None => (api.reply_null)(call),
+    }
+}
+
+extern "C" fn read(slot: *mut *mut c_void, privdata: *mut c_void) -> c_int {
+    unsafe {
+        let value = &mut *(privdata as *mut Option<i64>);
+        if !(*slot).is_null() {
+            *value = Some(*(*slot as *const i64));
+        }
+    }
+
+    MODULE_OK
+}
+
+extern "C" fn add_command(call: *mut CallContext, argv: *const ModuleString, _argc: c_int) -> c_int {
+    let api = api();
+    let int = |i| std::str::from_utf8(arg(argv, i)).ok().and_then(|s| s.parse::<i64>().ok());
+
+    match (int(1), int(2)) {
+        (Some(a), Some(b)) => match a.checked_add(b) {
+            Some(sum) => (api.reply_integer)(call, sum),
+            None => (api.reply_error)(call, c"ERR increment or decrement would overflow".as_ptr()),
+        },
+        _ => (api.reply_error)(call, c"ERR value is not an integer or out of range".as_ptr()),
+    }
+}
+
+extern "C" fn counter_free(value: *mut c_void) {
+    drop(unsafe { Box::from_raw(value as *mut i64) });
+}
+
+extern "C" fn counter_copy(value: *const c_void) -> *mut c_void {
+    let i = unsafe { *(value as *const i64) };
+    Box::into_raw(Box::new(i)) as *mut c_void
+}
+
+extern "C" fn counter_save(value: *const c_void, out: *mut SaveBuffer) {
+    let bytes = unsafe { *(value as *const i64) }.to_le_bytes();
+    (api().save_bytes)(out, bytes.as_ptr(), bytes.len());
+}
+
+extern "C" fn counter_load(data: *const u8, len: usize) -> *mut c_void {
+    let bytes: [u8; 8] = match unsafe { std::slice::from_raw_parts(data, len) }.try_into() {
+        Ok(bytes) => bytes,
+        Err(_) => return std::ptr::null_mut(),
+    };
+
+    Box::into_raw(Box::new(i64::from_le_bytes(bytes))) as *mut c_void
+}
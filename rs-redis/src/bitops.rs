@@ -0,0 +1,254 @@
+// bit level work on string values for SETBIT, BITCOUNT, BITPOS, BITOP and
+// BITFIELD. bits are numbered from the most significant bit of the first
+// byte, and a string reads as zero bits past its end
+
+// the most bits a string may hold, as Redis' proto-max-bulk-len of 512mb
+pub const MAX_BITS: u64 = 512 * 1024 * 1024 * 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    Not,
+    // the bits of the first source set in none of the others
+    Diff,
+}
+
+// what a BITFIELD write does when the value does not fit the field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+// a BITFIELD type such as i8 or u16
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    pub signed: bool,
+    pub bits: u32,
+}
+
+pub fn get_bit(bytes: &[u8], offset: u64) -> bool {
+    match bytes.get((offset / 8) as usize) {
+        Some(b) => b & (0x80 >> (offset % 8)) != 0,
+        None => false,
+    }
+}
+
+// sets one bit, growing bytes with zeros as needed; returns the old bit
+pub fn set_bit(bytes: &mut Vec<u8>, offset: u64, on: bool) -> bool {
+    let i = (offset / 8) as usize;
+    if bytes.len() <= i {
+        bytes.resize(i + 1, 0);
+    }
+
+    let mask = 0x80 >> (offset % 8);
+    let old = bytes[i] & mask != 0;
+    if on {
+        bytes[i] |= mask;
+    } else {
+        bytes[i] &= !mask;
+    }
+    old
+}
+
+// the inclusive range start..=end of a string len units long, with negative
+// ends counting from the back as in GETRANGE; None when it is empty
+pub fn range(len: u64, start: i64, end: i64) -> Option<(u64, u64)> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let end = if end < 0 { (len + end).max(0) } else { end.min(len - 1) };
+    if len == 0 || start > end {
+        return None;
+    }
+    Some((start as u64, end as u64))
+}
+
+// the bits of one byte from first to last inclusive, both 0..8
+fn byte_mask(first: u64, last: u64) -> u8 {
+    (0xff >> first) & (0xff << (7 - last))
+}
+
+// set bits from bit first to last inclusive, both within bytes
+pub fn count(bytes: &[u8], first: u64, last: u64) -> u64 {
+    let (a, b) = ((first / 8) as usize, (last / 8) as usize);
+    (a..=b)
+        .map(|i| {
+            let lo = if i == a { first % 8 } else { 0 };
+            let hi = if i == b { last % 8 } else { 7 };
+            (bytes[i] & byte_mask(lo, hi)).count_ones() as u64
+        })
+        .sum()
+}
+
+// the first bit equal to bit from first to last inclusive, both within bytes
+pub fn position(bytes: &[u8], bit: bool, first: u64, last: u64) -> Option<u64> {
+    let (a, b) = ((first / 8) as usize, (last / 8) as usize);
+    (a..=b).find_map(|i| {
+        let lo = if i == a { first % 8 } else { 0 };
+        let hi = if i == b { last % 8 } else { 7 };
+        let byte = if bit { bytes[i] } else { !bytes[i] };
+
+        match byte & byte_mask(lo, hi) {
+            0 => None,
+            m => Some(i as u64 * 8 + m.leading_zeros() as u64),
+        }
+    })
+}
+
+// BITOP over the given strings, shorter ones padded with zero bytes
+pub fn combine(op: BitOp, sources: &[Vec<u8>]) -> Vec<u8> {
+    let len = sources.iter().map(|s| s.len()).max().unwrap_or(0);
+    let byte = |s: &Vec<u8>, i: usize| s.get(i).copied().unwrap_or(0);
+
+    (0..len)
+        .map(|i| {
+            let rest = sources[1..].iter().map(|s| byte(s, i));
+            let first = byte(&sources[0], i);
+            match op {
+                BitOp::And => rest.fold(first, |acc, b| acc & b),
+                BitOp::Or => rest.fold(first, |acc, b| acc | b),
+                BitOp::Xor => rest.fold(first, |acc, b| acc ^ b),
+                BitOp::Not => !first,
+                BitOp::Diff => first & !rest.fold(0, |acc, b| acc | b),
+            }
+        })
+        .collect()
+}
+
+impl Field {
+    // parses i1..i64 or u1..u63
+    pub fn parse(s: &str) -> Option<Field> {
+        let signed = match s.as_bytes().first() {
+            Some(b'i' | b'I') => true,
+            Some(b'u' | b'U') => false,
+            _ => return None,
+        };
+
+        let bits: u32 = s[1..].parse().ok()?;
+        let max = if signed { 64 } else { 63 };
+        (1..=max).contains(&bits).then_some(Field { signed, bits })
+    }
+
+    fn min(&self) -> i128 {
+        if self.signed { -(1i128 << (self.bits - 1)) } else { 0 }
+    }
+
+    fn max(&self) -> i128 {
+        if self.signed { (1i128 << (self.bits - 1)) - 1 } else { (1i128 << self.bits) - 1 }
+    }
+
+    pub fn get(&self, bytes: &[u8], offset: u64) -> i64 {
+        let mut v: u64 = 0;
+        for i in 0..self.bits as u64 {
+            v = (v << 1) | get_bit(bytes, offset + i) as u64;
+        }
+
+        // sign extend
+        if self.signed && self.bits < 64 && v >> (self.bits - 1) & 1 == 1 {
+            v |= u64::MAX << self.bits;
+        }
+        v as i64
+    }
+
+    pub fn set(&self, bytes: &mut Vec<u8>, offset: u64, value: i64) {
+        let bits = self.bits as u64;
+        for i in 0..bits {
+            set_bit(bytes, offset + i, (value as u64) >> (bits - 1 - i) & 1 == 1);
+        }
+    }
+
+    // fits v into the field as overflow says, None when it fails
+    pub fn fit(&self, v: i128, overflow: Overflow) -> Option<i64> {
+        if (self.min()..=self.max()).contains(&v) {
+            return Some(v as i64);
+        }
+
+        match overflow {
+            Overflow::Wrap => {
+                let v = v.rem_euclid(1i128 << self.bits);
+                Some(if v > self.max() { v - (1i128 << self.bits) } else { v } as i64)
+            },
+            Overflow::Sat => Some(v.clamp(self.min(), self.max()) as i64),
+            Overflow::Fail => None,
+        }
+    }
+
+    // the value SET writes: an unsigned field takes the bits of a negative
+    // value as a large positive one, as Redis does
+    pub fn value(&self, value: i64) -> i128 {
+        if self.signed { value as i128 } else { value as u64 as i128 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bits_count_and_position() {
+        let mut bytes = Vec::new();
+        assert!(!set_bit(&mut bytes, 7, true));
+        assert!(set_bit(&mut bytes, 7, true));
+        set_bit(&mut bytes, 17, true);
+        assert_eq!(bytes, vec![0x01, 0x00, 0x40]);
+        assert!(get_bit(&bytes, 17));
+        assert!(!get_bit(&bytes, 1000));
+
+        assert_eq!(count(&bytes, 0, 23), 2);
+        assert_eq!(count(&bytes, 8, 17), 1);
+        assert_eq!(count(&bytes, 8, 16), 0);
+        assert_eq!(position(&bytes, true, 0, 23), Some(7));
+        assert_eq!(position(&bytes, true, 8, 23), Some(17));
+        assert_eq!(position(&bytes, false, 0, 23), Some(0));
+        assert_eq!(position(&[0xff], false, 0, 7), None);
+
+        assert_eq!(range(3, 0, -1), Some((0, 2)));
+        assert_eq!(range(3, -2, 100), Some((1, 2)));
+        assert_eq!(range(3, 2, 1), None);
+        assert_eq!(range(0, 0, -1), None);
+    }
+
+    #[test]
+    fn test_combine() {
+        let a = vec![0b1100, 0xff];
+        let b = vec![0b1010];
+        assert_eq!(combine(BitOp::And, &[a.clone(), b.clone()]), vec![0b1000, 0]);
+        assert_eq!(combine(BitOp::Or, &[a.clone(), b.clone()]), vec![0b1110, 0xff]);
+        assert_eq!(combine(BitOp::Xor, &[a.clone(), b.clone()]), vec![0b0110, 0xff]);
+        assert_eq!(combine(BitOp::Diff, &[a, b.clone()]), vec![0b0100, 0xff]);
+        assert_eq!(combine(BitOp::Not, &[b]), vec![!0b1010]);
+    }
+
+    #[test]
+    fn test_fields() {
+        assert_eq!(Field::parse("i64"), Some(Field { signed: true, bits: 64 }));
+        assert_eq!(Field::parse("u63"), Some(Field { signed: false, bits: 63 }));
+        assert_eq!(Field::parse("u64"), None);
+        assert_eq!(Field::parse("i0"), None);
+        assert_eq!(Field::parse("x8"), None);
+
+        let i8 = Field { signed: true, bits: 8 };
+        let u4 = Field { signed: false, bits: 4 };
+        let mut bytes = Vec::new();
+        i8.set(&mut bytes, 4, -2);
+        assert_eq!(bytes, vec![0x0f, 0xe0]);
+        assert_eq!(i8.get(&bytes, 4), -2);
+        assert_eq!(u4.get(&bytes, 4), 15);
+
+        assert_eq!(i8.fit(128, Overflow::Wrap), Some(-128));
+        assert_eq!(i8.fit(-129, Overflow::Wrap), Some(127));
+        assert_eq!(i8.fit(300, Overflow::Sat), Some(127));
+        assert_eq!(u4.fit(-1, Overflow::Sat), Some(0));
+        assert_eq!(u4.fit(17, Overflow::Wrap), Some(1));
+        assert_eq!(u4.fit(16, Overflow::Fail), None);
+        assert_eq!(u4.fit(u4.value(-1), Overflow::Sat), Some(15));
+
+        let i64 = Field { signed: true, bits: 64 };
+        i64.set(&mut bytes, 0, i64::MIN);
+        assert_eq!(i64.get(&bytes, 0), i64::MIN);
+        assert_eq!(i64.fit(i64::MAX as i128 + 1, Overflow::Wrap), Some(i64::MIN));
+    }
+}
@@ -0,0 +1,264 @@
+// clients parked by BLPOP, BRPOP, BLMOVE and BLMPOP until another client
+// pushes to a key they wait on. waiters on a key are served in the order they
+// blocked, each by the serve function of its command
+
+use crate::db::Db;
+use crate::error::Error;
+use crate::server::Server;
+use crate::types::RESPResult;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::oneshot;
+
+// tries a blocked command against db: with a key, the one that just became
+// ready, without it every key the command waits on. None when there is still
+// nothing to take
+pub type Serve = Box<dyn Fn(&Db, Option<&str>) -> Result<Option<RESPResult>, Error> + Send>;
+
+pub(crate) enum Attempt {
+    Served(RESPResult),
+    Waiting(Wait),
+}
+
+// a parked client's end: the reply once it is served
+#[derive(Debug)]
+pub struct Wait {
+    id: u64,
+    pub(crate) reply: oneshot::Receiver<Result<RESPResult, Error>>,
+    // None waits forever
+    pub(crate) deadline: Option<Instant>,
+    // the reply once the deadline passes
+    pub(crate) nil: fn() -> RESPResult,
+}
+
+struct Waiter {
+    db: usize,
+    keys: Vec<String>,
+    // the key BLMOVE pushes onto, which may free other waiters
+    pushes_to: Option<String>,
+    serve: Serve,
+    reply: oneshot::Sender<Result<RESPResult, Error>>,
+}
+
+#[derive(Default)]
+struct Waiters {
+    next_id: u64,
+    by_id: HashMap<u64, Waiter>,
+    // ids of the clients waiting on each key of each database, first blocked first
+    queues: HashMap<(usize, String), VecDeque<u64>>,
+}
+
+impl Waiters {
+    fn remove(&mut self, id: u64) -> Option<Waiter> {
+        let waiter = self.by_id.remove(&id)?;
+        for k in &waiter.keys {
+            let at = (waiter.db, k.clone());
+            if let Some(queue) = self.queues.get_mut(&at) {
+                queue.retain(|&i| i != id);
+                if queue.is_empty() {
+                    self.queues.remove(&at);
+                }
+            }
+        }
+        Some(waiter)
+    }
+}
+
+#[derive(Default)]
+pub struct Blocking {
+    waiters: Mutex<Waiters>,
+    // how many clients are parked, so writes skip the lock when none are
+    waiting: AtomicUsize,
+}
+
+impl Blocking {
+    pub fn new() -> Blocking {
+        Blocking::default()
+    }
+
+    // clients parked right now
+    pub fn blocked_clients(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Waiters> {
+        self.waiters.lock().expect("blocking lock failed")
+    }
+
+    // serves the command now if it can, otherwise parks it on keys until
+    // deadline. the first try runs under the lock, so a push that lands after
+    // it always finds the waiter
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn block(
+        &self,
+        server: &Server,
+        db: usize,
+        keys: Vec<String>,
+        pushes_to: Option<String>,
+        deadline: Option<Instant>,
+        nil: fn() -> RESPResult,
+        serve: Serve,
+    ) -> Result<Attempt, Error> {
+        let mut waiters = self.lock();
+
+        // counted before trying, so a writer that sees no waiters pushed before the try
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        match serve(&server.databases()[db], None) {
+            Ok(None) => {},
+            result => {
+                self.waiting.fetch_sub(1, Ordering::SeqCst);
+                return result.map(|reply| Attempt::Served(reply.unwrap()));
+            },
+        }
+
+        let id = waiters.next_id;
+        waiters.next_id += 1;
+        for k in &keys {
+            waiters.queues.entry((db, k.clone())).or_default().push_back(id);
+        }
+
+        let (tx, rx) = oneshot::channel();
+        waiters.by_id.insert(id, Waiter { db, keys, pushes_to, serve, reply: tx });
+        Ok(Attempt::Waiting(Wait { id, reply: rx, deadline, nil }))
+    }
+
+    // stops waiting, on timeout or when the client goes away. the reply may
+    // still have been sent just before
+    pub(crate) fn cancel(&self, wait: &Wait) {
+        if self.lock().remove(wait.id).is_some() {
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    // serves clients waiting on keys of db, after a write to them
+    pub fn signal(&self, server: &Server, db: usize, keys: &[String]) {
+        if self.blocked_clients() == 0 {
+            return;
+        }
+
+        let mut waiters = self.lock();
+        let mut ready: VecDeque<String> = keys.iter().cloned().collect();
+
+        while let Some(k) = ready.pop_front() {
+            while let Some(&id) = waiters.queues.get(&(db, k.clone())).and_then(|q| q.front()) {
+                let waiter = &waiters.by_id[&id];
+
+                // a client that went away takes nothing
+                if waiter.reply.is_closed() {
+                    waiters.remove(id);
+                    self.waiting.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+
+                let result = match (waiter.serve)(&server.databases()[db], Some(&k)) {
+                    Ok(None) => break,
+                    result => result.map(Option::unwrap),
+                };
+
+                let waiter = waiters.remove(id).unwrap();
+                self.waiting.fetch_sub(1, Ordering::SeqCst);
+                if result.is_ok() && let Some(dst) = waiter.pushes_to {
+                    ready.push_back(dst);
+                }
+                waiter.reply.send(result).ok();
+            }
+        }
+    }
+
+    // serves every client waiting on db, when its keys change all at once
+    pub fn signal_db(&self, server: &Server, db: usize) {
+        if self.blocked_clients() == 0 {
+            return;
+        }
+
+        let keys: Vec<String> = self.lock().queues.keys().filter(|(d, _)| *d == db).map(|(_, k)| k.clone()).collect();
+        self.signal(server, db, &keys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{command_router, command_router_in, Session};
+    use crate::config::Config;
+
+    fn bulk(s: &str) -> RESPResult {
+        RESPResult::BulkString(Some(s.as_bytes().to_vec()))
+    }
+
+    // runs a command as a client connection would, returning what it waits on
+    fn client_call(server: &Server, session: &mut Session, args: &[&str]) -> Option<Wait> {
+        let data: Vec<RESPResult> = args[1..].iter().map(|a| bulk(a)).collect();
+        command_router_in(server, session, args[0], &data).unwrap();
+        session.take_blocked()
+    }
+
+    fn push(server: &Server, key: &str, values: &[&str]) {
+        let mut data = vec![bulk(key)];
+        data.extend(values.iter().map(|v| bulk(v)));
+        command_router(server, "RPUSH", &data).unwrap();
+    }
+
+    #[test]
+    fn test_waiters_are_served_in_order() {
+        let server = Server::new(Config::default());
+        let (mut first, mut second) = (Session::client(), Session::client());
+
+        let mut a = client_call(&server, &mut first, &["BLPOP", "q", "other", "0"]).unwrap();
+        let mut b = client_call(&server, &mut second, &["BRPOP", "q", "0"]).unwrap();
+        assert_eq!(server.blocking().blocked_clients(), 2);
+
+        push(&server, "q", &["x"]);
+        assert_eq!(a.reply.try_recv(), Ok(Ok(RESPResult::Array(vec![bulk("q"), bulk("x")]))));
+        assert!(b.reply.try_recv().is_err());
+
+        // the first waiter is gone from every key it waited on
+        push(&server, "other", &["y"]);
+        push(&server, "q", &["z", "w"]);
+        assert_eq!(b.reply.try_recv(), Ok(Ok(RESPResult::Array(vec![bulk("q"), bulk("w")]))));
+        assert_eq!(server.blocking().blocked_clients(), 0);
+        assert_eq!(server.db().llen("other"), Ok(1));
+        assert_eq!(server.db().llen("q"), Ok(1));
+
+        // with something to take there is no waiting
+        assert!(client_call(&server, &mut first, &["BLPOP", "q", "0"]).is_none());
+        assert_eq!(server.db().llen("q"), Ok(0));
+    }
+
+    #[test]
+    fn test_moved_elements_wake_the_next_waiter() {
+        let server = Server::new(Config::default());
+        let (mut mover, mut popper) = (Session::client(), Session::client());
+
+        let mut moved = client_call(&server, &mut mover, &["BLMOVE", "src", "dst", "LEFT", "RIGHT", "0"]).unwrap();
+        let mut popped = client_call(&server, &mut popper, &["BLMPOP", "0", "1", "dst", "LEFT", "COUNT", "5"]).unwrap();
+
+        push(&server, "src", &["a"]);
+        assert_eq!(moved.reply.try_recv(), Ok(Ok(bulk("a"))));
+        assert_eq!(popped.reply.try_recv(), Ok(Ok(RESPResult::Array(vec![bulk("dst"), RESPResult::Array(vec![bulk("a")])]))));
+        assert_eq!(server.db().len(), 0);
+    }
+
+    #[test]
+    fn test_cancelled_waiters_take_nothing() {
+        let server = Server::new(Config::default());
+        let mut session = Session::client();
+
+        let wait = client_call(&server, &mut session, &["BLPOP", "q", "0"]).unwrap();
+        server.blocking().cancel(&wait);
+        let dropped = client_call(&server, &mut session, &["BLPOP", "q", "0"]).unwrap();
+        drop(dropped);
+
+        push(&server, "q", &["x"]);
+        assert_eq!(server.db().llen("q"), Ok(1));
+        assert_eq!(server.blocking().blocked_clients(), 0);
+
+        // a key that is no list keeps its waiters waiting
+        let mut wait = client_call(&server, &mut session, &["BLPOP", "s", "0"]).unwrap();
+        command_router(&server, "SET", &[bulk("s"), bulk("v")]).unwrap();
+        assert!(wait.reply.try_recv().is_err());
+        assert_eq!(server.blocking().blocked_clients(), 1);
+    }
+}
@@ -1,15 +1,13 @@
 use crate::command;
 use crate::parser;
 use crate::parser::parse_resp_message;
+use crate::server::Server;
 use crate::types::RESPResult;
 
-pub fn read_cli_input(message: &str) -> Result<Vec<u8>, String> {
+pub fn read_cli_input(server: &Server, message: &str) -> Result<Vec<u8>, String> {
 
     // get the resp format of the command
-    let resp_message: Vec<u8> = match parser::string_to_resp_message(message) {
-        Ok(s) => s,
-        Err(e) => return Err(e)
-    };
+    let resp_message: Vec<u8> = parser::string_to_resp_message(message)?;
 
     // get the result of the resp format message
     let message_result = match parse_resp_message(&resp_message) {
@@ -20,7 +18,7 @@ pub fn read_cli_input(message: &str) -> Result<Vec<u8>, String> {
     // check that message result is an array, and above len 0
     let command_values = match message_result {
         RESPResult::Array(a) => {
-            if a.len() == 0 {
+            if a.is_empty() {
                 return Err("Empty array".to_string());
             }
             a
@@ -41,10 +39,7 @@ pub fn read_cli_input(message: &str) -> Result<Vec<u8>, String> {
 
     let arguments = &command_values[1..];
 
-    let result = match command::command_router(&command, arguments) {
-        Ok(m) => m,
-        Err(e) => return Err(e),
-    };
+    let result = command::command_router(server, &command, arguments).map_err(|e| e.to_string())?;
 
-    Ok(parser::respresult_to_resp_string(&result).unwrap().as_bytes().to_vec())
+    Ok(parser::respresult_to_resp_bytes(&result))
 }
\ No newline at end of file
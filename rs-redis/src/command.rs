@@ -1,337 +1,2603 @@
-use crate::types::{RESPResult, DB_TYPE};
-use crate::db::{self};
-use std::time::SystemTime;
+use crate::types::{RESPResult, DB_TYPE, MEMORY_SAMPLES};
+use crate::bitops::{self, BitOp, Field, Overflow, MAX_BITS};
+use crate::blocking::{Attempt, Serve, Wait};
+use crate::db::{parse_float, Db, Ttl};
+use crate::config::{MaxmemoryPolicy, PARAMETERS};
+use crate::error::Error;
+use crate::evict;
+use crate::glob::glob_match;
+use crate::module;
+use crate::scripting::{self, RestorePolicy};
+use crate::server::Server;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+// command flags, named as Redis reports them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandFlags(u32);
+
+impl CommandFlags {
+    pub const NONE: CommandFlags = CommandFlags(0);
+    pub const WRITE: CommandFlags = CommandFlags(1);
+    pub const READONLY: CommandFlags = CommandFlags(1 << 1);
+    pub const DENYOOM: CommandFlags = CommandFlags(1 << 2);
+    pub const ADMIN: CommandFlags = CommandFlags(1 << 3);
+    pub const PUBSUB: CommandFlags = CommandFlags(1 << 4);
+    pub const NOSCRIPT: CommandFlags = CommandFlags(1 << 5);
+    pub const FAST: CommandFlags = CommandFlags(1 << 6);
+    // may park the client until another one writes
+    pub const BLOCKING: CommandFlags = CommandFlags(1 << 7);
+    // runs a script, which takes the script gate itself; not reported by COMMAND INFO
+    pub(crate) const RUNS_SCRIPT: CommandFlags = CommandFlags(1 << 31);
+
+    const NAMES: [(CommandFlags, &'static str); 8] = [
+        (CommandFlags::WRITE, "write"),
+        (CommandFlags::READONLY, "readonly"),
+        (CommandFlags::DENYOOM, "denyoom"),
+        (CommandFlags::ADMIN, "admin"),
+        (CommandFlags::PUBSUB, "pubsub"),
+        (CommandFlags::NOSCRIPT, "noscript"),
+        (CommandFlags::FAST, "fast"),
+        (CommandFlags::BLOCKING, "blocking"),
+    ];
+
+    pub fn contains(self, other: CommandFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    // parses space separated flag names, as modules declare them
+    pub fn parse(names: &str) -> Option<CommandFlags> {
+        let mut flags = CommandFlags::NONE;
+        for name in names.split_whitespace() {
+            let name = if name == "deny-oom" { "denyoom" } else { name };
+            match CommandFlags::NAMES.iter().find(|(_, n)| *n == name) {
+                Some((f, _)) => flags = flags | *f,
+                None => return None,
+            }
+        }
+
+        Some(flags)
+    }
+
+    pub fn names(self) -> Vec<&'static str> {
+        CommandFlags::NAMES
+            .iter()
+            .filter(|(f, _)| self.contains(*f))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+impl std::ops::BitOr for CommandFlags {
+    type Output = CommandFlags;
+
+    fn bitor(self, rhs: CommandFlags) -> CommandFlags {
+        CommandFlags(self.0 | rhs.0)
+    }
+}
+
+// per-connection state that outlives a single command
+#[derive(Debug, Default)]
+pub struct Session {
+    db: usize,
+    // only a client connection may be parked by a blocking command; scripts
+    // and direct calls get the reply at once
+    may_block: bool,
+    // set by a blocking command that found nothing, for the connection to wait on
+    blocked: Option<Wait>,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session::default()
+    }
+
+    // the session of a client connection
+    pub fn client() -> Session {
+        Session { may_block: true, ..Session::default() }
+    }
+
+    pub(crate) fn take_blocked(&mut self) -> Option<Wait> {
+        self.blocked.take()
+    }
+
+    // index of the selected database
+    pub fn db(&self) -> usize {
+        self.db
+    }
+
+    pub(crate) fn select(&mut self, db: usize) {
+        self.db = db;
+    }
+}
+
+// what a command can reach while it runs
+pub struct Context<'a> {
+    server: &'a Server,
+    session: &'a mut Session,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(server: &'a Server, session: &'a mut Session) -> Context<'a> {
+        Context { server, session }
+    }
+
+    pub fn server(&self) -> &'a Server {
+        self.server
+    }
+
+    // the database the connection has selected
+    pub fn db(&self) -> &'a Db {
+        &self.server.databases()[self.session.db]
+    }
+
+    pub fn session(&mut self) -> &mut Session {
+        self.session
+    }
+}
+
+// implement this to add a command to a server with Server::register_command
+pub trait Command: Send + Sync {
+    fn name(&self) -> &str;
+
+    // Redis convention: counts the command name, negative means "at least"
+    fn arity(&self) -> i32;
+
+    fn flags(&self) -> CommandFlags {
+        CommandFlags::NONE
+    }
+
+    // 1-based (first, last, step) argument positions of keys, last -1 means "to the end"
+    fn key_range(&self) -> (i32, i32, i32) {
+        (0, 0, 0)
+    }
+
+    // for commands whose keys move with their arguments, like those counted by
+    // EVAL's numkeys, finds them in place of key_range
+    fn movable_keys(&self) -> Option<KeysFn> {
+        None
+    }
+
+    fn acl_categories(&self) -> &[&str] {
+        &[]
+    }
+
+    // shown by COMMAND DOCS
+    fn summary(&self) -> &str {
+        ""
+    }
+
+    fn since(&self) -> &str {
+        ""
+    }
+
+    fn group(&self) -> &str {
+        "module"
+    }
+
+    // args excludes the command name, arity has already been checked
+    fn execute(&self, ctx: &mut Context<'_>, args: &[RESPResult]) -> Result<RESPResult, Error>;
+}
+
+impl dyn Command {
+    // argc includes the command name
+    pub fn accepts(&self, argc: usize) -> bool {
+        let arity = self.arity();
+        if arity >= 0 {
+            argc == arity as usize
+        }
+        else {
+            argc >= arity.unsigned_abs() as usize
+        }
+    }
+
+    // positions of the key arguments in the full argv, of which args leave
+    // out the command name
+    pub fn key_positions(&self, args: &[RESPResult]) -> Vec<usize> {
+        if let Some(keys) = self.movable_keys() {
+            return keys(args);
+        }
+
+        let argc = args.len() + 1;
+        let (first, last, step) = self.key_range();
+        if first <= 0 || step <= 0 {
+            return Vec::new();
+        }
+
+        let last = if last < 0 { argc as i32 + last } else { last };
+
+        (first..=last.min(argc as i32 - 1))
+            .step_by(step as usize)
+            .map(|i| i as usize)
+            .collect()
+    }
+
+    // the COMMAND INFO reply for this command
+    fn info(&self) -> RESPResult {
+        let status = |s: &str| RESPResult::SimpleString(s.to_string());
+        let (first, last, step) = self.key_range();
+
+        let mut flags = self.flags().names();
+        if self.movable_keys().is_some() {
+            flags.push("movablekeys");
+        }
+
+        RESPResult::Array(vec![
+            RESPResult::BulkString(Some(self.name().as_bytes().to_vec())),
+            RESPResult::Integer(self.arity() as i64),
+            RESPResult::Array(flags.into_iter().map(status).collect()),
+            RESPResult::Integer(first as i64),
+            RESPResult::Integer(last as i64),
+            RESPResult::Integer(step as i64),
+            RESPResult::Array(self.acl_categories().iter().map(|c| status(c)).collect()),
+            // tips, key specifications and subcommands
+            RESPResult::Array(Vec::new()),
+            RESPResult::Array(Vec::new()),
+            RESPResult::Array(Vec::new()),
+        ])
+    }
+
+    // the COMMAND DOCS reply for this command
+    fn docs(&self) -> RESPResult {
+        let bulk = |s: &str| RESPResult::BulkString(Some(s.as_bytes().to_vec()));
+
+        RESPResult::Array(vec![
+            bulk("summary"), bulk(self.summary()),
+            bulk("since"), bulk(self.since()),
+            bulk("group"), bulk(self.group()),
+        ])
+    }
+}
+
+type Handler = fn(&mut Context<'_>, &[RESPResult]) -> Result<RESPResult, Error>;
+
+// finds the key positions of a command with movable keys, see Command::key_positions
+pub type KeysFn = fn(&[RESPResult]) -> Vec<usize>;
+
+// a built in command, described by a row of the command table
+struct CommandSpec {
+    name: &'static str,
+    arity: i32,
+    flags: CommandFlags,
+    first_key: i32,
+    last_key: i32,
+    step: i32,
+    keys: Option<KeysFn>,
+    acl_categories: &'static [&'static str],
+    summary: &'static str,
+    since: &'static str,
+    group: &'static str,
+    handler: Handler,
+}
+
+impl Command for CommandSpec {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn arity(&self) -> i32 {
+        self.arity
+    }
+
+    fn flags(&self) -> CommandFlags {
+        self.flags
+    }
+
+    fn key_range(&self) -> (i32, i32, i32) {
+        (self.first_key, self.last_key, self.step)
+    }
+
+    fn movable_keys(&self) -> Option<KeysFn> {
+        self.keys
+    }
+
+    fn acl_categories(&self) -> &[&str] {
+        self.acl_categories
+    }
+
+    fn summary(&self) -> &str {
+        self.summary
+    }
+
+    fn since(&self) -> &str {
+        self.since
+    }
+
+    fn group(&self) -> &str {
+        self.group
+    }
+
+    fn execute(&self, ctx: &mut Context<'_>, args: &[RESPResult]) -> Result<RESPResult, Error> {
+        (self.handler)(ctx, args)
+    }
+}
+
+pub struct CommandTable {
+    commands: HashMap<String, Arc<dyn Command>>,
+}
+
+impl CommandTable {
+    pub fn new() -> CommandTable {
+        let mut commands: HashMap<String, Arc<dyn Command>> = HashMap::new();
+        for spec in builtin_commands() {
+            commands.insert(spec.name.to_string(), Arc::new(spec));
+        }
+
+        CommandTable { commands }
+    }
+
+    // command names are case-insensitive
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Command>> {
+        self.commands.get(&name.to_lowercase()).cloned()
+    }
+
+    pub fn register(&mut self, command: Arc<dyn Command>) -> Result<(), Error> {
+        let name = command.name().to_lowercase();
+        if self.commands.contains_key(&name) {
+            return Err(Error::Other(format!("command '{name}' already exists")));
+        }
+
+        self.commands.insert(name, command);
+        Ok(())
+    }
+
+    // returns false if there was no such command
+    pub fn unregister(&mut self, name: &str) -> bool {
+        self.commands.remove(&name.to_lowercase()).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<dyn Command>> {
+        self.commands.values()
+    }
+}
+
+impl Default for CommandTable {
+    fn default() -> Self {
+        CommandTable::new()
+    }
+}
+
+fn builtin_commands() -> Vec<CommandSpec> {
+    use CommandFlags as F;
+
+    vec![
+        CommandSpec {
+            name: "ping", arity: -1, flags: F::FAST,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@fast", "@connection"],
+            summary: "Returns the server's liveliness response.",
+            since: "1.0.0", group: "connection",
+            handler: |_, _| Ok(RESPResult::SimpleString("PONG".to_string())),
+        },
+        CommandSpec {
+            name: "echo", arity: 2, flags: F::FAST,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@fast", "@connection"],
+            summary: "Returns the given string.",
+            since: "1.0.0", group: "connection",
+            handler: |_, data| echo_command(data).map(RESPResult::SimpleString),
+        },
+        CommandSpec {
+            name: "set", arity: -3, flags: F::WRITE | F::DENYOOM,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@string", "@slow"],
+            summary: "Sets the string value of a key, ignoring its type. The key is created if it doesn't exist.",
+            since: "1.0.0", group: "string",
+            handler: |ctx, data| set_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "get", arity: 2, flags: F::READONLY | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@read", "@string", "@fast"],
+            summary: "Returns the string value of a key.",
+            since: "1.0.0", group: "string",
+            handler: |ctx, data| get_command(ctx.db(), data).map(bulk_reply),
+        },
+        CommandSpec {
+            name: "exists", arity: -2, flags: F::READONLY | F::FAST,
+            first_key: 1, last_key: -1, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@read", "@fast"],
+            summary: "Determines whether one or more keys exist.",
+            since: "1.0.0", group: "generic",
+            handler: |ctx, data| exists_command(ctx.db(), data).map(|i| RESPResult::Integer(i as i64)),
+        },
+        CommandSpec {
+            name: "del", arity: -2, flags: F::WRITE,
+            first_key: 1, last_key: -1, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@write", "@slow"],
+            summary: "Deletes one or more keys.",
+            since: "1.0.0", group: "generic",
+            handler: |ctx, data| delete_command(ctx.db(), data).map(|i| RESPResult::Integer(i as i64)),
+        },
+        CommandSpec {
+            name: "incr", arity: 2, flags: F::WRITE | F::DENYOOM | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@string", "@fast"],
+            summary: "Increments the integer value of a key by one. Uses 0 as initial value if the key doesn't exist.",
+            since: "1.0.0", group: "string",
+            handler: |ctx, data| increment_command(ctx.db(), data).map(RESPResult::Integer),
+        },
+        CommandSpec {
+            name: "decr", arity: 2, flags: F::WRITE | F::DENYOOM | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@string", "@fast"],
+            summary: "Decrements the integer value of a key by one. Uses 0 as initial value if the key doesn't exist.",
+            since: "1.0.0", group: "string",
+            handler: |ctx, data| decrement_command(ctx.db(), data).map(RESPResult::Integer),
+        },
+        CommandSpec {
+            name: "incrby", arity: 3, flags: F::WRITE | F::DENYOOM | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@string", "@fast"],
+            summary: "Increments the integer value of a key by a number. Uses 0 as initial value if the key doesn't exist.",
+            since: "1.0.0", group: "string",
+            handler: |ctx, data| incrby_command(ctx.db(), data).map(RESPResult::Integer),
+        },
+        CommandSpec {
+            name: "decrby", arity: 3, flags: F::WRITE | F::DENYOOM | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@string", "@fast"],
+            summary: "Decrements a number from the integer value of a key. Uses 0 as initial value if the key doesn't exist.",
+            since: "1.0.0", group: "string",
+            handler: |ctx, data| decrby_command(ctx.db(), data).map(RESPResult::Integer),
+        },
+        CommandSpec {
+            name: "incrbyfloat", arity: 3, flags: F::WRITE | F::DENYOOM | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@string", "@fast"],
+            summary: "Increment the floating point value of a key by a number. Uses 0 as initial value if the key doesn't exist.",
+            since: "2.6.0", group: "string",
+            handler: |ctx, data| incrbyfloat_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "lpush", arity: -3, flags: F::WRITE | F::DENYOOM | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@list", "@fast"],
+            summary: "Prepends one or more elements to a list. Creates the key if it doesn't exist.",
+            since: "1.0.0", group: "list",
+            handler: |ctx, data| lpush_command(ctx.db(), data).map(|n| RESPResult::Integer(n as i64)),
+        },
+        CommandSpec {
+            name: "rpush", arity: -3, flags: F::WRITE | F::DENYOOM | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@list", "@fast"],
+            summary: "Appends one or more elements to a list. Creates the key if it doesn't exist.",
+            since: "1.0.0", group: "list",
+            handler: |ctx, data| rpush_command(ctx.db(), data).map(|n| RESPResult::Integer(n as i64)),
+        },
+        CommandSpec {
+            name: "lpushx", arity: -3, flags: F::WRITE | F::DENYOOM | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@list", "@fast"],
+            summary: "Prepends one or more elements to a list only when the list exists.",
+            since: "2.2.0", group: "list",
+            handler: |ctx, data| pushx_command(ctx.db(), data, true),
+        },
+        CommandSpec {
+            name: "rpushx", arity: -3, flags: F::WRITE | F::DENYOOM | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@list", "@fast"],
+            summary: "Appends an element to a list only when the list exists.",
+            since: "2.2.0", group: "list",
+            handler: |ctx, data| pushx_command(ctx.db(), data, false),
+        },
+        CommandSpec {
+            name: "lpop", arity: -2, flags: F::WRITE | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@list", "@fast"],
+            summary: "Returns the first elements in a list after removing it. Deletes the list if the last element was popped.",
+            since: "1.0.0", group: "list",
+            handler: |ctx, data| pop_command(ctx.db(), data, true),
+        },
+        CommandSpec {
+            name: "rpop", arity: -2, flags: F::WRITE | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@list", "@fast"],
+            summary: "Returns and removes the last elements of a list. Deletes the list if the last element was popped.",
+            since: "1.0.0", group: "list",
+            handler: |ctx, data| pop_command(ctx.db(), data, false),
+        },
+        CommandSpec {
+            name: "lmpop", arity: -4, flags: F::WRITE,
+            first_key: 0, last_key: 0, step: 0, keys: Some(lmpop_keys),
+            acl_categories: &["@write", "@list", "@slow"],
+            summary: "Returns multiple elements from a list after removing them. Deletes the list if the last element was popped.",
+            since: "7.0.0", group: "list",
+            handler: |ctx, data| lmpop_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "llen", arity: 2, flags: F::READONLY | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@read", "@list", "@fast"],
+            summary: "Returns the length of a list.",
+            since: "1.0.0", group: "list",
+            handler: |ctx, data| ctx.db().llen(&bulk_to_string(&data[0])?).map(|n| RESPResult::Integer(n as i64)),
+        },
+        CommandSpec {
+            name: "lrange", arity: 4, flags: F::READONLY,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@read", "@list", "@slow"],
+            summary: "Returns a range of elements from a list.",
+            since: "1.0.0", group: "list",
+            handler: |ctx, data| lrange_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "lindex", arity: 3, flags: F::READONLY,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@read", "@list", "@slow"],
+            summary: "Returns an element from a list by its index.",
+            since: "1.0.0", group: "list",
+            handler: |ctx, data| lindex_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "lset", arity: 4, flags: F::WRITE | F::DENYOOM,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@list", "@slow"],
+            summary: "Sets the value of an element in a list by its index.",
+            since: "1.0.0", group: "list",
+            handler: |ctx, data| lset_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "linsert", arity: 5, flags: F::WRITE | F::DENYOOM,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@list", "@slow"],
+            summary: "Inserts an element before or after another element in a list.",
+            since: "2.2.0", group: "list",
+            handler: |ctx, data| linsert_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "lrem", arity: 4, flags: F::WRITE,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@list", "@slow"],
+            summary: "Removes elements from a list. Deletes the list if the last element was removed.",
+            since: "1.0.0", group: "list",
+            handler: |ctx, data| lrem_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "ltrim", arity: 4, flags: F::WRITE,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@list", "@slow"],
+            summary: "Removes elements from both ends a list. Deletes the list if all elements were trimmed.",
+            since: "1.0.0", group: "list",
+            handler: |ctx, data| ltrim_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "lpos", arity: -3, flags: F::READONLY,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@read", "@list", "@slow"],
+            summary: "Returns the index of matching elements in a list.",
+            since: "6.0.6", group: "list",
+            handler: |ctx, data| lpos_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "lmove", arity: 5, flags: F::WRITE | F::DENYOOM,
+            first_key: 1, last_key: 2, step: 1, keys: None,
+            acl_categories: &["@write", "@list", "@slow"],
+            summary: "Returns an element after popping it from one list and pushing it to another. Deletes the list if the last element was moved.",
+            since: "6.2.0", group: "list",
+            handler: |ctx, data| lmove_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "rpoplpush", arity: 3, flags: F::WRITE | F::DENYOOM,
+            first_key: 1, last_key: 2, step: 1, keys: None,
+            acl_categories: &["@write", "@list", "@slow"],
+            summary: "Returns the last element of a list after removing and pushing it to another list. Deletes the list if the last element was popped.",
+            since: "1.2.0", group: "list",
+            handler: |ctx, data| {
+                let (src, dst) = (bulk_to_string(&data[0])?, bulk_to_string(&data[1])?);
+                ctx.db().lmove(&src, &dst, false, true).map(|v| bulk_reply(v.and_then(|v| v.bytes())))
+            },
+        },
+        CommandSpec {
+            name: "blpop", arity: -3, flags: F::WRITE | F::BLOCKING,
+            first_key: 1, last_key: -2, step: 1, keys: None,
+            acl_categories: &["@write", "@list", "@slow", "@blocking"],
+            summary: "Removes and returns the first element in a list. Blocks until an element is available otherwise. Deletes the list if the last element was popped.",
+            since: "2.0.0", group: "list",
+            handler: |ctx, data| bpop_command(ctx, data, true),
+        },
+        CommandSpec {
+            name: "brpop", arity: -3, flags: F::WRITE | F::BLOCKING,
+            first_key: 1, last_key: -2, step: 1, keys: None,
+            acl_categories: &["@write", "@list", "@slow", "@blocking"],
+            summary: "Removes and returns the last element in a list. Blocks until an element is available otherwise. Deletes the list if the last element was popped.",
+            since: "2.0.0", group: "list",
+            handler: |ctx, data| bpop_command(ctx, data, false),
+        },
+        CommandSpec {
+            name: "blmove", arity: 6, flags: F::WRITE | F::DENYOOM | F::BLOCKING,
+            first_key: 1, last_key: 2, step: 1, keys: None,
+            acl_categories: &["@write", "@list", "@slow", "@blocking"],
+            summary: "Pops an element from a list, pushes it to another list and returns it. Blocks until an element is available otherwise. Deletes the list if the last element was moved.",
+            since: "6.2.0", group: "list",
+            handler: blmove_command,
+        },
+        CommandSpec {
+            name: "blmpop", arity: -5, flags: F::WRITE | F::BLOCKING,
+            first_key: 0, last_key: 0, step: 0, keys: Some(eval_keys),
+            acl_categories: &["@write", "@list", "@slow", "@blocking"],
+            summary: "Pops the first element from one of multiple lists. Blocks until an element is available otherwise. Deletes the list if the last element was popped.",
+            since: "7.0.0", group: "list",
+            handler: blmpop_command,
+        },
+        CommandSpec {
+            name: "save", arity: 1, flags: F::ADMIN | F::NOSCRIPT,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@admin", "@slow", "@dangerous"],
+            summary: "Synchronously saves the database(s) to disk.",
+            since: "1.0.0", group: "server",
+            handler: |ctx, _| save_command(ctx.server()).map(RESPResult::SimpleString),
+        },
+        CommandSpec {
+            name: "load", arity: 2, flags: F::WRITE | F::ADMIN | F::NOSCRIPT,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@admin", "@slow", "@dangerous"],
+            summary: "Replaces the dataset with the contents of a snapshot file.",
+            since: "0.1.0", group: "server",
+            handler: |ctx, data| load_command(ctx.server(), data).map(RESPResult::SimpleString),
+        },
+        CommandSpec {
+            name: "module", arity: -2, flags: F::ADMIN | F::NOSCRIPT,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@admin", "@slow", "@dangerous"],
+            summary: "Loads, lists and unloads modules.",
+            since: "4.0.0", group: "server",
+            handler: module::module_command,
+        },
+        CommandSpec {
+            name: "eval", arity: -3, flags: F::NOSCRIPT | F::RUNS_SCRIPT,
+            first_key: 0, last_key: 0, step: 0, keys: Some(eval_keys),
+            acl_categories: &["@slow", "@scripting"],
+            summary: "Executes a server-side Lua script.",
+            since: "2.6.0", group: "scripting",
+            handler: scripting::eval_command,
+        },
+        CommandSpec {
+            name: "eval_ro", arity: -3, flags: F::READONLY | F::NOSCRIPT | F::RUNS_SCRIPT,
+            first_key: 0, last_key: 0, step: 0, keys: Some(eval_keys),
+            acl_categories: &["@slow", "@scripting"],
+            summary: "Executes a read-only server-side Lua script.",
+            since: "7.0.0", group: "scripting",
+            handler: scripting::eval_ro_command,
+        },
+        CommandSpec {
+            name: "evalsha", arity: -3, flags: F::NOSCRIPT | F::RUNS_SCRIPT,
+            first_key: 0, last_key: 0, step: 0, keys: Some(eval_keys),
+            acl_categories: &["@slow", "@scripting"],
+            summary: "Executes a server-side Lua script by SHA1 digest.",
+            since: "2.6.0", group: "scripting",
+            handler: scripting::evalsha_command,
+        },
+        CommandSpec {
+            name: "evalsha_ro", arity: -3, flags: F::READONLY | F::NOSCRIPT | F::RUNS_SCRIPT,
+            first_key: 0, last_key: 0, step: 0, keys: Some(eval_keys),
+            acl_categories: &["@slow", "@scripting"],
+            summary: "Executes a read-only server-side Lua script by SHA1 digest.",
+            since: "7.0.0", group: "scripting",
+            handler: scripting::evalsha_ro_command,
+        },
+        CommandSpec {
+            name: "script", arity: -2, flags: F::NOSCRIPT,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@slow", "@scripting"],
+            summary: "Loads, checks, flushes and kills Lua scripts.",
+            since: "2.6.0", group: "scripting",
+            handler: scripting::script_command,
+        },
+        CommandSpec {
+            name: "fcall", arity: -3, flags: F::NOSCRIPT | F::RUNS_SCRIPT,
+            first_key: 0, last_key: 0, step: 0, keys: Some(eval_keys),
+            acl_categories: &["@slow", "@scripting"],
+            summary: "Invokes a function.",
+            since: "7.0.0", group: "scripting",
+            handler: scripting::fcall_command,
+        },
+        CommandSpec {
+            name: "fcall_ro", arity: -3, flags: F::READONLY | F::NOSCRIPT | F::RUNS_SCRIPT,
+            first_key: 0, last_key: 0, step: 0, keys: Some(eval_keys),
+            acl_categories: &["@slow", "@scripting"],
+            summary: "Invokes a read-only function.",
+            since: "7.0.0", group: "scripting",
+            handler: scripting::fcall_ro_command,
+        },
+        CommandSpec {
+            name: "function", arity: -2, flags: F::NOSCRIPT,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@slow", "@scripting"],
+            summary: "Loads, lists, deletes, dumps and restores function libraries.",
+            since: "7.0.0", group: "scripting",
+            handler: scripting::function_command,
+        },
+        CommandSpec {
+            name: "command", arity: -1, flags: F::NONE,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@slow", "@connection"],
+            summary: "Returns detailed information about all commands.",
+            since: "2.8.13", group: "server",
+            handler: command_command,
+        },
+        CommandSpec {
+            name: "select", arity: 2, flags: F::FAST,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@fast", "@connection"],
+            summary: "Changes the selected database.",
+            since: "1.0.0", group: "connection",
+            handler: select_command,
+        },
+        CommandSpec {
+            name: "move", arity: 3, flags: F::WRITE | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@write", "@fast"],
+            summary: "Moves a key to another database.",
+            since: "1.0.0", group: "generic",
+            handler: move_command,
+        },
+        CommandSpec {
+            name: "swapdb", arity: 3, flags: F::WRITE | F::FAST,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@keyspace", "@write", "@fast", "@dangerous"],
+            summary: "Swaps two Redis databases.",
+            since: "4.0.0", group: "server",
+            handler: swapdb_command,
+        },
+        CommandSpec {
+            name: "flushdb", arity: -1, flags: F::WRITE,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@keyspace", "@write", "@slow", "@dangerous"],
+            summary: "Removes all keys from the current database.",
+            since: "1.0.0", group: "server",
+            handler: flushdb_command,
+        },
+        CommandSpec {
+            name: "flushall", arity: -1, flags: F::WRITE,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@keyspace", "@write", "@slow", "@dangerous"],
+            summary: "Removes all keys from all databases.",
+            since: "1.0.0", group: "server",
+            handler: flushall_command,
+        },
+        CommandSpec {
+            name: "dbsize", arity: 1, flags: F::READONLY | F::FAST,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@keyspace", "@read", "@fast"],
+            summary: "Returns the number of keys in the database.",
+            since: "1.0.0", group: "server",
+            handler: |ctx, _| Ok(RESPResult::Integer(ctx.db().len() as i64)),
+        },
+        CommandSpec {
+            name: "keys", arity: 2, flags: F::READONLY,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@keyspace", "@read", "@slow", "@dangerous"],
+            summary: "Returns all key names that match a pattern.",
+            since: "1.0.0", group: "generic",
+            handler: |ctx, data| Ok(key_list(ctx.db().keys(&bulk_to_string(&data[0])?))),
+        },
+        CommandSpec {
+            name: "scan", arity: -2, flags: F::READONLY,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@keyspace", "@read", "@slow"],
+            summary: "Iterates over the key names in the database.",
+            since: "2.8.0", group: "generic",
+            handler: |ctx, data| scan_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "type", arity: 2, flags: F::READONLY | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@read", "@fast"],
+            summary: "Determines the type of value stored at a key.",
+            since: "1.0.0", group: "generic",
+            handler: |ctx, data| {
+                let name = ctx.db().type_of(&bulk_to_string(&data[0])?);
+                Ok(RESPResult::SimpleString(name.unwrap_or_else(|| "none".to_string())))
+            },
+        },
+        CommandSpec {
+            name: "rename", arity: 3, flags: F::WRITE,
+            first_key: 1, last_key: 2, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@write", "@slow"],
+            summary: "Renames a key and overwrites the destination.",
+            since: "1.0.0", group: "generic",
+            handler: |ctx, data| {
+                ctx.db().rename(&bulk_to_string(&data[0])?, &bulk_to_string(&data[1])?, false)?;
+                Ok(RESPResult::SimpleString("OK".to_string()))
+            },
+        },
+        CommandSpec {
+            name: "renamenx", arity: 3, flags: F::WRITE | F::FAST,
+            first_key: 1, last_key: 2, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@write", "@fast"],
+            summary: "Renames a key only when the target key name doesn't exist.",
+            since: "1.0.0", group: "generic",
+            handler: |ctx, data| {
+                let renamed = ctx.db().rename(&bulk_to_string(&data[0])?, &bulk_to_string(&data[1])?, true)?;
+                Ok(RESPResult::Integer(renamed as i64))
+            },
+        },
+        CommandSpec {
+            name: "randomkey", arity: 1, flags: F::READONLY,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@keyspace", "@read", "@slow"],
+            summary: "Returns a random key name from the database.",
+            since: "1.0.0", group: "generic",
+            handler: |ctx, _| Ok(RESPResult::BulkString(ctx.db().random_key().map(String::into_bytes))),
+        },
+        CommandSpec {
+            name: "copy", arity: -3, flags: F::WRITE | F::DENYOOM,
+            first_key: 1, last_key: 2, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@write", "@slow"],
+            summary: "Copies the value of a key to a new key.",
+            since: "6.2.0", group: "generic",
+            handler: copy_command,
+        },
+        CommandSpec {
+            name: "touch", arity: -2, flags: F::READONLY | F::FAST,
+            first_key: 1, last_key: -1, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@read", "@fast"],
+            summary: "Returns the number of existing keys out of those specified after updating the time they were last accessed.",
+            since: "3.2.1", group: "generic",
+            handler: |ctx, data| exists_command(ctx.db(), data).map(|i| RESPResult::Integer(i as i64)),
+        },
+        CommandSpec {
+            name: "unlink", arity: -2, flags: F::WRITE | F::FAST,
+            first_key: 1, last_key: -1, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@write", "@fast"],
+            summary: "Asynchronously deletes one or more keys.",
+            since: "4.0.0", group: "generic",
+            handler: |ctx, data| delete_command(ctx.db(), data).map(|i| RESPResult::Integer(i as i64)),
+        },
+        CommandSpec {
+            name: "expire", arity: -3, flags: F::WRITE | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@write", "@fast"],
+            summary: "Sets the expiration time of a key in seconds.",
+            since: "1.0.0", group: "generic",
+            handler: |ctx, data| expire_command(ctx.db(), data, "expire", 1000, false),
+        },
+        CommandSpec {
+            name: "pexpire", arity: -3, flags: F::WRITE | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@write", "@fast"],
+            summary: "Sets the expiration time of a key in milliseconds.",
+            since: "2.6.0", group: "generic",
+            handler: |ctx, data| expire_command(ctx.db(), data, "pexpire", 1, false),
+        },
+        CommandSpec {
+            name: "expireat", arity: -3, flags: F::WRITE | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@write", "@fast"],
+            summary: "Sets the expiration time of a key to a Unix timestamp.",
+            since: "1.2.0", group: "generic",
+            handler: |ctx, data| expire_command(ctx.db(), data, "expireat", 1000, true),
+        },
+        CommandSpec {
+            name: "pexpireat", arity: -3, flags: F::WRITE | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@write", "@fast"],
+            summary: "Sets the expiration time of a key to a Unix milliseconds timestamp.",
+            since: "2.6.0", group: "generic",
+            handler: |ctx, data| expire_command(ctx.db(), data, "pexpireat", 1, true),
+        },
+        CommandSpec {
+            name: "ttl", arity: 2, flags: F::READONLY | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@read", "@fast"],
+            summary: "Returns the expiration time in seconds of a key.",
+            since: "1.0.0", group: "generic",
+            handler: |ctx, data| ttl_command(ctx.db(), data, false, false),
+        },
+        CommandSpec {
+            name: "pttl", arity: 2, flags: F::READONLY | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@read", "@fast"],
+            summary: "Returns the expiration time in milliseconds of a key.",
+            since: "2.6.0", group: "generic",
+            handler: |ctx, data| ttl_command(ctx.db(), data, true, false),
+        },
+        CommandSpec {
+            name: "expiretime", arity: 2, flags: F::READONLY | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@read", "@fast"],
+            summary: "Returns the expiration time of a key as a Unix timestamp.",
+            since: "7.0.0", group: "generic",
+            handler: |ctx, data| ttl_command(ctx.db(), data, false, true),
+        },
+        CommandSpec {
+            name: "pexpiretime", arity: 2, flags: F::READONLY | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@read", "@fast"],
+            summary: "Returns the expiration time of a key as a Unix milliseconds timestamp.",
+            since: "7.0.0", group: "generic",
+            handler: |ctx, data| ttl_command(ctx.db(), data, true, true),
+        },
+        CommandSpec {
+            name: "persist", arity: 2, flags: F::WRITE | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@keyspace", "@write", "@fast"],
+            summary: "Removes the expiration time of a key.",
+            since: "2.2.0", group: "generic",
+            handler: |ctx, data| Ok(RESPResult::Integer(ctx.db().persist(&bulk_to_string(&data[0])?) as i64)),
+        },
+        CommandSpec {
+            name: "info", arity: -1, flags: F::NONE,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@slow", "@dangerous"],
+            summary: "Returns information and statistics about the server.",
+            since: "1.0.0", group: "server",
+            handler: |ctx, data| info_command(ctx.server(), data),
+        },
+        CommandSpec {
+            name: "memory", arity: -2, flags: F::NONE,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@slow"],
+            summary: "A container for memory diagnostics commands.",
+            since: "4.0.0", group: "server",
+            handler: memory_command,
+        },
+        CommandSpec {
+            name: "config", arity: -2, flags: F::ADMIN | F::NOSCRIPT,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@admin", "@slow", "@dangerous"],
+            summary: "A container for server configuration commands.",
+            since: "2.0.0", group: "server",
+            handler: config_command,
+        },
+        CommandSpec {
+            name: "object", arity: -2, flags: F::READONLY,
+            first_key: 0, last_key: 0, step: 0, keys: None,
+            acl_categories: &["@keyspace", "@read", "@slow"],
+            summary: "A container for object introspection commands.",
+            since: "2.2.3", group: "generic",
+            handler: |ctx, data| object_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "append", arity: 3, flags: F::WRITE | F::DENYOOM | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@string", "@fast"],
+            summary: "Appends a string to the value of a key. Creates the key if it doesn't exist.",
+            since: "2.0.0", group: "string",
+            handler: |ctx, data| {
+                let len = ctx.db().append(&bulk_to_string(&data[0])?, &bulk_to_bytes(&data[1])?)?;
+                Ok(RESPResult::Integer(len as i64))
+            },
+        },
+        CommandSpec {
+            name: "strlen", arity: 2, flags: F::READONLY | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@read", "@string", "@fast"],
+            summary: "Returns the length of a string value.",
+            since: "2.2.0", group: "string",
+            handler: |ctx, data| Ok(RESPResult::Integer(ctx.db().strlen(&bulk_to_string(&data[0])?)? as i64)),
+        },
+        CommandSpec {
+            name: "getrange", arity: 4, flags: F::READONLY,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@read", "@string", "@slow"],
+            summary: "Returns a substring of the string stored at a key.",
+            since: "2.4.0", group: "string",
+            handler: |ctx, data| {
+                let start = integer_arg(&data[1])?;
+                let end = integer_arg(&data[2])?;
+                let range = ctx.db().getrange(&bulk_to_string(&data[0])?, start, end)?;
+                Ok(bulk_reply(Some(range)))
+            },
+        },
+        CommandSpec {
+            name: "setrange", arity: 4, flags: F::WRITE | F::DENYOOM,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@string", "@slow"],
+            summary: "Overwrites a part of a string value with another by an offset. Creates the key if it doesn't exist.",
+            since: "2.2.0", group: "string",
+            handler: |ctx, data| setrange_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "getset", arity: 3, flags: F::WRITE | F::DENYOOM | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@string", "@fast"],
+            summary: "Returns the previous string value of a key after setting it to a new value.",
+            since: "1.0.0", group: "string",
+            handler: |ctx, data| {
+                let value = DB_TYPE::string(bulk_to_bytes(&data[1])?);
+                ctx.db().getset(&bulk_to_string(&data[0])?, value).map(bulk_reply)
+            },
+        },
+        CommandSpec {
+            name: "getdel", arity: 2, flags: F::WRITE | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@string", "@fast"],
+            summary: "Returns the string value of a key after deleting the key.",
+            since: "6.2.0", group: "string",
+            handler: |ctx, data| ctx.db().getdel(&bulk_to_string(&data[0])?).map(bulk_reply),
+        },
+        CommandSpec {
+            name: "getex", arity: -2, flags: F::WRITE | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@string", "@fast"],
+            summary: "Returns the string value of a key after setting its expiration time.",
+            since: "6.2.0", group: "string",
+            handler: |ctx, data| getex_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "mget", arity: -2, flags: F::READONLY | F::FAST,
+            first_key: 1, last_key: -1, step: 1, keys: None,
+            acl_categories: &["@read", "@string", "@fast"],
+            summary: "Atomically returns the string values of one or more keys.",
+            since: "1.0.0", group: "string",
+            handler: |ctx, data| {
+                let keys = data.iter().map(bulk_to_string).collect::<Result<Vec<_>, _>>()?;
+                // anything that is not a string reads as nil
+                let values = ctx.db().mget(&keys).into_iter()
+                    .map(|v| bulk_reply(v.and_then(|v| v.bytes())))
+                    .collect();
+                Ok(RESPResult::Array(values))
+            },
+        },
+        CommandSpec {
+            name: "mset", arity: -3, flags: F::WRITE | F::DENYOOM,
+            first_key: 1, last_key: -1, step: 2, keys: None,
+            acl_categories: &["@write", "@string", "@slow"],
+            summary: "Atomically creates or modifies the string values of one or more keys.",
+            since: "1.0.1", group: "string",
+            handler: |ctx, data| {
+                ctx.db().mset(key_value_pairs(data, "mset")?, false);
+                Ok(RESPResult::SimpleString("OK".to_string()))
+            },
+        },
+        CommandSpec {
+            name: "msetnx", arity: -3, flags: F::WRITE | F::DENYOOM,
+            first_key: 1, last_key: -1, step: 2, keys: None,
+            acl_categories: &["@write", "@string", "@slow"],
+            summary: "Atomically modifies the string values of one or more keys only when all keys don't exist.",
+            since: "1.0.1", group: "string",
+            handler: |ctx, data| {
+                let set = ctx.db().mset(key_value_pairs(data, "msetnx")?, true);
+                Ok(RESPResult::Integer(set as i64))
+            },
+        },
+        CommandSpec {
+            name: "setnx", arity: 3, flags: F::WRITE | F::DENYOOM | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@string", "@fast"],
+            summary: "Set the string value of a key only when the key doesn't exist.",
+            since: "1.0.0", group: "string",
+            handler: |ctx, data| {
+                let set = ctx.db().mset(key_value_pairs(data, "setnx")?, true);
+                Ok(RESPResult::Integer(set as i64))
+            },
+        },
+        CommandSpec {
+            name: "setex", arity: 4, flags: F::WRITE | F::DENYOOM,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@string", "@slow"],
+            summary: "Sets the string value and expiration time of a key. Creates the key if it doesn't exist.",
+            since: "2.0.0", group: "string",
+            handler: |ctx, data| setex_command(ctx.db(), data, "setex", 1000),
+        },
+        CommandSpec {
+            name: "psetex", arity: 4, flags: F::WRITE | F::DENYOOM,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@string", "@slow"],
+            summary: "Sets both string value and expiration time in milliseconds of a key. The key is created if it doesn't exist.",
+            since: "2.6.0", group: "string",
+            handler: |ctx, data| setex_command(ctx.db(), data, "psetex", 1),
+        },
+        CommandSpec {
+            name: "lcs", arity: -3, flags: F::READONLY,
+            first_key: 1, last_key: 2, step: 1, keys: None,
+            acl_categories: &["@read", "@string", "@slow"],
+            summary: "Finds the longest common substring.",
+            since: "7.0.0", group: "string",
+            handler: |ctx, data| lcs_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "setbit", arity: 4, flags: F::WRITE | F::DENYOOM,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@bitmap", "@slow"],
+            summary: "Sets or clears the bit at offset of the string value. Creates the key if it doesn't exist.",
+            since: "2.2.0", group: "bitmap",
+            handler: |ctx, data| setbit_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "getbit", arity: 3, flags: F::READONLY | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@read", "@bitmap", "@fast"],
+            summary: "Returns a bit value by offset.",
+            since: "2.2.0", group: "bitmap",
+            handler: |ctx, data| {
+                let offset = bit_offset(&data[1])?;
+                let bytes = ctx.db().get_string(&bulk_to_string(&data[0])?)?.unwrap_or_default();
+                Ok(RESPResult::Integer(bitops::get_bit(&bytes, offset) as i64))
+            },
+        },
+        CommandSpec {
+            name: "bitcount", arity: -2, flags: F::READONLY,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@read", "@bitmap", "@slow"],
+            summary: "Counts the number of set bits (population counting) in a string.",
+            since: "2.6.0", group: "bitmap",
+            handler: |ctx, data| bitcount_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "bitpos", arity: -3, flags: F::READONLY,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@read", "@bitmap", "@slow"],
+            summary: "Finds the first set (1) or clear (0) bit in a string.",
+            since: "2.8.7", group: "bitmap",
+            handler: |ctx, data| bitpos_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "bitop", arity: -4, flags: F::WRITE | F::DENYOOM,
+            first_key: 2, last_key: -1, step: 1, keys: None,
+            acl_categories: &["@write", "@bitmap", "@slow"],
+            summary: "Performs bitwise operations on multiple strings, and stores the result.",
+            since: "2.6.0", group: "bitmap",
+            handler: |ctx, data| bitop_command(ctx.db(), data),
+        },
+        CommandSpec {
+            name: "bitfield", arity: -2, flags: F::WRITE | F::DENYOOM,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@write", "@bitmap", "@slow"],
+            summary: "Performs arbitrary bitfield integer operations on strings.",
+            since: "3.2.0", group: "bitmap",
+            handler: |ctx, data| bitfield_command(ctx.db(), data, false),
+        },
+        CommandSpec {
+            name: "bitfield_ro", arity: -2, flags: F::READONLY | F::FAST,
+            first_key: 1, last_key: 1, step: 1, keys: None,
+            acl_categories: &["@read", "@bitmap", "@fast"],
+            summary: "Performs arbitrary read-only bitfield integer operations on strings.",
+            since: "6.0.0", group: "bitmap",
+            handler: |ctx, data| bitfield_command(ctx.db(), data, true),
+        },
+    ]
+}
+
+// runs a command outside of any connection, so against database 0
+pub fn command_router(server: &Server, command: &str, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    command_router_in(server, &mut Session::new(), command, data)
+}
+
+pub fn command_router_in(server: &Server, session: &mut Session, command: &str, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    // data holds the arguments only, arity counts the command name too
+    let spec = lookup(server, command, data.len() + 1)?;
+
+    // commands wait for a running script, SCRIPT KILL has to get past it
+    let script_kill = spec.name() == "script"
+        && matches!(data.first(), Some(RESPResult::BulkString(Some(b))) if b.eq_ignore_ascii_case(b"kill"));
+    let _permit = if spec.flags().contains(CommandFlags::RUNS_SCRIPT) || script_kill {
+        None
+    }
+    else {
+        Some(server.scripting().enter()?)
+    };
+
+    // make room first, commands that add data fail if there is none
+    if !evict::free_memory(server) && spec.flags().contains(CommandFlags::DENYOOM) {
+        return Err(Error::Oom);
+    }
+
+    let result = spec.execute(&mut Context::new(server, session), data);
+    if result.is_ok() {
+        signal_written(server, session.db(), spec.as_ref(), data);
+    }
+    result
+}
+
+// wakes clients blocked on the keys a write command was given
+pub(crate) fn signal_written(server: &Server, db: usize, spec: &(dyn Command + 'static), data: &[RESPResult]) {
+    if server.blocking().blocked_clients() == 0 || !spec.flags().contains(CommandFlags::WRITE) {
+        return;
+    }
+
+    let keys: Vec<String> = spec
+        .key_positions(data)
+        .into_iter()
+        .filter_map(|i| bulk_to_string(&data[i - 1]).ok())
+        .collect();
+    server.blocking().signal(server, db, &keys);
+}
+
+// finds a command and checks its arity, argc includes the command name
+pub(crate) fn lookup(server: &Server, command: &str, argc: usize) -> Result<Arc<dyn Command>, Error> {
+    // the table lock is released before running, so commands may register others
+    let spec = match server.commands().get(command) {
+        Some(spec) => spec,
+        None => return Err(Error::UnknownCommand(command.to_string())),
+    };
+
+    if !spec.accepts(argc) {
+        return Err(Error::WrongArity(spec.name().to_string()));
+    }
+
+    Ok(spec)
+}
+
+fn command_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let table = ctx.server().commands();
+
+    // plain COMMAND lists every command
+    if data.is_empty() {
+        return Ok(RESPResult::Array(table.iter().map(|c| c.info()).collect()));
+    }
+
+    let subcommand = bulk_to_string(&data[0])?.to_uppercase();
+    let args = &data[1..];
+
+    match subcommand.as_str() {
+        "COUNT" if args.is_empty() => Ok(RESPResult::Integer(table.len() as i64)),
+        "INFO" if args.is_empty() => Ok(RESPResult::Array(table.iter().map(|c| c.info()).collect())),
+        "INFO" => {
+            // unknown commands are reported as nil
+            let mut replies = Vec::new();
+            for name in args {
+                match table.get(&bulk_to_string(name)?) {
+                    Some(c) => replies.push(c.info()),
+                    None => replies.push(RESPResult::BulkString(None)),
+                }
+            }
+            Ok(RESPResult::Array(replies))
+        },
+        "DOCS" => {
+            // a flat map of name to docs, unknown commands are left out
+            let mut replies = Vec::new();
+            let specs: Vec<Arc<dyn Command>> = if args.is_empty() {
+                table.iter().cloned().collect()
+            }
+            else {
+                let mut specs = Vec::new();
+                for name in args {
+                    if let Some(c) = table.get(&bulk_to_string(name)?) {
+                        specs.push(c);
+                    }
+                }
+                specs
+            };
+            for c in specs {
+                replies.push(RESPResult::BulkString(Some(c.name().as_bytes().to_vec())));
+                replies.push(c.docs());
+            }
+            Ok(RESPResult::Array(replies))
+        },
+        "GETKEYS" if !args.is_empty() => {
+            let spec = match table.get(&bulk_to_string(&args[0])?) {
+                Some(c) => c,
+                None => return Err(Error::Other("Invalid command specified".to_string())),
+            };
+
+            if !spec.accepts(args.len()) {
+                return Err(Error::Other("Invalid number of arguments specified for command".to_string()));
+            }
+
+            let positions = spec.key_positions(&args[1..]);
+            if positions.is_empty() {
+                return Err(Error::Other("The command has no key arguments".to_string()));
+            }
+
+            let mut keys = Vec::new();
+            for i in positions {
+                keys.push(RESPResult::BulkString(Some(bulk_to_bytes(&args[i])?)));
+            }
+            Ok(RESPResult::Array(keys))
+        },
+        "COUNT" | "GETKEYS" => Err(Error::WrongArity(format!("command|{}", subcommand.to_lowercase()))),
+        _ => Err(Error::Other(format!("unknown subcommand '{}'. Try COMMAND HELP.", subcommand.to_lowercase()))),
+    }
+}
+
+// the keys counted by a numkeys argument at position at of argv, where args
+// leave out the command name
+fn numkeys_positions(args: &[RESPResult], at: usize) -> Vec<usize> {
+    let n = match args.get(at - 1).map(integer_arg) {
+        Some(Ok(n)) if n > 0 => n as usize,
+        _ => return Vec::new(),
+    };
+    (at + 1..=at + n).take_while(|&i| i <= args.len()).collect()
+}
+
+// EVAL script numkeys key ..., and the same shape of FCALL and BLMPOP
+fn eval_keys(args: &[RESPResult]) -> Vec<usize> {
+    numkeys_positions(args, 2)
+}
+
+// LMPOP numkeys key ...
+fn lmpop_keys(args: &[RESPResult]) -> Vec<usize> {
+    numkeys_positions(args, 1)
+}
+
+// keys are stored as text, so an argument that is not UTF-8 is refused
+// rather than rewritten into a different key
+pub(crate) fn bulk_to_string(value: &RESPResult) -> Result<String, Error> {
+    match value {
+        RESPResult::BulkString(Some(message)) => match std::str::from_utf8(message) {
+            Ok(s) => Ok(s.to_string()),
+            Err(e) => Err(Error::Other(format!("Invalid UTF-8: {}", e))),
+        },
+        _ => Err(Error::Protocol("expected bulk string".to_string())),
+    }
+}
+
+// a string argument as given, for values that may hold any bytes
+pub(crate) fn bulk_to_bytes(value: &RESPResult) -> Result<Vec<u8>, Error> {
+    match value {
+        RESPResult::BulkString(Some(message)) => Ok(message.clone()),
+        _ => Err(Error::Protocol("expected bulk string".to_string())),
+    }
+}
 
-pub fn command_router(command: &str, data: &[RESPResult]) -> Result<RESPResult, String> {
-    if command == "ECHO" {
-        match echo_command(data) {
-            Ok(s) => Ok(RESPResult::SimpleString(s)),
-            Err(e) => Err(e)
-        }
+fn echo_command(data: &[RESPResult]) -> Result<String, Error> {
+    let message_bulk_string = match &data[0] {
+        RESPResult::BulkString(Some(message)) => message,
+        _ => return Err(Error::Protocol("expected bulk string".to_string())),
+    };
+
+    match std::str::from_utf8(message_bulk_string) {
+        Ok(s) => Ok(s.to_string()), 
+        Err(e) => Err(Error::Other(format!("Invalid UTF-8: {}", e))),
     }
-    else if command == "SET" {
-        match set_command(data) {
-            Ok(s) => Ok(RESPResult::SimpleString(s)),
-            Err(e) => Err(e)
+}
+
+// SET key value [NX | XX] [GET] [EX seconds | PX milliseconds |
+// EXAT unix-time-seconds | PXAT unix-time-milliseconds | KEEPTTL]
+fn set_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let value = DB_TYPE::string(bulk_to_bytes(&data[1])?);
+
+    let (mut nx, mut xx, mut get) = (false, false, false);
+    // the expire option with its argument, at most one of them
+    let mut expire: Option<(String, i64)> = None;
+    let mut keep_ttl = false;
+
+    let mut i = 2;
+    while i < data.len() {
+        let option = bulk_to_string(&data[i])?.to_uppercase();
+        match option.as_str() {
+            "NX" if !xx => nx = true,
+            "XX" if !nx => xx = true,
+            "GET" => get = true,
+            "KEEPTTL" if expire.is_none() => keep_ttl = true,
+            "EX" | "PX" | "EXAT" | "PXAT" if expire.is_none() && !keep_ttl && i + 1 < data.len() => {
+                expire = Some((option, integer_arg(&data[i + 1])?));
+                i += 1;
+            },
+            _ => return Err(Error::Syntax),
         }
+        i += 1;
     }
-    else if command == "GET" {
-        match get_command(data) {
-            Ok(s) => Ok(RESPResult::SimpleString(s)),
-            Err(e) => Err(e)
+
+    let expire = match expire {
+        Some((option, n)) => Some(expire_option(&option, n, "set")?),
+        None if keep_ttl => None,
+        // a plain overwrite drops the old expire
+        None => Some(0),
+    };
+
+    let mut old = None;
+    let set = db.set_if(&key, value, expire, |current| {
+        if get && let Some(v) = current {
+            old = Some(v.bytes().ok_or(Error::WrongType)?);
         }
+        Ok(match current {
+            Some(_) => !nx,
+            None => !xx,
+        })
+    })?;
+
+    if get {
+        return Ok(bulk_reply(old));
     }
-    else if command == "PING" {
-        Ok(RESPResult::SimpleString("PONG".to_string()))
+    if set {
+        Ok(RESPResult::SimpleString("OK".to_string()))
+    } else {
+        Ok(bulk_reply(None))
     }
-    else if command == "EXISTS" {
-        match exists_command(data) {
-            Ok(i) => Ok(RESPResult::Integer(i as i64)),
-            Err(e) => Err(e)
-        }
+}
+
+fn get_command(db: &Db, data: &[RESPResult]) -> Result<Option<Vec<u8>>, Error> {
+    db.get_string(&bulk_to_string(&data[0])?)
+}
+
+fn bulk_reply(value: Option<Vec<u8>>) -> RESPResult {
+    RESPResult::BulkString(value)
+}
+
+fn integer_arg(value: &RESPResult) -> Result<i64, Error> {
+    bulk_to_string(value)?.parse().map_err(|_| Error::NotAnInteger)
+}
+
+// the key value pairs of MSET, MSETNX and SETNX
+fn key_value_pairs(data: &[RESPResult], name: &str) -> Result<Vec<(String, DB_TYPE)>, Error> {
+    if !data.len().is_multiple_of(2) {
+        return Err(Error::WrongArity(name.to_string()));
     }
-    else if command == "DEL" {
-        match delete_command(data) {
-            Ok(i) => Ok(RESPResult::Integer(i as i64)),
-            Err(e) => Err(e)
-        }
+
+    data.chunks(2)
+        .map(|pair| Ok((bulk_to_string(&pair[0])?, DB_TYPE::string(bulk_to_bytes(&pair[1])?))))
+        .collect()
+}
+
+fn setrange_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let offset = integer_arg(&data[1])?;
+    if offset < 0 {
+        return Err(Error::Other("offset is out of range".to_string()));
     }
-    else if command == "INCR" {
-        match increment_command(data) {
-            Ok(s) => Ok(RESPResult::SimpleString(s)),
-            Err(e) => Err(e)
-        }
+
+    let len = db.setrange(&key, offset as usize, &bulk_to_bytes(&data[2])?)?;
+    Ok(RESPResult::Integer(len as i64))
+}
+
+// SETEX and PSETEX, unit is the milliseconds per argument unit
+fn setex_command(db: &Db, data: &[RESPResult], name: &str, unit: i64) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let ttl = match integer_arg(&data[1])?.checked_mul(unit) {
+        Some(ms) if ms > 0 => ms as u64,
+        _ => return Err(Error::Other(format!("invalid expire time in '{name}' command"))),
+    };
+
+    db.set_with_ttl(&key, DB_TYPE::string(bulk_to_bytes(&data[2])?), Duration::from_millis(ttl));
+    Ok(RESPResult::SimpleString("OK".to_string()))
+}
+
+// the unix time in milliseconds meant by the EX, PX, EXAT or PXAT option
+// of SET and GETEX with argument n
+fn expire_option(option: &str, n: i64, name: &str) -> Result<u128, Error> {
+    let unit = if option == "EX" || option == "EXAT" { 1000 } else { 1 };
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as i64;
+
+    let when = match n.checked_mul(unit) {
+        Some(ms) if n > 0 && option.ends_with("AT") => Some(ms),
+        Some(ms) if n > 0 => ms.checked_add(now),
+        _ => None,
+    };
+    match when {
+        Some(when) => Ok(when as u128),
+        None => Err(Error::Other(format!("invalid expire time in '{name}' command"))),
     }
-    else if command == "DECR" {
-        match decrement_command(data) {
-            Ok(s) => Ok(RESPResult::SimpleString(s)),
-            Err(e) => Err(e)
+}
+
+// GETEX key [EX seconds | PX milliseconds | EXAT unix-time-seconds | PXAT unix-time-milliseconds | PERSIST]
+fn getex_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let option = match data.get(1) {
+        Some(arg) => bulk_to_string(arg)?.to_uppercase(),
+        None => return db.getex(&key, None).map(bulk_reply),
+    };
+
+    let expire = match (option.as_str(), data.len()) {
+        ("PERSIST", 2) => 0,
+        ("EX" | "PX" | "EXAT" | "PXAT", 3) => expire_option(&option, integer_arg(&data[2])?, "getex")?,
+        _ => return Err(Error::Syntax),
+    };
+
+    db.getex(&key, Some(expire)).map(bulk_reply)
+}
+
+// LCS key1 key2 [LEN] [IDX] [MINMATCHLEN len] [WITHMATCHLEN]
+// bytes the LCS table of IDX and plain LCS may take
+const LCS_MAX_TABLE: usize = 512 * 1024 * 1024;
+
+fn lcs_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let (mut len_only, mut idx, mut with_len, mut min_len) = (false, false, false, 0);
+
+    let mut i = 2;
+    while i < data.len() {
+        match bulk_to_string(&data[i])?.to_uppercase().as_str() {
+            "LEN" => len_only = true,
+            "IDX" => idx = true,
+            "WITHMATCHLEN" => with_len = true,
+            "MINMATCHLEN" if i + 1 < data.len() => {
+                min_len = integer_arg(&data[i + 1])?.max(0) as usize;
+                i += 1;
+            },
+            _ => return Err(Error::Syntax),
         }
+        i += 1;
     }
-    else if command == "LPUSH" {
-        match lpush_command(data) {
-            Ok(s) => Ok(RESPResult::SimpleString(s)),
-            Err(e) => Err(e)
+    if len_only && idx {
+        return Err(Error::Other("If you want both the length and indexes, please just use IDX.".to_string()));
+    }
+
+    // a missing key is an empty string
+    let mut strings = Vec::new();
+    for arg in &data[..2] {
+        match db.get_string(&bulk_to_string(arg)?) {
+            Ok(s) => strings.push(s.unwrap_or_default()),
+            Err(Error::WrongType) => return Err(Error::Other("The specified keys must contain string values".to_string())),
+            Err(e) => return Err(e),
         }
     }
-    else if command == "RPUSH" {
-        match rpush_command(data) {
-            Ok(s) => Ok(RESPResult::SimpleString(s)),
-            Err(e) => Err(e)
+    let (a, b) = (&strings[0], &strings[1]);
+
+    if len_only {
+        return Ok(RESPResult::Integer(lcs_len(a, b) as i64));
+    }
+
+    // the walk back needs the whole table, which like in Redis may take no
+    // more than proto-max-bulk-len bytes
+    let width = b.len() + 1;
+    let cells = (a.len() + 1).checked_mul(width).filter(|&n| n <= LCS_MAX_TABLE / std::mem::size_of::<u32>());
+    let Some(cells) = cells else {
+        return Err(Error::Other("Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len".to_string()));
+    };
+
+    // lengths[i * width + j] is the LCS length of the first i bytes of a and j of b
+    let mut lengths = vec![0u32; cells];
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            lengths[i * width + j] = if a[i - 1] == b[j - 1] {
+                lengths[(i - 1) * width + j - 1] + 1
+            } else {
+                lengths[(i - 1) * width + j].max(lengths[i * width + j - 1])
+            };
         }
     }
-    else if command == "SAVE" {
-        match save_command() {
-            Ok(s) => Ok(RESPResult::SimpleString(s)),
-            Err(e) => Err(e)
+
+    let total = lengths[cells - 1] as usize;
+
+    // walk back from the end, collecting the common bytes and, for IDX, the
+    // ranges of contiguous matches from last to first
+    let mut common = vec![0u8; total];
+    let mut matches = Vec::new();
+    let mut range: Option<(usize, usize, usize, usize)> = None;
+    let (mut i, mut j, mut k) = (a.len(), b.len(), total);
+    while i > 0 && j > 0 {
+        let emit;
+        if a[i - 1] == b[j - 1] {
+            common[k - 1] = a[i - 1];
+            range = match range {
+                Some((a_start, a_end, b_start, b_end)) => Some((a_start - 1, a_end, b_start - 1, b_end)),
+                None => Some((i - 1, i - 1, j - 1, j - 1)),
+            };
+            // a match with the first byte of either string is the last one
+            emit = i == 1 || j == 1;
+            i -= 1;
+            j -= 1;
+            k -= 1;
+        } else {
+            if lengths[(i - 1) * width + j] > lengths[i * width + j - 1] {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+            emit = range.is_some();
+        }
+
+        if emit && let Some((a_start, a_end, b_start, b_end)) = range.take() {
+            let len = a_end - a_start + 1;
+            if len >= min_len {
+                let mut m = vec![
+                    RESPResult::Array(vec![RESPResult::Integer(a_start as i64), RESPResult::Integer(a_end as i64)]),
+                    RESPResult::Array(vec![RESPResult::Integer(b_start as i64), RESPResult::Integer(b_end as i64)]),
+                ];
+                if with_len {
+                    m.push(RESPResult::Integer(len as i64));
+                }
+                matches.push(RESPResult::Array(m));
+            }
         }
     }
-    else if command == "LOAD" {
-        match load_command(data) {
-            Ok(s) => Ok(RESPResult::SimpleString(s)),
-            Err(e) => Err(e)
+
+    if !idx {
+        return Ok(bulk_reply(Some(common)));
+    }
+    Ok(RESPResult::Array(vec![
+        RESPResult::BulkString(Some(b"matches".to_vec())),
+        RESPResult::Array(matches),
+        RESPResult::BulkString(Some(b"len".to_vec())),
+        RESPResult::Integer(total as i64),
+    ]))
+}
+
+// the LCS length alone, from two rows as long as the shorter string
+fn lcs_len(a: &[u8], b: &[u8]) -> usize {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut previous = vec![0usize; short.len() + 1];
+    let mut row = vec![0usize; short.len() + 1];
+    for &x in long {
+        for j in 1..=short.len() {
+            row[j] = if x == short[j - 1] { previous[j - 1] + 1 } else { previous[j].max(row[j - 1]) };
         }
+        std::mem::swap(&mut previous, &mut row);
     }
-    else {
-        Err("panic".to_string())
+    previous[short.len()]
+}
+
+// a bit offset of SETBIT and GETBIT
+fn bit_offset(value: &RESPResult) -> Result<u64, Error> {
+    match bulk_to_string(value)?.parse::<u64>() {
+        Ok(offset) if offset < MAX_BITS => Ok(offset),
+        _ => Err(Error::Other("bit offset is not an integer or out of range".to_string())),
     }
 }
 
-fn echo_command(data: &[RESPResult]) -> Result<String, String> {    
-    if data.len() != 1 {
-        return Err("Incorrect number of arguments for echo".to_string());
+// the 0 or 1 argument of SETBIT and BITPOS
+fn bit_value(value: &RESPResult, error: &str) -> Result<bool, Error> {
+    match bulk_to_string(value)?.as_str() {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => Err(Error::Other(error.to_string())),
     }
+}
 
-    let message_bulk_string = match &data[0] {
-        RESPResult::BulkString(Some(message)) => message,
-        _ => return Err("Error: Not bulk string".to_string()),
+// the bits from start to end of BITCOUNT and BITPOS, which count in bytes
+// unless unit is BIT; None for an empty range
+fn bit_range(bytes: &[u8], start: i64, end: i64, unit: Option<&RESPResult>) -> Result<Option<(u64, u64)>, Error> {
+    let bits = match unit {
+        Some(unit) => match bulk_to_string(unit)?.to_uppercase().as_str() {
+            "BYTE" => false,
+            "BIT" => true,
+            _ => return Err(Error::Syntax),
+        },
+        None => false,
     };
 
-    match std::str::from_utf8(&message_bulk_string) {
-        Ok(s) => Ok(s.to_string()), 
-        Err(e) => Err(format!("Invalid UTF-8: {}", e)),
+    let len = bytes.len() as u64;
+    if bits {
+        Ok(bitops::range(len * 8, start, end))
+    } else {
+        Ok(bitops::range(len, start, end).map(|(first, last)| (first * 8, last * 8 + 7)))
+    }
+}
+
+fn setbit_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let offset = bit_offset(&data[1])?;
+    let on = bit_value(&data[2], "bit is not an integer or out of range")?;
+
+    let old = db.update_string(&key, |bytes| bitops::set_bit(bytes, offset, on))?;
+    Ok(RESPResult::Integer(old as i64))
+}
+
+// BITCOUNT key [start end [BYTE | BIT]]
+fn bitcount_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let bytes = db.get_string(&bulk_to_string(&data[0])?)?.unwrap_or_default();
+
+    let range = match data.len() {
+        1 => bitops::range(bytes.len() as u64 * 8, 0, -1),
+        3 | 4 => bit_range(&bytes, integer_arg(&data[1])?, integer_arg(&data[2])?, data.get(3))?,
+        _ => return Err(Error::Syntax),
+    };
+
+    let count = range.map_or(0, |(first, last)| bitops::count(&bytes, first, last));
+    Ok(RESPResult::Integer(count as i64))
+}
+
+// BITPOS key bit [start [end [BYTE | BIT]]]
+fn bitpos_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let bit = bit_value(&data[1], "The bit argument must be 1 or 0.")?;
+    if data.len() > 5 {
+        return Err(Error::Syntax);
+    }
+
+    let start = data.get(2).map(integer_arg).transpose()?.unwrap_or(0);
+    let end = data.get(3).map(integer_arg).transpose()?.unwrap_or(-1);
+    let bytes = db.get_string(&key)?.unwrap_or_default();
+    let range = bit_range(&bytes, start, end, data.get(4))?;
+
+    // a missing key is all clear bits
+    if bytes.is_empty() {
+        return Ok(RESPResult::Integer(if bit { -1 } else { 0 }));
+    }
+
+    let pos = match range {
+        Some((first, last)) => bitops::position(&bytes, bit, first, last),
+        None => return Ok(RESPResult::Integer(-1)),
+    };
+    let pos = match pos {
+        Some(pos) => pos as i64,
+        // without an end the string reads as if padded with clear bits
+        None if !bit && data.len() < 4 => bytes.len() as i64 * 8,
+        None => -1,
+    };
+    Ok(RESPResult::Integer(pos))
+}
+
+// BITOP AND | OR | XOR | NOT | DIFF destkey key [key ...]
+fn bitop_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let op = match bulk_to_string(&data[0])?.to_uppercase().as_str() {
+        "AND" => BitOp::And,
+        "OR" => BitOp::Or,
+        "XOR" => BitOp::Xor,
+        "NOT" => BitOp::Not,
+        "DIFF" => BitOp::Diff,
+        _ => return Err(Error::Syntax),
+    };
+    let dest = bulk_to_string(&data[1])?;
+    let sources = data[2..].iter().map(bulk_to_string).collect::<Result<Vec<_>, _>>()?;
+
+    if op == BitOp::Not && sources.len() != 1 {
+        return Err(Error::Other("BITOP NOT must be called with a single source key.".to_string()));
     }
+    if op == BitOp::Diff && sources.len() < 2 {
+        return Err(Error::Other("BITOP DIFF must be called with at least two source keys.".to_string()));
+    }
+
+    let len = db.bitop(&dest, &sources, |values| bitops::combine(op, values))?;
+    Ok(RESPResult::Integer(len as i64))
 }
 
-fn set_command(data: &[RESPResult]) -> Result<String, String> {
-    if data.len() != 2 && data.len() != 4 {
-        return Err("Missing key/value for SET".to_string());
+enum FieldOp {
+    Get(Field, u64),
+    Set(Field, u64, i64, Overflow),
+    IncrBy(Field, u64, i64, Overflow),
+}
+
+// BITFIELD key [GET encoding offset | [OVERFLOW WRAP | SAT | FAIL]
+// SET encoding offset value | INCRBY encoding offset increment ...]
+fn bitfield_command(db: &Db, data: &[RESPResult], read_only: bool) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+
+    // every operation is checked before any runs
+    let mut ops = Vec::new();
+    let mut overflow = Overflow::Wrap;
+    let mut i = 1;
+    while i < data.len() {
+        let op = bulk_to_string(&data[i])?.to_uppercase();
+        let args = data.len() - i - 1;
+
+        if op == "OVERFLOW" && args >= 1 {
+            overflow = match bulk_to_string(&data[i + 1])?.to_uppercase().as_str() {
+                "WRAP" => Overflow::Wrap,
+                "SAT" => Overflow::Sat,
+                "FAIL" => Overflow::Fail,
+                _ => return Err(Error::Other("Invalid OVERFLOW type specified".to_string())),
+            };
+            i += 2;
+            continue;
+        }
+
+        let arity = match op.as_str() {
+            "GET" if args >= 2 => 2,
+            "SET" | "INCRBY" if args >= 3 => 3,
+            _ => return Err(Error::Syntax),
+        };
+        if read_only && op != "GET" {
+            return Err(Error::Other("BITFIELD_RO only supports the GET subcommand".to_string()));
+        }
+
+        let field = Field::parse(&bulk_to_string(&data[i + 1])?).ok_or_else(|| {
+            Error::Other("Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.".to_string())
+        })?;
+        let offset = field_offset(&data[i + 2], field)?;
+        ops.push(match op.as_str() {
+            "GET" => FieldOp::Get(field, offset),
+            "SET" => FieldOp::Set(field, offset, integer_arg(&data[i + 3])?, overflow),
+            _ => FieldOp::IncrBy(field, offset, integer_arg(&data[i + 3])?, overflow),
+        });
+        i += 1 + arity;
+    }
+
+    let run = |bytes: &mut Vec<u8>| -> Vec<RESPResult> {
+        ops.iter()
+            .map(|op| match *op {
+                FieldOp::Get(field, offset) => RESPResult::Integer(field.get(bytes, offset)),
+                FieldOp::Set(field, offset, value, overflow) => match field.fit(field.value(value), overflow) {
+                    Some(value) => {
+                        let old = field.get(bytes, offset);
+                        field.set(bytes, offset, value);
+                        RESPResult::Integer(old)
+                    },
+                    None => RESPResult::BulkString(None),
+                },
+                FieldOp::IncrBy(field, offset, by, overflow) => {
+                    match field.fit(field.get(bytes, offset) as i128 + by as i128, overflow) {
+                        Some(value) => {
+                            field.set(bytes, offset, value);
+                            RESPResult::Integer(value)
+                        },
+                        None => RESPResult::BulkString(None),
+                    }
+                },
+            })
+            .collect()
+    };
+
+    // reads alone never create the key
+    let replies = if ops.iter().all(|op| matches!(op, FieldOp::Get(..))) {
+        run(&mut db.get_string(&key)?.unwrap_or_default())
+    } else {
+        db.update_string(&key, run)?
+    };
+    Ok(RESPResult::Array(replies))
+}
+
+// a BITFIELD offset in bits, or with # in multiples of the field's width
+fn field_offset(value: &RESPResult, field: Field) -> Result<u64, Error> {
+    let arg = bulk_to_string(value)?;
+    let offset = match arg.strip_prefix('#') {
+        Some(n) => n.parse::<u64>().ok().and_then(|n| n.checked_mul(field.bits as u64)),
+        None => arg.parse::<u64>().ok(),
+    };
+
+    match offset {
+        Some(offset) if offset + field.bits as u64 <= MAX_BITS => Ok(offset),
+        _ => Err(Error::Other("bit offset is not an integer or out of range".to_string())),
     }
+}
+
+fn exists_command(db: &Db, data: &[RESPResult]) -> Result<usize, Error> {
+    let keys = data.iter().map(bulk_to_string).collect::<Result<Vec<_>, _>>()?;
+    Ok(db.exists(&keys))
+}
+
+fn delete_command(db: &Db, data: &[RESPResult]) -> Result<usize, Error> {
+    let keys = data.iter().map(bulk_to_string).collect::<Result<Vec<_>, _>>()?;
+    Ok(db.delete(&keys))
+}
+
+fn increment_command(db: &Db, data: &[RESPResult]) -> Result<i64, Error> {
+    db.incr(&bulk_to_string(&data[0])?)
+}
+
+fn decrement_command(db: &Db, data: &[RESPResult]) -> Result<i64, Error> {
+    db.decr(&bulk_to_string(&data[0])?)
+}
+
+fn incrby_command(db: &Db, data: &[RESPResult]) -> Result<i64, Error> {
+    db.incr_by(&bulk_to_string(&data[0])?, integer_arg(&data[1])?)
+}
+
+fn decrby_command(db: &Db, data: &[RESPResult]) -> Result<i64, Error> {
+    let by = integer_arg(&data[1])?.checked_neg()
+        .ok_or_else(|| Error::Other("decrement would overflow".to_string()))?;
+    db.incr_by(&bulk_to_string(&data[0])?, by)
+}
+
+fn incrbyfloat_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let by = parse_float(&bulk_to_bytes(&data[1])?)
+        .ok_or_else(|| Error::Other("value is not a valid float".to_string()))?;
+    db.incr_by_float(&bulk_to_string(&data[0])?, by).map(|f| bulk_reply(Some(f.into_bytes())))
+}
+
+fn lpush_command(db: &Db, data: &[RESPResult]) -> Result<usize, Error> {
+    
+    // get key
+    let key = bulk_to_string(&data[0])?;
+    
+    // values to push
+    let mut values: Vec<DB_TYPE> = Vec::new();
+    for value in &data[1..] {
+        match &value {
+                RESPResult::BulkString(Some(message)) => values.push(DB_TYPE::string(message.clone())),
+                _ => return Err(Error::Protocol("expected bulk string".to_string())),
+        }
+    };
+
+    db.lpush(&key, values)
+}
 
+fn rpush_command(db: &Db, data: &[RESPResult]) -> Result<usize, Error> {
+    
     // get key
-    let key = match &data[0] {
-        RESPResult::BulkString(Some(message)) => String::from_utf8(message.clone()).unwrap(),
-        _ => return Err("Error: Not bulk string".to_string()),
+    let key = bulk_to_string(&data[0])?;
+    
+    // values to push
+    let mut values: Vec<DB_TYPE> = Vec::new();
+    for value in &data[1..] {
+        match &value {
+                RESPResult::BulkString(Some(message)) => values.push(DB_TYPE::string(message.clone())),
+                _ => return Err(Error::Protocol("expected bulk string".to_string())),
+        }
     };
     
-    // get value
-    let value = match &data[1] {
-        RESPResult::BulkString(Some(message)) => String::from_utf8(message.clone()).expect("Valid string"),
-        _ => return Err("Error: Not bulk string".to_string()),
+    db.rpush(&key, values)
+}
+
+// the elements given as arguments, as list values
+fn list_values(data: &[RESPResult]) -> Result<Vec<DB_TYPE>, Error> {
+    data.iter().map(|v| Ok(DB_TYPE::string(bulk_to_bytes(v)?))).collect()
+}
+
+fn list_reply(values: Vec<DB_TYPE>) -> RESPResult {
+    RESPResult::Array(values.into_iter().map(|v| bulk_reply(v.bytes())).collect())
+}
+
+// LEFT or RIGHT, true for LEFT
+fn list_side(value: &RESPResult) -> Result<bool, Error> {
+    match bulk_to_string(value)?.to_uppercase().as_str() {
+        "LEFT" => Ok(true),
+        "RIGHT" => Ok(false),
+        _ => Err(Error::Syntax),
+    }
+}
+
+// LPUSHX and RPUSHX
+fn pushx_command(db: &Db, data: &[RESPResult], left: bool) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let values = list_values(&data[1..])?;
+
+    let len = if left { db.lpushx(&key, values)? } else { db.rpushx(&key, values)? };
+    Ok(RESPResult::Integer(len as i64))
+}
+
+// LPOP and RPOP key [count]: one element as a bulk string, with a count an
+// array of them
+fn pop_command(db: &Db, data: &[RESPResult], left: bool) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let count = match data.len() {
+        1 => None,
+        2 => match integer_arg(&data[1])? {
+            n if n >= 0 => Some(n as usize),
+            _ => return Err(Error::Other("value is out of range, must be positive".to_string())),
+        },
+        _ => return Err(Error::Syntax),
+    };
+
+    let popped = db.pop(&key, left, count.unwrap_or(1))?;
+    Ok(match (popped, count) {
+        (Some(values), Some(_)) => list_reply(values),
+        (Some(mut values), None) => bulk_reply(values.pop().and_then(|v| v.bytes())),
+        (None, _) => bulk_reply(None),
+    })
+}
+
+// LMPOP numkeys key [key ...] LEFT | RIGHT [COUNT count]
+fn lmpop_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let (keys, left, count) = mpop_args(data)?;
+    Ok(match db.lmpop(&keys, left, count)? {
+        Some((key, values)) => mpop_reply(key, values),
+        None => RESPResult::NullArray,
+    })
+}
+
+// the numkeys key [key ...] LEFT | RIGHT [COUNT count] of LMPOP and BLMPOP
+fn mpop_args(data: &[RESPResult]) -> Result<(Vec<String>, bool, usize), Error> {
+    let numkeys = match integer_arg(&data[0]) {
+        Ok(n) if n > 0 => n as usize,
+        _ => return Err(Error::Other("numkeys should be greater than 0".to_string())),
     };
+    if data.len() < numkeys + 2 {
+        return Err(Error::Syntax);
+    }
 
+    let keys = data[1..=numkeys].iter().map(bulk_to_string).collect::<Result<Vec<_>, _>>()?;
+    let left = list_side(&data[numkeys + 1])?;
 
-    // if optional arguments, get argument, convert value
-    let mut t: u128 = 0;
-    if data.len() == 4 {
-        let command_arg = match &data[2] {
-            RESPResult::BulkString(Some(message)) => String::from_utf8(message.clone()).unwrap(),
-            _ => return Err("Error processing command argument".to_string()),
-        };
+    let count = match &data[numkeys + 2..] {
+        [] => 1,
+        [option, n] if bulk_to_string(option)?.eq_ignore_ascii_case("COUNT") => match integer_arg(n) {
+            Ok(n) if n > 0 => n as usize,
+            _ => return Err(Error::Other("count should be greater than 0".to_string())),
+        },
+        _ => return Err(Error::Syntax),
+    };
+    Ok((keys, left, count))
+}
+
+fn mpop_reply(key: String, values: Vec<DB_TYPE>) -> RESPResult {
+    RESPResult::Array(vec![bulk_reply(Some(key.into_bytes())), list_reply(values)])
+}
+
+fn lrange_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let (start, stop) = (integer_arg(&data[1])?, integer_arg(&data[2])?);
+    db.lrange(&key, start, stop).map(list_reply)
+}
+
+fn lindex_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let index = integer_arg(&data[1])?;
+    Ok(bulk_reply(db.lindex(&key, index)?.and_then(|v| v.bytes())))
+}
 
-        t = match &data[3] {
-            RESPResult::BulkString(Some(message)) => 
-                String::from_utf8(message.clone()).unwrap().parse().unwrap(),
-            _ => return Err("Error converting t to ms".to_string()),
+fn lset_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let index = integer_arg(&data[1])?;
+
+    db.lset(&key, index, DB_TYPE::string(bulk_to_bytes(&data[2])?))?;
+    Ok(RESPResult::SimpleString("OK".to_string()))
+}
+
+// LINSERT key BEFORE | AFTER pivot element
+fn linsert_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let before = match bulk_to_string(&data[1])?.to_uppercase().as_str() {
+        "BEFORE" => true,
+        "AFTER" => false,
+        _ => return Err(Error::Syntax),
+    };
+
+    let pivot = DB_TYPE::string(bulk_to_bytes(&data[2])?);
+    let value = DB_TYPE::string(bulk_to_bytes(&data[3])?);
+    db.linsert(&key, before, &pivot, value).map(RESPResult::Integer)
+}
+
+fn lrem_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let count = integer_arg(&data[1])?;
+    let value = DB_TYPE::string(bulk_to_bytes(&data[2])?);
+
+    db.lrem(&key, count, &value).map(|n| RESPResult::Integer(n as i64))
+}
+
+fn ltrim_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let (start, stop) = (integer_arg(&data[1])?, integer_arg(&data[2])?);
+
+    db.ltrim(&key, start, stop)?;
+    Ok(RESPResult::SimpleString("OK".to_string()))
+}
+
+// LPOS key element [RANK rank] [COUNT num-matches] [MAXLEN len]: without COUNT
+// the first match or nil, with it an array of matches
+fn lpos_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let value = DB_TYPE::string(bulk_to_bytes(&data[1])?);
+
+    let (mut rank, mut count, mut maxlen) = (1, None, 0);
+    for pair in data[2..].chunks(2) {
+        let [option, n] = pair else {
+            return Err(Error::Syntax);
         };
+        let n = integer_arg(n)?;
 
-        t = match command_arg.as_str() {
-            // t is expire in seconds
-            "EX" => SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() + (t * 1000),
-            // t is expire in milliseconds
-            "PX" => SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() + t,
-            // unix time in seconds
-            "EXAT" => t * 1000,
-            // unix time in milliseconds
-            "PXAT" => t,
-            _ => return Err("Optional argument not understood".to_string()),
+        match bulk_to_string(option)?.to_uppercase().as_str() {
+            "RANK" if n == 0 || n == i64::MIN => {
+                return Err(Error::Other(
+                    "RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list".to_string(),
+                ));
+            },
+            "RANK" => rank = n,
+            "COUNT" if n < 0 => return Err(Error::Other("COUNT can't be negative".to_string())),
+            "COUNT" => count = Some(n as usize),
+            "MAXLEN" if n < 0 => return Err(Error::Other("MAXLEN can't be negative".to_string())),
+            "MAXLEN" => maxlen = n as usize,
+            _ => return Err(Error::Syntax),
         }
     }
 
-    let set_val = match value.parse::<i64>() {
-        Ok(v) => DB_TYPE::Int(v),
-        Err(_) => DB_TYPE::Str(value),
-    };
+    let matches = db.lpos(&key, &value, rank, count.unwrap_or(1), maxlen)?;
+    let index = |i: usize| RESPResult::Integer(i as i64);
+    Ok(match count {
+        Some(_) => RESPResult::Array(matches.into_iter().map(index).collect()),
+        None => matches.first().map_or(bulk_reply(None), |&i| index(i)),
+    })
+}
+
+// LMOVE source destination LEFT | RIGHT LEFT | RIGHT
+fn lmove_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let (src, dst) = (bulk_to_string(&data[0])?, bulk_to_string(&data[1])?);
+    let (from_left, to_left) = (list_side(&data[2])?, list_side(&data[3])?);
+
+    let moved = db.lmove(&src, &dst, from_left, to_left)?;
+    Ok(bulk_reply(moved.and_then(|v| v.bytes())))
+}
+
+// the timeout of a blocking command in seconds, as the deadline it sets; 0
+// waits forever
+fn block_deadline(value: &RESPResult) -> Result<Option<Instant>, Error> {
+    let secs = parse_float(&bulk_to_bytes(value)?)
+        .ok_or_else(|| Error::Other("timeout is not a float or out of range".to_string()))?;
+    if secs < 0.0 {
+        return Err(Error::Other("timeout is negative".to_string()));
+    }
+    if secs == 0.0 {
+        return Ok(None);
+    }
+
+    match Duration::try_from_secs_f64(secs).ok().and_then(|d| Instant::now().checked_add(d)) {
+        Some(deadline) => Ok(Some(deadline)),
+        None => Err(Error::Other("timeout is out of range".to_string())),
+    }
+}
+
+// runs a blocking command: serve answers it now if it can, otherwise a client
+// connection is parked on keys until a push lets serve succeed or the deadline
+// passes, to get nil. scripts and direct calls never wait and get nil at once
+fn block_on(
+    ctx: &mut Context<'_>,
+    keys: Vec<String>,
+    pushes_to: Option<String>,
+    deadline: Option<Instant>,
+    nil: fn() -> RESPResult,
+    serve: Serve,
+) -> Result<RESPResult, Error> {
+    if !ctx.session().may_block {
+        return Ok(serve(ctx.db(), None)?.unwrap_or_else(nil));
+    }
+
+    let (server, db) = (ctx.server(), ctx.session().db());
+    match server.blocking().block(server, db, keys, pushes_to, deadline, nil, serve)? {
+        Attempt::Served(reply) => Ok(reply),
+        // the connection waits for the real reply
+        Attempt::Waiting(wait) => {
+            ctx.session().blocked = Some(wait);
+            Ok(bulk_reply(None))
+        },
+    }
+}
+
+// pops for a blocked command: off the first list of keys, or once parked off
+// the key that became ready. a ready key that is no list keeps it waiting
+fn pop_ready(db: &Db, ready: Option<&str>, keys: &[String], left: bool, count: usize) -> Result<Option<(String, Vec<DB_TYPE>)>, Error> {
+    match ready {
+        Some(k) => match db.lmpop(&[k.to_string()], left, count) {
+            Err(Error::WrongType) => Ok(None),
+            popped => popped,
+        },
+        None => db.lmpop(keys, left, count),
+    }
+}
+
+// BLPOP and BRPOP key [key ...] timeout
+fn bpop_command(ctx: &mut Context<'_>, data: &[RESPResult], left: bool) -> Result<RESPResult, Error> {
+    let (keys, timeout) = data.split_at(data.len() - 1);
+    let keys = keys.iter().map(bulk_to_string).collect::<Result<Vec<_>, _>>()?;
+    let deadline = block_deadline(&timeout[0])?;
+
+    let watched = keys.clone();
+    block_on(ctx, keys, None, deadline, || RESPResult::NullArray, Box::new(move |db, ready| {
+        Ok(pop_ready(db, ready, &watched, left, 1)?.map(|(key, mut values)| {
+            RESPResult::Array(vec![bulk_reply(Some(key.into_bytes())), bulk_reply(values.pop().and_then(|v| v.bytes()))])
+        }))
+    }))
+}
+
+// BLMPOP timeout numkeys key [key ...] LEFT | RIGHT [COUNT count]
+fn blmpop_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let deadline = block_deadline(&data[0])?;
+    let (keys, left, count) = mpop_args(&data[1..])?;
+
+    let watched = keys.clone();
+    block_on(ctx, keys, None, deadline, || RESPResult::NullArray, Box::new(move |db, ready| {
+        Ok(pop_ready(db, ready, &watched, left, count)?.map(|(key, values)| mpop_reply(key, values)))
+    }))
+}
+
+// BLMOVE source destination LEFT | RIGHT LEFT | RIGHT timeout
+fn blmove_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let (src, dst) = (bulk_to_string(&data[0])?, bulk_to_string(&data[1])?);
+    let (from_left, to_left) = (list_side(&data[2])?, list_side(&data[3])?);
+    let deadline = block_deadline(&data[4])?;
 
-    match db::set(key.clone(), set_val, t) {
+    let (source, destination) = (src.clone(), dst.clone());
+    block_on(ctx, vec![src], Some(dst), deadline, || bulk_reply(None), Box::new(move |db, ready| {
+        if ready.is_some() && !matches!(db.llen(&source), Ok(n) if n > 0) {
+            return Ok(None);
+        }
+        Ok(db.lmove(&source, &destination, from_left, to_left)?.map(|v| bulk_reply(v.bytes())))
+    }))
+}
+
+pub fn save_command(server: &Server) -> Result<String, Error> {
+    let libraries = server.scripting().library_codes();
+    match Db::write_snapshot(server.databases(), &server.config().dbfilename, &libraries) {
         Ok(_) => Ok("OK".to_string()),
         Err(e) => Err(e)
     }
 }
 
-fn get_command(data: &[RESPResult]) -> Result<String, String> { 
-    
-    if data.len() != 1 {
-        return Err("Missing key/value for GET".to_string());
+pub fn load_command(server: &Server, data: &[RESPResult]) -> Result<String, Error> {
+    let path = bulk_to_string(&data[0])?;
+
+    // the snapshot's keys and libraries take the place of the loaded ones,
+    // only once both have been read and compiled
+    let (staged, libraries) = Db::stage_snapshot(server.databases(), &path)?;
+    server.scripting().restore_libraries_with(&libraries, RestorePolicy::Flush, || Db::swap_all(server.databases(), &staged))?;
+    for db in 0..server.databases().len() {
+        server.blocking().signal_db(server, db);
+    }
+    Ok("OK".to_string())
+}
+
+// a database index argument, checked against the configured number of databases
+fn db_index(server: &Server, arg: &RESPResult) -> Result<usize, Error> {
+    let index: i64 = bulk_to_string(arg)?.parse().map_err(|_| Error::NotAnInteger)?;
+    match usize::try_from(index) {
+        Ok(index) if index < server.databases().len() => Ok(index),
+        _ => Err(Error::Other("DB index is out of range".to_string())),
+    }
+}
+
+// FLUSHDB and FLUSHALL empty the keyspace at once, ASYNC or not
+fn flush_mode(data: &[RESPResult]) -> Result<(), Error> {
+    match data {
+        [] => Ok(()),
+        [mode] if matches!(bulk_to_string(mode)?.to_uppercase().as_str(), "ASYNC" | "SYNC") => Ok(()),
+        _ => Err(Error::Syntax),
+    }
+}
+
+fn select_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let index = db_index(ctx.server(), &data[0])?;
+    ctx.session().select(index);
+    Ok(RESPResult::SimpleString("OK".to_string()))
+}
+
+fn move_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let dest = db_index(ctx.server(), &data[1])?;
+    if dest == ctx.session().db() {
+        return Err(Error::Other("source and destination objects are the same".to_string()));
     }
 
-    let key = match &data[0] {
-        RESPResult::BulkString(Some(message)) => String::from_utf8(message.clone()).unwrap(),
-        _ => return Err("Error: Not bulk string".to_string()),
+    let moved = ctx.db().move_key(&key, &ctx.server().databases()[dest]);
+    if moved {
+        ctx.server().blocking().signal(ctx.server(), dest, &[key]);
+    }
+    Ok(RESPResult::Integer(moved as i64))
+}
+
+fn swapdb_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let dbs = ctx.server().databases();
+    let index = |arg, which| match bulk_to_string(arg)?.parse::<i64>() {
+        Ok(i) if i >= 0 && (i as usize) < dbs.len() => Ok(i as usize),
+        Ok(_) => Err(Error::Other("DB index is out of range".to_string())),
+        Err(_) => Err(Error::Other(format!("invalid {which} DB index"))),
     };
-    
-    let value: Option<DB_TYPE> = db::get(&key);
-    
-    match value {
-        Some(val) => {
-            match val {
-                DB_TYPE::Int(i) => Ok(i.to_string()),
-                DB_TYPE::Str(s) =>  Ok(s),
-                
-                _ => return Err("Cannot unpack DB_TYPE".to_string())
-            }
-        },
-        None => Ok(String::new())
+
+    let (a, b) = (index(&data[0], "first")?, index(&data[1], "second")?);
+    dbs[a].swap(&dbs[b]);
+    // clients waiting on either database now see the other's keys
+    ctx.server().blocking().signal_db(ctx.server(), a);
+    ctx.server().blocking().signal_db(ctx.server(), b);
+    Ok(RESPResult::SimpleString("OK".to_string()))
+}
+
+fn flushdb_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    flush_mode(data)?;
+    ctx.db().flush();
+    Ok(RESPResult::SimpleString("OK".to_string()))
+}
+
+fn flushall_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    flush_mode(data)?;
+    for db in ctx.server().databases() {
+        db.flush();
+    }
+    Ok(RESPResult::SimpleString("OK".to_string()))
+}
+
+fn key_list(keys: Vec<String>) -> RESPResult {
+    RESPResult::Array(keys.into_iter().map(|k| RESPResult::BulkString(Some(k.into_bytes()))).collect())
+}
+
+fn scan_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let cursor: u64 = bulk_to_string(&data[0])?
+        .parse()
+        .map_err(|_| Error::Other("invalid cursor".to_string()))?;
+
+    let mut pattern = None;
+    let mut count = 10;
+    let mut type_name = None;
+
+    let mut args = data[1..].iter();
+    while let Some(arg) = args.next() {
+        let value = match args.next() {
+            Some(value) => bulk_to_string(value)?,
+            None => return Err(Error::Syntax),
+        };
+        match bulk_to_string(arg)?.to_uppercase().as_str() {
+            "MATCH" => pattern = Some(value),
+            "COUNT" => {
+                count = value.parse::<i64>().map_err(|_| Error::NotAnInteger)?;
+                if count < 1 {
+                    return Err(Error::Syntax);
+                }
+            },
+            "TYPE" => type_name = Some(value.to_lowercase()),
+            _ => return Err(Error::Syntax),
+        }
     }
+
+    let (next, keys) = db.scan(cursor, count as usize, |k, v| {
+        pattern.as_ref().is_none_or(|p| glob_match(p.as_bytes(), k.as_bytes()))
+            && type_name.as_ref().is_none_or(|t| v.type_name() == t)
+    });
+
+    Ok(RESPResult::Array(vec![
+        RESPResult::BulkString(Some(next.to_string().into_bytes())),
+        key_list(keys),
+    ]))
 }
 
-fn exists_command(data: &[RESPResult]) -> Result<i32, String> { 
-    
-    if data.len() != 1 {
-        return Err("Missing key/value for EXISTS".to_string());
-    }
+fn copy_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let src = bulk_to_string(&data[0])?;
+    let dst = bulk_to_string(&data[1])?;
+
+    let mut dest = ctx.session().db();
+    let mut replace = false;
 
-    let mut keys: Vec<String> = Vec::new();
-    for key in data {
-        match &key {
-                RESPResult::BulkString(Some(message)) => keys.push(String::from_utf8(message.clone()).unwrap()),
-                _ => return Err("Error: Not bulk string".to_string()),
+    let mut args = data[2..].iter();
+    while let Some(arg) = args.next() {
+        match bulk_to_string(arg)?.to_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            "DB" => match args.next() {
+                Some(index) => dest = db_index(ctx.server(), index)?,
+                None => return Err(Error::Syntax),
+            },
+            _ => return Err(Error::Syntax),
         }
     }
-    
-    Ok(db::exists(keys))
-}
 
-fn delete_command(data: &[RESPResult]) -> Result<i32, String> { 
-    
-    if data.len() != 1 {
-        return Err("Missing key/value for DEL".to_string());
+    if dest == ctx.session().db() && src == dst {
+        return Err(Error::Other("source and destination objects are the same".to_string()));
     }
 
-    let mut keys: Vec<String> = Vec::new();
-    for key in data {
-        match &key {
-                RESPResult::BulkString(Some(message)) => keys.push(String::from_utf8(message.clone()).unwrap()),
-                _ => return Err("Error: Not bulk string".to_string()),
-        }
-    }
-    
-    Ok(db::delete(keys))
+    let copied = ctx.db().copy_to(&src, &ctx.server().databases()[dest], &dst, replace);
+    Ok(RESPResult::Integer(copied as i64))
 }
 
-fn increment_command(data: &[RESPResult]) -> Result<String, String> { 
-    
-    if data.len() != 1 {
-        return Err("Missing key/value for INCR".to_string());
+// EXPIRE and friends: unit is the milliseconds per argument unit, absolute
+// when the argument is a unix time rather than a number from now
+fn expire_command(db: &Db, data: &[RESPResult], name: &str, unit: i64, absolute: bool) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let n: i64 = bulk_to_string(&data[1])?.parse().map_err(|_| Error::NotAnInteger)?;
+
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+    for arg in &data[2..] {
+        match bulk_to_string(arg)?.to_uppercase().as_str() {
+            "NX" => nx = true,
+            "XX" => xx = true,
+            "GT" => gt = true,
+            "LT" => lt = true,
+            other => return Err(Error::Other(format!("Unsupported option {other}"))),
+        }
+    }
+    if nx && (xx || gt || lt) {
+        return Err(Error::Other("NX and XX, GT or LT options at the same time are not compatible".to_string()));
+    }
+    if gt && lt {
+        return Err(Error::Other("GT and LT options at the same time are not compatible".to_string()));
     }
 
-    let key = match &data[0] {
-        RESPResult::BulkString(Some(message)) => String::from_utf8(message.clone()).unwrap(),
-        _ => return Err("Error: Not bulk string".to_string()),
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as i64;
+    let when = match n.checked_mul(unit) {
+        Some(ms) if absolute => Some(ms),
+        Some(ms) => ms.checked_add(now),
+        None => None,
+    };
+    let when = match when {
+        Some(when) => when.max(0) as u128,
+        None => return Err(Error::Other(format!("invalid expire time in '{name}' command"))),
     };
-    
-    db::increment(&key)
-}
 
-fn decrement_command(data: &[RESPResult]) -> Result<String, String> { 
-    
-    if data.len() != 1 {
-        return Err("Missing key/value for DECR".to_string());
-    }
+    // a key without an expire counts as never expiring for GT and LT
+    let set = db.expire_at(&key, when, |current| match current {
+        None => !xx && !gt,
+        Some(old) => !nx && (!gt || when > old) && (!lt || when < old),
+    });
+    Ok(RESPResult::Integer(set as i64))
+}
 
-    let key = match &data[0] {
-        RESPResult::BulkString(Some(message)) => String::from_utf8(message.clone()).unwrap(),
-        _ => return Err("Error: Not bulk string".to_string()),
+// TTL, PTTL, EXPIRETIME and PEXPIRETIME: -2 for a missing key, -1 for one
+// without an expire
+fn ttl_command(db: &Db, data: &[RESPResult], ms: bool, absolute: bool) -> Result<RESPResult, Error> {
+    let key = bulk_to_string(&data[0])?;
+    let ttl = if absolute { db.expire_time(&key) } else { db.ttl(&key) };
+
+    let reply = match ttl {
+        Ttl::Missing => -2,
+        Ttl::Persistent => -1,
+        Ttl::Expires(d) if ms => d.as_millis() as i64,
+        Ttl::Expires(d) => (d.as_millis() as i64 + 500) / 1000,
     };
-    
-    db::decrement(&key)
+    Ok(RESPResult::Integer(reply))
 }
 
-fn lpush_command(data: &[RESPResult]) -> Result<String, String> {
-    
-    if data.len() == 1 {
-        return Err("Missing key/value for LPUSH".to_string());
+// INFO [section ...], only the sections we keep numbers for
+fn info_command(server: &Server, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let mut sections = Vec::new();
+    for arg in data {
+        sections.push(bulk_to_string(arg)?.to_lowercase());
     }
-
-    // get key
-    let key = match &data[0] {
-        RESPResult::BulkString(Some(message)) => String::from_utf8(message.clone()).unwrap(),
-        _ => return Err("Error: Not bulk string".to_string()),
+    let wanted = |name: &str| {
+        sections.is_empty() || sections.iter().any(|s| s == name || matches!(s.as_str(), "all" | "default" | "everything"))
     };
-    
-    // values to push
-    let mut values: Vec<DB_TYPE> = Vec::new();
-    for value in &data[1..] {
-        match &value {
-                RESPResult::BulkString(Some(message)) => {
-                    let str_val = String::from_utf8(message.clone()).expect("Not valid string");
 
-                    let v = match str_val.parse::<i64>() {
-                        Ok(v) => DB_TYPE::Int(v),
-                        Err(_) => DB_TYPE::Str(str_val),
-                    };
+    let mut info = Vec::new();
+    if wanted("clients") {
+        info.push(format!("# Clients\r\nblocked_clients:{}\r\n", server.blocking().blocked_clients()));
+    }
+    if wanted("memory") {
+        let config = server.config();
+        info.push(format!(
+            "# Memory\r\nused_memory:{}\r\nmaxmemory:{}\r\nmaxmemory_policy:{}\r\n",
+            server.used_memory(),
+            config.maxmemory,
+            config.maxmemory_policy.name(),
+        ));
+    }
+    if wanted("stats") {
+        let expired: u64 = server.databases().iter().map(|db| db.expired_keys()).sum();
+        let evicted: u64 = server.databases().iter().map(|db| db.evicted_keys()).sum();
+        let stats = server.expire_stats();
+        info.push(format!(
+            "# Stats\r\nexpired_keys:{}\r\nexpired_stale_perc:{:.2}\r\nexpired_time_cap_reached_count:{}\r\nexpire_cycle_cpu_milliseconds:{}\r\nevicted_keys:{}\r\n",
+            expired,
+            stats.stale_perc,
+            stats.time_cap_reached,
+            stats.cpu_time.as_millis(),
+            evicted,
+        ));
+    }
+    if wanted("keyspace") {
+        let mut section = "# Keyspace\r\n".to_string();
+        for (i, db) in server.databases().iter().enumerate() {
+            let keys = db.len();
+            if keys > 0 {
+                section.push_str(&format!("db{i}:keys={keys},expires={},avg_ttl=0\r\n", db.expires()));
+            }
+        }
+        info.push(section);
+    }
+
+    Ok(RESPResult::BulkString(Some(info.join("\r\n").into_bytes())))
+}
 
-                    values.push(v);
+fn memory_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let subcommand = bulk_to_string(&data[0])?.to_uppercase();
+    let args = &data[1..];
+
+    match subcommand.as_str() {
+        "USAGE" if !args.is_empty() => {
+            // a few elements of big values are enough for an estimate, 0 means all
+            let samples = match args {
+                [_] => MEMORY_SAMPLES,
+                [_, option, n] if bulk_to_string(option)?.eq_ignore_ascii_case("SAMPLES") => {
+                    match bulk_to_string(n)?.parse::<i64>() {
+                        Ok(n) if n >= 0 => n as usize,
+                        Ok(_) => return Err(Error::Syntax),
+                        Err(_) => return Err(Error::NotAnInteger),
+                    }
                 },
-                _ => return Err("Error: Not bulk string".to_string()),
-        }
-    };
+                _ => return Err(Error::Syntax),
+            };
 
-    match db::lpush(&key, values) {
-        Ok(i) => Ok(i.to_string()),
-        Err(e) => Err(e)
+            let usage = ctx.db().memory_usage(&bulk_to_string(&args[0])?, samples);
+            Ok(usage.map_or(RESPResult::BulkString(None), |n| RESPResult::Integer(n as i64)))
+        },
+        "STATS" if args.is_empty() => Ok(memory_stats(ctx.server())),
+        "DOCTOR" if args.is_empty() => Ok(RESPResult::BulkString(Some(memory_doctor(ctx.server()).into_bytes()))),
+        "USAGE" | "STATS" | "DOCTOR" => Err(Error::WrongArity(format!("memory|{}", subcommand.to_lowercase()))),
+        _ => Err(Error::Other(format!("unknown subcommand '{}'. Try MEMORY HELP.", subcommand.to_lowercase()))),
     }
 }
 
-fn rpush_command(data: &[RESPResult]) -> Result<String, String> {
-    
-    if data.len() == 1 {
-        return Err("Missing key/value for RPUSH".to_string());
+// CONFIG GET pattern [pattern ...] and CONFIG SET parameter value [parameter value ...]
+fn config_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let subcommand = bulk_to_string(&data[0])?.to_uppercase();
+    let args = data[1..].iter().map(bulk_to_string).collect::<Result<Vec<_>, _>>()?;
+
+    match subcommand.as_str() {
+        "GET" if !args.is_empty() => {
+            let config = ctx.server().config();
+            let mut reply = Vec::new();
+            for name in PARAMETERS {
+                if args.iter().any(|p| glob_match(p.to_lowercase().as_bytes(), name.as_bytes())) {
+                    reply.push(RESPResult::BulkString(Some(name.as_bytes().to_vec())));
+                    reply.push(RESPResult::BulkString(config.get(name).map(String::into_bytes)));
+                }
+            }
+            Ok(RESPResult::Array(reply))
+        },
+        "SET" if !args.is_empty() && args.len().is_multiple_of(2) => {
+            let settings: Vec<(String, String)> = args.chunks(2).map(|p| (p[0].clone(), p[1].clone())).collect();
+            ctx.server().set_config(&settings).map_err(Error::Other)?;
+            Ok(RESPResult::SimpleString("OK".to_string()))
+        },
+        "GET" | "SET" => Err(Error::WrongArity(format!("config|{}", subcommand.to_lowercase()))),
+        _ => Err(Error::Other(format!("unknown subcommand '{}'. Try CONFIG HELP.", subcommand.to_lowercase()))),
     }
+}
 
-    // get key
-    let key = match &data[0] {
-        RESPResult::BulkString(Some(message)) => String::from_utf8(message.clone()).unwrap(),
-        _ => return Err("Error: Not bulk string".to_string()),
-    };
-    
-    // values to push
-    let mut values: Vec<DB_TYPE> = Vec::new();
-    for value in &data[1..] {
-        match &value {
-                RESPResult::BulkString(Some(message)) => {
-                    let str_val = String::from_utf8(message.clone()).expect("Not valid string");
-
-                    let v = match str_val.parse::<i64>() {
-                        Ok(v) => DB_TYPE::Int(v),
-                        Err(_) => DB_TYPE::Str(str_val),
-                    };
-
-                    values.push(v);
-                },
-                _ => return Err("Error: Not bulk string".to_string()),
+// a flat map of figure names to values, as MEMORY STATS replies
+fn memory_stats(server: &Server) -> RESPResult {
+    let name = |s: &str| RESPResult::BulkString(Some(s.as_bytes().to_vec()));
+
+    let used = server.used_memory();
+    let keys: usize = server.databases().iter().map(|db| db.len()).sum();
+    let mut overhead = 0;
+    let mut per_db = Vec::new();
+
+    for (i, db) in server.databases().iter().enumerate() {
+        let (main, expires) = db.memory_overhead();
+        overhead += main + expires;
+        if !db.is_empty() {
+            per_db.push(name(&format!("db.{i}")));
+            per_db.push(RESPResult::Array(vec![
+                name("overhead.hashtable.main"),
+                RESPResult::Integer(main as i64),
+                name("overhead.hashtable.expires"),
+                RESPResult::Integer(expires as i64),
+            ]));
         }
-    };
-    
-    match db::rpush(&key, values) {
-        Ok(i) => Ok(i.to_string()),
-        Err(e) => Err(e)
     }
-}
 
-pub fn save_command() -> Result<String, String> {
-    db::write_db_to_file()
+    let dataset = used.saturating_sub(overhead);
+    let percentage = if used == 0 { 0.0 } else { dataset as f64 * 100.0 / used as f64 };
+
+    let mut stats = vec![
+        name("total.allocated"),
+        RESPResult::Integer(used as i64),
+        name("overhead.total"),
+        RESPResult::Integer(overhead as i64),
+        name("keys.count"),
+        RESPResult::Integer(keys as i64),
+        name("keys.bytes-per-key"),
+        RESPResult::Integer(used.checked_div(keys).unwrap_or(0) as i64),
+        name("dataset.bytes"),
+        RESPResult::Integer(dataset as i64),
+        name("dataset.percentage"),
+        name(&format!("{percentage:.2}")),
+    ];
+    stats.extend(per_db);
+    RESPResult::Array(stats)
 }
 
-pub fn load_command(data: &[RESPResult]) -> Result<String, String> {
-    if data.len() != 1 {
-        return Err("Error: only provide path when loading: LOAD ./file.rdb".to_string());
+fn memory_doctor(server: &Server) -> String {
+    let config = server.config();
+    let used = server.used_memory();
+
+    if server.databases().iter().all(|db| db.is_empty()) {
+        return "Hi Sam, this instance is empty or is using very little memory, my issues detector can't be used in these conditions. \
+            Please, leave for your mission on Earth and fill it with some data. \
+            The new Sam and I will be back to our programming as soon as I finished rebooting.".to_string();
+    }
+
+    let mut issues = Vec::new();
+    if config.maxmemory > 0 && used * 10 > config.maxmemory * 9 {
+        let what = match config.maxmemory_policy {
+            MaxmemoryPolicy::NoEviction => "writes adding data will soon be refused with OOM",
+            _ => "keys are being evicted to stay under it",
+        };
+        issues.push(format!(
+            " * High memory usage: the keyspace uses {}% of maxmemory, {what}.",
+            used * 100 / config.maxmemory,
+        ));
+    }
+
+    if issues.is_empty() {
+        return "Hi Sam, I can't find any memory issue in your instance. I can only account for what occurs on this base.".to_string();
     }
+    format!(
+        "Sam, I detected a few issues in this Redis instance memory implants:\n\n{}\n\nI'm here to keep you safe, Sam. I want to help you.",
+        issues.join("\n"),
+    )
+}
+
+fn object_command(db: &Db, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let subcommand = bulk_to_string(&data[0])?.to_uppercase();
+    let key = match (subcommand.as_str(), &data[1..]) {
+        ("ENCODING" | "IDLETIME" | "FREQ" | "REFCOUNT", [key]) => bulk_to_string(key)?,
+        ("ENCODING" | "IDLETIME" | "FREQ" | "REFCOUNT", _) => {
+            return Err(Error::WrongArity(format!("object|{}", subcommand.to_lowercase())));
+        },
+        _ => return Err(Error::Other(format!("unknown subcommand '{}'. Try OBJECT HELP.", subcommand.to_lowercase()))),
+    };
 
-    let path = match &data[0] {
-        RESPResult::BulkString(Some(message)) => String::from_utf8(message.clone()).unwrap(),
-        _ => return Err("Error: Not bulk string".to_string()),
+    let info = match db.key_info(&key) {
+        Some(info) => info,
+        None => return Ok(RESPResult::BulkString(None)),
     };
 
-    db::read_db_from_file(&path)
+    Ok(match subcommand.as_str() {
+        "ENCODING" => RESPResult::BulkString(Some(info.encoding.as_bytes().to_vec())),
+        "IDLETIME" => RESPResult::Integer(info.idle.as_secs() as i64),
+        "FREQ" => RESPResult::Integer(info.freq as i64),
+        _ => RESPResult::Integer(info.refcount),
+    })
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::Config;
     use std::thread;
-    use std::time::Duration;
+
+    fn server() -> Server {
+        Server::new(Config::default())
+    }
 
     fn bulk(s: &str) -> RESPResult {
         RESPResult::BulkString(Some(s.as_bytes().to_vec()))
@@ -346,58 +2612,1059 @@ mod tests {
     #[test]
     fn test_echo_command_invalid_arg_count() {
         let data = vec![]; // no args
-        let result = echo_command(&data);
-        assert_eq!(result, Err("Incorrect number of arguments for echo".to_string()));
+        let result = command_router(&server(), "ECHO", &data);
+        assert_eq!(result, Err(Error::WrongArity("echo".to_string())));
         let data = vec![
             RESPResult::BulkString(Some(b"hello".to_vec())),
             RESPResult::BulkString(Some(b"world".to_vec())),
         ]; // too many args
-        let result = echo_command(&data);
-        assert_eq!(result, Err("Incorrect number of arguments for echo".to_string()));
+        let result = command_router(&server(), "ECHO", &data);
+        assert_eq!(result, Err(Error::WrongArity("echo".to_string())));
     }
     #[test]
     fn test_echo_command_not_bulk_string() {
         let data = vec![RESPResult::Integer(42)];
         let result = echo_command(&data);
-        assert_eq!(result, Err("Error: Not bulk string".to_string()));
+        assert_eq!(result, Err(Error::Protocol("expected bulk string".to_string())));
     }
     #[test]
     fn test_echo_command_invalid_utf8() {
         let data = vec![RESPResult::BulkString(Some(vec![0xff, 0xfe, 0xfd]))]; // Invalid UTF-8
         let result = echo_command(&data);
         assert!(result.is_err());
-        assert!(result.unwrap_err().starts_with("Invalid UTF-8"));
+        assert!(result.unwrap_err().to_string().starts_with("ERR Invalid UTF-8"));
     }
 
     #[test]
     fn test_ping_command_valid() {
         let data: Vec<_> = vec![RESPResult::BulkString(Some(b"PING".to_vec()))];
-        let result = command_router("PING", &data);
+        let result = command_router(&server(), "PING", &data);
         assert_eq!(result, Ok(RESPResult::SimpleString("PONG".to_string())));
     }
 
+    #[test]
+    fn test_unknown_command() {
+        let result = command_router(&server(), "FOO", &[]);
+        assert_eq!(result, Err(Error::UnknownCommand("FOO".to_string())));
+        assert_eq!(result.unwrap_err().to_string(), "ERR unknown command 'FOO'");
+    }
+
+    #[test]
+    fn test_wrong_type_error() {
+        let db = Db::new();
+        db.rpush("list_key", vec![DB_TYPE::Int(1)]).unwrap();
+
+        let result = get_command(&db, &[bulk("list_key")]);
+        assert_eq!(result, Err(Error::WrongType));
+        assert!(result.unwrap_err().to_string().starts_with("WRONGTYPE "));
+    }
+
+    #[test]
+    fn test_dispatch_is_case_insensitive() {
+        let server = server();
+        assert_eq!(
+            command_router(&server, "set", &[bulk("foo"), bulk("bar")]),
+            Ok(RESPResult::SimpleString("OK".to_string()))
+        );
+        assert_eq!(
+            command_router(&server, "GeT", &[bulk("foo")]),
+            Ok(bulk("bar"))
+        );
+    }
+
+    #[test]
+    fn test_exists_and_del_accept_many_keys() {
+        let server = server();
+        server.db().set("a", DB_TYPE::Int(1));
+        server.db().set("b", DB_TYPE::Int(2));
+
+        let keys = [bulk("a"), bulk("b"), bulk("c")];
+        assert_eq!(command_router(&server, "EXISTS", &keys), Ok(RESPResult::Integer(2)));
+        assert_eq!(command_router(&server, "DEL", &keys), Ok(RESPResult::Integer(2)));
+        assert_eq!(command_router(&server, "EXISTS", &keys), Ok(RESPResult::Integer(0)));
+    }
+
+    #[test]
+    fn test_non_utf8_keys() {
+        let server = server();
+        let key = || RESPResult::BulkString(Some(b"\xff".to_vec()));
+
+        for (command, args) in [
+            ("SET", vec![key(), bulk("a")]),
+            ("GET", vec![key()]),
+            ("LPUSH", vec![key(), bulk("x")]),
+            ("RPUSH", vec![key(), bulk("y")]),
+            ("EXISTS", vec![key()]),
+            ("DEL", vec![key()]),
+            ("LOAD", vec![key()]),
+        ] {
+            assert!(matches!(command_router(&server, command, &args), Err(Error::Other(e)) if e.starts_with("Invalid UTF-8")));
+        }
+
+        // two different binary keys never collapse into one
+        let other = RESPResult::BulkString(Some(b"\xfe".to_vec()));
+        assert!(command_router(&server, "GET", &[other]).is_err());
+        assert_eq!(command_router(&server, "DBSIZE", &[]), Ok(RESPResult::Integer(0)));
+    }
+
+    #[test]
+    fn test_command_table_metadata() {
+        let table = CommandTable::new();
+
+        let set = table.get("SET").unwrap();
+        assert_eq!(set.arity(), -3);
+        assert!(set.flags().contains(CommandFlags::WRITE | CommandFlags::DENYOOM));
+        assert_eq!(set.flags().names(), vec!["write", "denyoom"]);
+        assert_eq!(set.key_range(), (1, 1, 1));
+
+        let del = table.get("del").unwrap();
+        assert_eq!(del.key_range().1, -1);
+
+        assert!(table.get("nope").is_none());
+    }
+
+    struct Hello;
+
+    impl Command for Hello {
+        fn name(&self) -> &str {
+            "hello"
+        }
+
+        fn arity(&self) -> i32 {
+            1
+        }
+
+        fn execute(&self, _ctx: &mut Context<'_>, _args: &[RESPResult]) -> Result<RESPResult, Error> {
+            Ok(RESPResult::SimpleString("world".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_keyspace_commands() {
+        let server = server();
+        let run = |cmd: &str, args: &[&str]| {
+            let data: Vec<RESPResult> = args.iter().map(|a| bulk(a)).collect();
+            command_router(&server, cmd, &data)
+        };
+
+        server.db().set("user:1", DB_TYPE::Str(b"a".to_vec()));
+        server.db().set("user:2", DB_TYPE::Int(2));
+        server.db().rpush("list", vec![DB_TYPE::Str(b"x".to_vec())]).unwrap();
+
+        let keys = |reply: Result<RESPResult, Error>| match reply {
+            Ok(RESPResult::Array(items)) => {
+                let mut keys: Vec<String> = items.iter().map(|k| bulk_to_string(k).unwrap()).collect();
+                keys.sort();
+                keys
+            },
+            other => panic!("unexpected reply {other:?}"),
+        };
+        assert_eq!(keys(run("KEYS", &["user:*"])), vec!["user:1", "user:2"]);
+
+        assert_eq!(run("TYPE", &["user:2"]), Ok(RESPResult::SimpleString("string".to_string())));
+        assert_eq!(run("TYPE", &["list"]), Ok(RESPResult::SimpleString("list".to_string())));
+        assert_eq!(run("TYPE", &["nope"]), Ok(RESPResult::SimpleString("none".to_string())));
+
+        assert_eq!(run("RENAME", &["nope", "x"]), Err(Error::Other("no such key".to_string())));
+        assert_eq!(run("RENAMENX", &["user:1", "user:2"]), Ok(RESPResult::Integer(0)));
+        assert_eq!(run("RENAME", &["user:1", "user:3"]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(server.db().get("user:3"), Some(DB_TYPE::Str(b"a".to_vec())));
+
+        assert_eq!(run("COPY", &["user:3", "user:3"]), Err(Error::Other("source and destination objects are the same".to_string())));
+        assert_eq!(run("COPY", &["user:3", "user:2"]), Ok(RESPResult::Integer(0)));
+        assert_eq!(run("COPY", &["user:3", "user:2", "REPLACE"]), Ok(RESPResult::Integer(1)));
+        assert_eq!(run("COPY", &["user:3", "user:3", "DB", "1"]), Ok(RESPResult::Integer(1)));
+        assert_eq!(server.databases()[1].get("user:3"), Some(DB_TYPE::Str(b"a".to_vec())));
+        assert_eq!(run("COPY", &["user:3", "x", "DB", "99"]), Err(Error::Other("DB index is out of range".to_string())));
+
+        assert_eq!(run("TOUCH", &["user:2", "user:3", "nope"]), Ok(RESPResult::Integer(2)));
+        assert_eq!(run("UNLINK", &["user:2", "nope"]), Ok(RESPResult::Integer(1)));
+
+        let random = run("RANDOMKEY", &[]).and_then(|k| bulk_to_string(&k)).unwrap();
+        assert!(random == "user:3" || random == "list");
+        server.db().flush();
+        assert_eq!(run("RANDOMKEY", &[]), Ok(RESPResult::BulkString(None)));
+    }
+
+    #[test]
+    fn test_scan_command() {
+        let server = server();
+        for i in 0..25 {
+            server.db().set(&format!("k{i}"), DB_TYPE::Int(i));
+        }
+        server.db().rpush("list", vec![DB_TYPE::Int(1)]).unwrap();
+
+        let mut cursor = "0".to_string();
+        let mut seen = Vec::new();
+        loop {
+            let reply = command_router(&server, "SCAN", &[bulk(&cursor), bulk("MATCH"), bulk("k*"), bulk("COUNT"), bulk("4"), bulk("TYPE"), bulk("string")]);
+            let RESPResult::Array(parts) = reply.unwrap() else { panic!("expected array") };
+            cursor = bulk_to_string(&parts[0]).unwrap();
+            let RESPResult::Array(keys) = &parts[1] else { panic!("expected key list") };
+            seen.extend(keys.iter().map(|k| bulk_to_string(k).unwrap()));
+            if cursor == "0" {
+                break;
+            }
+        }
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), 25);
+
+        assert_eq!(command_router(&server, "SCAN", &[bulk("x")]), Err(Error::Other("invalid cursor".to_string())));
+        assert_eq!(command_router(&server, "SCAN", &[bulk("0"), bulk("COUNT"), bulk("0")]), Err(Error::Syntax));
+        assert_eq!(command_router(&server, "SCAN", &[bulk("0"), bulk("MATCH")]), Err(Error::Syntax));
+    }
+
+    #[test]
+    fn test_expire_family() {
+        let server = server();
+        let run = |cmd: &str, args: &[&str]| {
+            let data: Vec<RESPResult> = args.iter().map(|a| bulk(a)).collect();
+            command_router(&server, cmd, &data)
+        };
+        let int = |i| Ok(RESPResult::Integer(i));
+
+        server.db().set("k", DB_TYPE::Int(1));
+        assert_eq!(run("TTL", &["nope"]), int(-2));
+        assert_eq!(run("TTL", &["k"]), int(-1));
+        assert_eq!(run("EXPIRE", &["nope", "10"]), int(0));
+
+        // GT never applies to a key without an expire, XX needs one
+        assert_eq!(run("EXPIRE", &["k", "100", "GT"]), int(0));
+        assert_eq!(run("EXPIRE", &["k", "100", "XX"]), int(0));
+        assert_eq!(run("EXPIRE", &["k", "100", "NX"]), int(1));
+        assert_eq!(run("EXPIRE", &["k", "200", "NX"]), int(0));
+        assert_eq!(run("TTL", &["k"]), int(100));
+        assert_eq!(run("EXPIRE", &["k", "50", "GT"]), int(0));
+        assert_eq!(run("EXPIRE", &["k", "50", "LT"]), int(1));
+        assert_eq!(run("EXPIRE", &["k", "60", "XX", "GT"]), int(1));
+        assert_eq!(run("TTL", &["k"]), int(60));
+
+        assert_eq!(run("PEXPIRE", &["k", "5000"]), int(1));
+        match run("PTTL", &["k"]) {
+            Ok(RESPResult::Integer(ms)) => assert!(ms > 4000 && ms <= 5000),
+            other => panic!("unexpected reply {other:?}"),
+        }
+
+        assert_eq!(run("EXPIREAT", &["k", "33177600000"]), int(1));
+        assert_eq!(run("EXPIRETIME", &["k"]), int(33177600000));
+        assert_eq!(run("PEXPIRETIME", &["k"]), int(33177600000000));
+
+        assert_eq!(run("PERSIST", &["k"]), int(1));
+        assert_eq!(run("PERSIST", &["k"]), int(0));
+        assert_eq!(run("EXPIRETIME", &["k"]), int(-1));
+        assert_eq!(run("EXPIRETIME", &["nope"]), int(-2));
+
+        // a time in the past deletes the key
+        assert_eq!(run("PEXPIREAT", &["k", "1"]), int(1));
+        assert_eq!(run("EXISTS", &["k"]), int(0));
+
+        assert_eq!(run("EXPIRE", &["k", "x"]), Err(Error::NotAnInteger));
+        assert_eq!(run("EXPIRE", &["k", "10", "NX", "XX"]), Err(Error::Other("NX and XX, GT or LT options at the same time are not compatible".to_string())));
+        assert_eq!(run("EXPIRE", &["k", "10", "GT", "LT"]), Err(Error::Other("GT and LT options at the same time are not compatible".to_string())));
+        assert_eq!(run("EXPIRE", &["k", "10", "YY"]), Err(Error::Other("Unsupported option YY".to_string())));
+        assert_eq!(run("EXPIRE", &["k", "9223372036854775807"]), Err(Error::Other("invalid expire time in 'expire' command".to_string())));
+    }
+
+    #[test]
+    fn test_info_reports_expiry() {
+        let server = server();
+        server.db().set_with_expire_at("old", DB_TYPE::Int(1), 1);
+        server.db().set_with_ttl("new", DB_TYPE::Int(1), Duration::from_secs(100));
+        crate::expire::run_cycle(&server);
+
+        let info = command_router(&server, "INFO", &[]).and_then(|i| bulk_to_string(&i)).unwrap();
+        assert!(info.contains("expired_keys:1\r\n"));
+        assert!(info.contains("db0:keys=1,expires=1,avg_ttl=0\r\n"));
+
+        let info = command_router(&server, "INFO", &[bulk("keyspace")]).and_then(|i| bulk_to_string(&i)).unwrap();
+        assert!(!info.contains("# Stats"));
+    }
+
+    #[test]
+    fn test_noeviction_rejects_commands_that_add_data() {
+        let server = Server::new(Config { maxmemory: 1000, ..Config::default() });
+        for i in 0..20 {
+            server.db().set(&format!("k{i}"), DB_TYPE::Str(b"x".repeat(100)));
+        }
+
+        assert_eq!(command_router(&server, "SET", &[bulk("k"), bulk("v")]), Err(Error::Oom));
+        assert_eq!(command_router(&server, "GET", &[bulk("k0")]), Ok(bulk(&"x".repeat(100))));
+        assert_eq!(command_router(&server, "DEL", &[bulk("k0")]), Ok(RESPResult::Integer(1)));
+
+        let info = command_router(&server, "INFO", &[bulk("memory")]).and_then(|i| bulk_to_string(&i)).unwrap();
+        assert!(info.contains("maxmemory:1000\r\nmaxmemory_policy:noeviction\r\n"));
+    }
+
+    #[test]
+    fn test_config_get_and_set() {
+        let server = server();
+        let run = |cmd: &str, args: &[&str]| {
+            let data: Vec<RESPResult> = args.iter().map(|a| bulk(a)).collect();
+            command_router(&server, cmd, &data)
+        };
+        for i in 0..20 {
+            server.db().set(&format!("k{i}"), DB_TYPE::Str(b"x".repeat(100)));
+        }
+
+        assert_eq!(run("CONFIG", &["GET", "maxmemory*"]), Ok(RESPResult::Array(vec![
+            bulk("maxmemory"), bulk("0"), bulk("maxmemory-policy"), bulk("noeviction"),
+        ])));
+        assert_eq!(run("CONFIG", &["GET", "nope"]), Ok(RESPResult::Array(vec![])));
+
+        // the limit applies from the next command on
+        assert_eq!(run("CONFIG", &["SET", "maxmemory", "1k"]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(run("SET", &["k", "v"]), Err(Error::Oom));
+        assert_eq!(run("CONFIG", &["SET", "MAXMEMORY-POLICY", "allkeys-random"]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(run("SET", &["k", "v"]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert!(server.db().len() < 10);
+        assert_eq!(run("CONFIG", &["GET", "maxmemory", "maxmemory-policy"]), Ok(RESPResult::Array(vec![
+            bulk("maxmemory"), bulk("1000"), bulk("maxmemory-policy"), bulk("allkeys-random"),
+        ])));
+
+        // one bad setting and none is applied
+        assert!(matches!(run("CONFIG", &["SET", "maxmemory", "0", "maxmemory-policy", "lru"]), Err(Error::Other(_))));
+        assert_eq!(server.config().maxmemory, 1000);
+        assert!(matches!(run("CONFIG", &["SET", "port", "1"]), Err(Error::Other(_))));
+        assert_eq!(run("CONFIG", &["SET", "maxmemory"]), Err(Error::WrongArity("config|set".to_string())));
+    }
+
+    #[test]
+    fn test_memory_and_object_commands() {
+        let server = server();
+        let run = |cmd: &str, args: &[&str]| {
+            let data: Vec<RESPResult> = args.iter().map(|a| bulk(a)).collect();
+            command_router(&server, cmd, &data)
+        };
+
+        server.db().set("n", DB_TYPE::Int(42));
+        server.db().set("big", DB_TYPE::Int(123_456));
+        server.db().set("s", DB_TYPE::Str(b"x".repeat(100)));
+        let values: Vec<DB_TYPE> = (0..1000).map(|i| DB_TYPE::Str(format!("item{i}").into_bytes())).collect();
+        server.db().rpush("list", values).unwrap();
+
+        assert_eq!(run("OBJECT", &["ENCODING", "n"]), Ok(bulk("int")));
+        assert_eq!(run("OBJECT", &["ENCODING", "s"]), Ok(bulk("raw")));
+        assert_eq!(run("OBJECT", &["ENCODING", "list"]), Ok(bulk("quicklist")));
+        assert_eq!(run("OBJECT", &["REFCOUNT", "n"]), Ok(RESPResult::Integer(i32::MAX as i64)));
+        assert_eq!(run("OBJECT", &["REFCOUNT", "big"]), Ok(RESPResult::Integer(1)));
+        assert_eq!(run("OBJECT", &["IDLETIME", "s"]), Ok(RESPResult::Integer(0)));
+        assert!(matches!(run("OBJECT", &["FREQ", "s"]), Ok(RESPResult::Integer(f)) if f >= 5));
+        assert_eq!(run("OBJECT", &["ENCODING", "nope"]), Ok(RESPResult::BulkString(None)));
+        assert_eq!(run("OBJECT", &["ENCODING"]), Err(Error::WrongArity("object|encoding".to_string())));
+
+        let usage = |args: &[&str]| match run("MEMORY", args) {
+            Ok(RESPResult::Integer(n)) => n,
+            other => panic!("unexpected reply {other:?}"),
+        };
+        assert!(usage(&["USAGE", "s"]) > usage(&["USAGE", "n"]));
+        assert!(usage(&["USAGE", "list", "SAMPLES", "0"]) > 1000 * 5);
+        assert_eq!(run("MEMORY", &["USAGE", "nope"]), Ok(RESPResult::BulkString(None)));
+        assert_eq!(run("MEMORY", &["USAGE", "s", "SAMPLES", "-1"]), Err(Error::Syntax));
+
+        let RESPResult::Array(stats) = run("MEMORY", &["STATS"]).unwrap() else { panic!("expected array") };
+        assert_eq!(stats[0], bulk("total.allocated"));
+        assert_eq!(stats[1], RESPResult::Integer(server.used_memory() as i64));
+        assert!(stats.contains(&bulk("db.0")));
+
+        let doctor = run("MEMORY", &["DOCTOR"]).and_then(|d| bulk_to_string(&d)).unwrap();
+        assert!(doctor.contains("can't find any memory issue"));
+    }
+
+    #[test]
+    fn test_string_commands() {
+        let server = server();
+        let run = |cmd: &str, args: &[&str]| {
+            let data: Vec<RESPResult> = args.iter().map(|a| bulk(a)).collect();
+            command_router(&server, cmd, &data)
+        };
+        let int = |i| Ok(RESPResult::Integer(i));
+        let text = |s: &str| Ok(bulk(s));
+        let nil = Ok(bulk_reply(None));
+
+        assert_eq!(run("APPEND", &["s", "Hello"]), int(5));
+        assert_eq!(run("APPEND", &["s", " World"]), int(11));
+        assert_eq!(run("STRLEN", &["s"]), int(11));
+        assert_eq!(run("STRLEN", &["nope"]), int(0));
+        assert_eq!(run("GETRANGE", &["s", "0", "4"]), text("Hello"));
+        assert_eq!(run("GETRANGE", &["s", "-5", "-1"]), text("World"));
+        assert_eq!(run("GETRANGE", &["s", "5", "2"]), text(""));
+        assert_eq!(run("GETRANGE", &["s", "0", "100"]), text("Hello World"));
+
+        // integers read as their decimal text, and stay integers when edited into one
+        assert_eq!(run("SET", &["n", "10"]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(run("APPEND", &["n", "5"]), int(3));
+        assert_eq!(server.db().get("n"), Some(DB_TYPE::Int(105)));
+        assert_eq!(run("STRLEN", &["n"]), int(3));
+        assert_eq!(run("GETRANGE", &["n", "1", "1"]), text("0"));
+
+        assert_eq!(run("SETRANGE", &["s", "6", "Redis"]), int(11));
+        assert_eq!(run("GET", &["s"]), text("Hello Redis"));
+        assert_eq!(run("SETRANGE", &["pad", "3", "x"]), int(4));
+        assert_eq!(run("GET", &["pad"]), text("\0\0\0x"));
+        assert_eq!(run("SETRANGE", &["empty", "3", ""]), int(0));
+        assert_eq!(run("EXISTS", &["empty"]), int(0));
+        assert_eq!(run("SETRANGE", &["s", "-1", "x"]), Err(Error::Other("offset is out of range".to_string())));
+        assert_eq!(run("SETRANGE", &["s", "536870912", "x"]), Err(Error::Other("string exceeds maximum allowed size (proto-max-bulk-len)".to_string())));
+
+        assert_eq!(run("GETSET", &["s", "new"]), text("Hello Redis"));
+        assert_eq!(run("GETSET", &["fresh", "1"]), nil);
+        assert_eq!(run("GETDEL", &["fresh"]), text("1"));
+        assert_eq!(run("GETDEL", &["fresh"]), nil);
+
+        server.db().lpush("list", vec![DB_TYPE::Str(b"a".to_vec())]).unwrap();
+        assert_eq!(run("APPEND", &["list", "x"]), Err(Error::WrongType));
+        assert_eq!(run("GETDEL", &["list"]), Err(Error::WrongType));
+
+        assert_eq!(run("MSET", &["a", "1", "b", "2"]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(run("MSET", &["a", "1", "b"]), Err(Error::WrongArity("mset".to_string())));
+        assert_eq!(run("MGET", &["a", "nope", "b", "list"]), Ok(RESPResult::Array(vec![
+            bulk("1"), bulk_reply(None), bulk("2"), bulk_reply(None),
+        ])));
+        assert_eq!(run("MSETNX", &["c", "3", "a", "9"]), int(0));
+        assert_eq!(run("EXISTS", &["c"]), int(0));
+        assert_eq!(run("MSETNX", &["c", "3", "d", "4"]), int(1));
+        assert_eq!(run("SETNX", &["c", "5"]), int(0));
+        assert_eq!(run("SETNX", &["e", "5"]), int(1));
+        assert_eq!(run("GET", &["e"]), text("5"));
+    }
+
+    #[test]
+    fn test_string_expire_commands() {
+        let server = server();
+        let run = |cmd: &str, args: &[&str]| {
+            let data: Vec<RESPResult> = args.iter().map(|a| bulk(a)).collect();
+            command_router(&server, cmd, &data)
+        };
+        let int = |i| Ok(RESPResult::Integer(i));
+        let text = |s: &str| Ok(bulk(s));
+
+        assert_eq!(run("SETEX", &["k", "100", "v"]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(run("TTL", &["k"]), int(100));
+        assert_eq!(run("SETEX", &["k", "0", "v"]), Err(Error::Other("invalid expire time in 'setex' command".to_string())));
+        assert_eq!(run("PSETEX", &["k", "-5", "v"]), Err(Error::Other("invalid expire time in 'psetex' command".to_string())));
+        assert_eq!(run("PSETEX", &["p", "5000", "v"]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(run("TTL", &["p"]), int(5));
+
+        // MSET and GETSET drop an existing expire
+        assert_eq!(run("MSET", &["k", "w"]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(run("TTL", &["k"]), int(-1));
+        assert_eq!(run("GETSET", &["p", "w"]), text("v"));
+        assert_eq!(run("TTL", &["p"]), int(-1));
+
+        assert_eq!(run("GETEX", &["k"]), text("w"));
+        assert_eq!(run("GETEX", &["k", "EX", "50"]), text("w"));
+        assert_eq!(run("TTL", &["k"]), int(50));
+        assert_eq!(run("GETEX", &["k", "PERSIST"]), text("w"));
+        assert_eq!(run("TTL", &["k"]), int(-1));
+        assert_eq!(run("GETEX", &["k", "EXAT", "33177600000"]), text("w"));
+        assert_eq!(run("EXPIRETIME", &["k"]), int(33177600000));
+        assert_eq!(run("GETEX", &["nope", "PX", "100"]), Ok(bulk_reply(None)));
+        assert_eq!(run("GETEX", &["k", "EX", "0"]), Err(Error::Other("invalid expire time in 'getex' command".to_string())));
+        assert_eq!(run("GETEX", &["k", "EX"]), Err(Error::Syntax));
+        assert_eq!(run("GETEX", &["k", "EX", "1", "PERSIST"]), Err(Error::Syntax));
+
+        // a time already passed deletes the key once it has been read
+        assert_eq!(run("GETEX", &["k", "PXAT", "1"]), text("w"));
+        assert_eq!(run("EXISTS", &["k"]), int(0));
+    }
+
+    #[test]
+    fn test_lcs_command() {
+        let server = server();
+        let run = |cmd: &str, args: &[&str]| {
+            let data: Vec<RESPResult> = args.iter().map(|a| bulk(a)).collect();
+            command_router(&server, cmd, &data)
+        };
+        let int = |i| RESPResult::Integer(i);
+        let range = |a, b| RESPResult::Array(vec![int(a), int(b)]);
+
+        run("MSET", &["key1", "ohmytext", "key2", "mynewtext"]).unwrap();
+        assert_eq!(run("LCS", &["key1", "key2"]), Ok(bulk("mytext")));
+        assert_eq!(run("LCS", &["key1", "key2", "LEN"]), Ok(int(6)));
+        assert_eq!(run("LCS", &["key1", "nope"]), Ok(bulk("")));
+
+        assert_eq!(run("LCS", &["key1", "key2", "IDX"]), Ok(RESPResult::Array(vec![
+            bulk("matches"),
+            RESPResult::Array(vec![
+                RESPResult::Array(vec![range(4, 7), range(5, 8)]),
+                RESPResult::Array(vec![range(2, 3), range(0, 1)]),
+            ]),
+            bulk("len"),
+            int(6),
+        ])));
+        assert_eq!(run("LCS", &["key1", "key2", "IDX", "MINMATCHLEN", "4", "WITHMATCHLEN"]), Ok(RESPResult::Array(vec![
+            bulk("matches"),
+            RESPResult::Array(vec![RESPResult::Array(vec![range(4, 7), range(5, 8), int(4)])]),
+            bulk("len"),
+            int(6),
+        ])));
+
+        assert_eq!(run("LCS", &["key2", "key1", "LEN"]), Ok(int(6)));
+        assert_eq!(run("LCS", &["key1", "nope", "LEN"]), Ok(int(0)));
+
+        // LEN needs no table, the rest one that fits in proto-max-bulk-len
+        run("MSET", &["long1", &"a".repeat(12_000), "long2", &"a".repeat(11_200)]).unwrap();
+        assert_eq!(run("LCS", &["long1", "long2", "IDX"]),
+            Err(Error::Other("Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len".to_string())));
+
+        assert_eq!(run("LCS", &["key1", "key2", "LEN", "IDX"]), Err(Error::Other("If you want both the length and indexes, please just use IDX.".to_string())));
+        assert_eq!(run("LCS", &["key1", "key2", "MINMATCHLEN"]), Err(Error::Syntax));
+        server.db().lpush("list", vec![DB_TYPE::Str(b"a".to_vec())]).unwrap();
+        assert_eq!(run("LCS", &["key1", "list"]), Err(Error::Other("The specified keys must contain string values".to_string())));
+    }
+
+    #[test]
+    fn test_bitmap_commands() {
+        let server = server();
+        let run = |cmd: &str, args: &[&str]| {
+            let data: Vec<RESPResult> = args.iter().map(|a| bulk(a)).collect();
+            command_router(&server, cmd, &data)
+        };
+        let int = |i| Ok(RESPResult::Integer(i));
+
+        assert_eq!(run("SETBIT", &["m", "7", "1"]), int(0));
+        assert_eq!(run("SETBIT", &["m", "7", "1"]), int(1));
+        assert_eq!(run("GETBIT", &["m", "7"]), int(1));
+        assert_eq!(run("GETBIT", &["m", "100"]), int(0));
+        assert_eq!(run("GET", &["m"]), Ok(bulk("\x01")));
+        assert_eq!(run("SETBIT", &["m", "4294967296", "1"]), Err(Error::Other("bit offset is not an integer or out of range".to_string())));
+        assert_eq!(run("SETBIT", &["m", "0", "2"]), Err(Error::Other("bit is not an integer or out of range".to_string())));
+
+        run("SET", &["s", "foobar"]).unwrap();
+        assert_eq!(run("BITCOUNT", &["s"]), int(26));
+        assert_eq!(run("BITCOUNT", &["s", "1", "1"]), int(6));
+        assert_eq!(run("BITCOUNT", &["s", "-2", "-1", "BYTE"]), int(7));
+        assert_eq!(run("BITCOUNT", &["s", "5", "30", "BIT"]), int(17));
+        assert_eq!(run("BITCOUNT", &["nope"]), int(0));
+        assert_eq!(run("BITCOUNT", &["s", "0"]), Err(Error::Syntax));
+        assert_eq!(run("BITCOUNT", &["s", "0", "1", "BITS"]), Err(Error::Syntax));
+
+        server.db().set("p", DB_TYPE::Str(vec![0x00, 0xff, 0xf0]));
+        assert_eq!(run("BITPOS", &["p", "1"]), int(8));
+        assert_eq!(run("BITPOS", &["p", "1", "2"]), int(16));
+        assert_eq!(run("BITPOS", &["p", "1", "2", "-1", "BYTE"]), int(16));
+        assert_eq!(run("BITPOS", &["p", "1", "7", "15", "BIT"]), int(8));
+        assert_eq!(run("BITPOS", &["p", "0", "8", "-1", "BIT"]), int(20));
+        server.db().set("ones", DB_TYPE::Str(vec![0xff, 0xff]));
+        // without an end a clear bit is found just past the string
+        assert_eq!(run("BITPOS", &["ones", "0"]), int(16));
+        assert_eq!(run("BITPOS", &["ones", "0", "0", "-1"]), int(-1));
+        assert_eq!(run("BITPOS", &["nope", "0"]), int(0));
+        assert_eq!(run("BITPOS", &["nope", "1"]), int(-1));
+        assert_eq!(run("BITPOS", &["p", "2"]), Err(Error::Other("The bit argument must be 1 or 0.".to_string())));
+
+        run("SET", &["a", "foobar"]).unwrap();
+        run("SET", &["b", "abcdef"]).unwrap();
+        assert_eq!(run("BITOP", &["AND", "dest", "a", "b"]), int(6));
+        assert_eq!(run("GET", &["dest"]), Ok(bulk("`bc`ab")));
+        assert_eq!(run("BITOP", &["or", "dest", "a", "b"]), int(6));
+        assert_eq!(run("GET", &["dest"]), Ok(bulk("goofev")));
+        assert_eq!(run("BITOP", &["DIFF", "dest", "a", "b"]), int(6));
+        assert_eq!(run("GET", &["dest"]), Ok(bulk("\x06\x0d\x0c\x02\x00\x10")));
+        assert_eq!(run("BITOP", &["AND", "dest", "nope", "nope2"]), int(0));
+        assert_eq!(run("EXISTS", &["dest"]), int(0));
+        assert_eq!(run("BITOP", &["NOT", "dest", "a", "b"]), Err(Error::Other("BITOP NOT must be called with a single source key.".to_string())));
+        assert_eq!(run("BITOP", &["DIFF", "dest", "a"]), Err(Error::Other("BITOP DIFF must be called with at least two source keys.".to_string())));
+        assert_eq!(run("BITOP", &["NAND", "dest", "a"]), Err(Error::Syntax));
+        server.db().lpush("list", vec![DB_TYPE::Int(1)]).unwrap();
+        assert_eq!(run("BITOP", &["OR", "dest", "a", "list"]), Err(Error::WrongType));
+        assert_eq!(run("SETBIT", &["list", "0", "1"]), Err(Error::WrongType));
+    }
+
+    #[test]
+    fn test_bitfield_command() {
+        let server = server();
+        let run = |cmd: &str, args: &[&str]| {
+            let data: Vec<RESPResult> = args.iter().map(|a| bulk(a)).collect();
+            command_router(&server, cmd, &data)
+        };
+        let ints = |v: &[i64]| Ok(RESPResult::Array(v.iter().map(|&i| RESPResult::Integer(i)).collect()));
+
+        // reads alone never create the key
+        assert_eq!(run("BITFIELD", &["f", "GET", "i8", "0"]), ints(&[0]));
+        assert_eq!(run("EXISTS", &["f"]), Ok(RESPResult::Integer(0)));
+
+        assert_eq!(run("BITFIELD", &["f", "INCRBY", "i5", "100", "1", "GET", "u4", "0"]), ints(&[1, 0]));
+        for expected in [[1, 1], [2, 2], [3, 3], [0, 3]] {
+            let reply = run("BITFIELD", &["g", "INCRBY", "u2", "100", "1", "OVERFLOW", "SAT", "INCRBY", "u2", "102", "1"]);
+            assert_eq!(reply, ints(&expected));
+        }
+        assert_eq!(
+            run("BITFIELD", &["g", "OVERFLOW", "FAIL", "INCRBY", "u2", "102", "1", "SET", "u2", "102", "4"]),
+            Ok(RESPResult::Array(vec![RESPResult::BulkString(None), RESPResult::BulkString(None)]))
+        );
+
+        assert_eq!(run("BITFIELD", &["h", "SET", "i8", "#1", "-1", "GET", "u8", "#1", "GET", "i8", "8"]), ints(&[0, 255, -1]));
+        assert_eq!(run("BITFIELD", &["h", "SET", "u8", "0", "300", "GET", "u8", "0"]), ints(&[0, 44]));
+        assert_eq!(run("BITFIELD", &["h", "OVERFLOW", "SAT", "SET", "i8", "0", "-300", "GET", "i8", "0"]), ints(&[44, -128]));
+        assert_eq!(run("BITFIELD", &["h", "SET", "i64", "0", "-9223372036854775808", "INCRBY", "i64", "0", "-1"]), ints(&[-9151595917793558528, 9223372036854775807]));
+        assert_eq!(run("BITFIELD_RO", &["h", "GET", "i8", "0"]), ints(&[127]));
+
+        assert_eq!(run("BITFIELD_RO", &["h", "SET", "i8", "0", "1"]), Err(Error::Other("BITFIELD_RO only supports the GET subcommand".to_string())));
+        assert_eq!(run("BITFIELD", &["h", "GET", "u64", "0"]), Err(Error::Other("Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.".to_string())));
+        assert_eq!(run("BITFIELD", &["h", "GET", "i8", "-1"]), Err(Error::Other("bit offset is not an integer or out of range".to_string())));
+        assert_eq!(run("BITFIELD", &["h", "GET", "i8", "4294967290"]), Err(Error::Other("bit offset is not an integer or out of range".to_string())));
+        assert_eq!(run("BITFIELD", &["h", "OVERFLOW", "MAYBE"]), Err(Error::Other("Invalid OVERFLOW type specified".to_string())));
+        assert_eq!(run("BITFIELD", &["h", "SET", "i8", "0"]), Err(Error::Syntax));
+        assert_eq!(run("BITFIELD", &["h", "INCRBY", "i8", "0", "x"]), Err(Error::NotAnInteger));
+    }
+
+    #[test]
+    fn test_list_commands() {
+        let server = server();
+        let run = |cmd: &str, args: &[&str]| {
+            let data: Vec<RESPResult> = args.iter().map(|a| bulk(a)).collect();
+            command_router(&server, cmd, &data)
+        };
+        let int = |i| Ok(RESPResult::Integer(i));
+        let text = |s: &str| Ok(bulk(s));
+        let list = |items: &[&str]| Ok(RESPResult::Array(items.iter().map(|s| bulk(s)).collect()));
+        let nil = Ok(bulk_reply(None));
+        let ok = Ok(RESPResult::SimpleString("OK".to_string()));
+
+        assert_eq!(run("LPUSHX", &["l", "a"]), int(0));
+        assert_eq!(run("EXISTS", &["l"]), int(0));
+        assert_eq!(run("RPUSH", &["l", "a", "b", "c"]), int(3));
+        assert_eq!(run("LPUSH", &["l", "y", "z"]), int(5));
+        assert_eq!(run("RPUSHX", &["l", "d"]), int(6));
+        assert_eq!(run("LRANGE", &["l", "0", "-1"]), list(&["z", "y", "a", "b", "c", "d"]));
+        assert_eq!(run("LLEN", &["l"]), int(6));
+
+        assert_eq!(run("LINDEX", &["l", "0"]), text("z"));
+        assert_eq!(run("LINDEX", &["l", "-1"]), text("d"));
+        assert_eq!(run("LINDEX", &["l", "6"]), nil);
+        assert_eq!(run("LSET", &["l", "-2", "C"]), ok);
+        assert_eq!(run("LSET", &["l", "6", "x"]), Err(Error::Other("index out of range".to_string())));
+        assert_eq!(run("LSET", &["nope", "0", "x"]), Err(Error::Other("no such key".to_string())));
+
+        assert_eq!(run("LINSERT", &["l", "BEFORE", "a", "1"]), int(7));
+        assert_eq!(run("LINSERT", &["l", "after", "d", "2"]), int(8));
+        assert_eq!(run("LINSERT", &["l", "AFTER", "missing", "3"]), int(-1));
+        assert_eq!(run("LINSERT", &["nope", "AFTER", "a", "3"]), int(0));
+        assert_eq!(run("LINSERT", &["l", "AROUND", "a", "3"]), Err(Error::Syntax));
+        assert_eq!(run("LRANGE", &["l", "0", "-1"]), list(&["z", "y", "1", "a", "b", "C", "d", "2"]));
+
+        assert_eq!(run("LPOP", &["l"]), text("z"));
+        assert_eq!(run("RPOP", &["l", "2"]), list(&["2", "d"]));
+        assert_eq!(run("LPOP", &["l", "0"]), list(&[]));
+        assert_eq!(run("LPOP", &["l", "-1"]), Err(Error::Other("value is out of range, must be positive".to_string())));
+        assert_eq!(run("LPOP", &["nope"]), nil);
+        assert_eq!(run("LPOP", &["nope", "2"]), nil);
+
+        // popping the last element deletes the list
+        assert_eq!(run("LPOP", &["l", "10"]), list(&["y", "1", "a", "b", "C"]));
+        assert_eq!(run("EXISTS", &["l"]), int(0));
+
+        assert_eq!(run("RPUSH", &["r", "x", "1", "x", "2", "x", "x"]), int(6));
+        assert_eq!(run("LREM", &["r", "-2", "x"]), int(2));
+        assert_eq!(run("LRANGE", &["r", "0", "-1"]), list(&["x", "1", "x", "2"]));
+        assert_eq!(run("LREM", &["r", "1", "x"]), int(1));
+        assert_eq!(run("LREM", &["r", "0", "1"]), int(1));
+        assert_eq!(run("LRANGE", &["r", "0", "-1"]), list(&["x", "2"]));
+        assert_eq!(run("LTRIM", &["r", "1", "-1"]), ok);
+        assert_eq!(run("LRANGE", &["r", "0", "-1"]), list(&["2"]));
+        assert_eq!(run("LTRIM", &["r", "5", "10"]), ok);
+        assert_eq!(run("EXISTS", &["r"]), int(0));
+
+        assert_eq!(run("RPUSH", &["p", "a", "b", "c", "1", "2", "3", "c", "c"]), int(8));
+        assert_eq!(run("LPOS", &["p", "c"]), int(2));
+        assert_eq!(run("LPOS", &["p", "c", "RANK", "2"]), int(6));
+        assert_eq!(run("LPOS", &["p", "c", "RANK", "-1"]), int(7));
+        assert_eq!(run("LPOS", &["p", "c", "COUNT", "2"]), Ok(RESPResult::Array(vec![RESPResult::Integer(2), RESPResult::Integer(6)])));
+        assert_eq!(run("LPOS", &["p", "c", "COUNT", "0", "RANK", "-2"]), Ok(RESPResult::Array(vec![RESPResult::Integer(6), RESPResult::Integer(2)])));
+        assert_eq!(run("LPOS", &["p", "c", "MAXLEN", "2"]), nil);
+        assert_eq!(run("LPOS", &["p", "x", "COUNT", "1"]), list(&[]));
+        assert_eq!(run("LPOS", &["p", "c", "COUNT", "-1"]), Err(Error::Other("COUNT can't be negative".to_string())));
+        assert_eq!(run("LPOS", &["p", "c", "MAXLEN", "-1"]), Err(Error::Other("MAXLEN can't be negative".to_string())));
+        assert!(matches!(run("LPOS", &["p", "c", "RANK", "0"]), Err(Error::Other(e)) if e.starts_with("RANK can't be zero")));
+        assert_eq!(run("LPOS", &["p", "c", "RANK"]), Err(Error::Syntax));
+
+        assert_eq!(run("RPUSH", &["src", "a", "b", "c"]), int(3));
+        assert_eq!(run("LMOVE", &["src", "dst", "LEFT", "RIGHT"]), text("a"));
+        assert_eq!(run("LMOVE", &["src", "dst", "RIGHT", "LEFT"]), text("c"));
+        assert_eq!(run("RPOPLPUSH", &["src", "dst"]), text("b"));
+        assert_eq!(run("EXISTS", &["src"]), int(0));
+        assert_eq!(run("LRANGE", &["dst", "0", "-1"]), list(&["b", "c", "a"]));
+        assert_eq!(run("RPOPLPUSH", &["src", "dst"]), nil);
+        // rotating a list onto itself
+        assert_eq!(run("LMOVE", &["dst", "dst", "LEFT", "RIGHT"]), text("b"));
+        assert_eq!(run("LRANGE", &["dst", "0", "-1"]), list(&["c", "a", "b"]));
+        assert_eq!(run("LMOVE", &["dst", "dst", "UP", "RIGHT"]), Err(Error::Syntax));
+
+        assert_eq!(run("LMPOP", &["2", "nope", "dst", "RIGHT", "COUNT", "2"]),
+            Ok(RESPResult::Array(vec![bulk("dst"), RESPResult::Array(vec![bulk("b"), bulk("a")])])));
+        assert_eq!(run("LMPOP", &["1", "dst", "LEFT"]), Ok(RESPResult::Array(vec![bulk("dst"), RESPResult::Array(vec![bulk("c")])])));
+        assert_eq!(run("LMPOP", &["1", "dst", "LEFT"]), Ok(RESPResult::NullArray));
+        assert_eq!(run("LMPOP", &["0", "dst", "LEFT"]), Err(Error::Other("numkeys should be greater than 0".to_string())));
+        assert_eq!(run("LMPOP", &["1", "dst", "LEFT", "COUNT", "0"]), Err(Error::Other("count should be greater than 0".to_string())));
+        assert_eq!(run("LMPOP", &["2", "dst", "LEFT"]), Err(Error::Syntax));
+
+        // lists and strings do not mix
+        assert_eq!(run("SET", &["s", "v"]), ok);
+        for (cmd, args) in [
+            ("LPUSH", &["s", "a"][..]),
+            ("LPUSHX", &["s", "a"]),
+            ("LPOP", &["s"]),
+            ("LLEN", &["s"]),
+            ("LRANGE", &["s", "0", "-1"]),
+            ("LINDEX", &["s", "0"]),
+            ("LSET", &["s", "0", "a"]),
+            ("LINSERT", &["s", "BEFORE", "a", "b"]),
+            ("LREM", &["s", "0", "a"]),
+            ("LTRIM", &["s", "0", "1"]),
+            ("LPOS", &["s", "a"]),
+            ("LMOVE", &["s", "dst", "LEFT", "LEFT"]),
+            ("LMPOP", &["2", "nope", "s", "LEFT"]),
+        ] {
+            assert_eq!(run(cmd, args), Err(Error::WrongType), "{cmd}");
+        }
+        assert_eq!(run("RPUSH", &["p2", "a"]), int(1));
+        assert_eq!(run("LMOVE", &["p2", "s", "LEFT", "LEFT"]), Err(Error::WrongType));
+        assert_eq!(run("LLEN", &["p2"]), int(1));
+        assert_eq!(run("GET", &["p2"]), Err(Error::WrongType));
+    }
+
+    #[test]
+    fn test_blocking_list_commands_outside_a_connection() {
+        let server = server();
+        let run = |cmd: &str, args: &[&str]| {
+            let data: Vec<RESPResult> = args.iter().map(|a| bulk(a)).collect();
+            command_router(&server, cmd, &data)
+        };
+        let (nil, nil_array) = (Ok(bulk_reply(None)), Ok(RESPResult::NullArray));
+
+        assert_eq!(run("RPUSH", &["b", "x", "y", "z"]), Ok(RESPResult::Integer(3)));
+        assert_eq!(run("BLPOP", &["a", "b", "0"]), Ok(RESPResult::Array(vec![bulk("b"), bulk("x")])));
+        assert_eq!(run("BRPOP", &["b", "0.5"]), Ok(RESPResult::Array(vec![bulk("b"), bulk("z")])));
+        assert_eq!(run("BLMOVE", &["b", "c", "LEFT", "LEFT", "0"]), Ok(bulk("y")));
+        assert_eq!(run("BLMPOP", &["0", "2", "b", "c", "RIGHT"]),
+            Ok(RESPResult::Array(vec![bulk("c"), RESPResult::Array(vec![bulk("y")])])));
+
+        // nothing to take is nil at once, there is no connection to park
+        assert_eq!(run("BLPOP", &["b", "0"]), nil_array);
+        assert_eq!(run("BLMOVE", &["b", "c", "LEFT", "LEFT", "0"]), nil);
+        assert_eq!(run("BLMPOP", &["0", "1", "b", "LEFT"]), nil_array);
+        assert_eq!(server.blocking().blocked_clients(), 0);
+
+        assert_eq!(run("BLPOP", &["b", "-1"]), Err(Error::Other("timeout is negative".to_string())));
+        assert_eq!(run("BLPOP", &["b", "soon"]), Err(Error::Other("timeout is not a float or out of range".to_string())));
+        assert_eq!(run("BLMPOP", &["0", "0", "b", "LEFT"]), Err(Error::Other("numkeys should be greater than 0".to_string())));
+        assert_eq!(run("BLMOVE", &["b", "c", "UP", "LEFT", "0"]), Err(Error::Syntax));
+
+        assert_eq!(run("SET", &["s", "v"]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(run("BLPOP", &["b", "s", "0"]), Err(Error::WrongType));
+
+        // scripts never block either
+        assert_eq!(run("EVAL", &["return redis.call('BLPOP', KEYS[1], 0)", "1", "b"]), nil);
+    }
+
+    #[test]
+    fn test_select_move_and_swapdb() {
+        let server = server();
+        let mut session = Session::new();
+        let mut run = |command: &str, args: &[&str]| {
+            let data: Vec<RESPResult> = args.iter().map(|a| bulk(a)).collect();
+            command_router_in(&server, &mut session, command, &data)
+        };
+        let ok = Ok(RESPResult::SimpleString("OK".to_string()));
+
+        run("SET", &["k", "one"]).unwrap();
+        assert_eq!(run("SELECT", &["1"]), ok);
+        assert_eq!(run("EXISTS", &["k"]), Ok(RESPResult::Integer(0)));
+        run("SET", &["k", "two"]).unwrap();
+        run("SET", &["other", "x"]).unwrap();
+
+        assert_eq!(run("SELECT", &["16"]), Err(Error::Other("DB index is out of range".to_string())));
+        assert_eq!(run("SELECT", &["x"]), Err(Error::NotAnInteger));
+
+        // k exists in database 0 too, other does not
+        assert_eq!(run("MOVE", &["k", "0"]), Ok(RESPResult::Integer(0)));
+        assert_eq!(run("MOVE", &["other", "0"]), Ok(RESPResult::Integer(1)));
+        assert_eq!(run("MOVE", &["other", "0"]), Ok(RESPResult::Integer(0)));
+        assert_eq!(run("MOVE", &["k", "1"]), Err(Error::Other("source and destination objects are the same".to_string())));
+        assert_eq!(run("DBSIZE", &[]), Ok(RESPResult::Integer(1)));
+
+        assert_eq!(run("SWAPDB", &["0", "1"]), ok);
+        assert_eq!(run("DBSIZE", &[]), Ok(RESPResult::Integer(2)));
+        assert_eq!(run("SWAPDB", &["x", "1"]), Err(Error::Other("invalid first DB index".to_string())));
+        assert_eq!(run("SWAPDB", &["0", "99"]), Err(Error::Other("DB index is out of range".to_string())));
+
+        assert_eq!(server.db().get("k"), Some(DB_TYPE::Str(b"two".to_vec())));
+        assert_eq!(server.databases()[1].get("other"), Some(DB_TYPE::Str(b"x".to_vec())));
+    }
+
+    #[test]
+    fn test_flushdb_and_flushall() {
+        let server = server();
+        let mut session = Session::new();
+        server.db().set("a", DB_TYPE::Int(1));
+        server.databases()[1].set("b", DB_TYPE::Int(2));
+        server.databases()[2].set("c", DB_TYPE::Int(3));
+
+        session.select(1);
+        assert_eq!(command_router_in(&server, &mut session, "FLUSHDB", &[bulk("ASYNC")]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert!(server.databases()[1].is_empty());
+        assert_eq!(server.db().len(), 1);
+
+        assert_eq!(command_router_in(&server, &mut session, "FLUSHDB", &[bulk("LATER")]), Err(Error::Syntax));
+        assert_eq!(command_router(&server, "FLUSHALL", &[]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert!(server.databases().iter().all(|db| db.is_empty()));
+    }
+
+    #[test]
+    fn test_save_and_load_every_database() {
+        let dbfilename = std::env::temp_dir()
+            .join(format!("rs-redis-{}-databases.rdb", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let config = Config { dbfilename: dbfilename.clone(), databases: 4, ..Config::default() };
+
+        let server = Server::new(config.clone());
+        server.db().set("zero", DB_TYPE::Int(0));
+        server.databases()[3].set("three", DB_TYPE::Int(3));
+        assert_eq!(command_router(&server, "SAVE", &[]), Ok(RESPResult::SimpleString("OK".to_string())));
+
+        let loaded = Server::new(config);
+        assert_eq!(command_router(&loaded, "LOAD", &[bulk(&dbfilename)]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(loaded.db().get("zero"), Some(DB_TYPE::Int(0)));
+        assert_eq!(loaded.databases()[3].get("three"), Some(DB_TYPE::Int(3)));
+        assert!(loaded.databases()[1].is_empty());
+
+        // a snapshot with more databases than configured does not load
+        let small = Server::new(Config { databases: 2, ..Config::default() });
+        assert!(matches!(command_router(&small, "LOAD", &[bulk(&dbfilename)]), Err(Error::Other(_))));
+        assert!(small.databases().iter().all(|db| db.is_empty()));
+
+        // the snapshot replaces the keyspace rather than merging into it
+        loaded.db().set("extra", DB_TYPE::Int(1));
+        loaded.databases()[1].set("one", DB_TYPE::Int(1));
+        assert_eq!(command_router(&loaded, "LOAD", &[bulk(&dbfilename)]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(command_router(&loaded, "DBSIZE", &[]), Ok(RESPResult::Integer(1)));
+        assert!(loaded.databases()[1].is_empty());
+
+        // a missing or corrupt file is an error that leaves the keyspace alone
+        loaded.db().set("extra", DB_TYPE::Int(1));
+        let missing = format!("{dbfilename}.missing");
+        assert!(matches!(command_router(&loaded, "LOAD", &[bulk(&missing)]), Err(Error::Other(_))));
+
+        let contents = std::fs::read(&dbfilename).unwrap();
+        std::fs::write(&dbfilename, &contents[..contents.len() - 10]).unwrap();
+        assert!(matches!(command_router(&loaded, "LOAD", &[bulk(&dbfilename)]), Err(Error::Other(_))));
+        std::fs::write(&dbfilename, String::from_utf8_lossy(&contents).replace("FD ", "FD x")).unwrap();
+        assert!(matches!(command_router(&loaded, "LOAD", &[bulk(&dbfilename)]), Err(Error::Other(_))));
+        assert_eq!(command_router(&loaded, "DBSIZE", &[]), Ok(RESPResult::Integer(2)));
+
+        // as is a library that does not compile, which leaves the libraries alone too
+        let library = "#!lua name=lib\nredis.register_function('f', function() return 1 end)";
+        assert_eq!(command_router(&loaded, "FUNCTION", &[bulk("LOAD"), bulk(library)]), Ok(bulk("lib")));
+        let broken = vec!["#!lua name=broken\nerror('no')".to_string()];
+        assert_eq!(Db::write_snapshot(&server.databases()[..1], &dbfilename, &broken), Ok(()));
+        assert!(matches!(command_router(&loaded, "LOAD", &[bulk(&dbfilename)]), Err(Error::Other(_))));
+        assert_eq!(command_router(&loaded, "DBSIZE", &[]), Ok(RESPResult::Integer(2)));
+        assert_eq!(command_router(&loaded, "FCALL", &[bulk("f"), bulk("0")]), Ok(RESPResult::Integer(1)));
+        std::fs::remove_file(&dbfilename).ok();
+    }
+
+    #[test]
+    fn test_register_command() {
+        let server = server();
+        let count = server.commands().len();
+
+        assert_eq!(server.register_command(Hello), Ok(()));
+        assert_eq!(server.commands().len(), count + 1);
+        assert_eq!(command_router(&server, "HELLO", &[]), Ok(RESPResult::SimpleString("world".to_string())));
+        assert_eq!(command_router(&server, "hello", &[bulk("x")]), Err(Error::WrongArity("hello".to_string())));
+
+        // names are unique, built in commands can not be replaced
+        assert!(server.register_command(Hello).is_err());
+        assert!(server.register_command(Named("GET")).is_err());
+    }
+
+    struct Named(&'static str);
+
+    impl Command for Named {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn arity(&self) -> i32 {
+            1
+        }
+
+        fn execute(&self, _ctx: &mut Context<'_>, _args: &[RESPResult]) -> Result<RESPResult, Error> {
+            Ok(RESPResult::SimpleString("OK".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_command_count_and_info() {
+        let server = server();
+
+        let count = command_router(&server, "COMMAND", &[bulk("COUNT")]);
+        assert_eq!(count, Ok(RESPResult::Integer(server.commands().len() as i64)));
+
+        let info = command_router(&server, "COMMAND", &[bulk("info"), bulk("get"), bulk("nope")]).unwrap();
+        let status = |s: &str| RESPResult::SimpleString(s.to_string());
+        assert_eq!(
+            info,
+            RESPResult::Array(vec![
+                RESPResult::Array(vec![
+                    bulk("get"),
+                    RESPResult::Integer(2),
+                    RESPResult::Array(vec![status("readonly"), status("fast")]),
+                    RESPResult::Integer(1),
+                    RESPResult::Integer(1),
+                    RESPResult::Integer(1),
+                    RESPResult::Array(vec![status("@read"), status("@string"), status("@fast")]),
+                    RESPResult::Array(vec![]),
+                    RESPResult::Array(vec![]),
+                    RESPResult::Array(vec![]),
+                ]),
+                RESPResult::BulkString(None),
+            ])
+        );
+
+        match command_router(&server, "COMMAND", &[]) {
+            Ok(RESPResult::Array(all)) => assert_eq!(all.len(), server.commands().len()),
+            other => panic!("Expected array, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_command_getkeys() {
+        let server = server();
+
+        let keys = command_router(&server, "COMMAND", &[bulk("GETKEYS"), bulk("del"), bulk("a"), bulk("b")]);
+        assert_eq!(keys, Ok(RESPResult::Array(vec![bulk("a"), bulk("b")])));
+
+        let keys = command_router(&server, "COMMAND", &[bulk("GETKEYS"), bulk("set"), bulk("k"), bulk("v"), bulk("EX"), bulk("1")]);
+        assert_eq!(keys, Ok(RESPResult::Array(vec![bulk("k")])));
+
+        let no_keys = command_router(&server, "COMMAND", &[bulk("GETKEYS"), bulk("ping")]);
+        assert_eq!(no_keys, Err(Error::Other("The command has no key arguments".to_string())));
+
+        let bad_arity = command_router(&server, "COMMAND", &[bulk("GETKEYS"), bulk("get")]);
+        assert_eq!(bad_arity, Err(Error::Other("Invalid number of arguments specified for command".to_string())));
+
+        // keys counted by numkeys move with it
+        let getkeys = |args: &[&str]| {
+            let mut data = vec![bulk("GETKEYS")];
+            data.extend(args.iter().map(|a| bulk(a)));
+            command_router(&server, "COMMAND", &data)
+        };
+        let keys = |names: &[&str]| Ok(RESPResult::Array(names.iter().map(|k| bulk(k)).collect()));
+        assert_eq!(getkeys(&["eval", "return 1", "2", "a", "b", "arg"]), keys(&["a", "b"]));
+        assert_eq!(getkeys(&["fcall_ro", "f", "1", "a"]), keys(&["a"]));
+        assert_eq!(getkeys(&["lmpop", "2", "a", "b", "LEFT"]), keys(&["a", "b"]));
+        assert_eq!(getkeys(&["blmpop", "0", "1", "a", "LEFT"]), keys(&["a"]));
+        assert_eq!(getkeys(&["eval", "return 1", "0"]), Err(Error::Other("The command has no key arguments".to_string())));
+
+        let info = |name: &str| match command_router(&server, "COMMAND", &[bulk("INFO"), bulk(name)]) {
+            Ok(RESPResult::Array(mut infos)) => match infos.remove(0) {
+                RESPResult::Array(mut fields) => fields.remove(2),
+                other => panic!("unexpected info {other:?}"),
+            },
+            other => panic!("unexpected reply {other:?}"),
+        };
+        let flag = RESPResult::SimpleString("movablekeys".to_string());
+        assert!(matches!(info("evalsha"), RESPResult::Array(flags) if flags.contains(&flag)));
+        assert!(matches!(info("get"), RESPResult::Array(flags) if !flags.contains(&flag)));
+    }
+
+    #[test]
+    fn test_command_docs() {
+        let server = server();
+
+        let docs = command_router(&server, "COMMAND", &[bulk("DOCS"), bulk("echo"), bulk("nope")]);
+        assert_eq!(
+            docs,
+            Ok(RESPResult::Array(vec![
+                bulk("echo"),
+                RESPResult::Array(vec![
+                    bulk("summary"), bulk("Returns the given string."),
+                    bulk("since"), bulk("1.0.0"),
+                    bulk("group"), bulk("connection"),
+                ]),
+            ]))
+        );
+    }
+
     #[test]
     fn test_set_and_get_command_success() {
+        let db = Db::new();
         // Set a key
         let set_input = vec![bulk("foo"), bulk("bar")];
-        let set_result = set_command(&set_input);
-        assert_eq!(set_result, Ok("OK".to_string()));
+        let set_result = set_command(&db, &set_input);
+        assert_eq!(set_result, Ok(RESPResult::SimpleString("OK".to_string())));
 
         // Get the same key
         let get_input = vec![bulk("foo")];
-        let get_result = get_command(&get_input);
-        assert_eq!(get_result, Ok("bar".to_string()));
+        let get_result = get_command(&db, &get_input);
+        assert_eq!(get_result, Ok(Some(b"bar".to_vec())));
+    }
+
+    #[test]
+    fn test_set_options() {
+        let server = server();
+        let run = |args: &[&str]| {
+            let data: Vec<RESPResult> = args.iter().map(|a| bulk(a)).collect();
+            command_router(&server, "SET", &data)
+        };
+        let ttl = |k: &str| command_router(&server, "TTL", &[bulk(k)]);
+        let ok = Ok(RESPResult::SimpleString("OK".to_string()));
+        let nil = Ok(bulk_reply(None));
+
+        assert_eq!(run(&["k", "1", "xx"]), nil);
+        assert_eq!(run(&["k", "1", "nx"]), ok);
+        assert_eq!(run(&["k", "2", "NX"]), nil);
+        assert_eq!(run(&["k", "2", "XX", "EX", "100"]), ok);
+        assert_eq!(ttl("k"), Ok(RESPResult::Integer(100)));
+
+        // overwriting drops the expire unless KEEPTTL is given
+        assert_eq!(run(&["k", "3", "KEEPTTL"]), ok);
+        assert_eq!(ttl("k"), Ok(RESPResult::Integer(100)));
+        assert_eq!(run(&["k", "4"]), ok);
+        assert_eq!(ttl("k"), Ok(RESPResult::Integer(-1)));
+
+        // GET replies with the old value whether or not the key was set
+        assert_eq!(run(&["k", "5", "GET"]), Ok(bulk("4")));
+        assert_eq!(run(&["k", "6", "NX", "GET"]), Ok(bulk("5")));
+        assert_eq!(run(&["new", "1", "get", "px", "5000"]), nil);
+        assert_eq!(server.db().get("new"), Some(DB_TYPE::Int(1)));
+        server.db().lpush("list", vec![DB_TYPE::Str(b"a".to_vec())]).unwrap();
+        assert_eq!(run(&["list", "x", "GET"]), Err(Error::WrongType));
+        assert_eq!(run(&["list", "x"]), ok);
+
+        // a time already passed leaves no key behind
+        assert_eq!(run(&["k", "7", "PXAT", "1"]), ok);
+        assert_eq!(server.db().get("k"), None);
+
+        for args in [
+            &["k", "v", "NX", "XX"][..],
+            &["k", "v", "EX", "10", "PX", "100"],
+            &["k", "v", "EX", "10", "KEEPTTL"],
+            &["k", "v", "KEEPTTL", "EXAT", "10"],
+            &["k", "v", "EX"],
+            &["k", "v", "BOGUS"],
+        ] {
+            assert_eq!(run(args), Err(Error::Syntax), "{args:?}");
+        }
+        assert_eq!(run(&["k", "v", "EX", "ten"]), Err(Error::NotAnInteger));
+        assert_eq!(run(&["k", "v", "EX", "0"]), Err(Error::Other("invalid expire time in 'set' command".to_string())));
+        assert_eq!(run(&["k", "v", "PX", "9223372036854775807"]), Err(Error::Other("invalid expire time in 'set' command".to_string())));
     }
 
     #[test]
     fn test_set_command_missing_args() {
         let input = vec![bulk("foo")]; // only one argument
-        let result = set_command(&input);
-        assert_eq!(result, Err("Missing key/value for SET".to_string()));
+        let result = command_router(&server(), "SET", &input);
+        assert_eq!(result, Err(Error::WrongArity("set".to_string())));
     }
 
     #[test]
     fn test_ex_expiry() {
+        let db = Db::new();
         let data = vec![
             bulk("key_ex"),
             bulk("value"),
@@ -405,22 +3672,23 @@ mod tests {
             bulk("1"), // 1 second expiry
         ];
 
-        set_command(&data).unwrap();
+        set_command(&db, &data).unwrap();
 
         // Immediately get should return the value
-        let get_result = get_command(&[bulk("key_ex")]).unwrap();
-        assert_eq!(get_result, "value");
+        let get_result = get_command(&db, &[bulk("key_ex")]).unwrap();
+        assert_eq!(get_result, Some(b"value".to_vec()));
 
         // Wait for more than 1 second
         thread::sleep(Duration::from_millis(1100));
 
         // Should be expired now
-        let get_result = get_command(&[bulk("key_ex")]).unwrap();
-        assert_eq!(get_result, "");
+        let get_result = get_command(&db, &[bulk("key_ex")]).unwrap();
+        assert_eq!(get_result, None);
     }
 
     #[test]
     fn test_px_expiry() {
+        let db = Db::new();
         let data = vec![
             bulk("key_px"),
             bulk("value"),
@@ -428,15 +3696,16 @@ mod tests {
             bulk("500"), // 0.5 second expiry
         ];
 
-        set_command(&data).unwrap();
+        set_command(&db, &data).unwrap();
         thread::sleep(Duration::from_millis(600)); // Wait for expiry
 
-        let get_result = get_command(&[bulk("key_px")]).unwrap();
-        assert_eq!(get_result, "");
+        let get_result = get_command(&db, &[bulk("key_px")]).unwrap();
+        assert_eq!(get_result, None);
     }
 
     #[test]
     fn test_exat_expiry() {
+        let db = Db::new();
         let now_secs = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
@@ -451,15 +3720,16 @@ mod tests {
             bulk(&expire_at.to_string()),
         ];
 
-        set_command(&data).unwrap();
+        set_command(&db, &data).unwrap();
         thread::sleep(Duration::from_millis(1100));
 
-        let get_result = get_command(&[bulk("key_exat")]).unwrap();
-        assert_eq!(get_result, "");
+        let get_result = get_command(&db, &[bulk("key_exat")]).unwrap();
+        assert_eq!(get_result, None);
     }
 
     #[test]
     fn test_pxat_expiry() {
+        let db = Db::new();
         let now_ms = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
@@ -474,15 +3744,16 @@ mod tests {
             bulk(&expire_at.to_string()),
         ];
 
-        set_command(&data).unwrap();
+        set_command(&db, &data).unwrap();
         thread::sleep(Duration::from_millis(600));
 
-        let get_result = get_command(&[bulk("key_pxat")]).unwrap();
-        assert_eq!(get_result, "");
+        let get_result = get_command(&db, &[bulk("key_pxat")]).unwrap();
+        assert_eq!(get_result, None);
     }
 
     #[test]
     fn test_set_command_invalid_arg() {
+        let db = Db::new();
         let data = vec![
             RESPResult::BulkString(Some(b"key_invalid".to_vec())),
             RESPResult::BulkString(Some(b"value".to_vec())),
@@ -490,151 +3761,208 @@ mod tests {
             RESPResult::BulkString(Some(b"9999".to_vec())),
         ];
 
-        let result = set_command(&data);
+        let result = set_command(&db, &data);
         assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "Optional argument not understood");
+        assert_eq!(result.unwrap_err(), Error::Syntax);
     }
 
     #[test]
     fn test_get_command_missing_args() {
         let input = vec![];
-        let result = get_command(&input);
-        assert_eq!(result, Err("Missing key/value for GET".to_string()));
+        let result = command_router(&server(), "GET", &input);
+        assert_eq!(result, Err(Error::WrongArity("get".to_string())));
     }
 
     #[test]
     fn test_get_command_nonexistent_key() {
+        let db = Db::new();
         let input = vec![bulk("nonexistent")];
-        let result = get_command(&input);
-        assert_eq!(result, Ok(String::new())); // returns empty string for missing keys
+        let result = get_command(&db, &input);
+        assert_eq!(result, Ok(None)); // nil for missing keys
     }
 
     #[test]
     fn test_set_command_invalid_type() {
+        let db = Db::new();
         let input = vec![RESPResult::Integer(42), bulk("bar")];
-        let result = set_command(&input);
-        assert_eq!(result, Err("Error: Not bulk string".to_string()));
+        let result = set_command(&db, &input);
+        assert_eq!(result, Err(Error::Protocol("expected bulk string".to_string())));
     }
 
     #[test]
     fn test_get_command_invalid_type() {
+        let db = Db::new();
         let input = vec![RESPResult::Integer(42)];
-        let result = get_command(&input);
-        assert_eq!(result, Err("Error: Not bulk string".to_string()));
+        let result = get_command(&db, &input);
+        assert_eq!(result, Err(Error::Protocol("expected bulk string".to_string())));
     }
 
     
     #[test]
     fn test_exists_command_existing_key() {
-        db::set("exists_test".to_string(), DB_TYPE::Str("value".to_string()), 0).unwrap();
+        let db = Db::new();
+        db.set("exists_test", DB_TYPE::Str(b"value".to_vec()));
         let data = vec![bulk("exists_test")];
-        let result = exists_command(&data).unwrap();
+        let result = exists_command(&db, &data).unwrap();
         assert_eq!(result, 1);
     }
 
     #[test]
     fn test_exists_command_missing_key() {
+        let db = Db::new();
         let data = vec![bulk("nonexistent_key")];
-        let result = exists_command(&data).unwrap();
+        let result = exists_command(&db, &data).unwrap();
         assert_eq!(result, 0);
     }
 
     #[test]
     fn test_delete_command_existing_key() {
-        db::set("delete_test".to_string(), DB_TYPE::Str("value".to_string()), 0).unwrap();
+        let db = Db::new();
+        db.set("delete_test", DB_TYPE::Str(b"value".to_vec()));
         let data = vec![bulk("delete_test")];
-        let result = delete_command(&data).unwrap();
+        let result = delete_command(&db, &data).unwrap();
         assert_eq!(result, 1);
 
         // Confirm deletion
-        let exists = db::exists(vec!["delete_test".to_string()]);
+        let exists = db.exists(&["delete_test".to_string()]);
         assert_eq!(exists, 0);
     }
 
     #[test]
     fn test_delete_command_missing_key() {
+        let db = Db::new();
         let data = vec![bulk("nonexistent")];
-        let result = delete_command(&data).unwrap();
+        let result = delete_command(&db, &data).unwrap();
         assert_eq!(result, 0);
     }
 
     #[test]
     fn test_increment_command_initial_value() {
+        let db = Db::new();
         let data = vec![bulk("counter_test")];
-        let result = increment_command(&data).unwrap();
-        assert_eq!(result, "OK");
+        let result = increment_command(&db, &data).unwrap();
+        assert_eq!(result, 1);
 
-        let result2 = increment_command(&data).unwrap();
-        assert_eq!(result2, "OK");
+        let result2 = increment_command(&db, &data).unwrap();
+        assert_eq!(result2, 2);
 
-        let result3 = get_command(&data);
-        assert_eq!(result3, Ok("2".to_string()));
+        let result3 = get_command(&db, &data);
+        assert_eq!(result3, Ok(Some(b"2".to_vec())));
     }
 
     #[test]
     fn test_decrement_command_initial_value() {
+        let db = Db::new();
         let data = vec![bulk("dec_test")];
-        let result = decrement_command(&data).unwrap();
-        assert_eq!(result, "OK");
+        let result = decrement_command(&db, &data).unwrap();
+        assert_eq!(result, -1);
 
-        let result2 = decrement_command(&data).unwrap();
-        assert_eq!(result2, "OK");
+        let result2 = decrement_command(&db, &data).unwrap();
+        assert_eq!(result2, -2);
 
-        let result3 = get_command(&data);
-        assert_eq!(result3, Ok("0".to_string()));
+        let result3 = get_command(&db, &data);
+        assert_eq!(result3, Ok(Some(b"-2".to_vec())));
     }
 
     #[test]
     fn test_increment_after_set() {
-        db::set("num_key".to_string(), DB_TYPE::Int(5), 0).unwrap();
+        let db = Db::new();
+        db.set("num_key", DB_TYPE::Int(5));
         let data = vec![bulk("num_key")];
-        let result = increment_command(&data).unwrap();
-        assert_eq!(result, "OK");
+        let result = increment_command(&db, &data).unwrap();
+        assert_eq!(result, 6);
 
-        let result3 = get_command(&data);
-        assert_eq!(result3, Ok("6".to_string()));
+        let result3 = get_command(&db, &data);
+        assert_eq!(result3, Ok(Some(b"6".to_vec())));
     }
 
     #[test]
     fn test_decrement_after_set() {
-        db::set("dec_key".to_string(), DB_TYPE::Int(10), 0).unwrap();
+        let db = Db::new();
+        db.set("dec_key", DB_TYPE::Int(10));
         let data = vec![bulk("dec_key")];
-        let result = decrement_command(&data).unwrap();
+        let result = decrement_command(&db, &data).unwrap();
         println!("{:?}", result);
-        assert_eq!(result, "OK");
+        assert_eq!(result, 9);
 
-        let result2 = get_command(&data);
+        let result2 = get_command(&db, &data);
         println!("{:?}", result2);
-        assert_eq!(result2, Ok("9".to_string()));
+        assert_eq!(result2, Ok(Some(b"9".to_vec())));
+    }
+
+    #[test]
+    fn test_incrby_decrby_and_incrbyfloat() {
+        let server = server();
+        let run = |cmd: &str, args: &[&str]| {
+            let data: Vec<RESPResult> = args.iter().map(|a| bulk(a)).collect();
+            command_router(&server, cmd, &data)
+        };
+        let int = |i| Ok(RESPResult::Integer(i));
+        let overflow = Err(Error::Other("increment or decrement would overflow".to_string()));
+
+        assert_eq!(run("INCRBY", &["n", "10"]), int(10));
+        assert_eq!(run("DECRBY", &["n", "15"]), int(-5));
+        assert_eq!(run("INCRBY", &["n", "x"]), Err(Error::NotAnInteger));
+        assert_eq!(run("DECRBY", &["n", "-9223372036854775808"]), Err(Error::Other("decrement would overflow".to_string())));
+
+        run("SET", &["max", "9223372036854775807"]).unwrap();
+        assert_eq!(run("INCR", &["max"]), overflow);
+        assert_eq!(run("DECRBY", &["n", "9223372036854775805"]), overflow);
+        assert_eq!(run("GET", &["n"]), Ok(bulk("-5")));
+
+        // the expire survives
+        run("SET", &["t", "1", "EX", "100"]).unwrap();
+        assert_eq!(run("INCR", &["t"]), int(2));
+        assert_eq!(run("INCRBYFLOAT", &["t", "0.5"]), Ok(bulk("2.5")));
+        assert_eq!(run("TTL", &["t"]), int(100));
+
+        assert_eq!(run("INCRBYFLOAT", &["f", "10.5"]), Ok(bulk("10.5")));
+        assert_eq!(run("INCRBYFLOAT", &["f", "0.1"]), Ok(bulk("10.6")));
+        assert_eq!(run("INCRBYFLOAT", &["f", "-5"]), Ok(bulk("5.6")));
+        assert_eq!(run("INCRBYFLOAT", &["f", "2.0e2"]), Ok(bulk("205.6")));
+        assert_eq!(run("INCRBYFLOAT", &["g", "5.0e3"]), Ok(bulk("5000")));
+        // a whole result is stored as an integer again
+        assert_eq!(run("INCR", &["g"]), int(5001));
+
+        let not_float = Err(Error::Other("value is not a valid float".to_string()));
+        assert_eq!(run("INCRBYFLOAT", &["f", "abc"]), not_float);
+        assert_eq!(run("INCRBYFLOAT", &["f", "inf"]), not_float);
+        assert_eq!(run("INCRBYFLOAT", &["f", " 1"]), not_float);
+        run("SET", &["s", "hello"]).unwrap();
+        assert_eq!(run("INCRBYFLOAT", &["s", "1"]), not_float);
+        run("SET", &["big", "1.7e308"]).unwrap();
+        assert_eq!(run("INCRBYFLOAT", &["big", "1.7e308"]), Err(Error::Other("increment would produce NaN or Infinity".to_string())));
     }
 
     #[test]
     fn test_increment_invalid_data() {
-        db::set("bad_data".to_string(), DB_TYPE::Str("value".to_string()), 0).unwrap();
+        let db = Db::new();
+        db.set("bad_data", DB_TYPE::Str(b"value".to_vec()));
         let data = vec![bulk("bad_data")];
-        let result = increment_command(&data);
+        let result = increment_command(&db, &data);
         assert!(result.is_err());
     }
 
     
     #[test]
     fn test_lpush_valid_int_and_string() {
+        let db = Db::new();
         let input = vec![
             bulk("mylist"),
             bulk("123"),
             bulk("hello"),
         ];
 
-        let result = lpush_command(&input);
+        let result = lpush_command(&db, &input);
         assert!(result.is_ok());
-        assert_eq!(result.unwrap(), "2");
+        assert_eq!(result.unwrap(), 2);
 
-    let stored = db::get("mylist").unwrap();
+    let stored = db.get("mylist").unwrap();
 
     match stored {
         DB_TYPE::Array(ref items) => {
             assert_eq!(items, &vec![
-                DB_TYPE::Str("hello".to_string()),
+                DB_TYPE::Str(b"hello".to_vec()),
                 DB_TYPE::Int(123)
                 ]);
             },
@@ -647,40 +3975,42 @@ mod tests {
         let input = vec![
             bulk("mylist"),
         ];
-        let result = lpush_command(&input);
+        let result = command_router(&server(), "LPUSH", &input);
         assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "Missing key/value for LPUSH");
+        assert_eq!(result.unwrap_err(), Error::WrongArity("lpush".to_string()));
     }
 
     #[test]
     fn test_lpush_invalid_type() {
+        let db = Db::new();
         let input = vec![
             RESPResult::BulkString(None), // invalid key
             bulk("hello"),
         ];
-        let result = lpush_command(&input);
+        let result = lpush_command(&db, &input);
         assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "Error: Not bulk string");
+        assert_eq!(result.unwrap_err(), Error::Protocol("expected bulk string".to_string()));
     }
 
     #[test]
     fn test_rpush_valid_mixed_types() {
+        let db = Db::new();
         let input = vec![
             bulk("mylist1"),
             bulk("123"),
             bulk("hello"),
         ];
 
-        let result = rpush_command(&input);
+        let result = rpush_command(&db, &input);
         assert!(result.is_ok());
-        assert_eq!(result.unwrap(), "2");
+        assert_eq!(result.unwrap(), 2);
 
-        let stored = db::get("mylist1").unwrap();
+        let stored = db.get("mylist1").unwrap();
         match stored {
             DB_TYPE::Array(ref items) => {
                 assert_eq!(items, &vec![
                     DB_TYPE::Int(123),
-                    DB_TYPE::Str("hello".to_string()),
+                    DB_TYPE::Str(b"hello".to_vec()),
                 ]);
             }
             _ => panic!("Expected DB_TYPE::Array"),
@@ -693,38 +4023,40 @@ mod tests {
             bulk("mylist"),
         ];
 
-        let result = rpush_command(&input);
+        let result = command_router(&server(), "RPUSH", &input);
         assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "Missing key/value for RPUSH");
+        assert_eq!(result.unwrap_err(), Error::WrongArity("rpush".to_string()));
     }
 
     #[test]
     fn test_rpush_invalid_key_type() {
+        let db = Db::new();
         let input = vec![
             RESPResult::BulkString(None),
             bulk("hello"),
         ];
 
-        let result = rpush_command(&input);
+        let result = rpush_command(&db, &input);
         assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "Error: Not bulk string");
+        assert_eq!(result.unwrap_err(), Error::Protocol("expected bulk string".to_string()));
     }
 
     #[test]
     fn test_rpush_multiple_calls_appends_right() {
+        let db = Db::new();
         let input1 = vec![bulk("mylist2"), bulk("a")];
         let input2 = vec![bulk("mylist2"), bulk("b"), bulk("c")];
 
-        let _ = rpush_command(&input1);
-        let _ = rpush_command(&input2);
+        let _ = rpush_command(&db, &input1);
+        let _ = rpush_command(&db, &input2);
 
-        let stored = db::get("mylist2").unwrap();
+        let stored = db.get("mylist2").unwrap();
         match stored {
             DB_TYPE::Array(ref items) => {
                 assert_eq!(items, &vec![
-                    DB_TYPE::Str("a".to_string()),
-                    DB_TYPE::Str("b".to_string()),
-                    DB_TYPE::Str("c".to_string()),
+                    DB_TYPE::Str(b"a".to_vec()),
+                    DB_TYPE::Str(b"b".to_vec()),
+                    DB_TYPE::Str(b"c".to_vec()),
                 ]);
             }
             _ => panic!("Expected DB_TYPE::Array"),
@@ -0,0 +1,184 @@
+use std::time::Duration;
+
+// settings for a single server instance
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub bind: String,
+    pub port: u16,
+    pub dbfilename: String,
+    // number of logical databases, selected by index with SELECT
+    pub databases: usize,
+    // how long a script runs before other clients get BUSY and SCRIPT KILL works
+    pub busy_reply_threshold: Duration,
+    // scripts that have not written yet are stopped after this long
+    pub script_time_limit: Option<Duration>,
+    // how many times per second expired keys are looked for in the background
+    pub hz: u32,
+    // bytes the keyspace may use before maxmemory_policy applies, 0 for no limit
+    pub maxmemory: usize,
+    pub maxmemory_policy: MaxmemoryPolicy,
+    // keys looked at to pick each one to evict
+    pub maxmemory_samples: usize,
+}
+
+// what happens once maxmemory is reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MaxmemoryPolicy {
+    // writes that add data fail with OOM
+    #[default]
+    NoEviction,
+    AllkeysLru,
+    AllkeysLfu,
+    AllkeysRandom,
+    // the volatile policies only evict keys with an expire
+    VolatileLru,
+    VolatileLfu,
+    VolatileRandom,
+    // the key closest to expiring first
+    VolatileTtl,
+}
+
+impl MaxmemoryPolicy {
+    const ALL: [MaxmemoryPolicy; 8] = [
+        MaxmemoryPolicy::NoEviction,
+        MaxmemoryPolicy::AllkeysLru,
+        MaxmemoryPolicy::AllkeysLfu,
+        MaxmemoryPolicy::AllkeysRandom,
+        MaxmemoryPolicy::VolatileLru,
+        MaxmemoryPolicy::VolatileLfu,
+        MaxmemoryPolicy::VolatileRandom,
+        MaxmemoryPolicy::VolatileTtl,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaxmemoryPolicy::NoEviction => "noeviction",
+            MaxmemoryPolicy::AllkeysLru => "allkeys-lru",
+            MaxmemoryPolicy::AllkeysLfu => "allkeys-lfu",
+            MaxmemoryPolicy::AllkeysRandom => "allkeys-random",
+            MaxmemoryPolicy::VolatileLru => "volatile-lru",
+            MaxmemoryPolicy::VolatileLfu => "volatile-lfu",
+            MaxmemoryPolicy::VolatileRandom => "volatile-random",
+            MaxmemoryPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    // the policy with the given name, ignoring case
+    pub fn from_name(name: &str) -> Option<MaxmemoryPolicy> {
+        Self::ALL.into_iter().find(|p| p.name().eq_ignore_ascii_case(name))
+    }
+}
+
+// the settings CONFIG GET, CONFIG SET and the command line know, by their
+// redis.conf names
+pub const PARAMETERS: [&str; 2] = ["maxmemory", "maxmemory-policy"];
+
+impl Config {
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.bind, self.port)
+    }
+
+    // a config from redis-server style arguments, --name value for each setting
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let name = match arg.strip_prefix("--") {
+                Some(name) => name.to_string(),
+                None => return Err(format!("Invalid argument '{arg}'")),
+            };
+            match args.next() {
+                Some(value) => config.set(&name, &value)?,
+                None => return Err(format!("Missing value for '{arg}'")),
+            }
+        }
+        Ok(config)
+    }
+
+    // a setting as CONFIG GET shows it
+    pub fn get(&self, name: &str) -> Option<String> {
+        match name.to_lowercase().as_str() {
+            "maxmemory" => Some(self.maxmemory.to_string()),
+            "maxmemory-policy" => Some(self.maxmemory_policy.name().to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = |why: &str| format!("Invalid argument '{value}' for CONFIG SET '{name}' - {why}");
+        match name.to_lowercase().as_str() {
+            "maxmemory" => self.maxmemory = parse_memory(value).ok_or_else(|| invalid("argument must be a memory value"))?,
+            "maxmemory-policy" => {
+                self.maxmemory_policy = MaxmemoryPolicy::from_name(value).ok_or_else(|| invalid("argument must be one of the following: noeviction, allkeys-lru, allkeys-lfu, allkeys-random, volatile-lru, volatile-lfu, volatile-random, volatile-ttl"))?;
+            },
+            _ => return Err(format!("Unknown option or number of arguments for CONFIG SET - '{name}'")),
+        }
+        Ok(())
+    }
+}
+
+// a byte count with an optional unit, k and m and g being powers of 1000 and
+// kb, mb and gb powers of 1024, as redis.conf reads them
+fn parse_memory(value: &str) -> Option<usize> {
+    let lower = value.to_lowercase();
+    let split = lower.find(|c: char| !c.is_ascii_digit()).unwrap_or(lower.len());
+    let (number, unit) = lower.split_at(split);
+    let unit: usize = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: "127.0.0.1".to_string(),
+            port: 6379,
+            dbfilename: "./REDIS.rdb".to_string(),
+            databases: 16,
+            busy_reply_threshold: Duration::from_secs(5),
+            script_time_limit: None,
+            hz: 10,
+            maxmemory: 0,
+            maxmemory_policy: MaxmemoryPolicy::NoEviction,
+            maxmemory_samples: 5,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Config, String> {
+        Config::from_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn test_memory_settings() {
+        let config = args(&["--maxmemory", "100mb", "--maxmemory-policy", "ALLKEYS-LRU"]).unwrap();
+        assert_eq!(config.maxmemory, 100 * 1024 * 1024);
+        assert_eq!(config.maxmemory_policy, MaxmemoryPolicy::AllkeysLru);
+        assert_eq!(config.get("maxmemory-policy"), Some("allkeys-lru".to_string()));
+        assert_eq!(args(&[]), Ok(Config::default()));
+
+        assert_eq!(parse_memory("2k"), Some(2000));
+        assert_eq!(parse_memory("1GB"), Some(1 << 30));
+        assert_eq!(parse_memory("12"), Some(12));
+        assert_eq!(parse_memory("-1"), None);
+        assert_eq!(parse_memory("mb"), None);
+        assert_eq!(parse_memory("1tb"), None);
+
+        assert!(args(&["--maxmemory"]).is_err());
+        assert!(args(&["--maxmemory-policy", "lru"]).is_err());
+        assert!(args(&["maxmemory", "1"]).is_err());
+        assert!(args(&["--nope", "1"]).is_err());
+    }
+}
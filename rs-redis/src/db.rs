@@ -1,502 +1,2600 @@
-use once_cell::sync::Lazy;
-use std::collections::HashMap;
-use std::sync::Mutex;
-use std::time::SystemTime;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{BuildHasher, Hash, Hasher, RandomState};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 use std::vec::Vec;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Read, Write};
-use chrono;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+use crate::config::MaxmemoryPolicy;
+use crate::error::Error;
+use crate::glob::glob_match;
+use crate::types::{CustomType, CustomValue, List, DB_TYPE, MEMORY_SAMPLES};
+
+
+// a key's shard is given by the low bits of its hash
+const SHARD_BITS: u32 = 4;
+const SHARD_COUNT: usize = 1 << SHARD_BITS;
+
+// value and its expiry are stored together so a lookup only needs one lock
+#[derive(Clone)]
+struct Entry {
+    value: DB_TYPE,
+    expire: u128,
+    // estimated bytes of key and value, kept up to date by the shard
+    size: usize,
+    // unix time in milliseconds of the last access, for LRU
+    access: u64,
+    // logarithmic access counter, for LFU
+    freq: u8,
+}
+
+impl Entry {
+    fn new(value: DB_TYPE, expire: u128) -> Entry {
+        Entry {
+            value: value.canonical(),
+            expire,
+            size: 0,
+            access: now_ms() as u64,
+            freq: LFU_INIT,
+        }
+    }
+
+    // records an access: the counter first decays by a step per minute idle,
+    // then grows with a probability that falls as it gets larger
+    fn touch(&mut self, now: u64) {
+        let mut freq = self.lfu(now);
+        if freq < u8::MAX {
+            let base = freq.saturating_sub(LFU_INIT) as f64;
+            if random_unit() < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+                freq += 1;
+            }
+        }
+
+        self.freq = freq;
+        self.access = now;
+    }
+
+    // the access counter as of now
+    fn lfu(&self, now: u64) -> u8 {
+        let idle_minutes = now.saturating_sub(self.access) / 60_000;
+        self.freq.saturating_sub(idle_minutes.min(u8::MAX as u64) as u8)
+    }
+}
+
+// new keys start with some accesses so they are not evicted right away
+const LFU_INIT: u8 = 5;
+const LFU_LOG_FACTOR: f64 = 10.0;
+
+// bookkeeping of a key besides its name and value, roughly what a map slot,
+// the entry and its place in the SCAN position index cost
+const ENTRY_OVERHEAD: usize = std::mem::size_of::<Entry>() + std::mem::size_of::<String>() + 16
+    + std::mem::size_of::<(u64, String)>() + 16;
+
+// a key's cost besides its value; both the map and the position index hold
+// its name
+fn key_size(k: &str) -> usize {
+    ENTRY_OVERHEAD + 2 * k.len()
+}
+
+// a finite float the way Redis reads one, without surrounding spaces
+pub(crate) fn parse_float(s: &[u8]) -> Option<f64> {
+    let s = std::str::from_utf8(s).ok()?;
+    if s.trim() != s {
+        return None;
+    }
+    s.parse::<f64>().ok().filter(|f| f.is_finite())
+}
+
+// a float in plain decimal notation with as many digits as it takes to read
+// back the same value, so 10.5 + 0.1 is 10.6 and 3.0e3 is 3000
+fn format_float(f: f64) -> String {
+    // no "-0"
+    if f == 0.0 {
+        return "0".to_string();
+    }
+    f.to_string()
+}
+
+// pushes values one at a time, so onto the head they end up reversed
+fn push_list(arr: &mut List, values: Vec<DB_TYPE>, left: bool) {
+    for v in values {
+        if left { arr.push_front(v) } else { arr.push_back(v) }
+    }
+}
+
+// takes up to count elements off the head or the tail, in the order popped
+fn pop_list(arr: &mut List, left: bool, count: usize) -> Vec<DB_TYPE> {
+    (0..count.min(arr.len()))
+        .map_while(|_| if left { arr.pop_front() } else { arr.pop_back() })
+        .collect()
+}
+
+// a list index, negative from the tail, if it is in range
+fn list_index(len: usize, index: i64) -> Option<usize> {
+    let i = if index < 0 { len as i64 + index } else { index };
+    (0..len as i64).contains(&i).then_some(i as usize)
+}
+
+// the longest string SETRANGE may produce, as Redis' proto-max-bulk-len
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+// a key's place in the deadline index
+const DEADLINE_OVERHEAD: usize = std::mem::size_of::<(u128, String)>() + 16;
+
+// expired keys RANDOMKEY drops before it gives up on finding a live one
+const RANDOM_KEY_TRIES: usize = 100;
+
+// kept up to date after every change, so big hashes are sampled as MEMORY
+// USAGE does by default rather than walked
+fn entry_size(k: &str, value: &DB_TYPE) -> usize {
+    key_size(k) + value.sampled_memory_usage(MEMORY_SAMPLES)
+}
+
+// a shard's keys; those with an expire are also indexed by deadline, so the
+// expiry cycle finds them without walking the whole shard, and every key by
+// its SCAN position, so SCAN and RANDOMKEY neither sort nor walk it
+struct Shard {
+    entries: HashMap<String, Entry>,
+    deadlines: BTreeSet<(u128, String)>,
+    positions: BTreeSet<(u64, String)>,
+    // keys dropped because they expired, on access or by the expiry cycle
+    expired: u64,
+    // keys dropped to get under maxmemory
+    evicted: u64,
+    // estimated bytes of every entry
+    used: usize,
+    // the sum of used over every shard of the databases that share it, kept
+    // up to date so reading it takes no lock
+    total: Arc<AtomicUsize>,
+}
+
+impl Shard {
+    fn new(total: Arc<AtomicUsize>) -> Shard {
+        Shard {
+            entries: HashMap::new(),
+            deadlines: BTreeSet::new(),
+            positions: BTreeSet::new(),
+            expired: 0,
+            evicted: 0,
+            used: 0,
+            total,
+        }
+    }
+
+    fn get(&self, k: &str) -> Option<&Entry> {
+        self.entries.get(k)
+    }
+
+    fn contains_key(&self, k: &str) -> bool {
+        self.entries.contains_key(k)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn iter(&self) -> std::collections::hash_map::Iter<'_, String, Entry> {
+        self.entries.iter()
+    }
+
+    fn insert(&mut self, k: String, mut entry: Entry) -> Option<Entry> {
+        let old = self.remove(&k);
+        entry.size = entry_size(&k, &entry.value);
+        self.grow(entry.size);
+        if entry.expire > 0 {
+            self.grow(DEADLINE_OVERHEAD + k.len());
+            self.deadlines.insert((entry.expire, k.clone()));
+        }
+        self.positions.insert((scan_position(&k), k.clone()));
+        self.entries.insert(k, entry);
+        old
+    }
+
+    fn remove(&mut self, k: &str) -> Option<Entry> {
+        let entry = self.entries.remove(k)?;
+        self.shrink(entry.size);
+        if entry.expire > 0 {
+            self.shrink(DEADLINE_OVERHEAD + k.len());
+            self.deadlines.remove(&(entry.expire, k.to_string()));
+        }
+        self.positions.remove(&(scan_position(k), k.to_string()));
+        Some(entry)
+    }
+
+    // runs f on the value of k in place; f may create, replace or remove it.
+    // a value that stays keeps its expire, a new one has none
+    fn update<R>(&mut self, k: &str, f: impl FnOnce(&mut Option<DB_TYPE>) -> R) -> R {
+        let now = now_ms() as u64;
+        let mut value = self.entries.get_mut(k).map(|e| {
+            e.touch(now);
+            std::mem::replace(&mut e.value, DB_TYPE::Int(0))
+        });
+        let result = f(&mut value);
+
+        match (value, self.entries.get_mut(k)) {
+            (Some(value), Some(e)) => {
+                let size = entry_size(k, &value);
+                let old = std::mem::replace(&mut e.size, size);
+                e.value = value;
+                self.shrink(old);
+                self.grow(size);
+            },
+            (Some(value), None) => {
+                self.insert(k.to_string(), Entry::new(value, 0));
+            },
+            (None, Some(_)) => {
+                self.remove(k);
+            },
+            (None, None) => {},
+        }
+
+        result
+    }
+
+    // t of 0 removes the expire
+    fn set_expire(&mut self, k: &str, t: u128) {
+        if let Some(entry) = self.entries.get_mut(k) {
+            let old = std::mem::replace(&mut entry.expire, t);
+            if old > 0 {
+                self.shrink(DEADLINE_OVERHEAD + k.len());
+                self.deadlines.remove(&(old, k.to_string()));
+            }
+            if t > 0 {
+                self.grow(DEADLINE_OVERHEAD + k.len());
+                self.deadlines.insert((t, k.to_string()));
+            }
+        }
+    }
+
+    fn grow(&mut self, bytes: usize) {
+        self.used += bytes;
+        self.total.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn shrink(&mut self, bytes: usize) {
+        self.used -= bytes;
+        self.total.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    // drops k if its expire has passed, true if it did
+    fn remove_expired(&mut self, k: &str, now: u128) -> bool {
+        let expired = self.get(k).is_some_and(|e| e.expire > 0 && now > e.expire);
+        if expired {
+            self.remove(k);
+            self.expired += 1;
+        }
+        expired
+    }
+
+    // exchanges the keys with other's, the counters stay with the shard
+    fn swap_keys(&mut self, other: &mut Shard) {
+        let (mine, theirs) = (self.used, other.used);
+        self.shrink(mine);
+        other.shrink(theirs);
+
+        std::mem::swap(&mut self.entries, &mut other.entries);
+        std::mem::swap(&mut self.deadlines, &mut other.deadlines);
+        std::mem::swap(&mut self.positions, &mut other.positions);
+
+        self.grow(theirs);
+        other.grow(mine);
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.deadlines.clear();
+        self.positions.clear();
+        self.shrink(self.used);
+    }
+
+    // a random position in the shard's range, None if it is empty
+    fn random_position(&self) -> Option<u64> {
+        let shard_bits = self.positions.first()?.0 & !(u64::MAX >> SHARD_BITS);
+        Some(shard_bits | (random_u64() >> SHARD_BITS))
+    }
+
+    // the key at a random position of the shard's range
+    fn random_key(&self) -> Option<&String> {
+        let at = self.random_position()?;
+        self.positions.range((at, String::new())..).chain(&self.positions).next().map(|(_, k)| k)
+    }
+
+    // up to n keys from a random place, only those with an expire if volatile.
+    // both indexes are ordered, so finding the place is a range query
+    fn sample(&self, n: usize, volatile: bool) -> Vec<(&String, &Entry)> {
+        let keys: Vec<&String> = if volatile {
+            let (first, last) = match (self.deadlines.first(), self.deadlines.last()) {
+                (Some(first), Some(last)) => (first.0, last.0),
+                _ => return Vec::new(),
+            };
+            let at = first + random_u64() as u128 % (last - first + 1);
+            self.deadlines
+                .range((at, String::new())..)
+                .chain(&self.deadlines)
+                .take(n.min(self.deadlines.len()))
+                .map(|(_, k)| k)
+                .collect()
+        }
+        else {
+            let at = match self.random_position() {
+                Some(at) => at,
+                None => return Vec::new(),
+            };
+            self.positions
+                .range((at, String::new())..)
+                .chain(&self.positions)
+                .take(n.min(self.positions.len()))
+                .map(|(_, k)| k)
+                .collect()
+        };
+        keys.into_iter().filter_map(|k| self.entries.get_key_value(k)).collect()
+    }
+}
+
+impl Drop for Shard {
+    fn drop(&mut self) {
+        self.total.fetch_sub(self.used, Ordering::Relaxed);
+    }
+}
+
+// what OBJECT reports about a key
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyInfo {
+    pub encoding: &'static str,
+    pub idle: Duration,
+    pub freq: u8,
+    pub refcount: i64,
+}
+
+// integers below this are shared objects in Redis, OBJECT REFCOUNT says so
+const SHARED_INTEGERS: i64 = 10_000;
+
+// what one run of Db::expire_cycle did
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ExpireCycle {
+    pub sampled: usize,
+    pub expired: usize,
+    // the cycle stopped because it ran out of time
+    pub timed_out: bool,
+}
+
+// keys with an expire looked at per round of a shard
+const EXPIRE_SAMPLE: usize = 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ttl {
+    Missing,
+    Persistent,
+    Expires(Duration),
+}
+
+// rebuilds a custom value from its snapshot encoding
+pub type Loader = Arc<dyn Fn(&[u8]) -> Option<Box<dyn CustomValue>> + Send + Sync>;
+
+pub struct Db {
+    shards: Vec<Mutex<Shard>>,
+    // shared by every database of a server
+    loaders: Arc<RwLock<HashMap<String, Loader>>>,
+    // estimated bytes of every database of a server, which each shard adds
+    // its changes to
+    used: Arc<AtomicUsize>,
+    // where the next expiry cycle starts
+    next_expire_shard: AtomicUsize,
+}
+
+impl Db {
+    pub fn new() -> Db {
+        Db::sharing(Arc::new(RwLock::new(HashMap::new())), Arc::new(AtomicUsize::new(0)))
+    }
+
+    fn sharing(loaders: Arc<RwLock<HashMap<String, Loader>>>, used: Arc<AtomicUsize>) -> Db {
+        let mut shards = Vec::with_capacity(SHARD_COUNT);
+        for _ in 0..SHARD_COUNT {
+            shards.push(Mutex::new(Shard::new(used.clone())));
+        }
+
+        Db {
+            shards,
+            loaders,
+            used,
+            next_expire_shard: AtomicUsize::new(0),
+        }
+    }
+
+    // an empty database that knows the same custom types as this one, and
+    // counts its memory along with it
+    pub fn sharing_types(&self) -> Db {
+        Db::sharing(self.loaders.clone(), self.used.clone())
+    }
+
+    fn shard_index(&self, k: &str) -> usize {
+        (key_hash(k) as usize) % self.shards.len()
+    }
+
+    fn shard(&self, k: &str) -> MutexGuard<'_, Shard> {
+        self.shards[self.shard_index(k)].lock().expect("DB mutex lock failed")
+    }
+
+    // lock every shard touched by keys, always in ascending shard order so
+    // two multi-key commands can never deadlock each other
+    fn lock_shards(&self, keys: &[String]) -> BTreeMap<usize, MutexGuard<'_, Shard>> {
+        let indexes: BTreeSet<usize> = keys.iter().map(|k| self.shard_index(k)).collect();
+
+        let mut guards = BTreeMap::new();
+        for i in indexes {
+            guards.insert(i, self.shards[i].lock().expect("DB mutex lock failed"));
+        }
+
+        guards
+    }
+
+    fn lock_all(&self) -> Vec<MutexGuard<'_, Shard>> {
+        self.shards
+            .iter()
+            .map(|s| s.lock().expect("DB mutex lock failed"))
+            .collect()
+    }
+
+    // lets snapshots containing the named custom type be loaded
+    pub fn register_type(&self, name: &str, loader: Loader) -> Result<(), Error> {
+        let mut loaders = self.loaders.write().expect("type registry lock failed");
+        if loaders.contains_key(name) {
+            return Err(Error::Other(format!("type '{name}' already exists")));
+        }
+
+        loaders.insert(name.to_string(), loader);
+        Ok(())
+    }
+
+    // returns false if there was no such type
+    pub fn unregister_type(&self, name: &str) -> bool {
+        self.loaders.write().expect("type registry lock failed").remove(name).is_some()
+    }
+
+    pub fn set(&self, k: &str, v: DB_TYPE) {
+        self.insert(k, v, 0);
+    }
+
+    pub fn set_with_ttl(&self, k: &str, v: DB_TYPE, ttl: Duration) {
+        self.insert(k, v, now_ms() + ttl.as_millis());
+    }
+
+    // t is an absolute unix time in milliseconds
+    pub fn set_with_expire_at(&self, k: &str, v: DB_TYPE, t: u128) {
+        self.insert(k, v, t);
+    }
+
+    // replaces k along with its expire, t of 0 for none
+    fn insert(&self, k: &str, v: DB_TYPE, t: u128) {
+        let mut shard = self.shard(k);
+        shard.remove_expired(k, now_ms());
+
+        shard.insert(k.to_string(), Entry::new(v, t));
+    }
+
+    // SET with its options: allow sees the live value of k and decides whether
+    // v replaces it. expire is the new unix time in milliseconds, 0 for none,
+    // and None keeps the expire k already has. returns whether k was set
+    pub fn set_if(&self, k: &str, v: DB_TYPE, expire: Option<u128>, allow: impl FnOnce(Option<&DB_TYPE>) -> Result<bool, Error>) -> Result<bool, Error> {
+        let mut shard = self.shard(k);
+
+        let now = now_ms();
+        shard.remove_expired(k, now);
+
+        let current = shard.get(k);
+        if !allow(current.map(|e| &e.value))? {
+            return Ok(false);
+        }
+
+        let expire = match expire {
+            Some(t) => t,
+            None => current.map_or(0, |e| e.expire),
+        };
+        if expire > 0 && expire <= now {
+            shard.remove(k);
+        } else {
+            shard.insert(k.to_string(), Entry::new(v, expire));
+        }
+        Ok(true)
+    }
+
+    // every read of a single key goes through here: f sees the live value of
+    // k, an expired one is dropped first
+    pub fn read<R>(&self, k: &str, f: impl FnOnce(Option<&DB_TYPE>) -> R) -> R {
+        let mut shard = self.shard(k);
+
+        let now = now_ms();
+        shard.remove_expired(k, now);
+
+        match shard.entries.get_mut(k) {
+            Some(e) => {
+                e.touch(now as u64);
+                f(Some(&e.value))
+            },
+            None => f(None),
+        }
+    }
+
+    // every change of a single key goes through here: f runs on the live value
+    // of k under its shard lock, so a read-modify-write is atomic. f may
+    // replace or remove the value; a value that stays keeps its expire
+    pub fn update<R>(&self, k: &str, f: impl FnOnce(&mut Option<DB_TYPE>) -> R) -> R {
+        let mut shard = self.shard(k);
+        shard.remove_expired(k, now_ms());
+
+        shard.update(k, f)
+    }
+
+    pub fn get(&self, k: &str) -> Option<DB_TYPE> {
+        self.read(k, |v| v.cloned())
+    }
+
+    // the bytes of a string key
+    pub fn get_string(&self, k: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.read(k, |value| match value {
+            Some(v) => v.bytes().map(Some).ok_or(Error::WrongType),
+            None => Ok(None),
+        })
+    }
+
+    // the values of several keys, read together
+    pub fn mget(&self, keys: &[String]) -> Vec<Option<DB_TYPE>> {
+        let mut shards = self.lock_shards(keys);
+
+        let now = now_ms();
+        keys.iter()
+            .map(|k| {
+                let shard = shards.get_mut(&self.shard_index(k)).unwrap();
+                shard.remove_expired(k, now);
+                shard.entries.get_mut(k).map(|e| {
+                    e.touch(now as u64);
+                    e.value.clone()
+                })
+            })
+            .collect()
+    }
+
+    // sets every pair at once, dropping their expires; with nx nothing is set,
+    // and false returned, if any of the keys exists
+    pub fn mset(&self, pairs: Vec<(String, DB_TYPE)>, nx: bool) -> bool {
+        let keys: Vec<String> = pairs.iter().map(|(k, _)| k.clone()).collect();
+        let mut shards = self.lock_shards(&keys);
+
+        let now = now_ms();
+        for k in &keys {
+            shards.get_mut(&self.shard_index(k)).unwrap().remove_expired(k, now);
+        }
+        if nx && keys.iter().any(|k| shards[&self.shard_index(k)].contains_key(k)) {
+            return false;
+        }
+
+        for (k, v) in pairs {
+            shards.get_mut(&self.shard_index(&k)).unwrap().insert(k, Entry::new(v, 0));
+        }
+        true
+    }
+
+    // appends to a string key, creating it if missing; returns the new length
+    pub fn append(&self, k: &str, suffix: &[u8]) -> Result<usize, Error> {
+        self.update(k, |value| {
+            let mut bytes = match value {
+                Some(v) => v.bytes().ok_or(Error::WrongType)?,
+                None => Vec::new(),
+            };
+
+            bytes.extend_from_slice(suffix);
+            let len = bytes.len();
+            *value = Some(DB_TYPE::string(bytes));
+            Ok(len)
+        })
+    }
+
+    pub fn strlen(&self, k: &str) -> Result<usize, Error> {
+        Ok(self.get_string(k)?.map_or(0, |s| s.len()))
+    }
+
+    // the bytes from start to end inclusive, negative offsets count from the end
+    pub fn getrange(&self, k: &str, start: i64, end: i64) -> Result<Vec<u8>, Error> {
+        let bytes = self.get_string(k)?.unwrap_or_default();
+        let len = bytes.len() as i64;
+
+        let start = if start < 0 { (len + start).max(0) } else { start };
+        let end = if end < 0 { (len + end).max(0) } else { end.min(len - 1) };
+        if len == 0 || start > end {
+            return Ok(Vec::new());
+        }
+
+        Ok(bytes[start as usize..=end as usize].to_vec())
+    }
+
+    // overwrites part of a string key from offset on, padding with zero bytes;
+    // returns the new length. nothing is created for an empty s
+    pub fn setrange(&self, k: &str, offset: usize, s: &[u8]) -> Result<usize, Error> {
+        self.update(k, |value| {
+            let mut bytes = match value {
+                Some(v) => v.bytes().ok_or(Error::WrongType)?,
+                None if s.is_empty() => return Ok(0),
+                None => Vec::new(),
+            };
+            if s.is_empty() {
+                return Ok(bytes.len());
+            }
+
+            let end = offset + s.len();
+            if end > MAX_STRING_LEN {
+                return Err(Error::Other("string exceeds maximum allowed size (proto-max-bulk-len)".to_string()));
+            }
+            if bytes.len() < end {
+                bytes.resize(end, 0);
+            }
+            bytes[offset..end].copy_from_slice(s);
+
+            let len = bytes.len();
+            *value = Some(DB_TYPE::string(bytes));
+            Ok(len)
+        })
+    }
+
+    // runs f on the bytes of a string key, a missing key reading as empty, and
+    // keeps what f leaves there; a missing key left empty is not created
+    pub fn update_string<R>(&self, k: &str, f: impl FnOnce(&mut Vec<u8>) -> R) -> Result<R, Error> {
+        self.update(k, |value| {
+            let existed = value.is_some();
+            let mut bytes = match value.take() {
+                Some(DB_TYPE::Str(s)) => s,
+                Some(DB_TYPE::Int(i)) => i.to_string().into_bytes(),
+                Some(other) => {
+                    *value = Some(other);
+                    return Err(Error::WrongType);
+                },
+                None => Vec::new(),
+            };
+
+            let result = f(&mut bytes);
+            if existed || !bytes.is_empty() {
+                *value = Some(DB_TYPE::string(bytes));
+            }
+            Ok(result)
+        })
+    }
+
+    // BITOP: stores combine of the strings at sources in dest, without an
+    // expire, or removes dest when the result is empty; returns its length
+    pub fn bitop(&self, dest: &str, sources: &[String], combine: impl FnOnce(&[Vec<u8>]) -> Vec<u8>) -> Result<usize, Error> {
+        let mut keys = sources.to_vec();
+        keys.push(dest.to_string());
+        let mut shards = self.lock_shards(&keys);
+
+        let now = now_ms();
+        for k in &keys {
+            shards.get_mut(&self.shard_index(k)).unwrap().remove_expired(k, now);
+        }
+
+        let mut values = Vec::new();
+        for k in sources {
+            match shards[&self.shard_index(k)].get(k) {
+                Some(e) => values.push(e.value.bytes().ok_or(Error::WrongType)?),
+                None => values.push(Vec::new()),
+            }
+        }
+
+        let result = combine(&values);
+        let len = result.len();
+        let shard = shards.get_mut(&self.shard_index(dest)).unwrap();
+        if result.is_empty() {
+            shard.remove(dest);
+        } else {
+            shard.insert(dest.to_string(), Entry::new(DB_TYPE::string(result), 0));
+        }
+        Ok(len)
+    }
+
+    // sets k without an expire and returns the string it held
+    pub fn getset(&self, k: &str, v: DB_TYPE) -> Result<Option<Vec<u8>>, Error> {
+        let mut shard = self.shard(k);
+        shard.remove_expired(k, now_ms());
+
+        let old = match shard.get(k) {
+            Some(e) => Some(e.value.bytes().ok_or(Error::WrongType)?),
+            None => None,
+        };
+        shard.insert(k.to_string(), Entry::new(v, 0));
+        Ok(old)
+    }
+
+    // removes a string key and returns it
+    pub fn getdel(&self, k: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.update(k, |value| match value.as_ref().map(|v| v.bytes()) {
+            Some(Some(bytes)) => {
+                *value = None;
+                Ok(Some(bytes))
+            },
+            Some(None) => Err(Error::WrongType),
+            None => Ok(None),
+        })
+    }
+
+    // returns a string key and changes its expire: Some(0) removes it, Some(t)
+    // sets it to unix time t in milliseconds, and a time already passed deletes k
+    pub fn getex(&self, k: &str, expire: Option<u128>) -> Result<Option<Vec<u8>>, Error> {
+        let mut shard = self.shard(k);
+
+        let now = now_ms();
+        shard.remove_expired(k, now);
+
+        let bytes = match shard.entries.get_mut(k) {
+            Some(e) => {
+                e.touch(now as u64);
+                e.value.bytes().ok_or(Error::WrongType)?
+            },
+            None => return Ok(None),
+        };
+
+        match expire {
+            Some(t) if t > 0 && t <= now => {
+                shard.remove(k);
+            },
+            Some(t) => shard.set_expire(k, t),
+            None => {},
+        }
+        Ok(Some(bytes))
+    }
+
+    pub fn delete(&self, keys: &[String]) -> usize {
+        let mut shards = self.lock_shards(keys);
+
+        let now = now_ms();
+        let mut counter = 0;
+        for k in keys {
+            let shard = shards.get_mut(&self.shard_index(k)).unwrap();
+            shard.remove_expired(k, now);
+            if shard.remove(k).is_some() {
+                counter += 1;
+            }
+        }
+
+        counter
+    }
+
+    pub fn exists(&self, keys: &[String]) -> usize {
+        let mut shards = self.lock_shards(keys);
+
+        let now = now_ms();
+        let mut counter = 0;
+        for k in keys {
+            let shard = shards.get_mut(&self.shard_index(k)).unwrap();
+            shard.remove_expired(k, now);
+            if shard.contains_key(k) {
+                counter += 1;
+            }
+        }
+
+        counter
+    }
+
+    pub fn incr(&self, k: &str) -> Result<i64, Error> {
+        self.incr_by(k, 1)
+    }
+
+    pub fn decr(&self, k: &str) -> Result<i64, Error> {
+        self.incr_by(k, -1)
+    }
+
+    // adds by to an integer key, a missing key counting as 0; the expire stays
+    pub fn incr_by(&self, k: &str, by: i64) -> Result<i64, Error> {
+        self.update(k, |value| {
+            let current = match value {
+                Some(DB_TYPE::Int(i)) => *i,
+                Some(DB_TYPE::Str(_)) => return Err(Error::NotAnInteger),
+                Some(_) => return Err(Error::WrongType),
+                None => 0,
+            };
+
+            let new = current.checked_add(by)
+                .ok_or_else(|| Error::Other("increment or decrement would overflow".to_string()))?;
+            *value = Some(DB_TYPE::Int(new));
+            Ok(new)
+        })
+    }
+
+    // adds by to a key holding a number, a missing key counting as 0, and
+    // returns the new value's text; the expire stays
+    pub fn incr_by_float(&self, k: &str, by: f64) -> Result<String, Error> {
+        self.update(k, |value| {
+            let current = match value.as_ref().map(|v| v.bytes()) {
+                Some(Some(bytes)) => parse_float(&bytes).ok_or(Error::Other("value is not a valid float".to_string()))?,
+                Some(None) => return Err(Error::WrongType),
+                None => 0.0,
+            };
+
+            let new = current + by;
+            if !new.is_finite() {
+                return Err(Error::Other("increment would produce NaN or Infinity".to_string()));
+            }
+
+            let text = format_float(new);
+            *value = Some(DB_TYPE::string(text.clone().into_bytes()));
+            Ok(text)
+        })
+    }
+
+    pub fn lpush(&self, k: &str, values: Vec<DB_TYPE>) -> Result<usize, Error> {
+        self.push(k, values, true, true)
+    }
+
+    pub fn rpush(&self, k: &str, values: Vec<DB_TYPE>) -> Result<usize, Error> {
+        self.push(k, values, false, true)
+    }
+
+    // LPUSHX: only pushes onto a list that exists, returns 0 otherwise
+    pub fn lpushx(&self, k: &str, values: Vec<DB_TYPE>) -> Result<usize, Error> {
+        self.push(k, values, true, false)
+    }
+
+    pub fn rpushx(&self, k: &str, values: Vec<DB_TYPE>) -> Result<usize, Error> {
+        self.push(k, values, false, false)
+    }
+
+    // pushes values one at a time onto the head or the tail of a list; returns
+    // the new length
+    fn push(&self, k: &str, values: Vec<DB_TYPE>, left: bool, create: bool) -> Result<usize, Error> {
+        self.update(k, |value| {
+            if value.is_none() && !create {
+                return Ok(0);
+            }
+            let arr = match value.get_or_insert_with(|| DB_TYPE::Array(List::new())) {
+                DB_TYPE::Array(arr) => arr,
+                _ => return Err(Error::WrongType),
+            };
+
+            push_list(arr, values, left);
+            Ok(arr.len())
+        })
+    }
+
+    // runs f on the list at k, None when k is missing; a list f leaves empty
+    // is removed
+    fn update_list<R>(&self, k: &str, f: impl FnOnce(Option<&mut List>) -> Result<R, Error>) -> Result<R, Error> {
+        self.update(k, |value| {
+            let result = match value {
+                Some(DB_TYPE::Array(arr)) => f(Some(arr))?,
+                Some(_) => return Err(Error::WrongType),
+                None => f(None)?,
+            };
+
+            if matches!(value, Some(DB_TYPE::Array(arr)) if arr.is_empty()) {
+                *value = None;
+            }
+            Ok(result)
+        })
+    }
+
+    // pops up to count elements off the head or the tail, None for a missing key
+    pub fn pop(&self, k: &str, left: bool, count: usize) -> Result<Option<Vec<DB_TYPE>>, Error> {
+        self.update_list(k, |arr| Ok(arr.map(|arr| pop_list(arr, left, count))))
+    }
+
+    pub fn llen(&self, k: &str) -> Result<usize, Error> {
+        self.read(k, |value| match value {
+            Some(DB_TYPE::Array(arr)) => Ok(arr.len()),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(0),
+        })
+    }
+
+    // start and stop are inclusive, negative indexes count from the tail
+    pub fn lrange(&self, k: &str, start: i64, stop: i64) -> Result<Vec<DB_TYPE>, Error> {
+        self.read(k, |value| {
+            let arr = match value {
+                Some(DB_TYPE::Array(arr)) => arr,
+                Some(_) => return Err(Error::WrongType),
+                None => return Ok(Vec::new()),
+            };
+
+            let len = arr.len() as i64;
+            let start = if start < 0 { (len + start).max(0) } else { start };
+            let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+
+            if start > stop || start >= len {
+                return Ok(Vec::new());
+            }
+
+            Ok(arr.range(start as usize..=stop as usize).cloned().collect())
+        })
+    }
+
+    pub fn lindex(&self, k: &str, index: i64) -> Result<Option<DB_TYPE>, Error> {
+        self.read(k, |value| match value {
+            Some(DB_TYPE::Array(arr)) => Ok(list_index(arr.len(), index).map(|i| arr[i].clone())),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        })
+    }
+
+    pub fn lset(&self, k: &str, index: i64, v: DB_TYPE) -> Result<(), Error> {
+        self.update_list(k, |arr| {
+            let arr = arr.ok_or_else(|| Error::Other("no such key".to_string()))?;
+            let i = list_index(arr.len(), index).ok_or_else(|| Error::Other("index out of range".to_string()))?;
+            arr.set(i, v);
+            Ok(())
+        })
+    }
+
+    // inserts v before or after the first pivot; returns the new length, 0 for
+    // a missing key and -1 when there is no pivot
+    pub fn linsert(&self, k: &str, before: bool, pivot: &DB_TYPE, v: DB_TYPE) -> Result<i64, Error> {
+        self.update_list(k, |arr| {
+            let arr = match arr {
+                Some(arr) => arr,
+                None => return Ok(0),
+            };
+
+            let pivot = pivot.clone().canonical();
+            match arr.iter().position(|e| *e == pivot) {
+                Some(i) => {
+                    arr.insert(if before { i } else { i + 1 }, v);
+                    Ok(arr.len() as i64)
+                },
+                None => Ok(-1),
+            }
+        })
+    }
+
+    // removes up to count elements equal to v, from the tail for a negative
+    // count and all of them for 0; returns how many went
+    pub fn lrem(&self, k: &str, count: i64, v: &DB_TYPE) -> Result<usize, Error> {
+        self.update_list(k, |arr| {
+            let arr = match arr {
+                Some(arr) => arr,
+                None => return Ok(0),
+            };
+
+            let v = v.clone().canonical();
+            let limit = if count == 0 { usize::MAX } else { count.unsigned_abs() as usize };
+            let mut matches: Vec<usize> = arr.iter().enumerate().filter(|(_, e)| **e == v).map(|(i, _)| i).collect();
+            if count < 0 {
+                matches.reverse();
+            }
+            matches.truncate(limit);
+            matches.sort_unstable();
+
+            let mut i = 0;
+            arr.retain(|_| {
+                i += 1;
+                matches.binary_search(&(i - 1)).is_err()
+            });
+            Ok(matches.len())
+        })
+    }
+
+    // keeps only the elements from start to stop inclusive
+    pub fn ltrim(&self, k: &str, start: i64, stop: i64) -> Result<(), Error> {
+        self.update_list(k, |arr| {
+            if let Some(arr) = arr {
+                let len = arr.len() as i64;
+                let start = if start < 0 { (len + start).max(0) } else { start };
+                let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+
+                if start > stop || start >= len {
+                    arr.truncate(0);
+                } else {
+                    arr.truncate(stop as usize + 1);
+                    for _ in 0..start {
+                        arr.pop_front();
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // the indexes of elements equal to v: rank picks the first match to report,
+    // from the tail when negative, count how many at most (0 for all) and
+    // maxlen how many elements are compared at most (0 for all)
+    pub fn lpos(&self, k: &str, v: &DB_TYPE, rank: i64, count: usize, maxlen: usize) -> Result<Vec<usize>, Error> {
+        self.read(k, |value| {
+            let arr = match value {
+                Some(DB_TYPE::Array(arr)) => arr,
+                Some(_) => return Err(Error::WrongType),
+                None => return Ok(Vec::new()),
+            };
+
+            let v = v.clone().canonical();
+            let maxlen = if maxlen == 0 { arr.len() } else { maxlen.min(arr.len()) };
+            let count = if count == 0 { usize::MAX } else { count };
+            let skip = rank.unsigned_abs() as usize - 1;
+
+            let indexes: Box<dyn Iterator<Item = usize>> = if rank > 0 {
+                Box::new(0..maxlen)
+            } else {
+                Box::new((arr.len() - maxlen..arr.len()).rev())
+            };
+            Ok(indexes.filter(|&i| arr[i] == v).skip(skip).take(count).collect())
+        })
+    }
+
+    // pops an element off one end of src and pushes it onto one end of dst,
+    // both under their locks; None when src is missing
+    pub fn lmove(&self, src: &str, dst: &str, from_left: bool, to_left: bool) -> Result<Option<DB_TYPE>, Error> {
+        let keys = [src.to_string(), dst.to_string()];
+        let mut shards = self.lock_shards(&keys);
+
+        let now = now_ms();
+        for k in &keys {
+            shards.get_mut(&self.shard_index(k)).unwrap().remove_expired(k, now);
+        }
+
+        // both ends are checked before anything moves
+        for k in &keys {
+            match shards[&self.shard_index(k)].get(k).map(|e| &e.value) {
+                Some(DB_TYPE::Array(_)) => {},
+                Some(_) => return Err(Error::WrongType),
+                None if k == src => return Ok(None),
+                None => {},
+            }
+        }
+
+        let popped = shards.get_mut(&self.shard_index(src)).unwrap().update(src, |value| {
+            let popped = match value {
+                Some(DB_TYPE::Array(arr)) => pop_list(arr, from_left, 1).pop(),
+                _ => None,
+            };
+            if matches!(value, Some(DB_TYPE::Array(arr)) if arr.is_empty()) {
+                *value = None;
+            }
+            popped
+        });
+
+        if let Some(v) = &popped {
+            shards.get_mut(&self.shard_index(dst)).unwrap().update(dst, |value| {
+                if let DB_TYPE::Array(arr) = value.get_or_insert_with(|| DB_TYPE::Array(List::new())) {
+                    push_list(arr, vec![v.clone()], to_left);
+                }
+            });
+        }
+        Ok(popped)
+    }
+
+    // LMPOP: pops up to count elements off the first of keys that holds a list
+    pub fn lmpop(&self, keys: &[String], left: bool, count: usize) -> Result<Option<(String, Vec<DB_TYPE>)>, Error> {
+        let mut shards = self.lock_shards(keys);
+
+        let now = now_ms();
+        for k in keys {
+            let shard = shards.get_mut(&self.shard_index(k)).unwrap();
+            shard.remove_expired(k, now);
+
+            let popped = shard.update(k, |value| {
+                let popped = match value {
+                    Some(DB_TYPE::Array(arr)) => Ok(Some(pop_list(arr, left, count))),
+                    Some(_) => Err(Error::WrongType),
+                    None => Ok(None),
+                };
+                if matches!(value, Some(DB_TYPE::Array(arr)) if arr.is_empty()) {
+                    *value = None;
+                }
+                popped
+            })?;
+
+            if let Some(popped) = popped {
+                return Ok(Some((k.clone(), popped)));
+            }
+        }
+        Ok(None)
+    }
+
+    // returns true if the field is new
+    pub fn hset(&self, k: &str, field: &str, value: &str) -> Result<bool, Error> {
+        self.update(k, |v| match v.get_or_insert_with(|| DB_TYPE::Hash(HashMap::new())) {
+            DB_TYPE::Hash(h) => Ok(h.insert(field.to_string(), value.to_string()).is_none()),
+            _ => Err(Error::WrongType),
+        })
+    }
+
+    pub fn hget(&self, k: &str, field: &str) -> Result<Option<String>, Error> {
+        self.read(k, |value| match value {
+            Some(DB_TYPE::Hash(h)) => Ok(h.get(field).cloned()),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        })
+    }
+
+    pub fn hdel(&self, k: &str, fields: &[String]) -> Result<usize, Error> {
+        self.update(k, |value| {
+            let h = match value {
+                Some(DB_TYPE::Hash(h)) => h,
+                Some(_) => return Err(Error::WrongType),
+                None => return Ok(0),
+            };
+
+            let removed = fields.iter().filter(|f| h.remove(*f).is_some()).count();
+
+            // an empty hash is the same as no key
+            if h.is_empty() {
+                *value = None;
+            }
+
+            Ok(removed)
+        })
+    }
+
+    pub fn hgetall(&self, k: &str) -> Result<HashMap<String, String>, Error> {
+        self.read(k, |value| match value {
+            Some(DB_TYPE::Hash(h)) => Ok(h.clone()),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(HashMap::new()),
+        })
+    }
+
+    pub fn get_custom<T: CustomType>(&self, k: &str) -> Result<Option<T>, Error> {
+        self.read(k, |value| match value {
+            Some(DB_TYPE::Custom(c)) => match c.as_any().downcast_ref::<T>() {
+                Some(v) => Ok(Some(v.clone())),
+                None => Err(Error::WrongType),
+            },
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        })
+    }
+
+    // runs f on the stored value under the shard lock, creating it with init if missing
+    pub fn update_custom<T: CustomType, R>(&self, k: &str, init: impl FnOnce() -> T, f: impl FnOnce(&mut T) -> R) -> Result<R, Error> {
+        self.update(k, |value| match value.get_or_insert_with(|| DB_TYPE::custom(init())) {
+            DB_TYPE::Custom(c) => match c.as_any_mut().downcast_mut::<T>() {
+                Some(v) => Ok(f(v)),
+                None => Err(Error::WrongType),
+            },
+            _ => Err(Error::WrongType),
+        })
+    }
+
+    pub fn ttl(&self, k: &str) -> Ttl {
+        let mut shard = self.shard(k);
+
+        let now = now_ms();
+        if shard.remove_expired(k, now) {
+            return Ttl::Missing;
+        }
+
+        match shard.get(k) {
+            Some(e) if e.expire > 0 => Ttl::Expires(Duration::from_millis((e.expire - now) as u64)),
+            Some(_) => Ttl::Persistent,
+            None => Ttl::Missing,
+        }
+    }
+
+    // like ttl, but Expires holds the unix time k expires at
+    pub fn expire_time(&self, k: &str) -> Ttl {
+        let mut shard = self.shard(k);
+        shard.remove_expired(k, now_ms());
+
+        match shard.get(k) {
+            Some(e) if e.expire > 0 => Ttl::Expires(Duration::from_millis(e.expire as u64)),
+            Some(_) => Ttl::Persistent,
+            None => Ttl::Missing,
+        }
+    }
+
+    // makes k expire at unix time t in milliseconds, a time already passed
+    // deletes it. allow is given the current expire (None if there is none)
+    // and may refuse the change; false if refused or k is missing
+    pub fn expire_at(&self, k: &str, t: u128, allow: impl FnOnce(Option<u128>) -> bool) -> bool {
+        let mut shard = self.shard(k);
+
+        let now = now_ms();
+        shard.remove_expired(k, now);
+
+        let current = match shard.get(k) {
+            Some(e) => Some(e.expire).filter(|t| *t > 0),
+            None => return false,
+        };
+        if !allow(current) {
+            return false;
+        }
+
+        if t <= now {
+            shard.remove(k);
+        }
+        else {
+            shard.set_expire(k, t);
+        }
+        true
+    }
+
+    // removes the expire of k, false if it is missing or had none
+    pub fn persist(&self, k: &str) -> bool {
+        let mut shard = self.shard(k);
+        shard.remove_expired(k, now_ms());
+
+        match shard.get(k) {
+            Some(e) if e.expire > 0 => {
+                shard.set_expire(k, 0);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // one run of active expiry, stopping once until has passed. each shard,
+    // starting where the last run stopped, is sampled EXPIRE_SAMPLE keys at a
+    // time from its earliest deadlines, and sampled again while more than a
+    // quarter of those had expired
+    pub fn expire_cycle(&self, until: Instant) -> ExpireCycle {
+        let mut cycle = ExpireCycle::default();
+        let start = self.next_expire_shard.load(Ordering::Relaxed);
+
+        for n in 0..self.shards.len() {
+            let i = (start + n) % self.shards.len();
+            if Instant::now() >= until {
+                self.next_expire_shard.store(i, Ordering::Relaxed);
+                cycle.timed_out = true;
+                return cycle;
+            }
+
+            let mut shard = self.shards[i].lock().expect("DB mutex lock failed");
+            loop {
+                let now = now_ms();
+                let sample: Vec<(u128, String)> = shard.deadlines.iter().take(EXPIRE_SAMPLE).cloned().collect();
+
+                let mut expired = 0;
+                for (deadline, k) in &sample {
+                    if now > *deadline && shard.remove_expired(k, now) {
+                        expired += 1;
+                    }
+                }
+                cycle.sampled += sample.len();
+                cycle.expired += expired;
+
+                if expired * 4 <= sample.len() || sample.len() < EXPIRE_SAMPLE || Instant::now() >= until {
+                    break;
+                }
+            }
+        }
+
+        cycle
+    }
+
+    // keys removed because they expired, since the database was created
+    pub fn expired_keys(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|s| s.lock().expect("DB mutex lock failed").expired)
+            .sum()
+    }
+
+    // keys that have an expire
+    pub fn expires(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.lock().expect("DB mutex lock failed").deadlines.len())
+            .sum()
+    }
+
+    // moves k into dest unless it is missing here or already there, the two
+    // databases must be different
+    pub fn move_key(&self, k: &str, dest: &Db) -> bool {
+        let (mut from, mut to) = lock_pair(&self.shards[self.shard_index(k)], &dest.shards[dest.shard_index(k)]);
+
+        let now = now_ms();
+        from.remove_expired(k, now);
+        to.remove_expired(k, now);
+
+        if to.contains_key(k) {
+            return false;
+        }
+
+        match from.remove(k) {
+            Some(entry) => {
+                to.insert(k.to_string(), entry);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // exchanges the whole keyspace with other's; stats such as expired_keys
+    // stay with each database
+    pub fn swap(&self, other: &Db) {
+        if std::ptr::eq(self, other) {
+            return;
+        }
+
+        // lock in address order, which for a server's databases is index order
+        let (mut first, mut second) = if (self as *const Db) < (other as *const Db) {
+            let first = self.lock_all();
+            (first, other.lock_all())
+        }
+        else {
+            let second = other.lock_all();
+            (self.lock_all(), second)
+        };
+
+        for (a, b) in first.iter_mut().zip(second.iter_mut()) {
+            a.swap_keys(b);
+        }
+    }
+
+    pub fn flush(&self) {
+        for mut shard in self.lock_all() {
+            shard.clear();
+        }
+    }
+
+    pub fn keys(&self, pattern: &str) -> Vec<String> {
+        self.iter()
+            .map(|(k, _)| k)
+            .filter(|k| glob_match(pattern.as_bytes(), k.as_bytes()))
+            .collect()
+    }
+
+    // one step of a SCAN: up to count keys starting at cursor, of which only
+    // those passing filter are returned, plus the cursor to continue from (0
+    // once done). keys are visited in the order of their rotated hash, which
+    // groups them by shard and never changes when a shard's map grows, so a
+    // key present for the whole iteration is always returned. shards keep
+    // their keys in that order, so a step only reads the keys it visits
+    pub fn scan(&self, cursor: u64, count: usize, filter: impl Fn(&str, &DB_TYPE) -> bool) -> (u64, Vec<String>) {
+        let now = now_ms();
+        let mut keys = Vec::new();
+        let mut visited = 0;
+        let mut from = cursor;
+
+        for i in (cursor >> (64 - SHARD_BITS)) as usize..self.shards.len() {
+            if visited >= count {
+                return ((i as u64) << (64 - SHARD_BITS), keys);
+            }
+
+            let shard = self.shards[i].lock().expect("DB mutex lock failed");
+            let mut last = None;
+            for (p, k) in shard.positions.range((from, String::new())..) {
+                // stop after count keys, but never between two keys at the
+                // same position since the cursor could not resume in between
+                if visited >= count && last != Some(*p) {
+                    return (*p, keys);
+                }
+
+                let e = &shard.entries[k];
+                if e.expire != 0 && now > e.expire {
+                    continue;
+                }
+                visited += 1;
+                last = Some(*p);
+                if filter(k, &e.value) {
+                    keys.push(k.clone());
+                }
+            }
+            from = 0;
+        }
+
+        (0, keys)
+    }
+
+    pub fn type_of(&self, k: &str) -> Option<String> {
+        self.get(k).map(|v| v.type_name().to_string())
+    }
+
+    // renames src to dst with its expire; with nx an existing dst is left
+    // alone and false returned
+    pub fn rename(&self, src: &str, dst: &str, nx: bool) -> Result<bool, Error> {
+        let mut shards = self.lock_shards(&[src.to_string(), dst.to_string()]);
+
+        let now = now_ms();
+        for k in [src, dst] {
+            shards.get_mut(&self.shard_index(k)).unwrap().remove_expired(k, now);
+        }
+
+        if !shards[&self.shard_index(src)].contains_key(src) {
+            return Err(Error::Other("no such key".to_string()));
+        }
+        if nx && shards[&self.shard_index(dst)].contains_key(dst) {
+            return Ok(false);
+        }
+
+        let entry = shards.get_mut(&self.shard_index(src)).unwrap().remove(src).unwrap();
+        shards.get_mut(&self.shard_index(dst)).unwrap().insert(dst.to_string(), entry);
+        Ok(true)
+    }
+
+    // a key from a random non-empty shard, at a random position in it.
+    // expired keys it lands on are dropped and another is tried
+    pub fn random_key(&self) -> Option<String> {
+        let now = now_ms();
+        for _ in 0..RANDOM_KEY_TRIES {
+            let start = random_u64() as usize;
+            let mut shard = (0..self.shards.len())
+                .map(|n| self.shards[(start + n) % self.shards.len()].lock().expect("DB mutex lock failed"))
+                .find(|s| !s.is_empty())?;
+
+            let k = shard.random_key()?.clone();
+            if !shard.remove_expired(&k, now) {
+                return Some(k);
+            }
+        }
+        None
+    }
+
+    // copies src of this database to dst of dest, with its expire; without
+    // replace an existing dst is left alone and false returned
+    pub fn copy_to(&self, src: &str, dest: &Db, dst: &str, replace: bool) -> bool {
+        let now = now_ms();
+        let copy = |entry: Option<Entry>, to: &mut Shard| {
+            to.remove_expired(dst, now);
+            match entry {
+                Some(_) if !replace && to.contains_key(dst) => false,
+                Some(e) => {
+                    to.insert(dst.to_string(), e);
+                    true
+                },
+                None => false,
+            }
+        };
+
+        if std::ptr::eq(self, dest) {
+            let mut shards = self.lock_shards(&[src.to_string(), dst.to_string()]);
+            let from = shards.get_mut(&self.shard_index(src)).unwrap();
+            from.remove_expired(src, now);
+            let entry = from.get(src).cloned();
+            copy(entry, shards.get_mut(&self.shard_index(dst)).unwrap())
+        }
+        else {
+            let (mut from, mut to) = lock_pair(&self.shards[self.shard_index(src)], &dest.shards[dest.shard_index(dst)]);
+            from.remove_expired(src, now);
+            copy(from.get(src).cloned(), &mut to)
+        }
+    }
+
+    // what OBJECT reports about k; looking does not count as an access
+    pub fn key_info(&self, k: &str) -> Option<KeyInfo> {
+        let mut shard = self.shard(k);
+
+        let now = now_ms();
+        shard.remove_expired(k, now);
+
+        shard.get(k).map(|e| KeyInfo {
+            encoding: e.value.encoding(),
+            idle: Duration::from_millis((now as u64).saturating_sub(e.access)),
+            freq: e.lfu(now as u64),
+            refcount: match e.value {
+                DB_TYPE::Int(i) if (0..SHARED_INTEGERS).contains(&i) => i32::MAX as i64,
+                _ => 1,
+            },
+        })
+    }
+
+    // estimated bytes of k and its value, see DB_TYPE::sampled_memory_usage
+    pub fn memory_usage(&self, k: &str, samples: usize) -> Option<usize> {
+        let mut shard = self.shard(k);
+        shard.remove_expired(k, now_ms());
+
+        shard.get(k).map(|e| key_size(k) + e.value.sampled_memory_usage(samples))
+    }
+
+    // the parts of used_memory spent on bookkeeping rather than keys and
+    // values: for the keys, and for the index of those with an expire
+    pub fn memory_overhead(&self) -> (usize, usize) {
+        (self.len() * ENTRY_OVERHEAD, self.expires() * DEADLINE_OVERHEAD)
+    }
+
+    // estimated bytes of every key and value, of this database and those
+    // sharing its types
+    pub fn used_memory(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    // keys removed to get under maxmemory, since the database was created
+    pub fn evicted_keys(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|s| s.lock().expect("DB mutex lock failed").evicted)
+            .sum()
+    }
+
+    // the best key to evict out of samples keys of a random shard, with its
+    // score where higher means evict sooner; None if there is no candidate
+    pub fn eviction_candidate(&self, policy: MaxmemoryPolicy, samples: usize) -> Option<(u64, String)> {
+        let volatile = matches!(
+            policy,
+            MaxmemoryPolicy::VolatileLru | MaxmemoryPolicy::VolatileLfu | MaxmemoryPolicy::VolatileRandom | MaxmemoryPolicy::VolatileTtl
+        );
+        let now = now_ms() as u64;
+        let start = (random_u64() % self.shards.len() as u64) as usize;
+
+        // the first shard from a random one that has candidates
+        for n in 0..self.shards.len() {
+            let shard = self.shards[(start + n) % self.shards.len()].lock().expect("DB mutex lock failed");
+
+            let mut best: Option<(u64, &String)> = None;
+            for (k, e) in shard.sample(samples, volatile) {
+                let score = match policy {
+                    MaxmemoryPolicy::AllkeysLru | MaxmemoryPolicy::VolatileLru => now.saturating_sub(e.access),
+                    MaxmemoryPolicy::AllkeysLfu | MaxmemoryPolicy::VolatileLfu => (u8::MAX - e.lfu(now)) as u64,
+                    MaxmemoryPolicy::VolatileTtl => u64::MAX - e.expire as u64,
+                    MaxmemoryPolicy::AllkeysRandom | MaxmemoryPolicy::VolatileRandom | MaxmemoryPolicy::NoEviction => 0,
+                };
+                if best.is_none_or(|(b, _)| score > b) {
+                    best = Some((score, k));
+                }
+            }
+
+            if let Some((score, k)) = best {
+                return Some((score, k.clone()));
+            }
+        }
+
+        None
+    }
+
+    // removes k to free memory, returns the bytes freed
+    pub fn evict(&self, k: &str) -> usize {
+        let mut shard = self.shard(k);
+
+        let used = shard.used;
+        match shard.remove(k) {
+            Some(_) => {
+                shard.evicted += 1;
+                used - shard.used
+            },
+            None => 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.lock().expect("DB mutex lock failed").len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // walks the keyspace one shard at a time, so only one lock is held at once
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            db: self,
+            next_shard: 0,
+            buffer: Vec::new().into_iter(),
+        }
+    }
+
+    pub fn write_db_to_file(&self, file_path: &str) -> Result<(), Error> {
+        Db::write_snapshot(std::slice::from_ref(self), file_path, &[])
+    }
+
+    pub fn read_db_from_file(&self, file_path: &str) -> Result<(), Error> {
+        Db::read_snapshot(std::slice::from_ref(self), file_path).map(|_| ())
+    }
+
+    // every database by index, plus the code of every function library
+    // written to a file next to the old snapshot and renamed over it, so a
+    // save that fails part way leaves the old one whole
+    pub fn write_snapshot(dbs: &[Db], file_path: &str, libraries: &[String]) -> Result<(), Error> {
+        let temp = format!("{file_path}.tmp");
+        let written = File::create(&temp)
+            .and_then(|file| {
+                let mut buf_writer = BufWriter::new(file);
+                Db::write_entries(dbs, &mut buf_writer, libraries)?;
+                buf_writer.into_inner().map_err(|e| e.into_error())?.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&temp, file_path));
+
+        written.map_err(|e| {
+            std::fs::remove_file(&temp).ok();
+            Error::Persistence(e.to_string())
+        })
+    }
+
+    // replaces every database with the snapshot's and hands back the function
+    // libraries' code. nothing changes unless the whole file reads
+    pub fn read_snapshot(dbs: &[Db], file_path: &str) -> Result<Vec<String>, Error> {
+        let (staged, libraries) = Db::stage_snapshot(dbs, file_path)?;
+        Db::swap_all(dbs, &staged);
+        Ok(libraries)
+    }
+
+    // reads a snapshot into new databases next to dbs, for swap_all to put in
+    // their place once everything else loading it needs has succeeded
+    pub fn stage_snapshot(dbs: &[Db], file_path: &str) -> Result<(Vec<Db>, Vec<String>), Error> {
+        let staged: Vec<Db> = dbs.iter().map(Db::sharing_types).collect();
+        let libraries = Db::read_entries(&staged, file_path)
+            .map_err(|e| Error::Other(format!("Error loading {file_path}: {e}")))?;
+        Ok((staged, libraries))
+    }
+
+    pub fn swap_all(dbs: &[Db], staged: &[Db]) {
+        for (db, loaded) in dbs.iter().zip(staged) {
+            db.swap(loaded);
+        }
+    }
+
+    fn write_entries(dbs: &[Db], buf_writer: &mut impl Write, libraries: &[String]) -> io::Result<()> {
+        let locked: Vec<_> = dbs.iter().map(|db| db.lock_all()).collect();
+
+        // get some metadata
+        let time = chrono::Utc::now();
+
+        // append general detail
+         buf_writer.write_all("--------------------------------------------------------\r\n".as_bytes())?;
+         buf_writer.write_all("REDIS\r\n".as_bytes())?;
+         buf_writer.write_all("0001\r\n".as_bytes())?;
+         buf_writer.write_all("--------------------------------------------------------\r\n".as_bytes())?;
+         buf_writer.write_all(time.to_string().as_bytes())?;
+         buf_writer.write_all("\r\n--------------------------------------------------------\r\n".as_bytes())?;
+
+        // function libraries, as an array of bulk strings holding their code
+         buf_writer.write_all("FUNCTIONS\r\n".as_bytes())?;
+         buf_writer.write_all(format!("*{}\r\n", libraries.len()).as_bytes())?;
+        for code in libraries {
+            let clen = code.len();
+            buf_writer.write_all(format!("${clen}\r\n${code}\r\n").as_bytes())?;
+        }
+         buf_writer.write_all("--------------------------------------------------------\r\n".as_bytes())?;
+         buf_writer.write_all("KEYS-VALUES\r\n".as_bytes())?;
+
+        // for each string
+            // save expire
+            // get type
+            // save val as bytestring
+            // if array, say as array of bytestrings...
+        for (index, shards) in locked.iter().enumerate() {
+            if shards.iter().all(|s| s.is_empty()) {
+                continue;
+            }
+
+            // the entries after this line belong to database index
+            buf_writer.write_all("--------------------------------------------------------\r\n".as_bytes())?;
+            buf_writer.write_all(format!("SELECTDB {index}\r\n").as_bytes())?;
+
+            for (key, entry) in shards.iter().flat_map(|s| s.iter()) {
+                buf_writer.write_all("--------------------------------------------------------\r\n".as_bytes())?;
+
+                // get expire time
+                let exp_time = entry.expire;
+                buf_writer.write_all(format!("FD {exp_time}\r\n").as_bytes())?;
+
+                // write value type, then key, then value
+                let typing = match &entry.value {
+                    DB_TYPE::Int(_) => "$i",
+                    DB_TYPE::Str(_) => "$s",
+                    DB_TYPE::Array(_) => "$a",
+                    DB_TYPE::Hash(_) => "$h",
+                    DB_TYPE::Custom(_) => "$c",
+                };
+                buf_writer.write_all(format!("{typing}\r\n").as_bytes())?;
+
+                buf_writer.write_all(format!("${key}\r\n").as_bytes())?;
+
+                match &entry.value {
+                    DB_TYPE::Int(i) => { 
+                        buf_writer.write_all(format!("${i}\r\n").as_bytes())?;
+                    },
+                    DB_TYPE::Str(s) => {
+                        let slen = s.len();
+                        buf_writer.write_all(format!("${slen}\r\n$").as_bytes())?;
+                        buf_writer.write_all(s)?;
+                        buf_writer.write_all("\r\n".as_bytes())?;
+                    },
+                    DB_TYPE::Array(a) => {
+                        let alen = a.len();
+                        buf_writer.write_all(format!("*{alen}\r\n").as_bytes())?;
+                        for v in a.iter() {
+                            match v {
+                                DB_TYPE::Int(i) => {
+                                    buf_writer.write_all("$i\r\n".as_bytes())?;
+                                    buf_writer.write_all(format!("${i}\r\n").as_bytes())?;
+                                },
+                                DB_TYPE::Str(s) => {
+                                    buf_writer.write_all("$s\r\n".as_bytes())?;
+                                    let slen = s.len();
+                                    buf_writer.write_all(format!("${slen}\r\n$").as_bytes())?;
+                                    buf_writer.write_all(s)?;
+                                    buf_writer.write_all("\r\n".as_bytes())?;
+                                },
+                                _ => return Err(io::Error::other("nested arrays are not supported")),
+                            }
+                        }
+                    },
+                    DB_TYPE::Hash(h) => {
+                        // fields and values are written as one flat array of strings
+                        let alen = h.len() * 2;
+                        buf_writer.write_all(format!("*{alen}\r\n").as_bytes())?;
+                        for s in h.iter().flat_map(|(f, v)| [f, v]) {
+                            buf_writer.write_all("$s\r\n".as_bytes())?;
+                            let slen = s.len();
+                            buf_writer.write_all(format!("${slen}\r\n${s}\r\n").as_bytes())?;
+                        }
+                    },
+                    DB_TYPE::Custom(c) => {
+                        // type name, then the encoded value as hex so it stays on one line
+                        let data = match c.save() {
+                            Some(data) => data,
+                            None => return Err(io::Error::other(format!("custom type '{}' cannot be saved", c.type_name()))),
+                        };
+                        let name = c.type_name();
+                        let hex: String = data.iter().map(|b| format!("{b:02x}")).collect();
+                        buf_writer.write_all(format!("${name}\r\n${hex}\r\n").as_bytes())?;
+                    }
+                }
+            }
+        }
+
+        buf_writer.write_all("--------------------------------------------------------\r\n".as_bytes())?;
+        buf_writer.write_all("EOF\r\n".as_bytes())?;
+
+        Ok(())
+    }
+
+    fn read_entries(dbs: &[Db], file_path: &str) -> Result<Vec<String>, String> {
+        // open local file if existing via file path
+        let file = File::open(file_path).map_err(|e| e.to_string())?;
+        let mut buf_reader = BufReader::new(file);
+        let mut line = String::new();
+
+        // lock every shard of every database, entries go to database 0 until
+        // a SELECTDB line says otherwise
+        let mut locked: Vec<_> = dbs.iter().map(|db| db.lock_all()).collect();
+        let mut current = 0;
+
+        // read until strings.. ignore metadata but the function libraries
+        let mut libraries = Vec::new();
+        let mut kv = false;
+        // for each string
+        while !kv {
+            line.clear();
+            match buf_reader.read_line(&mut line) {
+                Ok(s)  => { 
+                    if s == 0 { 
+                        return Err("EOF".to_string());
+                    }
+                    if line.trim_end() == "KEYS-VALUES" {
+                        kv = true;
+                    }
+                    else if line.trim_end() == "FUNCTIONS" {
+                        libraries = read_libraries(&mut buf_reader)?;
+                    }
+                },
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+
+        buf_reader.read_line(&mut line).ok();
+        line.clear();
+
+        // now at strings.. read until eof
+        loop {
+            line.clear();
+
+            // get expire
+            let exp: u128 = match buf_reader.read_line(&mut line) {
+                Ok(size)  => { 
+                    if size == 0 {
+                        return Err("Unexpected EOF".to_string());
+                    }
+                    if line.trim() == "EOF" {
+                        return Ok(libraries);
+                    }
+                    if let Some(index) = line.trim_end().strip_prefix("SELECTDB ") {
+                        current = match index.parse::<usize>() {
+                            Ok(index) if index < dbs.len() => index,
+                            _ => return Err(format!("Invalid database index {index}")),
+                        };
+
+                        // skip the separator before the first entry
+                        line.clear();
+                        buf_reader.read_line(&mut line).ok();
+                        continue;
+                    }
+
+                    let vals: Vec<&str> = line.trim_end().split(" ").collect();
+                    if vals.len() == 2 {
+                        if vals[0] != "FD" {
+                            return Err("Cannot correctly read expire for object".to_string());
+                        }
+                        else {
+                            vals[1].parse().map_err(|_| "Cannot correctly read expire for object".to_string())?
+                        }
+                    }
+                    else {
+                        return Err("Cannot correctly read expire for object".to_string());
+                    }
+                },
+                Err(e) => return Err(e.to_string()),
+            };
+
+            // get value type
+            line.clear();
+            let typing: char = match buf_reader.read_line(&mut line) {
+                Ok(_)  => { 
+                    line.chars().nth(1).ok_or("Cannot read object type")?
+                },
+                Err(e) => return Err(e.to_string()),
+            };
+
+            // get key
+            line.clear();
+            let key = match buf_reader.read_line(&mut line) {
+                Ok(_)  => { 
+                    field(&line)?.to_string()
+                },
+                Err(e) => return Err(e.to_string()),
+            };
+
+            line.clear();
+            // get value
+            let value: DB_TYPE = match typing {
+                // int
+                'i' => {
+                    match buf_reader.read_line(&mut line) {
+                        Ok(_)  => { 
+                            DB_TYPE::Int(parse_field(&line)?)
+                        },
+                        Err(e) => return Err(e.to_string()),
+                    }
+                },
+                // string
+                's' => {
+                    // read bulk string value
+                    let bytes_to_read = match buf_reader.read_line(&mut line) {
+                        Ok(_)  => { 
+                            parse_field(&line)?
+                        },
+                        Err(e) => return Err(e.to_string()),
+                    };
+
+                    line.clear();
+
+                    // read bulk string
+                    DB_TYPE::string(read_bulk(&mut buf_reader, bytes_to_read)?)
+                },
+                // array, or hash stored as a flat array of fields and values
+                'a' | 'h' => {
+                    // objects to read
+                    let objects_to_read = match buf_reader.read_line(&mut line) {
+                        Ok(_)  => { 
+                            parse_field(&line)?
+                        },
+                        Err(e) => return Err(e.to_string()),
+                    };
 
-use crate::types::DB_TYPE;
+                    let mut objects: Vec<DB_TYPE> = Vec::new();
 
+                    for _ in 0..objects_to_read {
+                        // get value type
+                        line.clear();
+                        let t: char = match buf_reader.read_line(&mut line) {
+                            Ok(_)  => { 
+                                line.chars().nth(1).ok_or("Cannot read object type")?
+                            },
+                            Err(e) => return Err(e.to_string()),
+                        };
 
-static REDIS_DB: Lazy<Mutex<HashMap<String, DB_TYPE>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+                        line.clear();
 
-static EXPIRE_DB: Lazy<Mutex<HashMap<String, u128>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+                        // save key with value
+                        let value: DB_TYPE = match t {
+                        // int
+                        'i' => {
+                            match buf_reader.read_line(&mut line) {
+                                Ok(_)  => { 
+                                    DB_TYPE::Int(parse_field(&line)?)
+                                },
+                                Err(e) => return Err(e.to_string()),
+                            }
+                        },
+                        // string
+                        's' => {
+                            // read bulk string value
+                            let bytes_to_read = match buf_reader.read_line(&mut line) {
+                                Ok(_)  => { 
+                                    parse_field(&line)?
+                                },
+                                Err(e) => return Err(e.to_string()),
+                            };
+
+                            line.clear();
+
+                            // read bulk string
+                            DB_TYPE::string(read_bulk(&mut buf_reader, bytes_to_read)?)
+                        },
+                        _ => return Err("Invalid char encountered for object type".to_string()),
+                        };
 
-pub fn set(k: String, v: DB_TYPE, t: u128) -> Result<String, String> {
-    let mut db = REDIS_DB.lock().expect("DB mutex lock failed");
+                        objects.push(value);
+                    }
 
-    db.insert(k.clone(), v);
+                    line.clear();
 
-    if t > 0 {
-        expire(&k, t);
-    }
+                    if typing == 'h' {
+                        let mut h = HashMap::new();
+                        for pair in objects.chunks(2) {
+                            match pair {
+                                [DB_TYPE::Str(f), DB_TYPE::Str(v)] => match (String::from_utf8(f.clone()), String::from_utf8(v.clone())) {
+                                    (Ok(f), Ok(v)) => h.insert(f, v),
+                                    _ => return Err("Invalid hash field encountered".to_string()),
+                                },
+                                _ => return Err("Invalid hash field encountered".to_string()),
+                            };
+                        }
+                        DB_TYPE::Hash(h)
+                    }
+                    else {
+                        DB_TYPE::Array(objects.into())
+                    }
+                },
+                // custom type
+                'c' => {
+                    buf_reader.read_line(&mut line).map_err(|e| e.to_string())?;
+                    let name = field(&line)?.to_string();
 
-    Ok("OK".to_string())
+                    line.clear();
+                    buf_reader.read_line(&mut line).map_err(|e| e.to_string())?;
+                    let data = decode_hex(field(&line)?)?;
+                    line.clear();
+
+                    let loaders = dbs[current].loaders.read().expect("type registry lock failed");
+                    let loader = match loaders.get(&name) {
+                        Some(loader) => loader,
+                        None => return Err(format!("unknown custom type '{name}'")),
+                    };
+
+                    match loader(&data) {
+                        Some(value) => DB_TYPE::Custom(value),
+                        None => return Err(format!("invalid value for custom type '{name}'")),
+                    }
+                },
+                _ => return Err("Invalid char encountered for object type".to_string()),
+            };
+
+            // save key with its expire
+            let i = dbs[current].shard_index(&key);
+            locked[current][i].insert(key, Entry::new(value, exp));
+
+
+            buf_reader.read_line(&mut line).ok();
+        }
+    }
 }
 
-pub fn get(k: &str) -> Option<DB_TYPE> {
-    let k_expire: u128;
+// locks two shards, of the same or different databases, in address order
+fn lock_pair<'a>(a: &'a Mutex<Shard>, b: &'a Mutex<Shard>) -> (MutexGuard<'a, Shard>, MutexGuard<'a, Shard>) {
+    if (a as *const Mutex<Shard>) < (b as *const Mutex<Shard>) {
+        let first = a.lock().expect("DB mutex lock failed");
+        (first, b.lock().expect("DB mutex lock failed"))
+    }
+    else {
+        let second = b.lock().expect("DB mutex lock failed");
+        (a.lock().expect("DB mutex lock failed"), second)
+    }
+}
 
-    // get lock on expire db
-    {
-        let db = EXPIRE_DB.lock().expect("DB mutex lock failed");
+// the FUNCTIONS section of a snapshot: *count, then one bulk string per library
+fn read_libraries(buf_reader: &mut BufReader<File>) -> Result<Vec<String>, String> {
+    let mut line = String::new();
+    buf_reader.read_line(&mut line).map_err(|e| e.to_string())?;
+    let count = match line.trim_end().strip_prefix('*').and_then(|n| n.parse::<usize>().ok()) {
+        Some(count) => count,
+        None => return Err("Cannot correctly read function libraries".to_string()),
+    };
 
-        // check if key is in expire db
-        k_expire = match db.get(k).cloned() {
-            Some(t) => t,
-            None => 0,
+    let mut libraries = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        buf_reader.read_line(&mut line).map_err(|e| e.to_string())?;
+        let len = match line.trim_end().strip_prefix('$').and_then(|n| n.parse::<usize>().ok()) {
+            Some(len) => len,
+            None => return Err("Cannot correctly read function library".to_string()),
         };
-    } 
-
-    // if key exists, and is expired then delete from expire db and db
-    if k_expire > 0
-        && SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis()
-            > k_expire
-    {
-        delete(vec![k.to_string()]);
-        return None;
+
+        let code = String::from_utf8(read_bulk(buf_reader, len)?).map_err(|e| e.to_string())?;
+        libraries.push(code);
     }
 
-    // get lock on redis db
-    let db = REDIS_DB.lock().unwrap();
+    Ok(libraries)
+}
+
+// $ + len bytes + \r\n, read without trusting len for the allocation, as a
+// corrupt file could claim any length
+fn read_bulk(buf_reader: &mut BufReader<File>, len: usize) -> Result<Vec<u8>, String> {
+    let mut bulk = Vec::new();
+    let wanted = len.checked_add(3).ok_or("Invalid bulk length")?;
+    buf_reader.take(wanted as u64).read_to_end(&mut bulk).map_err(|e| e.to_string())?;
 
-    // return option for key
-    db.get(k).cloned()
+    if bulk.len() < wanted {
+        return Err("Unexpected end of bulk string".to_string());
+    }
+    Ok(bulk[1..=len].to_vec())
 }
 
-fn expire(k: &str, t: u128) {
-    let mut db = EXPIRE_DB.lock().unwrap();
-    db.insert(k.to_string(), t);
+// a snapshot line's value, after its $ or * marker
+fn field(line: &str) -> Result<&str, String> {
+    match line.trim_end().get(1..) {
+        Some(value) => Ok(value),
+        None => Err(format!("Invalid line '{}'", line.trim_end())),
+    }
 }
 
-pub fn delete(keys: Vec<String>) -> i32 {
-    let mut db = REDIS_DB.lock().unwrap();
-    let mut e_db = EXPIRE_DB.lock().unwrap();
+fn parse_field<T: std::str::FromStr>(line: &str) -> Result<T, String> {
+    let value = field(line)?;
+    value.parse().map_err(|_| format!("Invalid number '{value}'"))
+}
 
-    let mut counter = 0;
-    for k in keys {
-        if let Some(_) = db.remove(&k) {
-            counter += 1;
-            e_db.remove(&k);
-        }
+fn decode_hex(s: &str) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) {
+        return Err("Invalid hex value".to_string());
     }
 
-    counter
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|e| e.to_string()))
+        .collect()
 }
 
-pub fn increment(k: &str) -> Result<String, String> {
-    match get(k) {
-        Some(val) => match val {
-            DB_TYPE::Int(i) => {
-                match set(k.to_string(), DB_TYPE::Int(i + 1_i64), 0) {
-                    Ok(_) => return Ok("OK".to_string()),
-                    Err(e) => return Err(e)
-                }
-            },
-            // error parsing...
-            _ => return Err("value is not an integer or out of range".to_string()),
-        },
-        None => {
-            match set(k.to_string(), DB_TYPE::Int(1), 0) {
-                Ok(_) => return Ok("OK".to_string()),
-                Err(e) => return Err(e)
-            }
-        }
-    };
+impl Default for Db {
+    fn default() -> Self {
+        Db::new()
+    }
 }
 
-pub fn decrement(k: &str) -> Result<String, String> {
-    match get(k) {
-        Some(val) => match val {
-            DB_TYPE::Int(i) => {
-                // if key is parsed correctly, increment and set
-                match set(k.to_string(), DB_TYPE::Int(i - 1_i64), 0) {
-                    Ok(_) => return Ok("OK".to_string()),
-                    Err(e) => return Err(e)
-                }
-            },
-            // error parsing...
-            _ => return Err("value is not an integer or out of range".to_string()),
-        },
-        // if no key, set key to 1
-        None => {
-            match set(k.to_string(), DB_TYPE::Int(1), 0) {
-                Ok(_) => return Ok("OK".to_string()),
-                Err(e) => return Err(e)
-            }
-        }
-    };
+pub struct Iter<'a> {
+    db: &'a Db,
+    next_shard: usize,
+    buffer: std::vec::IntoIter<(String, DB_TYPE)>,
 }
-pub fn exists(keys: Vec<String>) -> i32 {
-    let db = REDIS_DB.lock().unwrap();
 
-    let mut counter = 0;
-    for k in keys {
-        if let Some(_) = db.get(&k) {
-            counter += 1;
+impl Iterator for Iter<'_> {
+    type Item = (String, DB_TYPE);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.buffer.next() {
+                return Some(item);
+            }
+
+            if self.next_shard >= self.db.shards.len() {
+                return None;
+            }
+
+            // copy out the live entries of the next shard
+            let now = now_ms();
+            let shard = self.db.shards[self.next_shard].lock().expect("DB mutex lock failed");
+            self.buffer = shard
+                .iter()
+                .filter(|(_, e)| e.expire == 0 || now <= e.expire)
+                .map(|(k, e)| (k.clone(), e.value.clone()))
+                .collect::<Vec<_>>()
+                .into_iter();
+            self.next_shard += 1;
         }
     }
-
-    counter
 }
 
-pub fn lpush(k: &str, values: Vec<DB_TYPE>) -> Result<i64, String> {
-    let mut db = REDIS_DB.lock().unwrap();
-
-    let mut v = match db.get(k) {
-        Some(v) => match v {
-            DB_TYPE::Array(arr) => arr.clone(),
-            _ => return Err("Not an array".to_string()),
-        },
-        None => Vec::<DB_TYPE>::new()
-    };
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
 
-    
-    for value in values {
-        v.insert(0, value);
-    }
+// in [0, 1)
+fn random_unit() -> f64 {
+    (random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
 
-    let l = v.len();
+fn key_hash(k: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    k.hash(&mut hasher);
+    hasher.finish()
+}
 
-    db.insert(k.to_string(), DB_TYPE::Array(v));
+// where SCAN visits a key: the shard bits move to the top, so positions are
+// ordered by shard first
+fn scan_position(k: &str) -> u64 {
+    key_hash(k).rotate_right(SHARD_BITS)
+}
 
-    Ok(l as i64)
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
 }
 
-pub fn rpush(k: &str, values: Vec<DB_TYPE>) -> Result<i64, String> {
-    let mut db = REDIS_DB.lock().unwrap();
 
-    let mut v = match db.get(k) {
-        Some(v) => match v {
-            DB_TYPE::Array(arr) => arr.clone(),
-            _ => return Err("Not an array".to_string()),
-        },
-        None => Vec::<DB_TYPE>::new()
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::sync::Arc;
+    use std::thread;
 
-    
-    for value in values {
-        v.push(value);
+    fn insert(db: &Db, k: &str, value: DB_TYPE, expire: u128) {
+        db.shard(k).insert(k.to_string(), Entry::new(value, expire));
     }
 
-    let l = v.len();
+    fn entry(db: &Db, k: &str) -> Option<(DB_TYPE, u128)> {
+        db.shard(k).get(k).map(|e| (e.value.clone(), e.expire))
+    }
 
-    db.insert(k.to_string(), DB_TYPE::Array(v));
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("rs-redis-{}-{name}.rdb", std::process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
 
-    Ok(l as i64)
-}
+    #[test]
+    fn test_keys_spread_across_shards() {
+        let db = Db::new();
+        let indexes: BTreeSet<usize> = (0..100)
+            .map(|i| db.shard_index(&format!("spread_{i}")))
+            .collect();
+
+        assert!(indexes.len() > 1);
+        assert!(indexes.iter().all(|i| *i < SHARD_COUNT));
+    }
 
-pub fn write_db_to_file() -> Result<String, String> {
-    let db = REDIS_DB.lock().unwrap();
-    let e_db = EXPIRE_DB.lock().unwrap();
-
-    // get some metadata
-    let time = chrono::Utc::now();
-    // create file
-    let file = File::create("./REDIS.rdb").expect("Cannot create rdb file");
-    let mut buf_writer = BufWriter::new(file);
-
-    // append general detail
-     buf_writer.write("--------------------------------------------------------\r\n".as_bytes()).ok();
-     buf_writer.write("REDIS\r\n".as_bytes()).ok();
-     buf_writer.write("0001\r\n".as_bytes()).ok();
-     buf_writer.write("--------------------------------------------------------\r\n".as_bytes()).ok();
-     buf_writer.write(time.to_string().as_bytes()).ok();
-     buf_writer.write("\r\n--------------------------------------------------------\r\n".as_bytes()).ok();
-     buf_writer.write("KEYS-VALUES\r\n".as_bytes()).ok();
-    
-    // for each string
-        // save expire
-        // get type
-        // save val as bytestring
-        // if array, say as array of bytestrings...
-    for(key, value) in db.iter() {
-        buf_writer.write("--------------------------------------------------------\r\n".as_bytes()).ok();
-
-        // get expire time
-        let exp_time = match e_db.get(key) {
-            Some(i) => i,
-            None => &0
-        };
-        buf_writer.write(format!("FD {exp_time}\r\n").as_bytes()).ok();
+    #[test]
+    fn test_instances_are_isolated() {
+        let a = Db::new();
+        let b = Db::new();
 
-        // write value type
+        a.set("isolated", DB_TYPE::Int(1));
 
-        // write key
-        buf_writer.write(format!("${key}\r\n").as_bytes()).ok();
+        assert_eq!(a.get("isolated"), Some(DB_TYPE::Int(1)));
+        assert_eq!(b.get("isolated"), None);
+    }
 
-        // write value
-        match value {
-            DB_TYPE::Int(i) => { 
-                buf_writer.write(format!("$i\r\n").as_bytes()).ok();
-                buf_writer.write(format!("${i}\r\n").as_bytes()).ok();
-            },
-            DB_TYPE::Str(s) => {
-                buf_writer.write(format!("$s\r\n").as_bytes()).ok();
-                let slen = s.len();
-                buf_writer.write(format!("${slen}${s}\r\n").as_bytes()).ok();
-            },
-            DB_TYPE::Array(a) => {
-                let alen = a.len();
-                buf_writer.write(format!("$a\r\n").as_bytes()).ok();
-                buf_writer.write(format!("*{alen}\r\n").as_bytes()).ok();
-                for v in a {
-                    match v {
-                        DB_TYPE::Int(i) => {
-                            buf_writer.write(format!("$i\r\n").as_bytes()).ok();
-                            buf_writer.write(format!("${i}\r\n").as_bytes()).ok();
-                        },
-                        DB_TYPE::Str(s) => {
-                            buf_writer.write(format!("$s\r\n").as_bytes()).ok();
-                            let slen = s.len();
-                            buf_writer.write(format!("${slen}${s}\r\n").as_bytes()).ok();
-                        },
-                        DB_TYPE::Array(_) => return Err("nested arrays are not supported".to_string()),
-                    }
-                }
-            }
+    #[test]
+    fn test_delete_and_exists_across_shards() {
+        let db = Db::new();
+        let keys: Vec<String> = (0..32).map(|i| format!("multi_{i}")).collect();
+        for k in &keys {
+            db.set(k, DB_TYPE::Int(1));
         }
+
+        assert_eq!(db.exists(&keys), 32);
+        assert_eq!(db.delete(&keys), 32);
+        assert_eq!(db.exists(&keys), 0);
     }
-    
-    buf_writer.write("--------------------------------------------------------\r\n".as_bytes()).ok();
-    buf_writer.write("EOF\r\n".as_bytes()).ok();
 
-    Ok("OK".to_string())
-}
+    #[test]
+    fn test_concurrent_rpush_same_key() {
+        let db = Db::new();
+        let db = Arc::new(db);
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        db.rpush("concurrent_list", vec![DB_TYPE::Int(i)]).unwrap();
+                    }
+                })
+            })
+            .collect();
 
+        for h in handles {
+            h.join().unwrap();
+        }
 
-pub fn read_db_from_file(file_path: &str) -> Result<String, String> {
-    // open local file if existing via file path
-    let file = File::open(file_path).expect("Cannot create rdb file");
-    let mut buf_reader = BufReader::new(file);
-    let mut line = String::new();
+        match db.get("concurrent_list") {
+            Some(DB_TYPE::Array(a)) => assert_eq!(a.len(), 800),
+            other => panic!("Expected DB_TYPE::Array, got {:?}", other),
+        }
+    }
 
-    // db lock
-    // expire db lock
-    let mut db = REDIS_DB.lock().unwrap();
-    let mut e_db = EXPIRE_DB.lock().unwrap();
+    #[test]
+    fn test_concurrent_incr_same_key() {
+        let db = Arc::new(Db::new());
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        db.incr("counter").unwrap();
+                    }
+                })
+            })
+            .collect();
 
-    // read until strings.. ignore metadata
-    let mut kv = false;
-    // for each string
-    while !kv {
-        line.clear();
-        match buf_reader.read_line(&mut line) {
-            Ok(s)  => { 
-                if s == 0 { 
-                    return Err("EOF".to_string());
-                }
-                if line.trim_end() == "KEYS-VALUES".to_string() {
-                    kv = true;
-                }
-            },
-            Err(e) => return Err(e.to_string()),
+        for h in handles {
+            h.join().unwrap();
         }
-    }
 
-    buf_reader.read_line(&mut line).ok();
-    line.clear();
+        assert_eq!(db.get("counter"), Some(DB_TYPE::Int(800)));
+    }
 
-    // now at strings.. read until eof
-    let eof = false;
+    #[test]
+    fn test_expired_keys_are_gone_for_every_operation() {
+        let db = Db::new();
+        insert(&db, "list", DB_TYPE::Array(vec![DB_TYPE::Int(1)].into()), 1);
+        insert(&db, "num", DB_TYPE::Int(41), 1);
+        insert(&db, "hash", DB_TYPE::Hash(HashMap::from([("f".to_string(), "v".to_string())])), 1);
+
+        assert_eq!(db.exists(&["list".to_string(), "num".to_string()]), 0);
+        assert_eq!(db.hget("hash", "f"), Ok(None));
+
+        // writing to an expired key starts over, without the old expire
+        assert_eq!(db.lpush("list", vec![DB_TYPE::Int(2)]), Ok(1));
+        assert_eq!(entry(&db, "list"), Some((DB_TYPE::Array(vec![DB_TYPE::Int(2)].into()), 0)));
+        assert_eq!(db.incr("num"), Ok(1));
+        assert_eq!(db.delete(&["hash".to_string()]), 0);
+        assert_eq!(db.expired_keys(), 3);
+    }
 
-    while !eof {
-        line.clear();
+    #[test]
+    fn test_incr_and_decr_return_new_value() {
+        let db = Db::new();
+
+        assert_eq!(db.incr("counter"), Ok(1));
+        assert_eq!(db.incr("counter"), Ok(2));
+        assert_eq!(db.decr("counter"), Ok(1));
+        assert_eq!(db.decr("missing"), Ok(-1));
+        assert_eq!(db.incr_by("missing", i64::MIN), Err(Error::Other("increment or decrement would overflow".to_string())));
+        assert_eq!(db.get("missing"), Some(DB_TYPE::Int(-1)));
+
+        db.set("text", DB_TYPE::Str(b"hello".to_vec()));
+        assert_eq!(db.incr("text"), Err(Error::NotAnInteger));
+
+        // only strings hold numbers
+        db.rpush("list", vec![DB_TYPE::Int(1)]).unwrap();
+        assert_eq!(db.incr_by("list", 1), Err(Error::WrongType));
+        assert_eq!(db.incr_by_float("list", 1.0), Err(Error::WrongType));
+        assert_eq!(db.lrange("list", 0, -1), Ok(vec![DB_TYPE::Int(1)]));
+    }
 
-        // get expire
-        let exp: u128 = match buf_reader.read_line(&mut line) {
-            Ok(size)  => { 
-                if size == 0 {
-                    return Err("Unexpected EOF".to_string());
-                }
-                if line.trim() == "EOF" {
-                    return Ok("OK".to_string());
-                }
+    #[test]
+    fn test_list_operations() {
+        let db = Db::new();
+
+        assert_eq!(db.rpush("list", vec![DB_TYPE::Int(1), DB_TYPE::Int(2)]), Ok(2));
+        assert_eq!(db.lpush("list", vec![DB_TYPE::Int(0)]), Ok(3));
+        assert_eq!(db.llen("list"), Ok(3));
+        assert_eq!(db.lrange("list", 0, -1), Ok(vec![DB_TYPE::Int(0), DB_TYPE::Int(1), DB_TYPE::Int(2)]));
+        assert_eq!(db.lrange("list", -2, 10), Ok(vec![DB_TYPE::Int(1), DB_TYPE::Int(2)]));
+        assert_eq!(db.lrange("list", 2, 1), Ok(vec![]));
+        assert_eq!(db.lrange("missing", 0, -1), Ok(vec![]));
+
+        db.set("text", DB_TYPE::Str(b"hello".to_vec()));
+        assert_eq!(db.lpush("text", vec![DB_TYPE::Int(1)]), Err(Error::WrongType));
+        assert_eq!(db.llen("text"), Err(Error::WrongType));
+    }
 
-                let vals: Vec<&str> = line.trim_end().split(" ").collect();
-                if vals.len() == 2 {
-                    if vals[0] != "FD" {
-                        return Err("Cannot correctly read expire for object".to_string());
-                    }
-                    else {
-                        vals[1].parse().expect("Valid int")
-                    }
-                }
-                else {
-                    return Err("Cannot correctly read expire for object".to_string());
-                }
+    #[test]
+    fn test_list_size_follows_changes() {
+        let db = Db::new();
+        // the size a list keeps must match one counted from its elements
+        let check = |db: &Db| match db.get("list") {
+            Some(DB_TYPE::Array(list)) => {
+                let heap: usize = list.iter().map(|v| v.memory_usage()).sum();
+                assert_eq!(list.memory_usage(), list.capacity() * std::mem::size_of::<DB_TYPE>() + heap);
             },
-            Err(e) => return Err(e.to_string()),
+            other => panic!("Expected DB_TYPE::Array, got {:?}", other),
         };
 
-        // get value type
-        line.clear();
-        let typing: char = match buf_reader.read_line(&mut line) {
-            Ok(_)  => { 
-                line.chars().nth(1).expect("No char????")
-            },
-            Err(e) => return Err(e.to_string()),
-        };
+        let long = |c: u8| DB_TYPE::Str(vec![c; 100]);
+        db.rpush("list", vec![long(b'a'), DB_TYPE::Int(1), long(b'b')]).unwrap();
+        db.lpush("list", vec![long(b'c'), long(b'a')]).unwrap();
+        check(&db);
+
+        assert_eq!(db.pop("list", true, 1), Ok(Some(vec![long(b'a')])));
+        assert_eq!(db.pop("list", false, 1), Ok(Some(vec![long(b'b')])));
+        db.lset("list", 0, long(b'd')).unwrap();
+        db.linsert("list", true, &DB_TYPE::Int(1), long(b'e')).unwrap();
+        check(&db);
+
+        assert_eq!(db.lrem("list", 0, &long(b'a')), Ok(1));
+        db.ltrim("list", 1, -1).unwrap();
+        assert_eq!(db.lrange("list", 0, -1), Ok(vec![long(b'e'), DB_TYPE::Int(1)]));
+        check(&db);
+        assert_eq!(db.used_memory(), db.memory_usage("list", 0).unwrap());
+    }
 
-        // get key
-        line.clear();
-        let key = match buf_reader.read_line(&mut line) {
-            Ok(_)  => { 
-                line.trim_end()[1..].to_string()
-            },
-            Err(e) => return Err(e.to_string()),
-        };
-        
-        line.clear();
-        // get value
-        let value: DB_TYPE = match typing {
-            // int
-            'i' => {
-                match buf_reader.read_line(&mut line) {
-                    Ok(_)  => { 
-                        DB_TYPE::Int(line.trim_end()[1..].parse::<i64>().expect("Not a valid integer"))
-                    },
-                    Err(e) => return Err(e.to_string()),
-                }
-            },
-            // string
-            's' => {
-                // read bulk string value
-                let bytes_to_read = match buf_reader.read_line(&mut line) {
-                    Ok(_)  => { 
-                        line.trim_end()[1..].parse::<usize>().expect("Not a valid integer")
-                    },
-                    Err(e) => return Err(e.to_string()),
-                };
+    #[test]
+    fn test_hash_operations() {
+        let db = Db::new();
 
-                line.clear();
+        assert_eq!(db.hset("user", "name", "ann"), Ok(true));
+        assert_eq!(db.hset("user", "name", "bob"), Ok(false));
+        assert_eq!(db.hset("user", "age", "30"), Ok(true));
+        assert_eq!(db.hget("user", "name"), Ok(Some("bob".to_string())));
+        assert_eq!(db.hget("user", "missing"), Ok(None));
+        assert_eq!(db.hgetall("user").unwrap().len(), 2);
 
-                // read bulk string
-                let mut bulk = vec![0u8; bytes_to_read + 3]; 
-                buf_reader.read_exact(&mut bulk).ok();
+        assert_eq!(db.hdel("user", &["name".to_string(), "age".to_string()]), Ok(2));
+        assert_eq!(db.get("user"), None);
 
-                let s = match String::from_utf8(bulk[1..(bulk.len() - 2)].to_vec()) {
-                    Ok(s) => s,
-                    Err(e) => return Err(e.to_string()),
-                };
+        db.set("text", DB_TYPE::Str(b"hello".to_vec()));
+        assert_eq!(db.hset("text", "f", "v"), Err(Error::WrongType));
+    }
 
-                DB_TYPE::Str(s)
-            },
-            // array
-            'a' => {
-                // objects to read
-                let objects_to_read = match buf_reader.read_line(&mut line) {
-                    Ok(_)  => { 
-                        line.trim_end()[1..].parse::<usize>().expect("Not a valid integer")
-                    },
-                    Err(e) => return Err(e.to_string()),
-                };
+    #[derive(Debug, Clone, PartialEq)]
+    struct Counter(u32);
 
-                let mut objects: Vec<DB_TYPE> = Vec::new();
+    impl CustomType for Counter {
+        fn type_name(&self) -> &str {
+            "counter"
+        }
+    }
 
-                for _ in 0..objects_to_read {
-                    // get value type
-                    line.clear();
-                    let t: char = match buf_reader.read_line(&mut line) {
-                        Ok(_)  => { 
-                            line.chars().nth(1).expect("No char????")
-                        },
-                        Err(e) => return Err(e.to_string()),
-                    };
+    #[test]
+    fn test_custom_values() {
+        let db = Db::new();
+
+        assert_eq!(db.get_custom::<Counter>("c"), Ok(None));
+        assert_eq!(db.update_custom("c", || Counter(0), |c| { c.0 += 1; c.0 }), Ok(1));
+        assert_eq!(db.update_custom("c", || Counter(0), |c| { c.0 += 1; c.0 }), Ok(2));
+        assert_eq!(db.get_custom::<Counter>("c"), Ok(Some(Counter(2))));
+        assert_eq!(db.get("c"), Some(DB_TYPE::custom(Counter(2))));
+
+        db.set("text", DB_TYPE::Str(b"hello".to_vec()));
+        assert_eq!(db.get_custom::<Counter>("text"), Err(Error::WrongType));
+        assert_eq!(db.update_custom("text", || Counter(0), |c| c.0), Err(Error::WrongType));
+
+        // custom values have no snapshot format yet, and a save that fails
+        // keeps the previous snapshot
+        let path = temp_path("write_custom");
+        let saved = Db::new();
+        saved.set("kept", DB_TYPE::Int(1));
+        assert_eq!(saved.write_db_to_file(&path), Ok(()));
+
+        let result = db.write_db_to_file(&path);
+        assert_eq!(result, Err(Error::Persistence("custom type 'counter' cannot be saved".to_string())));
+        assert_eq!(Db::read_snapshot(std::slice::from_ref(&saved), &path), Ok(Vec::new()));
+        assert_eq!(saved.get("kept"), Some(DB_TYPE::Int(1)));
+        assert!(!std::path::Path::new(&format!("{path}.tmp")).exists());
+
+        // so does one that can not create its file
+        let missing = temp_path("missing/write_custom");
+        assert!(matches!(saved.write_db_to_file(&missing), Err(Error::Persistence(_))));
+    }
 
-                    line.clear();
+    #[test]
+    fn test_ttl_inspection() {
+        let db = Db::new();
+
+        db.set("plain", DB_TYPE::Int(1));
+        db.set_with_ttl("volatile", DB_TYPE::Int(1), Duration::from_secs(100));
+        db.set_with_expire_at("expired", DB_TYPE::Int(1), 1);
+
+        assert_eq!(db.ttl("plain"), Ttl::Persistent);
+        assert_eq!(db.ttl("missing"), Ttl::Missing);
+        assert_eq!(db.ttl("expired"), Ttl::Missing);
+        match db.ttl("volatile") {
+            Ttl::Expires(d) => assert!(d > Duration::from_secs(99) && d <= Duration::from_secs(100)),
+            other => panic!("Expected Ttl::Expires, got {:?}", other),
+        }
+    }
 
-                    // save key with value
-                    let value: DB_TYPE = match t {
-                    // int
-                    'i' => {
-                        match buf_reader.read_line(&mut line) {
-                            Ok(_)  => { 
-                                DB_TYPE::Int(line.trim_end()[1..].parse::<i64>().expect("Not a valid integer"))
-                            },
-                            Err(e) => return Err(e.to_string()),
-                        }
-                    },
-                    // string
-                    's' => {
-                        // read bulk string value
-                        let bytes_to_read = match buf_reader.read_line(&mut line) {
-                            Ok(_)  => { 
-                                line.trim_end()[1..].parse::<usize>().expect("Not a valid integer")
-                            },
-                            Err(e) => return Err(e.to_string()),
-                        };
+    #[test]
+    fn test_scan_survives_growth() {
+        let db = Db::new();
+        for i in 0..100 {
+            db.set(&format!("old{i}"), DB_TYPE::Int(i));
+        }
 
-                        line.clear();
+        // keys added between steps grow the shards' maps, yet every key that
+        // was there from the start must still come back
+        let mut cursor = 0;
+        let mut seen = BTreeSet::new();
+        let mut added = 0;
+        loop {
+            let (next, keys) = db.scan(cursor, 7, |_, _| true);
+            seen.extend(keys);
+            for _ in 0..50 {
+                db.set(&format!("new{added}"), DB_TYPE::Int(added));
+                added += 1;
+            }
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
 
-                        // read bulk string
-                        let mut bulk = vec![0u8; bytes_to_read + 3]; 
-                        buf_reader.read_exact(&mut bulk).ok();
+        for i in 0..100 {
+            assert!(seen.contains(&format!("old{i}")));
+        }
+    }
 
-                        let s = match String::from_utf8(bulk[1..(bulk.len() - 2)].to_vec()) {
-                            Ok(s) => s,
-                            Err(e) => return Err(e.to_string()),
-                        };
+    #[test]
+    fn test_rename_and_copy_keep_expire() {
+        let db = Db::new();
+        let other = Db::new();
+        let expire = now_ms() + 60_000;
+        insert(&db, "a", DB_TYPE::Int(1), expire);
+        insert(&db, "gone", DB_TYPE::Int(1), 1);
+
+        assert_eq!(db.rename("gone", "b", false), Err(Error::Other("no such key".to_string())));
+        assert_eq!(db.rename("a", "b", false), Ok(true));
+        assert_eq!(entry(&db, "b"), Some((DB_TYPE::Int(1), expire)));
+        assert_eq!(entry(&db, "a"), None);
+
+        assert!(db.copy_to("b", &other, "c", false));
+        assert_eq!(entry(&other, "c"), Some((DB_TYPE::Int(1), expire)));
+        assert!(!db.copy_to("b", &other, "c", false));
+        assert!(db.copy_to("b", &db, "c", false));
+        assert!(!db.copy_to("gone", &db, "d", true));
+    }
 
-                        DB_TYPE::Str(s)
-                    },
-                    _ => return Err("Invalid char encountered for object type".to_string()),
-                    };
+    #[test]
+    fn test_used_memory_follows_changes() {
+        let db = Db::new();
+        assert_eq!(db.used_memory(), 0);
 
-                    objects.push(value);
-                }
+        db.set("k", DB_TYPE::Str(b"x".repeat(1000)));
+        let one = db.used_memory();
+        assert!(one > 1000);
 
-                line.clear();
+        db.set_with_ttl("k", DB_TYPE::Str(b"x".repeat(10)), Duration::from_secs(100));
+        assert!(db.used_memory() < one);
 
-                DB_TYPE::Array(objects)
-            },
-            _ => return Err("Invalid char encountered for object type".to_string()),
-        };
+        db.rpush("list", vec![DB_TYPE::Str(b"y".repeat(500))]).unwrap();
+        db.rpush("list", vec![DB_TYPE::Str(b"y".repeat(500))]).unwrap();
+        assert_eq!(db.used_memory(), db.memory_usage("k", 0).unwrap() + DEADLINE_OVERHEAD + 1 + db.memory_usage("list", 0).unwrap());
+
+        db.delete(&["k".to_string(), "list".to_string()]);
+        assert_eq!(db.used_memory(), 0);
+
+        // big hashes are sampled, as MEMORY USAGE does by default
+        for i in 0..100 {
+            db.hset("hash", &format!("f{i}"), "v").unwrap();
+        }
+        assert_eq!(db.used_memory(), db.memory_usage("hash", MEMORY_SAMPLES).unwrap());
+        db.delete(&["hash".to_string()]);
+        assert_eq!(db.used_memory(), 0);
+
+        // custom values report their own size
+        db.update_custom("c", || Counter(0), |c| c.0 += 1).unwrap();
+        assert_eq!(db.used_memory(), key_size("c") + std::mem::size_of::<Counter>());
+        db.delete(&["c".to_string()]);
+
+        // databases sharing types count together, until one is dropped
+        let other = db.sharing_types();
+        other.set("k", DB_TYPE::Int(1));
+        assert_eq!(db.used_memory(), other.memory_usage("k", 0).unwrap());
+        db.swap(&other);
+        db.flush();
+        assert_eq!(other.used_memory(), 0);
+        other.set("k", DB_TYPE::Int(1));
+        drop(other);
+        assert_eq!(db.used_memory(), 0);
+    }
+
+    #[test]
+    fn test_iter_skips_expired_keys() {
+        let db = Db::new();
 
-        // save expire
-        if exp > 0 {
-            e_db.insert(key.clone(), exp);
+        for i in 0..50 {
+            db.set(&format!("key_{i}"), DB_TYPE::Int(i));
         }
-        db.insert(key.clone(), value);
+        db.set_with_expire_at("expired", DB_TYPE::Int(1), 1);
 
+        let mut keys: Vec<String> = db.iter().map(|(k, _)| k).collect();
+        keys.sort();
 
-        buf_reader.read_line(&mut line).ok();
+        assert_eq!(keys.len(), 50);
+        assert!(!keys.contains(&"expired".to_string()));
+        assert_eq!(db.len(), 51);
     }
 
-    Ok("OK".to_string())
-}
+    #[test]
+    fn test_integer_text_is_stored_one_way() {
+        let db = Db::new();
+        db.set("n", DB_TYPE::Str(b"10".to_vec()));
+        assert_eq!(db.get("n"), Some(DB_TYPE::Int(10)));
+        assert_eq!(db.key_info("n").map(|i| i.encoding), Some("int"));
+
+        // however a list element came in, equal text matches it
+        db.rpush("l", vec![DB_TYPE::Str(b"1".to_vec()), DB_TYPE::Int(1), DB_TYPE::Str(b"01".to_vec())]).unwrap();
+        assert_eq!(db.lpos("l", &DB_TYPE::Int(1), 1, 0, 0), Ok(vec![0, 1]));
+        assert_eq!(db.linsert("l", false, &DB_TYPE::Str(b"01".to_vec()), DB_TYPE::Str(b"2".to_vec())), Ok(4));
+        assert_eq!(db.lrem("l", 0, &DB_TYPE::Str(b"1".to_vec())), Ok(2));
+        assert_eq!(db.lrange("l", 0, -1), Ok(vec![DB_TYPE::Str(b"01".to_vec()), DB_TYPE::Int(2)]));
+
+        // and so is what a snapshot holds
+        let path = temp_path("canonical");
+        let file = "--------------------------------------------------------\r\nKEYS-VALUES\r\n\
+            --------------------------------------------------------\r\nFD 0\r\n$s\r\n$n\r\n$2\r\n$10\r\n\
+            --------------------------------------------------------\r\nEOF\r\n";
+        std::fs::write(&path, file).unwrap();
+        let loaded = Db::new();
+        assert_eq!(loaded.read_db_from_file(&path), Ok(()));
+        assert_eq!(loaded.get("n"), Some(DB_TYPE::Int(10)));
+    }
+
+    #[test]
+    fn test_swap_keeps_stats() {
+        let db = Db::new();
+        db.set_with_expire_at("gone", DB_TYPE::Int(1), 1);
+        assert_eq!(db.get("gone"), None);
+        assert_eq!(db.expired_keys(), 1);
+
+        let other = db.sharing_types();
+        other.set("k", DB_TYPE::Int(1));
+        db.swap(&other);
+        assert_eq!(db.get("k"), Some(DB_TYPE::Int(1)));
+        assert_eq!((db.expired_keys(), other.expired_keys()), (1, 0));
+
+        // used memory follows the keys, also to a database counted apart
+        let apart = Db::new();
+        apart.swap(&db);
+        assert_eq!(apart.used_memory(), apart.memory_usage("k", 0).unwrap());
+        assert_eq!(db.used_memory(), 0);
+        assert_eq!(db.expired_keys(), 1);
+    }
 
+    #[test]
+    fn test_random_key_skips_expired_keys() {
+        let db = Db::new();
+        assert_eq!(db.random_key(), None);
+
+        for i in 0..20 {
+            db.set_with_expire_at(&format!("expired_{i}"), DB_TYPE::Int(i), 1);
+        }
+        db.set("live", DB_TYPE::Int(1));
+        assert_eq!(db.random_key(), Some("live".to_string()));
+
+        // every key comes up sooner or later
+        let mut seen = BTreeSet::new();
+        for i in 0..10 {
+            db.set(&format!("key_{i}"), DB_TYPE::Int(i));
+        }
+        for _ in 0..1000 {
+            seen.insert(db.random_key().unwrap());
+        }
+        assert_eq!(seen.len(), 11);
+    }
+
+    #[test]
+    fn test_shard_sample() {
+        let mut shard = Shard::new(Arc::new(AtomicUsize::new(0)));
+        assert!(shard.sample(5, false).is_empty());
+
+        for i in 0..10 {
+            let expire = if i < 3 { 1000 + i as u128 } else { 0 };
+            shard.insert(format!("k{i}"), Entry::new(DB_TYPE::Int(i), expire));
+        }
+
+        // distinct keys, never more than there are, and only volatile ones if asked
+        for _ in 0..100 {
+            let keys: BTreeSet<&String> = shard.sample(5, false).into_iter().map(|(k, _)| k).collect();
+            assert_eq!(keys.len(), 5);
+            assert_eq!(shard.sample(20, false).len(), 10);
+
+            let volatile = shard.sample(5, true);
+            assert_eq!(volatile.len(), 3);
+            assert!(volatile.iter().all(|(_, e)| e.expire > 0));
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use std::fs::OpenOptions;
-    
     #[test]
     fn test_write_db_to_file_basic() {
-        {
-            let mut db = REDIS_DB.lock().unwrap();
-            let mut exp = EXPIRE_DB.lock().unwrap();
-            db.clear();
-            exp.clear();
-
-            db.insert("intkey".to_string(), DB_TYPE::Int(42));
-            db.insert("strkey".to_string(), DB_TYPE::Str("hello".to_string()));
-            db.insert(
-                "arrkey".to_string(),
-                DB_TYPE::Array(vec![
-                    DB_TYPE::Int(1),
-                    DB_TYPE::Str("hi".to_string()),
-                ]),
-            );
-
-            exp.insert("intkey".to_string(), 100);
-            exp.insert("strkey".to_string(), 200);
-            // no expire on arrkey
-        }
-
-        let result = write_db_to_file();
+        let db = Db::new();
+        insert(&db, "intkey", DB_TYPE::Int(42), 100);
+        insert(&db, "strkey", DB_TYPE::Str(b"hello".to_vec()), 200);
+        // no expire on arrkey
+        insert(
+            &db,
+            "arrkey",
+            DB_TYPE::Array(vec![
+                DB_TYPE::Int(1),
+                DB_TYPE::Str(b"hi".to_vec()),
+            ].into()),
+            0,
+        );
+
+        let path = temp_path("write_basic");
+        let result = db.write_db_to_file(&path);
         println!("{:?}", result);
         assert!(result.is_ok());
 
         // read the file and check contents
-        let contents = fs::read_to_string("./REDIS.rdb").expect("Failed to read RDB file");
+        let contents = fs::read_to_string(&path).expect("Failed to read RDB file");
         assert!(contents.contains("REDIS"));
         assert!(contents.contains("KEYS-VALUES"));
         assert!(contents.contains("FD 100"));
@@ -507,31 +2605,98 @@ mod tests {
         assert!(contents.contains("hello"));
     }
 
-    #[test]
-    fn test_write_db_to_file_with_nested_array_should_fail() {
-        {
-            let mut db = REDIS_DB.lock().unwrap();
-            let mut exp = EXPIRE_DB.lock().unwrap();
-            db.clear();
-            exp.clear();
+    #[derive(Debug, Clone, PartialEq)]
+    struct Point(i32, i32);
+
+    impl CustomType for Point {
+        fn type_name(&self) -> &str {
+            "point"
+        }
+
+        fn save(&self) -> Option<Vec<u8>> {
+            Some(format!("{},{}", self.0, self.1).into_bytes())
+        }
+    }
+
+    fn load_point(data: &[u8]) -> Option<Box<dyn CustomValue>> {
+        let s = std::str::from_utf8(data).ok()?;
+        let (x, y) = s.split_once(',')?;
+        Some(Box::new(Point(x.parse().ok()?, y.parse().ok()?)))
+    }
 
-            db.insert(
-                "bad".to_string(),
-                DB_TYPE::Array(vec![
-                    DB_TYPE::Array(vec![DB_TYPE::Int(1)])
-                ]),
-            );
+    #[test]
+    fn test_write_then_read_round_trip() {
+        let db = Db::new();
+        db.register_type("point", Arc::new(load_point)).unwrap();
+        insert(&db, "int", DB_TYPE::Int(-7), 0);
+        insert(&db, "str", DB_TYPE::Str(b"two words".to_vec()), 12345);
+        insert(&db, "bytes", DB_TYPE::Str(b"\xff\r\n\x00".to_vec()), 0);
+        insert(&db, "list", DB_TYPE::Array(vec![DB_TYPE::Int(1), DB_TYPE::Str(b"\x80".to_vec())].into()), 0);
+        insert(&db, "hash", DB_TYPE::Hash(HashMap::from([("f".to_string(), "v".to_string())])), 0);
+        insert(&db, "point", DB_TYPE::custom(Point(3, -4)), 0);
+
+        let path = temp_path("round_trip");
+        assert_eq!(db.write_db_to_file(&path), Ok(()));
+
+        let loaded = Db::new();
+        assert!(loaded.read_db_from_file(&path).is_err());
+
+        loaded.register_type("point", Arc::new(load_point)).unwrap();
+        assert_eq!(loaded.read_db_from_file(&path), Ok(()));
+        for k in ["int", "str", "bytes", "list", "hash", "point"] {
+            assert_eq!(entry(&loaded, k), entry(&db, k));
         }
 
-        let result = write_db_to_file();
+        // a type name can only be registered once, until it is unregistered
+        assert!(loaded.register_type("point", Arc::new(load_point)).is_err());
+        assert!(loaded.unregister_type("point"));
+        assert!(!loaded.unregister_type("point"));
+        assert_eq!(loaded.register_type("point", Arc::new(load_point)), Ok(()));
+    }
+
+    #[test]
+    fn test_snapshot_keeps_function_libraries() {
+        let db = Db::new();
+        insert(&db, "k", DB_TYPE::Int(1), 0);
+        let libraries = vec![
+            "#!lua name=one\nredis.register_function('f', function() return 1 end)".to_string(),
+            "#!lua name=two\r\n\r\nKEYS-VALUES\r\n".to_string(),
+        ];
+
+        let path = temp_path("functions");
+        assert_eq!(Db::write_snapshot(std::slice::from_ref(&db), &path, &libraries), Ok(()));
+
+        let loaded = Db::new();
+        assert_eq!(Db::read_snapshot(std::slice::from_ref(&loaded), &path), Ok(libraries));
+        assert_eq!(entry(&loaded, "k"), entry(&db, "k"));
+
+        // snapshots without libraries still load
+        assert_eq!(db.write_db_to_file(&path), Ok(()));
+        assert_eq!(Db::read_snapshot(std::slice::from_ref(&loaded), &path), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_write_db_to_file_with_nested_array_should_fail() {
+        let db = Db::new();
+        insert(
+            &db,
+            "bad",
+            DB_TYPE::Array(vec![
+                DB_TYPE::Array(vec![DB_TYPE::Int(1)].into())
+            ].into()),
+            0,
+        );
+
+        let result = db.write_db_to_file(&temp_path("write_nested"));
         assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "nested arrays are not supported".to_string());
+        assert_eq!(result.unwrap_err(), Error::Persistence("nested arrays are not supported".to_string()));
     }
 
     #[tokio::test]
     async fn test_read_db_from_file() {
-        // Prepare a dummy REDIS.rdb file with minimal valid content
-        let test_file_path = "REDIS.rdb";
+        let db = Db::new();
+        // Prepare a dummy rdb file with minimal valid content
+        let test_file_path = &temp_path("read");
 
         // Create or overwrite the file with a minimal valid DB content
         let mut file = BufWriter::new(
@@ -578,29 +2743,14 @@ EOF";
         file.flush().expect("Flush failed");
 
         // Call the function to read from file
-        let result = read_db_from_file(test_file_path);
+        let result = db.read_db_from_file(test_file_path);
 
         // Assert it returns OK
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), "OK".to_string());
+        assert_eq!(result, Ok(()));
 
         // Check that the key exists in the DB with expected value
-        let db_lock = REDIS_DB.lock().unwrap();
-        let expire_lock = EXPIRE_DB.lock().unwrap();
-
-        assert!(db_lock.contains_key("intkey"));
-        assert!(db_lock.contains_key("strkey"));
-        assert!(db_lock.contains_key("arrkey"));
-
-        assert!(expire_lock.contains_key("intkey"));
-        assert!(expire_lock.contains_key("strkey"));
-        assert!(!expire_lock.contains_key("arrkey"));
-
-        assert_eq!(expire_lock.get("intkey").unwrap(), &100_u128);
-        assert_eq!(expire_lock.get("strkey").unwrap(), &200_u128);
-
-        assert_eq!(db_lock.get("intkey").unwrap(), &DB_TYPE::Int(42));
-        assert_eq!(db_lock.get("strkey").unwrap(), &DB_TYPE::Str("hello".to_string()));
-        assert_eq!(db_lock.get("arrkey").unwrap(), &DB_TYPE::Array(vec![DB_TYPE::Int(1), DB_TYPE::Str("hi".to_string())]));
+        assert_eq!(entry(&db, "intkey"), Some((DB_TYPE::Int(42), 100)));
+        assert_eq!(entry(&db, "strkey"), Some((DB_TYPE::Str(b"hello".to_vec()), 200)));
+        assert_eq!(entry(&db, "arrkey"), Some((DB_TYPE::Array(vec![DB_TYPE::Int(1), DB_TYPE::Str(b"hi".to_vec())].into()), 0)));
     }
 }
\ No newline at end of file
@@ -0,0 +1,94 @@
+use std::fmt;
+
+// every error a command can reply with; Display gives the exact text sent
+// on the wire, starting with the Redis error prefix
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    UnknownCommand(String),
+    WrongArity(String),
+    Syntax,
+    Protocol(String),
+    WrongType,
+    NotAnInteger,
+    NoAuth,
+    NoPerm(String),
+    ExecAbort,
+    Loading,
+    Oom,
+    Busy,
+    NoScript,
+    NotBusy,
+    Unkillable,
+    BusyKey,
+    Persistence(String),
+    Other(String),
+}
+
+impl Error {
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Error::WrongType => "WRONGTYPE",
+            Error::NoAuth => "NOAUTH",
+            Error::NoPerm(_) => "NOPERM",
+            Error::ExecAbort => "EXECABORT",
+            Error::Loading => "LOADING",
+            Error::Oom => "OOM",
+            Error::Busy => "BUSY",
+            Error::NoScript => "NOSCRIPT",
+            Error::NotBusy => "NOTBUSY",
+            Error::Unkillable => "UNKILLABLE",
+            Error::BusyKey => "BUSYKEY",
+            _ => "ERR",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Error::UnknownCommand(c) => format!("unknown command '{c}'"),
+            Error::WrongArity(c) => format!("wrong number of arguments for '{}' command", c.to_lowercase()),
+            Error::Syntax => "syntax error".to_string(),
+            Error::Protocol(e) => format!("Protocol error: {e}"),
+            Error::WrongType => "Operation against a key holding the wrong kind of value".to_string(),
+            Error::NotAnInteger => "value is not an integer or out of range".to_string(),
+            Error::NoAuth => "Authentication required.".to_string(),
+            Error::NoPerm(c) => format!("this user has no permissions to run the '{}' command", c.to_lowercase()),
+            Error::ExecAbort => "Transaction discarded because of previous errors.".to_string(),
+            Error::Loading => "Redis is loading the dataset in memory".to_string(),
+            Error::Oom => "command not allowed when used memory > 'maxmemory'.".to_string(),
+            Error::Busy => "Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE.".to_string(),
+            Error::NoScript => "No matching script. Please use EVAL.".to_string(),
+            Error::NotBusy => "No scripts in execution right now.".to_string(),
+            Error::Unkillable => "Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.".to_string(),
+            Error::BusyKey => "Target key name already exists.".to_string(),
+            Error::Persistence(e) => e.clone(),
+            Error::Other(e) => e.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.prefix(), self.message())
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_prefixes() {
+        assert_eq!(Error::WrongType.to_string(), "WRONGTYPE Operation against a key holding the wrong kind of value");
+        assert_eq!(Error::Oom.prefix(), "OOM");
+        assert_eq!(Error::NoAuth.prefix(), "NOAUTH");
+        assert_eq!(Error::Syntax.to_string(), "ERR syntax error");
+    }
+
+    #[test]
+    fn test_standard_messages() {
+        assert_eq!(Error::UnknownCommand("FOO".to_string()).to_string(), "ERR unknown command 'FOO'");
+        assert_eq!(Error::WrongArity("GET".to_string()).to_string(), "ERR wrong number of arguments for 'get' command");
+    }
+}
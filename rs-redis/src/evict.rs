@@ -0,0 +1,122 @@
+// keeps the keyspace under maxmemory by evicting keys as the configured
+// policy picks them, approximated by sampling a few keys at a time
+
+use crate::config::MaxmemoryPolicy;
+use crate::server::Server;
+
+// evicts until used memory is at most maxmemory, false if that is not possible
+// because the policy is noeviction or has nothing left to evict
+pub fn free_memory(server: &Server) -> bool {
+    let (maxmemory, policy, samples) = {
+        let config = server.config();
+        (config.maxmemory, config.maxmemory_policy, config.maxmemory_samples)
+    };
+    if maxmemory == 0 {
+        return true;
+    }
+
+    let mut used = server.used_memory();
+    while used > maxmemory {
+        if policy == MaxmemoryPolicy::NoEviction {
+            return false;
+        }
+
+        // the best of each database's sample goes
+        let mut best: Option<(u64, usize, String)> = None;
+        for (i, db) in server.databases().iter().enumerate() {
+            if let Some((score, k)) = db.eviction_candidate(policy, samples)
+                && best.as_ref().is_none_or(|(b, _, _)| score > *b)
+            {
+                best = Some((score, i, k));
+            }
+        }
+
+        match best {
+            Some((_, i, k)) => used = used.saturating_sub(server.databases()[i].evict(&k)),
+            None => return false,
+        }
+    }
+
+    true
+}
+
+// true while used memory is over a configured maxmemory
+pub fn over_limit(server: &Server) -> bool {
+    let maxmemory = server.config().maxmemory;
+    maxmemory > 0 && server.used_memory() > maxmemory
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::types::DB_TYPE;
+    use std::time::Duration;
+
+    fn server(policy: MaxmemoryPolicy) -> Server {
+        Server::new(Config {
+            maxmemory: 20_000,
+            maxmemory_policy: policy,
+            ..Config::default()
+        })
+    }
+
+    fn fill(server: &Server, n: usize, ttl: bool) {
+        for i in 0..n {
+            let value = DB_TYPE::Str(b"x".repeat(100));
+            if ttl {
+                server.databases()[1].set_with_ttl(&format!("t{i}"), value, Duration::from_secs(100 + i as u64));
+            }
+            else {
+                server.db().set(&format!("k{i}"), value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_noeviction_refuses() {
+        let server = server(MaxmemoryPolicy::NoEviction);
+        fill(&server, 200, false);
+        assert!(over_limit(&server));
+        assert!(!free_memory(&server));
+        assert_eq!(server.db().len(), 200);
+    }
+
+    #[test]
+    fn test_allkeys_policies_evict_until_under_limit() {
+        for policy in [MaxmemoryPolicy::AllkeysLru, MaxmemoryPolicy::AllkeysLfu, MaxmemoryPolicy::AllkeysRandom] {
+            let server = server(policy);
+            fill(&server, 200, false);
+            assert!(free_memory(&server));
+            assert!(server.used_memory() <= 20_000);
+            assert!(server.db().evicted_keys() > 0);
+        }
+    }
+
+    #[test]
+    fn test_volatile_policies_only_evict_keys_with_an_expire() {
+        for policy in [MaxmemoryPolicy::VolatileLru, MaxmemoryPolicy::VolatileLfu, MaxmemoryPolicy::VolatileRandom, MaxmemoryPolicy::VolatileTtl] {
+            let server = server(policy);
+            fill(&server, 150, false);
+            fill(&server, 200, true);
+
+            // the plain keys alone are over the limit, so all volatile ones go
+            assert!(!free_memory(&server));
+            assert_eq!(server.databases()[1].len(), 0);
+            assert_eq!(server.db().len(), 150);
+        }
+    }
+
+    #[test]
+    fn test_volatile_ttl_prefers_the_nearest_expire() {
+        let server = server(MaxmemoryPolicy::VolatileTtl);
+        fill(&server, 200, true);
+        assert!(free_memory(&server));
+
+        // sampling is approximate, but keys expiring late survive far more often
+        let survivors = |range: std::ops::Range<usize>| {
+            range.filter(|i| server.databases()[1].get(&format!("t{i}")).is_some()).count()
+        };
+        assert!(survivors(150..200) > survivors(0..50));
+    }
+}
@@ -0,0 +1,111 @@
+// active expiry: a background task that removes expired keys nobody reads,
+// which lazy expiry on access would otherwise keep forever
+
+use crate::server::Server;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+// share of each period a cycle may spend, in percent
+const CYCLE_BUDGET: u32 = 25;
+
+// reported by INFO stats
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ExpireStats {
+    pub cycles: u64,
+    // running estimate of how many of the sampled keys were already expired, in percent
+    pub stale_perc: f64,
+    // cycles that stopped because they ran out of time
+    pub time_cap_reached: u64,
+    pub cpu_time: Duration,
+    // the database the next cycle starts with
+    next_db: usize,
+}
+
+// runs one cycle over every database, at most CYCLE_BUDGET percent of a period
+pub fn run_cycle(server: &Server) {
+    let started = Instant::now();
+    let until = started + period(server) * CYCLE_BUDGET / 100;
+
+    let dbs = server.databases();
+    let start = server.expire_stats().next_db;
+    let (mut sampled, mut expired, mut stopped_at) = (0, 0, None);
+
+    for n in 0..dbs.len() {
+        let i = (start + n) % dbs.len();
+        let cycle = dbs[i].expire_cycle(until);
+        sampled += cycle.sampled;
+        expired += cycle.expired;
+
+        // a database cut short goes first next time
+        if cycle.timed_out {
+            stopped_at = Some(i);
+            break;
+        }
+    }
+
+    let mut stats = server.expire_stats();
+    stats.cycles += 1;
+    stats.cpu_time += started.elapsed();
+    if sampled > 0 {
+        let perc = expired as f64 * 100.0 / sampled as f64;
+        stats.stale_perc = perc * 0.05 + stats.stale_perc * 0.95;
+    }
+    match stopped_at {
+        Some(i) => {
+            stats.time_cap_reached += 1;
+            stats.next_db = i;
+        },
+        None => stats.next_db = 0,
+    }
+}
+
+// runs a cycle every period until shutdown is signalled
+pub(crate) async fn run(server: Arc<Server>, mut shutdown: watch::Receiver<bool>) {
+    let mut ticks = tokio::time::interval(period(&server));
+
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {},
+            _ = shutdown.changed() => return,
+        }
+
+        // the cycle takes shard locks, keep it off the async workers
+        let server = server.clone();
+        if tokio::task::spawn_blocking(move || run_cycle(&server)).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn period(server: &Server) -> Duration {
+    Duration::from_secs(1) / server.config().hz.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::types::DB_TYPE;
+
+    #[test]
+    fn test_cycle_removes_keys_nobody_reads() {
+        let server = Server::new(Config::default());
+        for i in 0..200 {
+            server.databases()[3].set_with_expire_at(&format!("k{i}"), DB_TYPE::Int(i), 1);
+        }
+        server.databases()[3].set_with_ttl("later", DB_TYPE::Int(1), Duration::from_secs(100));
+        server.databases()[3].set("plain", DB_TYPE::Int(1));
+        assert_eq!(server.databases()[3].expires(), 201);
+
+        // all expired keys are found, adapting to how many there are
+        run_cycle(&server);
+        assert_eq!(server.databases()[3].expired_keys(), 200);
+        assert_eq!(server.databases()[3].expires(), 1);
+        assert_eq!(server.databases()[3].len(), 2);
+
+        let stats = server.expire_stats().clone();
+        assert_eq!(stats.cycles, 1);
+        assert!(stats.stale_perc > 0.0);
+    }
+}
@@ -0,0 +1,96 @@
+// glob-style patterns as used by KEYS, SCAN MATCH and FUNCTION LIST
+//
+// *        any run of bytes, also an empty one
+// ?        exactly one byte
+// [abc]    one of the listed bytes, [^abc] one that is not listed, [a-z] a range
+// \x       x itself, so \* matches a literal *
+
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => string.is_empty(),
+        Some((b'*', rest)) => {
+            // collapse runs of stars, then try every split point
+            let rest = trim_stars(rest);
+            if rest.is_empty() {
+                return true;
+            }
+            (0..=string.len()).any(|i| glob_match(rest, &string[i..]))
+        },
+        Some((b'?', rest)) => !string.is_empty() && glob_match(rest, &string[1..]),
+        Some((b'[', rest)) => match string.split_first() {
+            Some((&c, tail)) => {
+                let (matched, rest) = match_class(rest, c);
+                matched && glob_match(rest, tail)
+            },
+            None => false,
+        },
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            string.first() == Some(&rest[0]) && glob_match(&rest[1..], &string[1..])
+        },
+        Some((&p, rest)) => string.first() == Some(&p) && glob_match(rest, &string[1..]),
+    }
+}
+
+fn trim_stars(mut pattern: &[u8]) -> &[u8] {
+    while let Some((b'*', rest)) = pattern.split_first() {
+        pattern = rest;
+    }
+    pattern
+}
+
+// matches c against the class after '[', returns the pattern after the closing ']'
+fn match_class(mut pattern: &[u8], c: u8) -> (bool, &[u8]) {
+    let negate = pattern.first() == Some(&b'^');
+    if negate {
+        pattern = &pattern[1..];
+    }
+
+    let mut matched = false;
+    loop {
+        match pattern {
+            // an unterminated class ends with the pattern
+            [] => break,
+            [b']', rest @ ..] => {
+                pattern = rest;
+                break;
+            },
+            [b'\\', x, rest @ ..] => {
+                matched |= *x == c;
+                pattern = rest;
+            },
+            [lo, b'-', hi, rest @ ..] if *hi != b']' => {
+                let (lo, hi) = if lo <= hi { (*lo, *hi) } else { (*hi, *lo) };
+                matched |= (lo..=hi).contains(&c);
+                pattern = rest;
+            },
+            [x, rest @ ..] => {
+                matched |= *x == c;
+                pattern = rest;
+            },
+        }
+    }
+
+    (matched != negate, pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"h*llo", b"heeello"));
+        assert!(glob_match(b"h?llo", b"hallo"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"h[ae]llo", b"hello"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo"));
+        assert!(glob_match(b"h[b-a]llo", b"hallo"));
+        assert!(glob_match(b"h\\*llo", b"h*llo"));
+        assert!(!glob_match(b"h\\*llo", b"hello"));
+        assert!(glob_match(b"user:**:name", b"user:42:name"));
+        assert!(!glob_match(b"user:*", b"users"));
+        assert!(!glob_match(b"abc", b"abcd"));
+    }
+}
@@ -1,6 +1,16 @@
 pub mod parser;
-mod types;
-mod db;
+pub mod types;
+pub mod error;
+pub mod db;
+pub mod expire;
+pub mod evict;
+pub mod config;
+pub mod server;
 pub mod command;
+pub mod module;
+pub mod scripting;
+pub mod blocking;
+pub mod glob;
+pub mod bitops;
 pub mod cli;
 pub mod network;
\ No newline at end of file
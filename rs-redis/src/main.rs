@@ -1,6 +1,18 @@
 use rs_redis::{network};
+use rs_redis::config::Config;
+use rs_redis::server::Server;
+use std::sync::Arc;
 
 #[tokio::main]
 async fn main() {
-    network::start_network().await.ok();
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        },
+    };
+
+    let server = Arc::new(Server::new(config));
+    network::start_network(server).await.ok();
 }
\ No newline at end of file
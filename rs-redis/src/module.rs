@@ -0,0 +1,601 @@
+// dynamically loaded command modules
+//
+// a module is a cdylib that exports
+//
+//     extern "C" fn rs_redis_module_onload(api: *const ModuleApi, ctx: *mut LoadContext,
+//                                          argv: *const ModuleString, argc: c_int) -> c_int
+//
+// onload calls api.init first, then registers its commands and data types
+// through the api table and returns MODULE_OK. an optional
+// rs_redis_module_onunload(ctx) -> c_int can refuse MODULE UNLOAD by returning
+// MODULE_ERR. the repr(C) items below are the whole ABI, modules only mirror them
+
+use crate::command::{bulk_to_string, Command, CommandFlags, Context};
+use crate::db::{Db, Loader};
+use crate::error::Error;
+use crate::server::Server;
+use crate::types::{CustomValue, RESPResult, DB_TYPE};
+use libloading::Library;
+use std::any::Any;
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::ptr;
+use std::sync::Arc;
+
+pub const MODULE_OK: c_int = 0;
+pub const MODULE_ERR: c_int = 1;
+pub const MODULE_API_VERSION: c_int = 1;
+
+const ONLOAD: &[u8] = b"rs_redis_module_onload";
+const ONUNLOAD: &[u8] = b"rs_redis_module_onunload";
+
+const LOAD_ERROR: &str = "Error loading the extension. Please check the server logs.";
+
+#[repr(C)]
+pub struct ModuleString {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+// argv[0] is the command name
+pub type CommandFunc = extern "C" fn(call: *mut CallContext<'_>, argv: *const ModuleString, argc: c_int) -> c_int;
+
+// slot points at the stored value, or at null if the key is missing; storing a
+// new pointer replaces the value (the module frees the old one), null deletes it
+pub type UpdateFunc = extern "C" fn(slot: *mut *mut c_void, privdata: *mut c_void) -> c_int;
+
+type OnLoad = unsafe extern "C" fn(*const ModuleApi, *mut LoadContext, *const ModuleString, c_int) -> c_int;
+type OnUnload = unsafe extern "C" fn(*mut LoadContext) -> c_int;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TypeMethods {
+    pub free: extern "C" fn(value: *mut c_void),
+    pub copy: extern "C" fn(value: *const c_void) -> *mut c_void,
+    // writes the encoded value with api.save_bytes
+    pub save: extern "C" fn(value: *const c_void, out: *mut SaveBuffer),
+    // returns null if data is not a valid encoding
+    pub load: extern "C" fn(data: *const u8, len: usize) -> *mut c_void,
+}
+
+#[repr(C)]
+pub struct ModuleApi {
+    pub version: c_int,
+    pub init: extern "C" fn(ctx: *mut LoadContext, name: *const c_char, version: c_int) -> c_int,
+    pub create_command: extern "C" fn(
+        ctx: *mut LoadContext,
+        name: *const c_char,
+        handler: CommandFunc,
+        arity: c_int,
+        flags: *const c_char,
+        first_key: c_int,
+        last_key: c_int,
+        step: c_int,
+    ) -> c_int,
+    pub create_data_type: extern "C" fn(ctx: *mut LoadContext, name: *const c_char, methods: *const TypeMethods) -> c_int,
+    pub reply_simple_string: extern "C" fn(call: *mut CallContext<'_>, s: *const c_char) -> c_int,
+    // s is the whole error line, e.g. "ERR something went wrong"
+    pub reply_error: extern "C" fn(call: *mut CallContext<'_>, s: *const c_char) -> c_int,
+    pub reply_integer: extern "C" fn(call: *mut CallContext<'_>, i: i64) -> c_int,
+    pub reply_bulk: extern "C" fn(call: *mut CallContext<'_>, ptr: *const u8, len: usize) -> c_int,
+    pub reply_null: extern "C" fn(call: *mut CallContext<'_>) -> c_int,
+    // runs f on the key's value under its lock, MODULE_ERR if it holds another
+    // type; ty names one of the calling module's data types
+    pub update_value: extern "C" fn(
+        call: *mut CallContext<'_>,
+        key: *const u8,
+        keylen: usize,
+        ty: *const c_char,
+        f: UpdateFunc,
+        privdata: *mut c_void,
+    ) -> c_int,
+    pub save_bytes: extern "C" fn(out: *mut SaveBuffer, ptr: *const u8, len: usize),
+}
+
+static API: ModuleApi = ModuleApi {
+    version: MODULE_API_VERSION,
+    init: api_init,
+    create_command: api_create_command,
+    create_data_type: api_create_data_type,
+    reply_simple_string: api_reply_simple_string,
+    reply_error: api_reply_error,
+    reply_integer: api_reply_integer,
+    reply_bulk: api_reply_bulk,
+    reply_null: api_reply_null,
+    update_value: api_update_value,
+    save_bytes: api_save_bytes,
+};
+
+// collects what a module registers while its onload runs
+pub struct LoadContext {
+    lib: Arc<Library>,
+    name: Option<String>,
+    version: c_int,
+    commands: Vec<ModuleCommand>,
+    types: Vec<Arc<ModuleType>>,
+}
+
+// what a module command can reach while it runs
+pub struct CallContext<'a> {
+    db: &'a Db,
+    types: &'a [Arc<ModuleType>],
+    reply: Option<RESPResult>,
+}
+
+pub struct SaveBuffer(Vec<u8>);
+
+pub struct ModuleType {
+    name: String,
+    methods: TypeMethods,
+    _lib: Arc<Library>,
+}
+
+// a value of a module data type, owned by the keyspace
+struct ModuleValue {
+    ty: Arc<ModuleType>,
+    ptr: *mut c_void,
+}
+
+// the module promises its values can be used from any thread
+unsafe impl Send for ModuleValue {}
+unsafe impl Sync for ModuleValue {}
+
+impl ModuleValue {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = SaveBuffer(Vec::new());
+        (self.ty.methods.save)(self.ptr, &mut out);
+        out.0
+    }
+}
+
+impl std::fmt::Debug for ModuleValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ModuleValue({})", self.ty.name)
+    }
+}
+
+impl Drop for ModuleValue {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            (self.ty.methods.free)(self.ptr);
+        }
+    }
+}
+
+impl CustomValue for ModuleValue {
+    fn type_name(&self) -> &str {
+        &self.ty.name
+    }
+
+    fn save(&self) -> Option<Vec<u8>> {
+        Some(self.encode())
+    }
+
+    // the module api has no way to ask a value's size, and encoding it after
+    // every change would cost as much as the value is big
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<ModuleValue>()
+    }
+
+    fn clone_value(&self) -> Box<dyn CustomValue> {
+        Box::new(ModuleValue {
+            ty: self.ty.clone(),
+            ptr: (self.ty.methods.copy)(self.ptr),
+        })
+    }
+
+    fn eq_value(&self, other: &dyn CustomValue) -> bool {
+        match other.as_any().downcast_ref::<ModuleValue>() {
+            Some(other) => Arc::ptr_eq(&self.ty, &other.ty) && self.encode() == other.encode(),
+            None => false,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+struct ModuleCommand {
+    name: String,
+    arity: i32,
+    flags: CommandFlags,
+    keys: (i32, i32, i32),
+    handler: CommandFunc,
+    types: Vec<Arc<ModuleType>>,
+    // keeps the code alive while a command is still running after MODULE UNLOAD
+    _lib: Arc<Library>,
+}
+
+impl Command for ModuleCommand {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn arity(&self) -> i32 {
+        self.arity
+    }
+
+    fn flags(&self) -> CommandFlags {
+        self.flags
+    }
+
+    fn key_range(&self) -> (i32, i32, i32) {
+        self.keys
+    }
+
+    fn execute(&self, ctx: &mut Context<'_>, args: &[RESPResult]) -> Result<RESPResult, Error> {
+        let mut argv = vec![ModuleString { ptr: self.name.as_ptr(), len: self.name.len() }];
+        for arg in args {
+            match arg {
+                RESPResult::BulkString(Some(b)) => argv.push(ModuleString { ptr: b.as_ptr(), len: b.len() }),
+                _ => return Err(Error::Protocol("expected bulk string".to_string())),
+            }
+        }
+
+        let mut call = CallContext { db: ctx.db(), types: &self.types, reply: None };
+        let status = (self.handler)(&mut call, argv.as_ptr(), argv.len() as c_int);
+
+        match call.reply {
+            Some(reply) => Ok(reply),
+            None if status == MODULE_OK => Ok(RESPResult::SimpleString("OK".to_string())),
+            None => Err(Error::Other(format!("module command '{}' failed", self.name))),
+        }
+    }
+}
+
+// a loaded module, as MODULE LIST reports it
+pub struct Module {
+    name: String,
+    version: c_int,
+    path: String,
+    args: Vec<String>,
+    commands: Vec<String>,
+    types: Vec<Arc<ModuleType>>,
+    lib: Arc<Library>,
+}
+
+pub fn load(server: &Server, path: &str, args: &[String]) -> Result<(), Error> {
+    let fail = |reason: String| {
+        eprintln!("Module {path} failed to load: {reason}");
+        Error::Other(LOAD_ERROR.to_string())
+    };
+
+    // running the library's initialisers is the point of loading a module
+    let lib = Arc::new(unsafe { Library::new(path) }.map_err(|e| fail(e.to_string()))?);
+    let onload = unsafe { lib.get::<OnLoad>(ONLOAD) }.map_err(|e| fail(e.to_string()))?;
+
+    let mut ctx = LoadContext {
+        lib: lib.clone(),
+        name: None,
+        version: 0,
+        commands: Vec::new(),
+        types: Vec::new(),
+    };
+
+    let argv: Vec<ModuleString> = args
+        .iter()
+        .map(|a| ModuleString { ptr: a.as_ptr(), len: a.len() })
+        .collect();
+
+    if unsafe { onload(&API, &mut ctx, argv.as_ptr(), argv.len() as c_int) } != MODULE_OK {
+        return Err(fail("onload returned an error".to_string()));
+    }
+
+    let name = match ctx.name.take() {
+        Some(name) => name,
+        None => return Err(fail("onload did not call init".to_string())),
+    };
+
+    let mut modules = server.modules();
+    if modules.iter().any(|m| m.name == name) {
+        return Err(fail(format!("module '{name}' is already loaded")));
+    }
+
+    // register everything or nothing
+    let mut commands: Vec<String> = Vec::new();
+    for mut command in ctx.commands.drain(..) {
+        command.types = ctx.types.clone();
+        let command_name = command.name.clone();
+        if let Err(e) = server.register_shared_command(Arc::new(command)) {
+            for c in &commands {
+                server.unregister_command(c);
+            }
+            return Err(fail(e.to_string()));
+        }
+        commands.push(command_name);
+    }
+
+    let mut types: Vec<&str> = Vec::new();
+    for ty in &ctx.types {
+        let loader_ty = ty.clone();
+        let loader: Loader = Arc::new(move |data: &[u8]| {
+            let ptr = (loader_ty.methods.load)(data.as_ptr(), data.len());
+            if ptr.is_null() {
+                return None;
+            }
+            Some(Box::new(ModuleValue { ty: loader_ty.clone(), ptr }) as Box<dyn CustomValue>)
+        });
+
+        if let Err(e) = server.db().register_type(&ty.name, loader) {
+            for t in &types {
+                server.db().unregister_type(t);
+            }
+            for c in &commands {
+                server.unregister_command(c);
+            }
+            return Err(fail(e.to_string()));
+        }
+        types.push(&ty.name);
+    }
+
+    modules.push(Module {
+        name,
+        version: ctx.version,
+        path: path.to_string(),
+        args: args.to_vec(),
+        commands,
+        types: ctx.types,
+        lib,
+    });
+
+    Ok(())
+}
+
+pub fn unload(server: &Server, name: &str) -> Result<(), Error> {
+    let mut modules = server.modules();
+
+    let i = match modules.iter().position(|m| m.name == name) {
+        Some(i) => i,
+        None => return Err(Error::Other("Error unloading module: no such module with that name".to_string())),
+    };
+
+    // stored values would outlive the code that frees them
+    if !modules[i].types.is_empty() {
+        return Err(Error::Other(
+            "Error unloading module: the module exports one or more module-side data types, can't unload".to_string(),
+        ));
+    }
+
+    if let Ok(onunload) = unsafe { modules[i].lib.get::<OnUnload>(ONUNLOAD) } {
+        let mut ctx = LoadContext {
+            lib: modules[i].lib.clone(),
+            name: Some(name.to_string()),
+            version: modules[i].version,
+            commands: Vec::new(),
+            types: Vec::new(),
+        };
+
+        if unsafe { onunload(&mut ctx) } != MODULE_OK {
+            return Err(Error::Other("Error unloading module: operation not possible.".to_string()));
+        }
+    }
+
+    let module = modules.remove(i);
+    for c in &module.commands {
+        server.unregister_command(c);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn module_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let subcommand = bulk_to_string(&data[0])?.to_uppercase();
+    let mut args = Vec::new();
+    for arg in &data[1..] {
+        args.push(bulk_to_string(arg)?);
+    }
+
+    match subcommand.as_str() {
+        "LOAD" if !args.is_empty() => {
+            load(ctx.server(), &args[0], &args[1..])?;
+            Ok(RESPResult::SimpleString("OK".to_string()))
+        },
+        "UNLOAD" if args.len() == 1 => {
+            unload(ctx.server(), &args[0])?;
+            Ok(RESPResult::SimpleString("OK".to_string()))
+        },
+        "LIST" if args.is_empty() => {
+            let bulk = |s: &str| RESPResult::BulkString(Some(s.as_bytes().to_vec()));
+
+            let modules = ctx.server().modules();
+            let list = modules
+                .iter()
+                .map(|m| {
+                    RESPResult::Array(vec![
+                        bulk("name"), bulk(&m.name),
+                        bulk("ver"), RESPResult::Integer(m.version as i64),
+                        bulk("path"), bulk(&m.path),
+                        bulk("args"), RESPResult::Array(m.args.iter().map(|a| bulk(a)).collect()),
+                    ])
+                })
+                .collect();
+
+            Ok(RESPResult::Array(list))
+        },
+        "LOAD" | "UNLOAD" | "LIST" => Err(Error::WrongArity(format!("module|{}", subcommand.to_lowercase()))),
+        _ => Err(Error::Other(format!("unknown subcommand '{}'. Try MODULE HELP.", subcommand.to_lowercase()))),
+    }
+}
+
+fn c_str(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        return None;
+    }
+
+    unsafe { CStr::from_ptr(s) }.to_str().ok().map(|s| s.to_string())
+}
+
+fn set_reply(call: *mut CallContext<'_>, reply: RESPResult) -> c_int {
+    match unsafe { call.as_mut() } {
+        Some(call) => {
+            call.reply = Some(reply);
+            MODULE_OK
+        },
+        None => MODULE_ERR,
+    }
+}
+
+extern "C" fn api_init(ctx: *mut LoadContext, name: *const c_char, version: c_int) -> c_int {
+    let (ctx, name) = match (unsafe { ctx.as_mut() }, c_str(name)) {
+        (Some(ctx), Some(name)) => (ctx, name),
+        _ => return MODULE_ERR,
+    };
+
+    ctx.name = Some(name);
+    ctx.version = version;
+    MODULE_OK
+}
+
+#[allow(clippy::too_many_arguments)]
+extern "C" fn api_create_command(
+    ctx: *mut LoadContext,
+    name: *const c_char,
+    handler: CommandFunc,
+    arity: c_int,
+    flags: *const c_char,
+    first_key: c_int,
+    last_key: c_int,
+    step: c_int,
+) -> c_int {
+    let (ctx, name) = match (unsafe { ctx.as_mut() }, c_str(name)) {
+        (Some(ctx), Some(name)) => (ctx, name.to_lowercase()),
+        _ => return MODULE_ERR,
+    };
+
+    let flags = match CommandFlags::parse(&c_str(flags).unwrap_or_default()) {
+        Some(flags) => flags,
+        None => return MODULE_ERR,
+    };
+
+    if ctx.commands.iter().any(|c| c.name == name) {
+        return MODULE_ERR;
+    }
+
+    ctx.commands.push(ModuleCommand {
+        name,
+        arity,
+        flags,
+        keys: (first_key, last_key, step),
+        handler,
+        types: Vec::new(),
+        _lib: ctx.lib.clone(),
+    });
+
+    MODULE_OK
+}
+
+extern "C" fn api_create_data_type(ctx: *mut LoadContext, name: *const c_char, methods: *const TypeMethods) -> c_int {
+    let (ctx, name, methods) = match (unsafe { ctx.as_mut() }, c_str(name), unsafe { methods.as_ref() }) {
+        (Some(ctx), Some(name), Some(methods)) => (ctx, name, *methods),
+        _ => return MODULE_ERR,
+    };
+
+    if ctx.types.iter().any(|t| t.name == name) {
+        return MODULE_ERR;
+    }
+
+    ctx.types.push(Arc::new(ModuleType {
+        name,
+        methods,
+        _lib: ctx.lib.clone(),
+    }));
+
+    MODULE_OK
+}
+
+extern "C" fn api_reply_simple_string(call: *mut CallContext<'_>, s: *const c_char) -> c_int {
+    match c_str(s) {
+        Some(s) => set_reply(call, RESPResult::SimpleString(s)),
+        None => MODULE_ERR,
+    }
+}
+
+extern "C" fn api_reply_error(call: *mut CallContext<'_>, s: *const c_char) -> c_int {
+    match c_str(s) {
+        Some(s) => set_reply(call, RESPResult::Error(s)),
+        None => MODULE_ERR,
+    }
+}
+
+extern "C" fn api_reply_integer(call: *mut CallContext<'_>, i: i64) -> c_int {
+    set_reply(call, RESPResult::Integer(i))
+}
+
+extern "C" fn api_reply_bulk(call: *mut CallContext<'_>, ptr: *const u8, len: usize) -> c_int {
+    if ptr.is_null() {
+        return MODULE_ERR;
+    }
+
+    let bytes = unsafe { std::slice::from_raw_parts(ptr, len) }.to_vec();
+    set_reply(call, RESPResult::BulkString(Some(bytes)))
+}
+
+extern "C" fn api_reply_null(call: *mut CallContext<'_>) -> c_int {
+    set_reply(call, RESPResult::BulkString(None))
+}
+
+extern "C" fn api_update_value(
+    call: *mut CallContext<'_>,
+    key: *const u8,
+    keylen: usize,
+    ty: *const c_char,
+    f: UpdateFunc,
+    privdata: *mut c_void,
+) -> c_int {
+    let (call, name) = match (unsafe { call.as_ref() }, c_str(ty)) {
+        (Some(call), Some(name)) if !key.is_null() => (call, name),
+        _ => return MODULE_ERR,
+    };
+
+    let ty = match call.types.iter().find(|t| t.name == name) {
+        Some(ty) => ty,
+        None => return MODULE_ERR,
+    };
+
+    let key = match std::str::from_utf8(unsafe { std::slice::from_raw_parts(key, keylen) }) {
+        Ok(key) => key.to_string(),
+        Err(_) => return MODULE_ERR,
+    };
+
+    call.db.update(&key, |value| {
+        let mut slot = match value {
+            None => ptr::null_mut(),
+            Some(DB_TYPE::Custom(c)) => match c.as_any().downcast_ref::<ModuleValue>() {
+                Some(v) if Arc::ptr_eq(&v.ty, ty) => v.ptr,
+                _ => return MODULE_ERR,
+            },
+            Some(_) => return MODULE_ERR,
+        };
+
+        let before = slot;
+        let status = f(&mut slot, privdata);
+
+        if slot != before {
+            // the module owns the old value now
+            if let Some(DB_TYPE::Custom(c)) = value
+                && let Some(v) = c.as_any_mut().downcast_mut::<ModuleValue>()
+            {
+                v.ptr = ptr::null_mut();
+            }
+
+            *value = if slot.is_null() {
+                None
+            }
+            else {
+                Some(DB_TYPE::Custom(Box::new(ModuleValue { ty: ty.clone(), ptr: slot })))
+            };
+        }
+
+        status
+    })
+}
+
+extern "C" fn api_save_bytes(out: *mut SaveBuffer, ptr: *const u8, len: usize) {
+    if let (Some(out), false) = (unsafe { out.as_mut() }, ptr.is_null()) {
+        out.0.extend_from_slice(unsafe { std::slice::from_raw_parts(ptr, len) });
+    }
+}
@@ -1,34 +1,70 @@
 use crate::{command, parser, network};
+use crate::blocking::Wait;
+use crate::command::Session;
+use crate::expire;
+use crate::error::Error;
+use crate::server::Server;
 use crate::types::RESPResult;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::ReadHalf;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::sync::watch;
 
+// input a blocked client may send before it is watched no more for disconnects
+const READ_AHEAD_LIMIT: usize = 1024 * 1024;
 
-pub async fn start_network() -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind("127.0.0.1:6379").await?;
+
+pub async fn start_network(server: Arc<Server>) -> Result<(), Box<dyn std::error::Error>> {
+    let address = server.config().address();
+    let listener = TcpListener::bind(address).await?;
+
+    // nothing ever asks this server to stop
+    let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    serve(listener, server, shutdown_rx).await?;
+
+    Ok(())
+}
+
+// accept connections until shutdown is signalled, then drop every open connection
+pub(crate) async fn serve(listener: TcpListener, server: Arc<Server>, mut shutdown: watch::Receiver<bool>) -> std::io::Result<()> {
+    tokio::spawn(expire::run(server.clone(), shutdown.clone()));
 
     loop {
-        let (socket, _) = listener.accept().await?;
+        let socket = tokio::select! {
+            accepted = listener.accept() => accepted?.0,
+            _ = shutdown.changed() => return Ok(()),
+        };
+
+        let server = server.clone();
+        let mut shutdown = shutdown.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = process_stream(socket).await {
-                eprintln!("Error handling connection: {:?}", e);
+            tokio::select! {
+                result = process_stream(&server, socket) => {
+                    if let Err(e) = result {
+                        eprintln!("Error handling connection: {:?}", e);
+                    }
+                },
+                _ = shutdown.changed() => {},
             }
         });
     }
-
-    // technically unreachable
-    // Ok(())
 }
 
-async fn process_stream(mut socket: TcpStream) -> Result<(), Box<dyn std::error::Error>> {
+async fn process_stream(server: &Arc<Server>, mut socket: TcpStream) -> Result<(), Box<dyn std::error::Error>> {
     // split the socket into read/write
 
 
     let (reader, mut writer) = socket.split();
 
-    let mut reader = BufReader::new(reader);
+    let mut reader = BufReader::new(Input { socket: reader, ahead: Vec::new() });
     let mut buffer = String::new();
+    let mut session = Session::client();
 
     loop {
         buffer.clear();
@@ -53,13 +89,31 @@ async fn process_stream(mut socket: TcpStream) -> Result<(), Box<dyn std::error:
                 let mut bulk = vec![0u8; bulk_len + 2]; 
                 reader.read_exact(&mut bulk).await?;
 
-                let clean = String::from_utf8_lossy(&bulk[..bulk_len]).to_string();
-                command_parts.push(clean);
+                bulk.truncate(bulk_len);
+                command_parts.push(bulk);
             }
             
-            let response = match network::read_network_input(command_parts) {
+            // commands can wait on locks or run a script for a long time, so they
+            // run on the blocking pool instead of holding up an async worker
+            let shared = server.clone();
+            let (result, returned) = tokio::task::spawn_blocking(move || {
+                let result = network::read_network_input(&shared, &mut session, command_parts);
+                (result, session)
+            }).await?;
+            session = returned;
+
+            // a blocking command that found nothing parks the client until served
+            let result = match session.take_blocked() {
+                Some(wait) => match wait_blocked(server, &mut reader, wait).await {
+                    Some(reply) => reply.map(|reply| parser::respresult_to_resp_bytes(&reply)),
+                    None => break,
+                },
+                None => result,
+            };
+
+            let response = match result {
                 Ok(res) => res,
-                Err(e) => format!("Error: {0}", e).as_bytes().to_vec()
+                Err(e) => parser::respresult_to_resp_bytes(&RESPResult::Error(e.to_string()))
             };
 
             writer.write_all(&response).await?;
@@ -69,22 +123,86 @@ async fn process_stream(mut socket: TcpStream) -> Result<(), Box<dyn std::error:
     Ok(())
 }
 
-pub fn read_network_input(commands: Vec<String>) -> Result<Vec<u8>, String> {
+// the read half of a connection, which hands out the input read ahead while
+// the client was blocked before reading the socket again
+struct Input<'a> {
+    socket: ReadHalf<'a>,
+    ahead: Vec<u8>,
+}
+
+impl Input<'_> {
+    // reads more of the socket into ahead, 0 at the end of the connection
+    async fn read_ahead(&mut self) -> io::Result<usize> {
+        self.socket.read_buf(&mut self.ahead).await
+    }
+}
+
+impl AsyncRead for Input<'_> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let input = self.get_mut();
+        if input.ahead.is_empty() {
+            return Pin::new(&mut input.socket).poll_read(cx, buf);
+        }
+
+        let n = input.ahead.len().min(buf.remaining());
+        buf.put_slice(&input.ahead[..n]);
+        input.ahead.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+// waits until a parked client is served or its timeout passes, which replies
+// nil; None when the client disconnects first
+async fn wait_blocked(server: &Server, reader: &mut BufReader<Input<'_>>, mut wait: Wait) -> Option<Result<RESPResult, Error>> {
+    let deadline = wait.deadline;
+    let timeout = async {
+        match deadline {
+            Some(d) => tokio::time::sleep_until(d.into()).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(timeout);
+
+    // commands pipelined behind the blocking one wait their turn, set aside
+    // while the socket is watched for the end of the connection
+    let mut watch_input = true;
+    let served = loop {
+        tokio::select! {
+            reply = &mut wait.reply => break reply.ok(),
+            _ = &mut timeout => break None,
+            input = reader.get_mut().read_ahead(), if watch_input => match input {
+                Ok(n) if n > 0 => watch_input = reader.get_ref().ahead.len() < READ_AHEAD_LIMIT,
+                _ => {
+                    server.blocking().cancel(&wait);
+                    return None;
+                },
+            },
+        }
+    };
+
+    if let Some(reply) = served {
+        return Some(reply);
+    }
+    server.blocking().cancel(&wait);
+
+    // served just as the time ran out
+    Some(wait.reply.try_recv().unwrap_or_else(|_| Ok((wait.nil)())))
+}
+
+pub fn read_network_input(server: &Server, session: &mut Session, commands: Vec<Vec<u8>>) -> Result<Vec<u8>, Error> {
     // check that commands is an array, and above len 0
-    if commands.len() == 0 {
-        return Err("Empty array".to_string());
+    if commands.is_empty() {
+        return Err(Error::Protocol("empty command".to_string()));
     };
 
     let mut arguments = Vec::<RESPResult>::new();
 
     for args in &commands[1..] {
-        arguments.push(RESPResult::BulkString(Some(args.as_bytes().to_vec())));
+        arguments.push(RESPResult::BulkString(Some(args.clone())));
     }
 
-    let result = match command::command_router(&commands[0], &arguments) {
-        Ok(m) => m,
-        Err(e) => return Err(e),
-    };
+    let name = String::from_utf8_lossy(&commands[0]);
+    let result = command::command_router_in(server, session, &name, &arguments)?;
 
-    Ok(parser::respresult_to_resp_string(&result).unwrap().as_bytes().to_vec())
+    Ok(parser::respresult_to_resp_bytes(&result))
 }
\ No newline at end of file
@@ -3,14 +3,12 @@ use shell_words;
 use crate::types::RESPResult;
 
 pub fn string_to_resp_message(message: &str) -> Result<Vec<u8>, String> {
-    let msg_str: Vec<String>;
-
-    match shell_words::split(message.trim_end_matches("\r")) {
-        Ok(message) => msg_str = message,
+    let msg_str: Vec<String> = match shell_words::split(message.trim_end_matches("\r")) {
+        Ok(message) => message,
         Err(e) => return Err(e.to_string())
-    }
+    };
 
-    if msg_str.len() < 1 {
+    if msg_str.is_empty() {
         return Err("No command".to_string());
     }
 
@@ -24,23 +22,31 @@ pub fn string_to_resp_message(message: &str) -> Result<Vec<u8>, String> {
 }
 
 pub fn respresult_to_resp_string(respmessage: &RESPResult) -> Result<String, String> {
-    let result = match respmessage {
-        RESPResult::SimpleString(s) => format!("+{}\r\n", s),
-        RESPResult::Error(e) => format!("-{}\r\n", e),
-        RESPResult::Integer(i) => format!("-{}\r\n", i),
-        RESPResult::BulkString(Some(bytes)) => format!("${}\r\n{}\r\n", &bytes.len(), String::from_utf8(bytes.clone()).unwrap()),
-        RESPResult::BulkString(None) => "$0\r\n\r\n".to_string(),
+    String::from_utf8(respresult_to_resp_bytes(respmessage)).map_err(|e| e.to_string())
+}
+
+// the wire encoding of a reply; bulk strings may hold any bytes
+pub fn respresult_to_resp_bytes(respmessage: &RESPResult) -> Vec<u8> {
+    match respmessage {
+        RESPResult::SimpleString(s) => format!("+{}\r\n", s).into_bytes(),
+        RESPResult::Error(e) => format!("-{}\r\n", e).into_bytes(),
+        RESPResult::Integer(i) => format!(":{}\r\n", i).into_bytes(),
+        RESPResult::BulkString(Some(bytes)) => {
+            let mut s = format!("${}\r\n", bytes.len()).into_bytes();
+            s.extend_from_slice(bytes);
+            s.extend_from_slice(b"\r\n");
+            s
+        },
+        RESPResult::BulkString(None) => b"$-1\r\n".to_vec(),
         RESPResult::Array(elements) => {
-            elements
-                .iter()
-                .enumerate()
-                .map(|(i, elem)| format!("{}) {}", i + 1, respresult_to_resp_string(elem).unwrap()))
-                .collect::<Vec<String>>()
-                .join("")
+            let mut s = format!("*{}\r\n", elements.len()).into_bytes();
+            for elem in elements {
+                s.extend(respresult_to_resp_bytes(elem));
+            }
+            s
         },
-    };
-
-    Ok(result)
+        RESPResult::NullArray => b"*-1\r\n".to_vec(),
+    }
 }
 
 pub fn resp_message_to_string(respmessage: &RESPResult) -> String {
@@ -49,7 +55,7 @@ pub fn resp_message_to_string(respmessage: &RESPResult) -> String {
         RESPResult::Error(e) => format!("(error) {}", e),
         RESPResult::Integer(i) => i.to_string(),
         RESPResult::BulkString(Some(bytes)) => String::from_utf8_lossy(bytes).into_owned(),
-        RESPResult::BulkString(None) => "(nil)".to_string(),
+        RESPResult::BulkString(None) | RESPResult::NullArray => "(nil)".to_string(),
         RESPResult::Array(elements) => {
             elements
                 .iter()
@@ -104,10 +110,7 @@ fn parse_integer_string(message: &[u8]) -> Result<(RESPResult, usize), String>
 
     let bytes: &[u8] = &message[1..pos];
 
-    let integer: i64 = match String::from_utf8_lossy(bytes).parse() {
-        Ok(val) => val,
-        Err(_) => 0,
-    };
+    let integer: i64 = String::from_utf8_lossy(bytes).parse().unwrap_or_default();
 
     Ok((
         RESPResult::Integer(integer),
@@ -155,6 +158,9 @@ fn parse_array(message: &[u8]) -> Result<(RESPResult, usize), String> {
     pos += 2;
 
     let mut len: isize = byte_str.parse().unwrap();
+    if len < 0 {
+        return Ok((RESPResult::NullArray, pos));
+    }
 
     while len > 0 {
         match parse_resp_message(&message[pos..]) {
@@ -190,7 +196,7 @@ mod tests {
         let (resp, bytes) = parse_resp_message(input).expect("Parsing failed");
         assert_eq!(
             (resp, bytes),
-            (RESPResult::SimpleString("PONG".to_string()), 7 as usize)
+            (RESPResult::SimpleString("PONG".to_string()), 7_usize)
         );
     }
 
@@ -221,6 +227,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_null_array() {
+        assert_eq!(parse_resp_message(b"*-1\r\n"), Ok((RESPResult::NullArray, 5)));
+        assert_eq!(respresult_to_resp_string(&RESPResult::NullArray), Ok("*-1\r\n".to_string()));
+    }
+
+    #[test]
+    fn test_error_and_integer_to_resp_string() {
+        let error = RESPResult::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string());
+        assert_eq!(
+            respresult_to_resp_string(&error),
+            Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string())
+        );
+        assert_eq!(respresult_to_resp_string(&RESPResult::Integer(3)), Ok(":3\r\n".to_string()));
+    }
+
+    #[test]
+    fn test_array_to_resp_string() {
+        let array = RESPResult::Array(vec![
+            RESPResult::BulkString(Some(b"get".to_vec())),
+            RESPResult::Integer(2),
+            RESPResult::BulkString(None),
+        ]);
+        assert_eq!(respresult_to_resp_string(&array), Ok("*3\r\n$3\r\nget\r\n:2\r\n$-1\r\n".to_string()));
+    }
+
     #[test]
     fn test_parse_integer() {
         let input = b":12345\r\n";
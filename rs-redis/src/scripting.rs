@@ -0,0 +1,1352 @@
+// Lua scripting: EVAL, EVALSHA, EVAL_RO, EVALSHA_RO and SCRIPT, plus function
+// libraries loaded with FUNCTION LOAD and run with FCALL
+//
+// a script runs alone: it holds the gate exclusively while every other command
+// holds it shared, so nothing interleaves with the commands a script calls.
+// once a script has run for busy_reply_threshold other clients get BUSY
+// instead of waiting, and SCRIPT KILL can stop it unless it already wrote
+
+use crate::command::{self, bulk_to_string, CommandFlags, Context, Session};
+use crate::config::Config;
+use crate::error::Error;
+use crate::evict;
+use crate::glob::glob_match;
+use crate::server::Server;
+use crate::types::RESPResult;
+use mlua::{Function, HookTriggers, Lua, LuaOptions, MultiValue, Scope, StdLib, Table, Value, VmState};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+pub struct Scripting {
+    gate: Mutex<Gate>,
+    changed: Condvar,
+    // commands running, and scripts waiting or running. kept outside the gate
+    // so that while no script is around a command only touches the first
+    commands: AtomicUsize,
+    scripts_pending: AtomicUsize,
+    busy_reply_threshold: Duration,
+    time_limit: Option<Duration>,
+    // script bodies by sha1
+    scripts: Mutex<HashMap<String, String>>,
+    // function libraries by name
+    libraries: Mutex<BTreeMap<String, Library>>,
+    lua: Mutex<Lua>,
+}
+
+// a library's functions keep the environment they were loaded in, redis.call
+// is only set in it while one of them runs
+#[derive(Clone)]
+struct Library {
+    code: String,
+    redis: Table,
+    functions: BTreeMap<String, LibraryFunction>,
+}
+
+#[derive(Clone)]
+struct LibraryFunction {
+    callback: Function,
+    flags: Vec<String>,
+    description: Option<String>,
+}
+
+impl LibraryFunction {
+    fn no_writes(&self) -> bool {
+        self.flags.iter().any(|f| f == "no-writes")
+    }
+}
+
+// what FUNCTION RESTORE does with the libraries already loaded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestorePolicy {
+    Flush,
+    Append,
+    Replace,
+}
+
+const FUNCTION_FLAGS: [&str; 5] = ["no-writes", "allow-oom", "allow-stale", "no-cluster", "allow-cross-slot-keys"];
+
+const LOAD_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Default)]
+struct Gate {
+    waiting_scripts: usize,
+    running: Option<Arc<RunningScript>>,
+}
+
+struct RunningScript {
+    started: Instant,
+    killed: AtomicBool,
+    wrote: AtomicBool,
+}
+
+// held by a command while it runs
+pub(crate) struct CommandPermit<'a> {
+    scripting: &'a Scripting,
+}
+
+impl Drop for CommandPermit<'_> {
+    fn drop(&mut self) {
+        let scripting = self.scripting;
+        if scripting.commands.fetch_sub(1, Ordering::SeqCst) == 1 && scripting.scripts_pending.load(Ordering::SeqCst) > 0 {
+            // under the gate lock, so a script about to wait can not miss it
+            let _gate = scripting.lock_gate();
+            scripting.changed.notify_all();
+        }
+    }
+}
+
+// held by a script while it runs
+struct ScriptPermit<'a> {
+    scripting: &'a Scripting,
+}
+
+impl Drop for ScriptPermit<'_> {
+    fn drop(&mut self) {
+        let mut gate = self.scripting.lock_gate();
+        gate.running = None;
+        self.scripting.scripts_pending.fetch_sub(1, Ordering::SeqCst);
+        self.scripting.changed.notify_all();
+    }
+}
+
+// what redis.call runs commands as; SELECT in a script only changes the
+// script's database, not the caller's
+struct Caller<'a> {
+    server: &'a Server,
+    script: &'a RunningScript,
+    session: RefCell<Session>,
+    read_only: bool,
+}
+
+impl<'a> Caller<'a> {
+    fn new(server: &'a Server, script: &'a RunningScript, db: usize, read_only: bool) -> Caller<'a> {
+        let mut session = Session::new();
+        session.select(db);
+        Caller { server, script, session: RefCell::new(session), read_only }
+    }
+}
+
+// an error reply raised out of a script, sent to the client as is
+#[derive(Debug)]
+struct ReplyError(String);
+
+impl fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ReplyError {}
+
+impl Scripting {
+    pub fn new(config: &Config) -> Scripting {
+        // no io, os, package or debug, scripts only reach the server through redis.call
+        let libs = StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8;
+        let lua = Lua::new_with(libs, LuaOptions::default()).expect("Lua state should be created");
+        seal(&lua).expect("Lua state should be sealed");
+
+        Scripting {
+            gate: Mutex::new(Gate::default()),
+            changed: Condvar::new(),
+            commands: AtomicUsize::new(0),
+            scripts_pending: AtomicUsize::new(0),
+            busy_reply_threshold: config.busy_reply_threshold,
+            time_limit: config.script_time_limit,
+            scripts: Mutex::new(HashMap::new()),
+            libraries: Mutex::new(BTreeMap::new()),
+            lua: Mutex::new(lua),
+        }
+    }
+
+    fn lock_gate(&self) -> MutexGuard<'_, Gate> {
+        self.gate.lock().expect("script gate lock failed")
+    }
+
+    fn lock_scripts(&self) -> MutexGuard<'_, HashMap<String, String>> {
+        self.scripts.lock().expect("script cache lock failed")
+    }
+
+    fn lock_libraries(&self) -> MutexGuard<'_, BTreeMap<String, Library>> {
+        self.libraries.lock().expect("function library lock failed")
+    }
+
+    fn lock_lua(&self) -> MutexGuard<'_, Lua> {
+        self.lua.lock().expect("Lua state lock failed")
+    }
+
+    // how long to wait for the running script before replying BUSY, None if there is none
+    fn busy_wait(&self, gate: &Gate) -> Result<Option<Duration>, Error> {
+        match &gate.running {
+            Some(script) => match self.busy_reply_threshold.checked_sub(script.started.elapsed()) {
+                Some(wait) if !wait.is_zero() => Ok(Some(wait)),
+                _ => Err(Error::Busy),
+            },
+            None => Ok(None),
+        }
+    }
+
+    // waits for a running script to finish; new scripts go first so a steady
+    // stream of commands can not starve them
+    pub(crate) fn enter(&self) -> Result<CommandPermit<'_>, Error> {
+        // with no script around, counting the command is all there is to do.
+        // a script counts itself before it looks at commands, and a command
+        // the other way round, so at least one of them sees the other
+        self.commands.fetch_add(1, Ordering::SeqCst);
+        let permit = CommandPermit { scripting: self };
+        if self.scripts_pending.load(Ordering::SeqCst) == 0 {
+            return Ok(permit);
+        }
+        drop(permit);
+
+        let mut gate = self.lock_gate();
+
+        loop {
+            gate = match self.busy_wait(&gate)? {
+                Some(wait) => self.changed.wait_timeout(gate, wait).expect("script gate lock failed").0,
+                None if gate.waiting_scripts > 0 => self.changed.wait(gate).expect("script gate lock failed"),
+                None => break,
+            };
+        }
+
+        // no script can start while the gate is held
+        self.commands.fetch_add(1, Ordering::SeqCst);
+        Ok(CommandPermit { scripting: self })
+    }
+
+    fn start(&self) -> Result<(ScriptPermit<'_>, Arc<RunningScript>), Error> {
+        let mut gate = self.lock_gate();
+        gate.waiting_scripts += 1;
+        self.scripts_pending.fetch_add(1, Ordering::SeqCst);
+
+        loop {
+            let wait = match self.busy_wait(&gate) {
+                Ok(wait) => wait,
+                Err(e) => {
+                    gate.waiting_scripts -= 1;
+                    self.scripts_pending.fetch_sub(1, Ordering::SeqCst);
+                    self.changed.notify_all();
+                    return Err(e);
+                },
+            };
+
+            gate = match wait {
+                Some(wait) => self.changed.wait_timeout(gate, wait).expect("script gate lock failed").0,
+                None if self.commands.load(Ordering::SeqCst) > 0 => self.changed.wait(gate).expect("script gate lock failed"),
+                None => break,
+            };
+        }
+
+        let script = Arc::new(RunningScript {
+            started: Instant::now(),
+            killed: AtomicBool::new(false),
+            wrote: AtomicBool::new(false),
+        });
+
+        gate.waiting_scripts -= 1;
+        gate.running = Some(script.clone());
+
+        // commands waiting behind it now wait with a BUSY deadline
+        self.changed.notify_all();
+
+        Ok((ScriptPermit { scripting: self }, script))
+    }
+
+    pub fn kill(&self) -> Result<(), Error> {
+        match &self.lock_gate().running {
+            None => Err(Error::NotBusy),
+            Some(script) if script.wrote.load(Ordering::SeqCst) => Err(Error::Unkillable),
+            Some(script) => {
+                script.killed.store(true, Ordering::SeqCst);
+                Ok(())
+            },
+        }
+    }
+
+    // caches the script and returns its sha1
+    pub fn load(&self, body: &str) -> String {
+        let sha = sha1_hex(body.as_bytes());
+        self.lock_scripts().insert(sha.clone(), body.to_string());
+        sha
+    }
+
+    pub fn exists(&self, sha: &str) -> bool {
+        self.lock_scripts().contains_key(&sha.to_lowercase())
+    }
+
+    pub fn flush(&self) {
+        self.lock_scripts().clear();
+    }
+
+    // db is the index of the database the script starts in
+    pub fn eval(&self, server: &Server, db: usize, body: &str, keys: &[Vec<u8>], argv: &[Vec<u8>], read_only: bool) -> Result<RESPResult, Error> {
+        self.run_script(|lua, script| run(lua, &Caller::new(server, script, db, read_only), body, keys, argv))
+    }
+
+    pub fn fcall(&self, server: &Server, db: usize, name: &str, keys: &[Vec<u8>], argv: &[Vec<u8>], read_only: bool) -> Result<RESPResult, Error> {
+        let found = self.lock_libraries()
+            .values()
+            .find_map(|library| library.functions.get(name).map(|f| (f.clone(), library.redis.clone())));
+        let (function, redis) = match found {
+            Some(found) => found,
+            None => return Err(Error::Other("Function not found".to_string())),
+        };
+
+        if read_only && !function.no_writes() {
+            return Err(Error::Other("Can not execute a script with write flag using *_ro command.".to_string()));
+        }
+        let read_only = read_only || function.no_writes();
+
+        self.run_script(|lua, script| {
+            let caller = Caller::new(server, script, db, read_only);
+            let result = lua.scope(|scope| {
+                set_calls(scope, &redis, &caller)?;
+                let value: Value = function.callback.call((strings_table(lua, keys)?, strings_table(lua, argv)?))?;
+                Ok(lua_to_resp(value))
+            });
+
+            // the calls went away with the scope, clear them for good
+            redis.set("call", Value::Nil)?;
+            redis.set("pcall", Value::Nil)?;
+            result
+        })
+    }
+
+    // runs f alone, with SCRIPT KILL and the time limit checked as it goes
+    fn run_script(&self, f: impl FnOnce(&Lua, &RunningScript) -> mlua::Result<RESPResult>) -> Result<RESPResult, Error> {
+        let (_permit, script) = self.start()?;
+
+        let lua = self.lock_lua();
+
+        let running = script.clone();
+        let time_limit = self.time_limit;
+        lua.set_hook(HookTriggers::new().every_nth_instruction(1000), move |_, _| {
+            if running.killed.load(Ordering::SeqCst) {
+                return Err(mlua::Error::external(ReplyError("ERR Script killed by user with SCRIPT KILL...".to_string())));
+            }
+
+            // a script that wrote can not be stopped without breaking atomicity
+            let expired = time_limit.is_some_and(|limit| running.started.elapsed() > limit);
+            if expired && !running.wrote.load(Ordering::SeqCst) {
+                return Err(mlua::Error::external(ReplyError("ERR Script exceeded the configured time limit".to_string())));
+            }
+
+            Ok(VmState::Continue)
+        });
+
+        let result = f(&lua, &script);
+        lua.remove_hook();
+
+        match result {
+            Ok(reply) => Ok(reply),
+            Err(e) => match reply_error(&e) {
+                Some(line) => Ok(RESPResult::Error(line)),
+                None if matches!(e, mlua::Error::SyntaxError { .. }) => {
+                    Err(Error::Other(format!("Error compiling script: {}", first_line(&e))))
+                },
+                None => Err(Error::Other(format!("Error running script: {}", first_line(&e)))),
+            },
+        }
+    }
+}
+
+impl Scripting {
+    // loads a library and returns its name
+    pub fn load_library(&self, code: &str, replace: bool) -> Result<String, Error> {
+        let (name, library) = compile_library(&self.lock_lua(), code)?;
+        let policy = if replace { RestorePolicy::Replace } else { RestorePolicy::Append };
+        self.install(vec![(name.clone(), library)], policy, || {})?;
+        Ok(name)
+    }
+
+    // loads every library or none of them
+    pub fn restore_libraries(&self, codes: &[String], policy: RestorePolicy) -> Result<(), Error> {
+        self.restore_libraries_with(codes, policy, || {})
+    }
+
+    // like restore_libraries, but runs swap once the libraries compiled and fit,
+    // under the library lock, so what it swaps changes together with them
+    pub fn restore_libraries_with(&self, codes: &[String], policy: RestorePolicy, swap: impl FnOnce()) -> Result<(), Error> {
+        let libraries = {
+            let lua = self.lock_lua();
+            codes.iter().map(|code| compile_library(&lua, code)).collect::<Result<Vec<_>, _>>()?
+        };
+        self.install(libraries, policy, swap)
+    }
+
+    fn install(&self, new: Vec<(String, Library)>, policy: RestorePolicy, swap: impl FnOnce()) -> Result<(), Error> {
+        let mut libraries = self.lock_libraries();
+        let mut next = match policy {
+            RestorePolicy::Flush => BTreeMap::new(),
+            _ => libraries.clone(),
+        };
+
+        for (name, library) in new {
+            if next.contains_key(&name) {
+                if policy != RestorePolicy::Replace {
+                    return Err(Error::Other(format!("Library '{name}' already exists")));
+                }
+                next.remove(&name);
+            }
+
+            // function names are global, not per library
+            for function in library.functions.keys() {
+                if next.values().any(|other| other.functions.contains_key(function)) {
+                    return Err(Error::Other(format!("Function {function} already exists")));
+                }
+            }
+
+            next.insert(name, library);
+        }
+
+        swap();
+        *libraries = next;
+        Ok(())
+    }
+
+    pub fn delete_library(&self, name: &str) -> Result<(), Error> {
+        match self.lock_libraries().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(Error::Other("Library not found".to_string())),
+        }
+    }
+
+    pub fn flush_libraries(&self) {
+        self.lock_libraries().clear();
+    }
+
+    // the code of every library, ordered by name
+    pub fn library_codes(&self) -> Vec<String> {
+        self.lock_libraries().values().map(|library| library.code.clone()).collect()
+    }
+}
+
+// checks the #!lua name=<library> line and runs the rest, which registers the
+// library's functions
+fn compile_library(lua: &Lua, code: &str) -> Result<(String, Library), Error> {
+    let (name, body) = library_metadata(code)?;
+
+    let env = script_env(lua).map_err(|e| Error::Other(first_line(&e)))?;
+    let redis = redis_table(lua).map_err(|e| Error::Other(first_line(&e)))?;
+    let functions = RefCell::new(BTreeMap::new());
+
+    // loading runs arbitrary code, so it gets a deadline of its own
+    let started = Instant::now();
+    lua.set_hook(HookTriggers::new().every_nth_instruction(1000), move |_, _| {
+        if started.elapsed() > LOAD_TIMEOUT {
+            return Err(raise_reply("FUNCTION LOAD timeout"));
+        }
+        Ok(VmState::Continue)
+    });
+
+    let result = lua.scope(|scope| {
+        redis.set("register_function", scope.create_function(|_, args: MultiValue| register_function(&functions, args))?)?;
+        env.set("redis", redis.clone())?;
+        lua.load(body).set_name("@user_function").set_environment(env).exec()
+    });
+    lua.remove_hook();
+
+    if let Err(e) = result.and_then(|_| redis.set("register_function", Value::Nil)) {
+        let message = reply_error(&e).unwrap_or_else(|| first_line(&e));
+        return Err(Error::Other(format!("Error registering functions: {message}")));
+    }
+
+    let functions = functions.into_inner();
+    if functions.is_empty() {
+        return Err(Error::Other("No functions registered".to_string()));
+    }
+
+    Ok((name, Library { code: code.to_string(), redis, functions }))
+}
+
+// the library name from the shebang line, and the code after it
+fn library_metadata(code: &str) -> Result<(String, &str), Error> {
+    let (shebang, body) = code.split_once('\n').unwrap_or((code, ""));
+    let shebang = match shebang.strip_prefix("#!") {
+        Some(shebang) => shebang.trim_end_matches('\r'),
+        None => return Err(Error::Other("Missing library metadata".to_string())),
+    };
+
+    let mut parts = shebang.split(' ').filter(|p| !p.is_empty());
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(Error::Other(format!("Engine '{engine}' not found")));
+    }
+
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(n) => name = Some(n),
+            None => return Err(Error::Other(format!("Invalid metadata value given: {part}"))),
+        }
+    }
+
+    match name {
+        Some(name) if valid_name(name) => Ok((name.to_string(), body)),
+        Some(_) => Err(Error::Other("Library names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string())),
+        None => Err(Error::Other("Library name was not given".to_string())),
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+// redis.register_function('name', callback) or
+// redis.register_function{function_name='name', callback=f, flags={...}, description='...'}
+fn register_function(functions: &RefCell<BTreeMap<String, LibraryFunction>>, args: MultiValue) -> mlua::Result<()> {
+    let args: Vec<Value> = args.into_iter().collect();
+    let (name, callback, flags, description) = match args.as_slice() {
+        [Value::Table(t)] => (t.get("function_name")?, t.get("callback")?, t.get("flags")?, t.get("description")?),
+        [name, callback] => (name.clone(), callback.clone(), Value::Nil, Value::Nil),
+        _ => return Err(raise_reply("wrong number of arguments to redis.register_function")),
+    };
+
+    let name = match name {
+        Value::String(s) => s.to_str()?.to_string(),
+        _ => return Err(raise_reply("function_name argument given to redis.register_function must be a string")),
+    };
+    if !valid_name(&name) {
+        return Err(raise_reply("Function names can only contain letters, numbers, or underscores(_) and must be at least one character long"));
+    }
+
+    let callback = match callback {
+        Value::Function(f) => f,
+        _ => return Err(raise_reply("callback argument given to redis.register_function must be a function")),
+    };
+
+    let flags = match flags {
+        Value::Nil => Vec::new(),
+        Value::Table(t) => {
+            let mut names = Vec::new();
+            for flag in t.sequence_values::<Value>() {
+                match flag? {
+                    Value::String(s) if FUNCTION_FLAGS.contains(&s.to_str()?.as_ref()) => names.push(s.to_str()?.to_string()),
+                    _ => return Err(raise_reply("unknown flag given")),
+                }
+            }
+            names
+        },
+        _ => return Err(raise_reply("flags argument to redis.register_function must be a table representing function flags")),
+    };
+
+    let description = match description {
+        Value::Nil => None,
+        Value::String(s) => Some(s.to_str()?.to_string()),
+        _ => return Err(raise_reply("description argument given to redis.register_function must be a string")),
+    };
+
+    let mut functions = functions.borrow_mut();
+    if functions.contains_key(&name) {
+        return Err(raise_reply("Function already exists in the library"));
+    }
+    functions.insert(name, LibraryFunction { callback, flags, description });
+    Ok(())
+}
+
+fn run(lua: &Lua, caller: &Caller<'_>, body: &str, keys: &[Vec<u8>], argv: &[Vec<u8>]) -> mlua::Result<RESPResult> {
+    lua.scope(|scope| {
+        let env = script_env(lua)?;
+        env.set("KEYS", strings_table(lua, keys)?)?;
+        env.set("ARGV", strings_table(lua, argv)?)?;
+
+        let redis = redis_table(lua)?;
+        set_calls(scope, &redis, caller)?;
+        env.set("redis", redis)?;
+
+        let value: Value = lua.load(body).set_name("@user_script").set_environment(env).eval()?;
+        Ok(lua_to_resp(value))
+    })
+}
+
+// globals set by a script stay in its own environment, and the shared library
+// tables are only reached through read-only views, so no script can break them
+// for the next
+fn script_env(lua: &Lua) -> mlua::Result<Table> {
+    let env = lua.create_table()?;
+    for pair in lua.globals().pairs::<Value, Value>() {
+        match pair? {
+            (Value::String(name), _) if name.as_bytes() == b"_G".as_slice() => {},
+            (name, Value::Table(lib)) => env.set(name, readonly_table(lua, lib)?)?,
+            _ => {},
+        }
+    }
+    env.set("_G", env.clone())?;
+
+    // chunks loaded by the script run in its environment rather than the globals
+    let load: Function = lua.named_registry_value(SANDBOX_LOAD)?;
+    env.set("load", load.call::<Function>(env.clone())?)?;
+
+    let meta = lua.create_table()?;
+    meta.set("__index", lua.globals())?;
+    meta.set("__metatable", false)?;
+    env.set_metatable(Some(meta));
+    Ok(env)
+}
+
+const SANDBOX_LOAD: &str = "sandbox_load";
+
+// makes the string methods read-only and keeps the load that script_env hands
+// out, done once as both are shared by every script
+fn seal(lua: &Lua) -> mlua::Result<()> {
+    let strings: Table = lua.globals().get::<Function>("getmetatable")?.call("")?;
+    strings.set("__index", readonly_table(lua, lua.globals().get("string")?)?)?;
+    strings.set("__metatable", false)?;
+
+    let load = lua.load(
+        "local load = load
+        return function(env)
+            return function(chunk, name, _, e) return load(chunk, name, 't', e or env) end
+        end",
+    ).eval::<Function>()?;
+    lua.set_named_registry_value(SANDBOX_LOAD, load)
+}
+
+fn readonly_table(lua: &Lua, table: Table) -> mlua::Result<Table> {
+    let view = lua.create_table()?;
+    let meta = lua.create_table()?;
+    meta.set("__index", table)?;
+    meta.set("__newindex", lua.create_function(|_, _: MultiValue| -> mlua::Result<()> {
+        Err(mlua::Error::runtime("Attempt to modify a readonly table"))
+    })?)?;
+    // hides the table behind it from getmetatable and setmetatable
+    meta.set("__metatable", false)?;
+    view.set_metatable(Some(meta));
+    Ok(view)
+}
+
+// the redis table without call and pcall, which only live while a script runs
+fn redis_table(lua: &Lua) -> mlua::Result<Table> {
+    let redis = lua.create_table()?;
+    redis.set("status_reply", lua.create_function(|lua, s: mlua::String| reply_table(lua, "ok", s))?)?;
+    redis.set("error_reply", lua.create_function(|lua, s: mlua::String| reply_table(lua, "err", s))?)?;
+    redis.set("sha1hex", lua.create_function(|_, s: mlua::String| Ok(sha1_hex(&s.as_bytes())))?)?;
+    Ok(redis)
+}
+
+fn set_calls<'scope>(scope: &'scope Scope<'scope, '_>, redis: &Table, caller: &'scope Caller<'_>) -> mlua::Result<()> {
+    redis.set("call", scope.create_function(move |lua, args: MultiValue| redis_call(lua, caller, args, true))?)?;
+    redis.set("pcall", scope.create_function(move |lua, args: MultiValue| redis_call(lua, caller, args, false))?)?;
+    Ok(())
+}
+
+fn redis_call(lua: &Lua, caller: &Caller<'_>, args: MultiValue, raise: bool) -> mlua::Result<Value> {
+    let mut parts = Vec::new();
+    for arg in args {
+        match arg {
+            Value::String(s) => parts.push(s.as_bytes().to_vec()),
+            Value::Integer(i) => parts.push(i.to_string().into_bytes()),
+            Value::Number(n) => parts.push(n.to_string().into_bytes()),
+            _ => return Err(raise_reply("ERR Lua redis lib command arguments must be strings or integers")),
+        }
+    }
+
+    if parts.is_empty() {
+        return Err(raise_reply("ERR Please specify at least one argument for this redis lib call"));
+    }
+
+    let name = String::from_utf8_lossy(&parts[0]).into_owned();
+    let data: Vec<RESPResult> = parts[1..].iter().map(|p| RESPResult::BulkString(Some(p.clone()))).collect();
+
+    let error = match call_from_script(caller, &name, &data) {
+        Ok(RESPResult::Error(e)) => e,
+        Ok(reply) => return resp_to_lua(lua, reply),
+        Err(e) => e.to_string(),
+    };
+
+    if raise {
+        Err(raise_reply(&error))
+    }
+    else {
+        Ok(Value::Table(reply_table(lua, "err", lua.create_string(&error)?)?))
+    }
+}
+
+// like command_router, without the gate the script already holds
+fn call_from_script(caller: &Caller<'_>, name: &str, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let spec = command::lookup(caller.server, name, data.len() + 1)?;
+    let flags = spec.flags();
+
+    if flags.contains(CommandFlags::NOSCRIPT) {
+        return Err(Error::Other("This Redis command is not allowed from script".to_string()));
+    }
+
+    if flags.contains(CommandFlags::WRITE) {
+        if caller.read_only {
+            return Err(Error::Other("Write commands are not allowed from read-only scripts.".to_string()));
+        }
+        caller.script.wrote.store(true, Ordering::SeqCst);
+    }
+
+    // nothing is evicted while a script runs
+    if flags.contains(CommandFlags::DENYOOM) && evict::over_limit(caller.server) {
+        return Err(Error::Oom);
+    }
+
+    let mut session = caller.session.borrow_mut();
+    let result = spec.execute(&mut Context::new(caller.server, &mut session), data);
+    if result.is_ok() {
+        command::signal_written(caller.server, session.db(), spec.as_ref(), data);
+    }
+    result
+}
+
+fn raise_reply(line: &str) -> mlua::Error {
+    mlua::Error::external(ReplyError(line.to_string()))
+}
+
+// the reply raised by redis.call or SCRIPT KILL somewhere in the error's causes
+fn reply_error(e: &mlua::Error) -> Option<String> {
+    match e {
+        mlua::Error::ExternalError(err) => err.downcast_ref::<ReplyError>().map(|r| r.0.clone()),
+        mlua::Error::CallbackError { cause, .. } => reply_error(cause),
+        mlua::Error::WithContext { cause, .. } => reply_error(cause),
+        _ => None,
+    }
+}
+
+fn first_line(e: &mlua::Error) -> String {
+    e.to_string().lines().next().unwrap_or_default().to_string()
+}
+
+fn strings_table(lua: &Lua, items: &[Vec<u8>]) -> mlua::Result<Table> {
+    let table = lua.create_table()?;
+    for item in items {
+        table.raw_push(lua.create_string(item)?)?;
+    }
+
+    Ok(table)
+}
+
+fn reply_table(lua: &Lua, field: &str, s: mlua::String) -> mlua::Result<Table> {
+    let table = lua.create_table()?;
+    table.set(field, s)?;
+    Ok(table)
+}
+
+fn resp_to_lua(lua: &Lua, reply: RESPResult) -> mlua::Result<Value> {
+    Ok(match reply {
+        RESPResult::Integer(i) => Value::Integer(i),
+        RESPResult::BulkString(Some(b)) => Value::String(lua.create_string(&b)?),
+        // nil would end a Lua array early
+        RESPResult::BulkString(None) | RESPResult::NullArray => Value::Boolean(false),
+        RESPResult::SimpleString(s) => Value::Table(reply_table(lua, "ok", lua.create_string(&s)?)?),
+        RESPResult::Error(e) => Value::Table(reply_table(lua, "err", lua.create_string(&e)?)?),
+        RESPResult::Array(items) => {
+            let table = lua.create_table()?;
+            for item in items {
+                table.raw_push(resp_to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        },
+    })
+}
+
+fn lua_to_resp(value: Value) -> RESPResult {
+    match value {
+        Value::Integer(i) => RESPResult::Integer(i),
+        // Redis truncates numbers to integers
+        Value::Number(n) => RESPResult::Integer(n as i64),
+        Value::String(s) => RESPResult::BulkString(Some(s.as_bytes().to_vec())),
+        Value::Boolean(true) => RESPResult::Integer(1),
+        Value::Table(t) => {
+            if let Ok(Value::String(e)) = t.raw_get::<Value>("err") {
+                return RESPResult::Error(e.to_string_lossy());
+            }
+            if let Ok(Value::String(s)) = t.raw_get::<Value>("ok") {
+                return RESPResult::SimpleString(s.to_string_lossy());
+            }
+
+            // an array ends at its first nil
+            let mut items = Vec::new();
+            for i in 1.. {
+                match t.raw_get::<Value>(i) {
+                    Ok(Value::Nil) | Err(_) => break,
+                    Ok(v) => items.push(lua_to_resp(v)),
+                }
+            }
+            RESPResult::Array(items)
+        },
+        _ => RESPResult::BulkString(None),
+    }
+}
+
+fn sha1_hex(data: &[u8]) -> String {
+    sha1_smol::Sha1::from(data).digest().to_string()
+}
+
+fn bulk_bytes(value: &RESPResult) -> Result<Vec<u8>, Error> {
+    match value {
+        RESPResult::BulkString(Some(b)) => Ok(b.clone()),
+        _ => Err(Error::Protocol("expected bulk string".to_string())),
+    }
+}
+
+type Args = Vec<Vec<u8>>;
+
+// splits EVAL style arguments after the script into KEYS and ARGV
+fn keys_and_args(data: &[RESPResult]) -> Result<(Args, Args), Error> {
+    let numkeys: i64 = bulk_to_string(&data[0])?.parse().map_err(|_| Error::NotAnInteger)?;
+    if numkeys < 0 {
+        return Err(Error::Other("Number of keys can't be negative".to_string()));
+    }
+
+    let rest = &data[1..];
+    if numkeys as usize > rest.len() {
+        return Err(Error::Other("Number of keys can't be greater than number of args".to_string()));
+    }
+
+    let (keys, argv) = rest.split_at(numkeys as usize);
+    let keys = keys.iter().map(bulk_bytes).collect::<Result<Vec<_>, _>>()?;
+    let argv = argv.iter().map(bulk_bytes).collect::<Result<Vec<_>, _>>()?;
+
+    Ok((keys, argv))
+}
+
+fn eval_generic(ctx: &mut Context<'_>, data: &[RESPResult], by_sha: bool, read_only: bool) -> Result<RESPResult, Error> {
+    let server = ctx.server();
+    let db = ctx.session().db();
+    let scripting = server.scripting();
+
+    let body = if by_sha {
+        let sha = bulk_to_string(&data[0])?.to_lowercase();
+        match scripting.lock_scripts().get(&sha) {
+            Some(body) => body.clone(),
+            None => return Err(Error::NoScript),
+        }
+    }
+    else {
+        // EVAL caches the script too, so EVALSHA works afterwards
+        let body = bulk_to_string(&data[0])?;
+        scripting.load(&body);
+        body
+    };
+
+    let (keys, argv) = keys_and_args(&data[1..])?;
+    scripting.eval(server, db, &body, &keys, &argv, read_only)
+}
+
+pub(crate) fn eval_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    eval_generic(ctx, data, false, false)
+}
+
+pub(crate) fn eval_ro_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    eval_generic(ctx, data, false, true)
+}
+
+pub(crate) fn evalsha_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    eval_generic(ctx, data, true, false)
+}
+
+pub(crate) fn evalsha_ro_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    eval_generic(ctx, data, true, true)
+}
+
+pub(crate) fn script_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let scripting = ctx.server().scripting();
+    let subcommand = bulk_to_string(&data[0])?.to_uppercase();
+    let args = &data[1..];
+
+    match subcommand.as_str() {
+        "LOAD" if args.len() == 1 => {
+            let sha = scripting.load(&bulk_to_string(&args[0])?);
+            Ok(RESPResult::BulkString(Some(sha.into_bytes())))
+        },
+        "EXISTS" if !args.is_empty() => {
+            let mut replies = Vec::new();
+            for sha in args {
+                replies.push(RESPResult::Integer(scripting.exists(&bulk_to_string(sha)?) as i64));
+            }
+            Ok(RESPResult::Array(replies))
+        },
+        "FLUSH" if args.len() <= 1 => {
+            // the cache is dropped at once either way
+            if let Some(mode) = args.first() {
+                let mode = bulk_to_string(mode)?.to_uppercase();
+                if mode != "ASYNC" && mode != "SYNC" {
+                    return Err(Error::Other("SCRIPT FLUSH only support SYNC|ASYNC option".to_string()));
+                }
+            }
+            scripting.flush();
+            Ok(RESPResult::SimpleString("OK".to_string()))
+        },
+        "KILL" if args.is_empty() => {
+            scripting.kill()?;
+            Ok(RESPResult::SimpleString("OK".to_string()))
+        },
+        "LOAD" | "EXISTS" | "FLUSH" | "KILL" => Err(Error::WrongArity(format!("script|{}", subcommand.to_lowercase()))),
+        _ => Err(Error::Other(format!("unknown subcommand '{}'. Try SCRIPT HELP.", subcommand.to_lowercase()))),
+    }
+}
+
+fn fcall_generic(ctx: &mut Context<'_>, data: &[RESPResult], read_only: bool) -> Result<RESPResult, Error> {
+    let server = ctx.server();
+    let name = bulk_to_string(&data[0])?;
+    let (keys, argv) = keys_and_args(&data[1..])?;
+    server.scripting().fcall(server, ctx.session().db(), &name, &keys, &argv, read_only)
+}
+
+pub(crate) fn fcall_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    fcall_generic(ctx, data, false)
+}
+
+pub(crate) fn fcall_ro_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    fcall_generic(ctx, data, true)
+}
+
+// FUNCTION DUMP payloads hold every library's code as a length prefixed string
+fn dump_payload(codes: &[String]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    for code in codes {
+        payload.extend_from_slice(format!("${}\r\n{code}\r\n", code.len()).as_bytes());
+    }
+    payload
+}
+
+fn parse_payload(mut payload: &[u8]) -> Option<Vec<String>> {
+    let mut codes = Vec::new();
+    while !payload.is_empty() {
+        let end = payload.windows(2).position(|w| w == b"\r\n")?;
+        let len: usize = std::str::from_utf8(payload[..end].strip_prefix(b"$")?).ok()?.parse().ok()?;
+
+        let rest = &payload[end + 2..];
+        if rest.len() < len + 2 || &rest[len..len + 2] != b"\r\n" {
+            return None;
+        }
+        codes.push(String::from_utf8(rest[..len].to_vec()).ok()?);
+        payload = &rest[len + 2..];
+    }
+    Some(codes)
+}
+
+fn bulk(s: &str) -> RESPResult {
+    RESPResult::BulkString(Some(s.as_bytes().to_vec()))
+}
+
+fn list_libraries(scripting: &Scripting, args: &[RESPResult]) -> Result<RESPResult, Error> {
+    let mut pattern = None;
+    let mut with_code = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match bulk_to_string(arg)?.to_uppercase().as_str() {
+            "WITHCODE" => with_code = true,
+            "LIBRARYNAME" if pattern.is_none() => match args.next() {
+                Some(p) => pattern = Some(bulk_to_string(p)?),
+                None => return Err(Error::Other("library name argument was not given".to_string())),
+            },
+            "LIBRARYNAME" => return Err(Error::Other("library name can be given only once".to_string())),
+            other => return Err(Error::Other(format!("Unknown argument {}", other.to_lowercase()))),
+        }
+    }
+
+    let libraries = scripting.lock_libraries();
+    let mut replies = Vec::new();
+    for (name, library) in libraries.iter() {
+        if let Some(pattern) = &pattern && !glob_match(pattern.as_bytes(), name.as_bytes()) {
+            continue;
+        }
+
+        let functions = library.functions.iter().map(|(function, f)| {
+            RESPResult::Array(vec![
+                bulk("name"),
+                bulk(function),
+                bulk("description"),
+                RESPResult::BulkString(f.description.as_ref().map(|d| d.as_bytes().to_vec())),
+                bulk("flags"),
+                RESPResult::Array(f.flags.iter().map(|flag| bulk(flag)).collect()),
+            ])
+        });
+
+        let mut reply = vec![
+            bulk("library_name"),
+            bulk(name),
+            bulk("engine"),
+            bulk("LUA"),
+            bulk("functions"),
+            RESPResult::Array(functions.collect()),
+        ];
+        if with_code {
+            reply.push(bulk("library_code"));
+            reply.push(bulk(&library.code));
+        }
+        replies.push(RESPResult::Array(reply));
+    }
+
+    Ok(RESPResult::Array(replies))
+}
+
+pub(crate) fn function_command(ctx: &mut Context<'_>, data: &[RESPResult]) -> Result<RESPResult, Error> {
+    let scripting = ctx.server().scripting();
+    let subcommand = bulk_to_string(&data[0])?.to_uppercase();
+    let args = &data[1..];
+
+    match subcommand.as_str() {
+        "LOAD" if !args.is_empty() && args.len() <= 2 => {
+            let replace = args.len() == 2;
+            if replace && !bulk_to_string(&args[0])?.eq_ignore_ascii_case("replace") {
+                return Err(Error::Other(format!("Unknown option given: {}", bulk_to_string(&args[0])?)));
+            }
+            let name = scripting.load_library(&bulk_to_string(&args[args.len() - 1])?, replace)?;
+            Ok(bulk(&name))
+        },
+        "DELETE" if args.len() == 1 => {
+            scripting.delete_library(&bulk_to_string(&args[0])?)?;
+            Ok(RESPResult::SimpleString("OK".to_string()))
+        },
+        "FLUSH" if args.len() <= 1 => {
+            if let Some(mode) = args.first() {
+                let mode = bulk_to_string(mode)?.to_uppercase();
+                if mode != "ASYNC" && mode != "SYNC" {
+                    return Err(Error::Other("FUNCTION FLUSH only supports SYNC|ASYNC option".to_string()));
+                }
+            }
+            scripting.flush_libraries();
+            Ok(RESPResult::SimpleString("OK".to_string()))
+        },
+        "LIST" => list_libraries(scripting, args),
+        "DUMP" if args.is_empty() => Ok(RESPResult::BulkString(Some(dump_payload(&scripting.library_codes())))),
+        "RESTORE" if !args.is_empty() && args.len() <= 2 => {
+            let policy = match args.get(1).map(bulk_to_string).transpose()?.map(|p| p.to_uppercase()).as_deref() {
+                None | Some("APPEND") => RestorePolicy::Append,
+                Some("REPLACE") => RestorePolicy::Replace,
+                Some("FLUSH") => RestorePolicy::Flush,
+                Some(_) => return Err(Error::Other("Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE.".to_string())),
+            };
+            let codes = match parse_payload(&bulk_bytes(&args[0])?) {
+                Some(codes) => codes,
+                None => return Err(Error::Other("payload version or checksum are wrong".to_string())),
+            };
+            scripting.restore_libraries(&codes, policy)?;
+            Ok(RESPResult::SimpleString("OK".to_string()))
+        },
+        "LOAD" | "DELETE" | "FLUSH" | "DUMP" | "RESTORE" => Err(Error::WrongArity(format!("function|{}", subcommand.to_lowercase()))),
+        _ => Err(Error::Other(format!("unknown subcommand '{}'. Try FUNCTION HELP.", subcommand.to_lowercase()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::command_router;
+    use crate::types::DB_TYPE;
+
+    fn server_with(config: Config) -> Server {
+        Server::new(config)
+    }
+
+    fn server() -> Server {
+        server_with(Config::default())
+    }
+
+    fn eval(server: &Server, script: &str, keys: &[&str], argv: &[&str]) -> Result<RESPResult, Error> {
+        let mut data = vec![bulk(script), bulk(&keys.len().to_string())];
+        data.extend(keys.iter().chain(argv).map(|s| bulk(s)));
+        command_router(server, "EVAL", &data)
+    }
+
+    #[test]
+    fn test_eval_converts_lua_values() {
+        let server = server();
+
+        assert_eq!(eval(&server, "return 1", &[], &[]), Ok(RESPResult::Integer(1)));
+        assert_eq!(eval(&server, "return 3.9", &[], &[]), Ok(RESPResult::Integer(3)));
+        assert_eq!(eval(&server, "return 'hi'", &[], &[]), Ok(bulk("hi")));
+        assert_eq!(eval(&server, "return true", &[], &[]), Ok(RESPResult::Integer(1)));
+        assert_eq!(eval(&server, "return false", &[], &[]), Ok(RESPResult::BulkString(None)));
+        assert_eq!(eval(&server, "return nil", &[], &[]), Ok(RESPResult::BulkString(None)));
+        assert_eq!(
+            eval(&server, "return {1, 'a', {2}, nil, 3}", &[], &[]),
+            Ok(RESPResult::Array(vec![RESPResult::Integer(1), bulk("a"), RESPResult::Array(vec![RESPResult::Integer(2)])])),
+        );
+        assert_eq!(eval(&server, "return redis.status_reply('FINE')", &[], &[]), Ok(RESPResult::SimpleString("FINE".to_string())));
+        assert_eq!(eval(&server, "return {err='MY error'}", &[], &[]), Ok(RESPResult::Error("MY error".to_string())));
+        assert_eq!(eval(&server, "return redis.sha1hex('')", &[], &[]), Ok(bulk("da39a3ee5e6b4b0d3255bfef95601890afd80709")));
+    }
+
+    #[test]
+    fn test_eval_keys_argv_and_redis_call() {
+        let server = server();
+
+        let script = "redis.call('SET', KEYS[1], ARGV[1]); return redis.call('EXISTS', KEYS[1], KEYS[2])";
+        assert_eq!(eval(&server, script, &["a", "b"], &["v"]), Ok(RESPResult::Integer(1)));
+        assert_eq!(server.db().get("a"), Some(DB_TYPE::Str(b"v".to_vec())));
+
+        assert_eq!(eval(&server, "return {KEYS[1], ARGV[1], ARGV[2]}", &["k"], &["x", "y"]),
+            Ok(RESPResult::Array(vec![bulk("k"), bulk("x"), bulk("y")])));
+
+        // numbers are passed as strings
+        eval(&server, "redis.call('rpush', 'l', 1, 2.5)", &[], &[]).unwrap();
+        assert_eq!(server.db().lrange("l", 0, -1), Ok(vec![DB_TYPE::Int(1), DB_TYPE::Str(b"2.5".to_vec())]));
+
+        // globals set by one script do not leak into the next
+        assert_eq!(eval(&server, "x = 5; return x", &[], &[]), Ok(RESPResult::Integer(5)));
+        assert_eq!(eval(&server, "return x", &[], &[]), Ok(RESPResult::BulkString(None)));
+
+        // nor can one change the libraries the next one uses
+        assert!(matches!(eval(&server, "string.rep = nil", &[], &[]), Err(Error::Other(e)) if e.contains("Attempt to modify a readonly table")));
+        assert!(eval(&server, "_G.math.floor = nil", &[], &[]).is_err());
+        assert!(eval(&server, "setmetatable(table, {})", &[], &[]).is_err());
+        assert_eq!(eval(&server, "return string.rep('a', 2)", &[], &[]), Ok(bulk("aa")));
+        assert_eq!(eval(&server, "return ('b'):rep(2) .. math.floor(1.5)", &[], &[]), Ok(bulk("bb1")));
+
+        // nor reach the shared tables behind the environment and strings
+        assert!(eval(&server, "getmetatable('').__index.upper = nil", &[], &[]).is_err());
+        assert!(eval(&server, "getmetatable(_G).__index.tostring = nil", &[], &[]).is_err());
+        assert!(eval(&server, "setmetatable(_G, {})", &[], &[]).is_err());
+        assert_eq!(eval(&server, "load('y = 1')(); return y", &[], &[]), Ok(RESPResult::Integer(1)));
+        assert!(eval(&server, "load('tostring = nil')()", &[], &[]).is_ok());
+        assert_eq!(eval(&server, "return y", &[], &[]), Ok(RESPResult::BulkString(None)));
+        assert_eq!(eval(&server, "return ('a'):upper() .. tostring(1)", &[], &[]), Ok(bulk("A1")));
+    }
+
+    #[test]
+    fn test_eval_errors() {
+        let server = server();
+        server.db().set("s", DB_TYPE::Str(b"x".to_vec()));
+
+        // redis.call raises the command error, redis.pcall hands it back
+        assert_eq!(eval(&server, "return redis.call('LPUSH', 's', 1)", &[], &[]), Ok(RESPResult::Error(Error::WrongType.to_string())));
+        assert_eq!(eval(&server, "local r = redis.pcall('LPUSH', 's', 1); return r['err']", &[], &[]), Ok(bulk(&Error::WrongType.to_string())));
+        assert_eq!(eval(&server, "return redis.call('NOPE')", &[], &[]), Ok(RESPResult::Error("ERR unknown command 'NOPE'".to_string())));
+        assert_eq!(eval(&server, "return redis.call('SAVE')", &[], &[]),
+            Ok(RESPResult::Error("ERR This Redis command is not allowed from script".to_string())));
+
+        assert!(matches!(eval(&server, "return +", &[], &[]), Err(Error::Other(e)) if e.starts_with("Error compiling script")));
+        assert!(matches!(eval(&server, "return nil + 1", &[], &[]), Err(Error::Other(e)) if e.starts_with("Error running script")));
+
+        assert_eq!(command_router(&server, "EVAL", &[bulk("return 1"), bulk("x")]), Err(Error::NotAnInteger));
+        assert_eq!(command_router(&server, "EVAL", &[bulk("return 1"), bulk("-1")]),
+            Err(Error::Other("Number of keys can't be negative".to_string())));
+        assert_eq!(command_router(&server, "EVAL", &[bulk("return 1"), bulk("2"), bulk("a")]),
+            Err(Error::Other("Number of keys can't be greater than number of args".to_string())));
+    }
+
+    #[test]
+    fn test_read_only_scripts() {
+        let server = server();
+        server.db().set("a", DB_TYPE::Int(1));
+
+        let reply = command_router(&server, "EVAL_RO", &[bulk("return redis.call('SET', KEYS[1], 'x')"), bulk("1"), bulk("a")]);
+        assert_eq!(reply, Ok(RESPResult::Error("ERR Write commands are not allowed from read-only scripts.".to_string())));
+        assert_eq!(server.db().get("a"), Some(DB_TYPE::Int(1)));
+
+        let reply = command_router(&server, "EVAL_RO", &[bulk("return redis.call('EXISTS', KEYS[1])"), bulk("1"), bulk("a")]);
+        assert_eq!(reply, Ok(RESPResult::Integer(1)));
+    }
+
+    #[test]
+    fn test_script_cache() {
+        let server = server();
+        let sha = sha1_hex(b"return ARGV[1]");
+
+        assert_eq!(command_router(&server, "EVALSHA", &[bulk(&sha), bulk("0"), bulk("a")]), Err(Error::NoScript));
+        assert_eq!(command_router(&server, "SCRIPT", &[bulk("load"), bulk("return ARGV[1]")]), Ok(bulk(&sha)));
+        assert_eq!(command_router(&server, "EVALSHA", &[bulk(&sha.to_uppercase()), bulk("0"), bulk("a")]), Ok(bulk("a")));
+        assert_eq!(command_router(&server, "EVALSHA_RO", &[bulk(&sha), bulk("0"), bulk("b")]), Ok(bulk("b")));
+
+        assert_eq!(
+            command_router(&server, "SCRIPT", &[bulk("EXISTS"), bulk(&sha), bulk("nope")]),
+            Ok(RESPResult::Array(vec![RESPResult::Integer(1), RESPResult::Integer(0)])),
+        );
+
+        assert_eq!(command_router(&server, "SCRIPT", &[bulk("FLUSH"), bulk("ASYNC")]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(command_router(&server, "EVALSHA", &[bulk(&sha), bulk("0")]), Err(Error::NoScript));
+
+        // EVAL caches what it runs
+        eval(&server, "return ARGV[1]", &[], &["c"]).unwrap();
+        assert_eq!(command_router(&server, "EVALSHA", &[bulk(&sha), bulk("0"), bulk("c")]), Ok(bulk("c")));
+    }
+
+    #[test]
+    fn test_time_limit_stops_script() {
+        let server = server_with(Config {
+            script_time_limit: Some(Duration::from_millis(50)),
+            ..Config::default()
+        });
+
+        let reply = eval(&server, "while true do end", &[], &[]);
+        assert_eq!(reply, Ok(RESPResult::Error("ERR Script exceeded the configured time limit".to_string())));
+
+        // the gate is free again
+        assert_eq!(eval(&server, "return 1", &[], &[]), Ok(RESPResult::Integer(1)));
+    }
+
+    #[test]
+    fn test_kill_depends_on_running_script() {
+        let server = server();
+        let scripting = server.scripting();
+
+        assert_eq!(scripting.kill(), Err(Error::NotBusy));
+
+        let (permit, script) = scripting.start().unwrap();
+        script.wrote.store(true, Ordering::SeqCst);
+        assert_eq!(scripting.kill(), Err(Error::Unkillable));
+
+        script.wrote.store(false, Ordering::SeqCst);
+        assert_eq!(scripting.kill(), Ok(()));
+        assert!(script.killed.load(Ordering::SeqCst));
+
+        drop(permit);
+        assert_eq!(scripting.kill(), Err(Error::NotBusy));
+    }
+
+    #[test]
+    fn test_commands_skip_the_gate_without_scripts() {
+        let server = server();
+        let scripting = server.scripting();
+
+        // with the gate held, commands still run while no script is around
+        let gate = scripting.lock_gate();
+        let permits: Vec<_> = (0..3).map(|_| scripting.enter().unwrap()).collect();
+        assert_eq!(scripting.commands.load(Ordering::SeqCst), 3);
+        drop(gate);
+
+        // a script waits until they are done
+        std::thread::scope(|s| {
+            let script = s.spawn(|| scripting.start().map(|(permit, _)| drop(permit)));
+            while scripting.scripts_pending.load(Ordering::SeqCst) == 0 {
+                std::thread::yield_now();
+            }
+            drop(permits);
+            assert!(script.join().unwrap().is_ok());
+        });
+
+        assert_eq!(scripting.scripts_pending.load(Ordering::SeqCst), 0);
+        drop(scripting.enter().unwrap());
+        assert_eq!(scripting.commands.load(Ordering::SeqCst), 0);
+    }
+
+    const LIB: &str = "#!lua name=mylib
+redis.register_function('set_it', function(keys, args) return redis.call('SET', keys[1], args[1]) end)
+redis.register_function{function_name='get_it', callback=function(keys) return redis.call('EXISTS', keys[1]) end, flags={'no-writes'}, description='checks'}
+";
+
+    fn function(server: &Server, args: &[&str]) -> Result<RESPResult, Error> {
+        let data: Vec<RESPResult> = args.iter().map(|s| bulk(s)).collect();
+        command_router(server, "FUNCTION", &data)
+    }
+
+    fn fcall(server: &Server, command: &str, name: &str, keys: &[&str], argv: &[&str]) -> Result<RESPResult, Error> {
+        let mut data = vec![bulk(name), bulk(&keys.len().to_string())];
+        data.extend(keys.iter().chain(argv).map(|s| bulk(s)));
+        command_router(server, command, &data)
+    }
+
+    #[test]
+    fn test_function_load_and_fcall() {
+        let server = server();
+
+        assert_eq!(function(&server, &["LOAD", LIB]), Ok(bulk("mylib")));
+        assert_eq!(fcall(&server, "FCALL", "set_it", &["a"], &["v"]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(server.db().get("a"), Some(DB_TYPE::Str(b"v".to_vec())));
+        assert_eq!(fcall(&server, "FCALL_RO", "get_it", &["a"], &[]), Ok(RESPResult::Integer(1)));
+
+        assert_eq!(fcall(&server, "FCALL_RO", "set_it", &["a"], &["w"]),
+            Err(Error::Other("Can not execute a script with write flag using *_ro command.".to_string())));
+        assert_eq!(fcall(&server, "FCALL", "nope", &[], &[]), Err(Error::Other("Function not found".to_string())));
+
+        // names are unique, unless the library is replaced
+        assert_eq!(function(&server, &["LOAD", LIB]), Err(Error::Other("Library 'mylib' already exists".to_string())));
+        let other = "#!lua name=other\nredis.register_function('set_it', function() return 1 end)";
+        assert_eq!(function(&server, &["LOAD", other]), Err(Error::Other("Function set_it already exists".to_string())));
+
+        let replaced = "#!lua name=mylib\nredis.register_function('set_it', function() return 2 end)";
+        assert_eq!(function(&server, &["LOAD", "REPLACE", replaced]), Ok(bulk("mylib")));
+        assert_eq!(fcall(&server, "FCALL", "set_it", &[], &[]), Ok(RESPResult::Integer(2)));
+        assert_eq!(fcall(&server, "FCALL", "get_it", &["a"], &[]), Err(Error::Other("Function not found".to_string())));
+
+        assert_eq!(function(&server, &["DELETE", "mylib"]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(function(&server, &["DELETE", "mylib"]), Err(Error::Other("Library not found".to_string())));
+    }
+
+    #[test]
+    fn test_function_load_errors() {
+        let server = server();
+        let load = |code: &str| function(&server, &["LOAD", code]);
+        let err = |s: &str| Err(Error::Other(s.to_string()));
+
+        assert_eq!(load("return 1"), err("Missing library metadata"));
+        assert_eq!(load("#!js name=x\n"), err("Engine 'js' not found"));
+        assert_eq!(load("#!lua\n"), err("Library name was not given"));
+        assert_eq!(load("#!lua name=x foo=bar\n"), err("Invalid metadata value given: foo=bar"));
+        assert_eq!(load("#!lua name=x\nlocal a = 1"), err("No functions registered"));
+        assert_eq!(load("#!lua name=x\nredis.register_function('f', function() end, 1)"),
+            err("Error registering functions: wrong number of arguments to redis.register_function"));
+        assert_eq!(load("#!lua name=x\nredis.register_function{function_name='f', callback=function() end, flags={'bad'}}"),
+            err("Error registering functions: unknown flag given"));
+        assert_eq!(load("#!lua name=x\nwhile true do end"), err("Error registering functions: FUNCTION LOAD timeout"));
+        assert!(matches!(load("#!lua name=x\nredis.call('SET', 'a', 'b')"), Err(Error::Other(e)) if e.starts_with("Error registering functions")));
+
+        // functions can not register more functions once loaded
+        let code = "#!lua name=x\nredis.register_function('f', function() redis.register_function('g', function() end) end)";
+        assert_eq!(load(code), Ok(bulk("x")));
+        assert!(matches!(fcall(&server, "FCALL", "f", &[], &[]), Err(Error::Other(e)) if e.starts_with("Error running script")));
+        assert_eq!(server.scripting().library_codes(), vec![code.to_string()]);
+    }
+
+    #[test]
+    fn test_function_list_dump_restore() {
+        let server = server();
+        function(&server, &["LOAD", LIB]).unwrap();
+        function(&server, &["LOAD", "#!lua name=another\nredis.register_function('f', function() return 1 end)"]).unwrap();
+
+        let listed = function(&server, &["LIST", "LIBRARYNAME", "my*", "WITHCODE"]).unwrap();
+        let get_it = RESPResult::Array(vec![
+            bulk("name"), bulk("get_it"),
+            bulk("description"), bulk("checks"),
+            bulk("flags"), RESPResult::Array(vec![bulk("no-writes")]),
+        ]);
+        let set_it = RESPResult::Array(vec![
+            bulk("name"), bulk("set_it"),
+            bulk("description"), RESPResult::BulkString(None),
+            bulk("flags"), RESPResult::Array(vec![]),
+        ]);
+        assert_eq!(listed, RESPResult::Array(vec![RESPResult::Array(vec![
+            bulk("library_name"), bulk("mylib"),
+            bulk("engine"), bulk("LUA"),
+            bulk("functions"), RESPResult::Array(vec![get_it, set_it]),
+            bulk("library_code"), bulk(LIB),
+        ])]));
+
+        let payload = match function(&server, &["DUMP"]) {
+            Ok(RESPResult::BulkString(Some(payload))) => payload,
+            other => panic!("unexpected DUMP reply {other:?}"),
+        };
+        let restore = |policy: &[&str]| {
+            let mut data = vec![bulk("RESTORE"), RESPResult::BulkString(Some(payload.clone()))];
+            data.extend(policy.iter().map(|p| bulk(p)));
+            command_router(&server, "FUNCTION", &data)
+        };
+
+        assert_eq!(restore(&[]), Err(Error::Other("Library 'another' already exists".to_string())));
+        assert_eq!(restore(&["REPLACE"]), Ok(RESPResult::SimpleString("OK".to_string())));
+
+        assert_eq!(function(&server, &["FLUSH"]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(function(&server, &["LIST"]), Ok(RESPResult::Array(vec![])));
+        assert_eq!(restore(&["APPEND"]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(fcall(&server, "FCALL", "f", &[], &[]), Ok(RESPResult::Integer(1)));
+
+        assert_eq!(function(&server, &["RESTORE", "garbage"]), Err(Error::Other("payload version or checksum are wrong".to_string())));
+        assert_eq!(restore(&["MERGE"]),
+            Err(Error::Other("Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE.".to_string())));
+    }
+
+    #[test]
+    fn test_libraries_survive_save_and_load() {
+        let path = std::env::temp_dir().join(format!("rs-redis-{}-functions.rdb", std::process::id()));
+        let config = Config { dbfilename: path.to_string_lossy().into_owned(), ..Config::default() };
+
+        let server = server_with(config.clone());
+        function(&server, &["LOAD", LIB]).unwrap();
+        assert_eq!(command_router(&server, "SAVE", &[]), Ok(RESPResult::SimpleString("OK".to_string())));
+
+        let restarted = server_with(config.clone());
+        assert_eq!(command_router(&restarted, "LOAD", &[bulk(&config.dbfilename)]), Ok(RESPResult::SimpleString("OK".to_string())));
+        assert_eq!(restarted.scripting().library_codes(), vec![LIB.to_string()]);
+        assert_eq!(fcall(&restarted, "FCALL", "set_it", &["k"], &["v"]), Ok(RESPResult::SimpleString("OK".to_string())));
+    }
+}
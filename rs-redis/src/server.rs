@@ -0,0 +1,167 @@
+use crate::blocking::Blocking;
+use crate::command::{Command, CommandTable};
+use crate::error::Error;
+use crate::config::Config;
+use crate::db::Db;
+use crate::expire::ExpireStats;
+use crate::module::Module;
+use crate::scripting::Scripting;
+use crate::network;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+// owns everything a running instance needs, so two servers (or two tests)
+// never share a keyspace
+pub struct Server {
+    dbs: Vec<Db>,
+    // CONFIG SET changes it while the server runs
+    config: RwLock<Config>,
+    commands: RwLock<CommandTable>,
+    modules: Mutex<Vec<Module>>,
+    scripting: Scripting,
+    blocking: Blocking,
+    expire_stats: Mutex<ExpireStats>,
+}
+
+impl Server {
+    // binds the configured address (port 0 picks a free port) and serves it in
+    // the background; the returned handle stops the server when dropped
+    pub async fn start(config: Config) -> std::io::Result<ServerHandle> {
+        let server = Arc::new(Server::new(config));
+        let address = server.config().address();
+        let listener = TcpListener::bind(address).await?;
+        let addr = listener.local_addr()?;
+
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let task = tokio::spawn(network::serve(listener, server.clone(), shutdown_rx));
+
+        Ok(ServerHandle {
+            addr,
+            server,
+            shutdown,
+            task: Some(task),
+        })
+    }
+
+    pub fn new(config: Config) -> Server {
+        // a custom type registered with one database can be loaded into any of them
+        let first = Db::new();
+        let mut dbs: Vec<Db> = (1..config.databases).map(|_| first.sharing_types()).collect();
+        dbs.insert(0, first);
+
+        Server {
+            dbs,
+            scripting: Scripting::new(&config),
+            blocking: Blocking::new(),
+            config: RwLock::new(config),
+            commands: RwLock::new(CommandTable::new()),
+            modules: Mutex::new(Vec::new()),
+            expire_stats: Mutex::new(ExpireStats::default()),
+        }
+    }
+
+    // database 0, where every connection starts
+    pub fn db(&self) -> &Db {
+        &self.dbs[0]
+    }
+
+    pub fn databases(&self) -> &[Db] {
+        &self.dbs
+    }
+
+    // estimated bytes of every database, which all count them together
+    pub fn used_memory(&self) -> usize {
+        self.db().used_memory()
+    }
+
+    pub fn scripting(&self) -> &Scripting {
+        &self.scripting
+    }
+
+    // clients parked by blocking list commands
+    pub fn blocking(&self) -> &Blocking {
+        &self.blocking
+    }
+
+    pub fn config(&self) -> RwLockReadGuard<'_, Config> {
+        self.config.read().expect("config lock failed")
+    }
+
+    // applies every setting or, if one is invalid, none of them
+    pub fn set_config(&self, settings: &[(String, String)]) -> Result<(), String> {
+        let mut config = self.config.write().expect("config lock failed");
+        let mut changed = config.clone();
+        for (name, value) in settings {
+            changed.set(name, value)?;
+        }
+        *config = changed;
+        Ok(())
+    }
+
+    pub fn commands(&self) -> RwLockReadGuard<'_, CommandTable> {
+        self.commands.read().expect("command table lock failed")
+    }
+
+    // adds a command from outside the crate, also allowed while the server is running
+    pub fn register_command(&self, command: impl Command + 'static) -> Result<(), Error> {
+        self.commands
+            .write()
+            .expect("command table lock failed")
+            .register(Arc::new(command))
+    }
+
+    pub(crate) fn register_shared_command(&self, command: Arc<dyn Command>) -> Result<(), Error> {
+        self.commands.write().expect("command table lock failed").register(command)
+    }
+
+    // returns false if there was no such command
+    pub fn unregister_command(&self, name: &str) -> bool {
+        self.commands.write().expect("command table lock failed").unregister(name)
+    }
+
+    pub fn expire_stats(&self) -> MutexGuard<'_, ExpireStats> {
+        self.expire_stats.lock().expect("expire stats lock failed")
+    }
+
+    pub(crate) fn modules(&self) -> MutexGuard<'_, Vec<Module>> {
+        self.modules.lock().expect("module list lock failed")
+    }
+}
+
+
+pub struct ServerHandle {
+    addr: SocketAddr,
+    server: Arc<Server>,
+    shutdown: watch::Sender<bool>,
+    task: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+impl ServerHandle {
+    // the address the server is actually listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn server(&self) -> &Arc<Server> {
+        &self.server
+    }
+
+    // stop accepting, close every connection and wait for the listener to be released
+    pub async fn shutdown(mut self) -> std::io::Result<()> {
+        self.shutdown.send_replace(true);
+
+        match self.task.take() {
+            Some(task) => task.await.unwrap_or(Ok(())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.shutdown.send_replace(true);
+    }
+}
@@ -1,3 +1,8 @@
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::ops::Deref;
+
 #[derive(Debug, PartialEq)]
 pub enum RESPResult {
     SimpleString(String),
@@ -5,12 +10,297 @@ pub enum RESPResult {
     Integer(i64),
     BulkString(Option<Vec<u8>>),
     Array(Vec<RESPResult>),
+    // *-1, the nil of commands that otherwise reply with an array
+    NullArray,
 }
 
+#[allow(non_camel_case_types)]
 #[derive(Debug, PartialEq, Clone)]
 pub enum DB_TYPE {
     Int(i64),
-    Str(String),
-    Array(Vec<DB_TYPE>)
+    // strings are binary safe, so kept as raw bytes
+    Str(Vec<u8>),
+    Array(List),
+    Hash(HashMap<String, String>),
+    Custom(Box<dyn CustomValue>),
+}
+
+// a value type defined outside rs-redis, stored with DB_TYPE::Custom
+pub trait CustomType: Clone + PartialEq + Debug + Send + Sync + 'static {
+    // reported to clients, e.g. by WRONGTYPE checks and TYPE
+    fn type_name(&self) -> &str;
+
+    // the snapshot encoding, None if the type is not saved
+    fn save(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    // estimated bytes of the value, counted towards used_memory after every
+    // change, so it should be cheap; by default only its inline size
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
+}
+
+// object safe view of a CustomType, implemented for every CustomType
+pub trait CustomValue: Debug + Send + Sync {
+    fn type_name(&self) -> &str;
+    fn save(&self) -> Option<Vec<u8>>;
+    fn memory_usage(&self) -> usize;
+    fn clone_value(&self) -> Box<dyn CustomValue>;
+    fn eq_value(&self, other: &dyn CustomValue) -> bool;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: CustomType> CustomValue for T {
+    fn type_name(&self) -> &str {
+        CustomType::type_name(self)
+    }
+
+    fn save(&self) -> Option<Vec<u8>> {
+        CustomType::save(self)
+    }
+
+    fn memory_usage(&self) -> usize {
+        CustomType::memory_usage(self)
+    }
+
+    fn clone_value(&self) -> Box<dyn CustomValue> {
+        Box::new(self.clone())
+    }
+
+    fn eq_value(&self, other: &dyn CustomValue) -> bool {
+        other.as_any().downcast_ref::<T>() == Some(self)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Clone for Box<dyn CustomValue> {
+    fn clone(&self) -> Self {
+        self.clone_value()
+    }
 }
 
+impl PartialEq for Box<dyn CustomValue> {
+    fn eq(&self, other: &Self) -> bool {
+        self.eq_value(other.as_ref())
+    }
+}
+
+// elements MEMORY USAGE looks at in a big value unless told otherwise
+pub const MEMORY_SAMPLES: usize = 5;
+
+impl DB_TYPE {
+    pub fn custom<T: CustomType>(value: T) -> DB_TYPE {
+        DB_TYPE::Custom(Box::new(value))
+    }
+
+    // a string value, kept as an Int when it is exactly an integer's decimal text
+    pub fn string(s: Vec<u8>) -> DB_TYPE {
+        // no integer is longer than i64::MIN
+        if s.len() > 20 {
+            return DB_TYPE::Str(s);
+        }
+        match std::str::from_utf8(&s).ok().and_then(|t| t.parse::<i64>().ok()) {
+            Some(i) if i.to_string().as_bytes() == s => DB_TYPE::Int(i),
+            _ => DB_TYPE::Str(s),
+        }
+    }
+
+    // the same value, with a string kept the way DB_TYPE::string keeps it, so
+    // equal text is always stored, and compares, the same way
+    pub fn canonical(self) -> DB_TYPE {
+        match self {
+            DB_TYPE::Str(s) => DB_TYPE::string(s),
+            other => other,
+        }
+    }
+
+    // the bytes of a string value, an Int as its decimal text; None for other types
+    pub fn bytes(&self) -> Option<Vec<u8>> {
+        match self {
+            DB_TYPE::Int(i) => Some(i.to_string().into_bytes()),
+            DB_TYPE::Str(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+
+    // estimated bytes the value owns on the heap, not counting the DB_TYPE itself
+    pub fn memory_usage(&self) -> usize {
+        self.sampled_memory_usage(0)
+    }
+
+    // like memory_usage, but hashes are estimated from their first samples
+    // fields when they have more; 0 looks at all of them
+    pub fn sampled_memory_usage(&self, samples: usize) -> usize {
+        let extrapolate = |sizes: Vec<usize>, len: usize| match sizes.len() {
+            0 => 0,
+            n => sizes.iter().sum::<usize>() * len / n,
+        };
+        let take = |len: usize| if samples == 0 { len } else { samples.min(len) };
+
+        match self {
+            DB_TYPE::Int(_) => 0,
+            DB_TYPE::Str(s) => s.capacity(),
+            // a list knows its size, so is never sampled
+            DB_TYPE::Array(list) => list.memory_usage(),
+            // each field also costs a map slot of two strings and a control byte
+            DB_TYPE::Hash(h) => {
+                let sizes = h.iter().take(take(h.len())).map(|(k, v)| k.capacity() + v.capacity()).collect();
+                h.capacity() * (2 * std::mem::size_of::<String>() + 1) + extrapolate(sizes, h.len())
+            },
+            DB_TYPE::Custom(c) => c.memory_usage(),
+        }
+    }
+
+    // the encoding OBJECT ENCODING reports, as Redis would pick it
+    pub fn encoding(&self) -> &'static str {
+        let small = |len: usize, longest: usize| len <= 128 && longest <= 64;
+
+        match self {
+            DB_TYPE::Int(_) => "int",
+            DB_TYPE::Str(s) if s.len() <= 44 => "embstr",
+            DB_TYPE::Str(_) => "raw",
+            DB_TYPE::Array(arr) => {
+                let longest = arr.iter().map(|v| v.memory_usage()).max().unwrap_or(0);
+                if small(arr.len(), longest) { "listpack" } else { "quicklist" }
+            },
+            DB_TYPE::Hash(h) => {
+                let longest = h.iter().map(|(k, v)| k.len().max(v.len())).max().unwrap_or(0);
+                if small(h.len(), longest) { "listpack" } else { "hashtable" }
+            },
+            DB_TYPE::Custom(_) => "raw",
+        }
+    }
+
+    // the name TYPE reports
+    pub fn type_name(&self) -> &str {
+        match self {
+            DB_TYPE::Int(_) | DB_TYPE::Str(_) => "string",
+            DB_TYPE::Array(_) => "list",
+            DB_TYPE::Hash(_) => "hash",
+            DB_TYPE::Custom(c) => c.type_name(),
+        }
+    }
+}
+
+// a list value: a deque, so pushes and pops at either end are O(1), that also
+// keeps the heap bytes of its elements so its size is known without a walk.
+// reads go through the deque, changes through the methods below, which store
+// every element in its canonical form
+#[derive(Debug, Clone, Default)]
+pub struct List {
+    items: VecDeque<DB_TYPE>,
+    // memory_usage of every element
+    heap: usize,
+}
+
+impl List {
+    pub fn new() -> List {
+        List::default()
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.items.capacity() * std::mem::size_of::<DB_TYPE>() + self.heap
+    }
+
+    pub fn push_front(&mut self, v: DB_TYPE) {
+        let v = v.canonical();
+        self.heap += v.memory_usage();
+        self.items.push_front(v);
+    }
+
+    pub fn push_back(&mut self, v: DB_TYPE) {
+        let v = v.canonical();
+        self.heap += v.memory_usage();
+        self.items.push_back(v);
+    }
+
+    pub fn pop_front(&mut self) -> Option<DB_TYPE> {
+        self.items.pop_front().inspect(|v| self.heap -= v.memory_usage())
+    }
+
+    pub fn pop_back(&mut self) -> Option<DB_TYPE> {
+        self.items.pop_back().inspect(|v| self.heap -= v.memory_usage())
+    }
+
+    pub fn insert(&mut self, i: usize, v: DB_TYPE) {
+        let v = v.canonical();
+        self.heap += v.memory_usage();
+        self.items.insert(i, v);
+    }
+
+    pub fn remove(&mut self, i: usize) -> Option<DB_TYPE> {
+        self.items.remove(i).inspect(|v| self.heap -= v.memory_usage())
+    }
+
+    // replaces the element at i, which must be in range
+    pub fn set(&mut self, i: usize, v: DB_TYPE) {
+        let v = v.canonical();
+        self.heap = self.heap - self.items[i].memory_usage() + v.memory_usage();
+        self.items[i] = v;
+    }
+
+    // keeps only the elements f returns true for, visiting them in order
+    pub fn retain(&mut self, mut f: impl FnMut(&DB_TYPE) -> bool) {
+        let heap = &mut self.heap;
+        self.items.retain(|v| {
+            let keep = f(v);
+            if !keep {
+                *heap -= v.memory_usage();
+            }
+            keep
+        });
+    }
+
+    // drops every element from len on
+    pub fn truncate(&mut self, len: usize) {
+        while self.items.len() > len {
+            self.pop_back();
+        }
+    }
+}
+
+impl Deref for List {
+    type Target = VecDeque<DB_TYPE>;
+
+    fn deref(&self) -> &VecDeque<DB_TYPE> {
+        &self.items
+    }
+}
+
+impl PartialEq for List {
+    fn eq(&self, other: &Self) -> bool {
+        self.items == other.items
+    }
+}
+
+impl PartialEq<Vec<DB_TYPE>> for List {
+    fn eq(&self, other: &Vec<DB_TYPE>) -> bool {
+        self.items == *other
+    }
+}
+
+impl FromIterator<DB_TYPE> for List {
+    fn from_iter<I: IntoIterator<Item = DB_TYPE>>(iter: I) -> List {
+        let mut list = List::new();
+        for v in iter {
+            list.push_back(v);
+        }
+        list
+    }
+}
+
+impl From<Vec<DB_TYPE>> for List {
+    fn from(values: Vec<DB_TYPE>) -> List {
+        values.into_iter().collect()
+    }
+}
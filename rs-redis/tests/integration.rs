@@ -3,19 +3,37 @@ mod tests {
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::TcpStream;
     use std::time::Duration;
-    use rs_redis::network;
+    use rs_redis::config::Config;
+    use rs_redis::command::{Command, CommandFlags, Context};
+    use rs_redis::error::Error;
+    use rs_redis::server::{Server, ServerHandle};
+    use rs_redis::types::{CustomType, RESPResult};
     use std::fs;
+    use std::sync::OnceLock;
+
+    fn test_config(name: &str) -> Config {
+        let dbfilename = std::env::temp_dir()
+            .join(format!("rs-redis-it-{}-{name}.rdb", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+
+        Config {
+            port: 0,
+            dbfilename,
+            ..Config::default()
+        }
+    }
+
+    async fn start(name: &str) -> ServerHandle {
+        Server::start(test_config(name)).await.expect("server should start")
+    }
 
     #[tokio::test]
     async fn test_simple_set_and_get() {
         // Start server
-        tokio::spawn(async {
-            network::start_network().await.ok();
-        });
-
-        tokio::time::sleep(Duration::from_millis(100)).await;
+        let handle = start("set_get").await;
 
-        let stream = TcpStream::connect("127.0.0.1:6379").await.unwrap();
+        let stream = TcpStream::connect(handle.addr()).await.unwrap();
         let (mut reader, mut writer) = stream.into_split();
 
         // Send SET command: *3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n
@@ -42,36 +60,32 @@ mod tests {
     #[tokio::test]
     async fn test_save_creates_rdb_file() {
         // Start server
-        tokio::spawn(async {
-            rs_redis::network::start_network().await.ok();
-        });
+        let handle = start("save").await;
 
-        tokio::time::sleep(Duration::from_millis(100)).await;
-
-        let stream = TcpStream::connect("127.0.0.1:6379").await.unwrap();
+        let stream = TcpStream::connect(handle.addr()).await.unwrap();
         let (mut reader, mut writer) = stream.into_split();
 
         // Insert a String value
         writer.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nstr\r\n$5\r\nhello\r\n").await.unwrap();
-        reader.read(&mut [0u8; 64]).await.unwrap();
+        assert!(reader.read(&mut [0u8; 64]).await.unwrap() > 0);
 
         // Insert an Int value
         writer.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nint\r\n$2\r\n42\r\n").await.unwrap();
-        reader.read(&mut [0u8; 64]).await.unwrap();
+        assert!(reader.read(&mut [0u8; 64]).await.unwrap() > 0);
 
 
         // Insert an array using LPUSH
         writer.write_all(b"*3\r\n$5\r\nLPUSH\r\n$5\r\narray\r\n$1\r\n1\r\n").await.unwrap();
-        reader.read(&mut [0u8; 64]).await.unwrap();
+        assert!(reader.read(&mut [0u8; 64]).await.unwrap() > 0);
 
         writer.write_all(b"*3\r\n$5\r\nLPUSH\r\n$5\r\narray\r\n$1\r\n2\r\n").await.unwrap();
-        reader.read(&mut [0u8; 64]).await.unwrap();
+        assert!(reader.read(&mut [0u8; 64]).await.unwrap() > 0);
 
         writer.write_all(b"*3\r\n$5\r\nLPUSH\r\n$5\r\narray\r\n$1\r\n3\r\n").await.unwrap();
-        reader.read(&mut [0u8; 64]).await.unwrap();
+        assert!(reader.read(&mut [0u8; 64]).await.unwrap() > 0);
 
         writer.write_all(b"*3\r\n$5\r\nLPUSH\r\n$5\r\narray\r\n$1\r\na\r\n").await.unwrap();
-        reader.read(&mut [0u8; 64]).await.unwrap();
+        assert!(reader.read(&mut [0u8; 64]).await.unwrap() > 0);
 
         // Send the SAVE command
         writer.write_all(b"*1\r\n$4\r\nSAVE\r\n").await.unwrap();
@@ -82,12 +96,422 @@ mod tests {
 
         assert!(reply.contains("+OK"));
 
-        // Give the server a moment to write the file
-        tokio::time::sleep(Duration::from_millis(100)).await;
-
         // Check that the file exists
-        let metadata = fs::metadata("REDIS.rdb").expect("REDIS.rdb file should exist");
+        let metadata = fs::metadata(&handle.server().config().dbfilename).expect("rdb file should exist");
         assert!(metadata.is_file());
         assert!(metadata.len() > 0);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_errors_use_redis_prefixes() {
+        let handle = start("errors").await;
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+
+        stream.write_all(b"*1\r\n$3\r\nFOO\r\n").await.unwrap();
+        let mut response = [0u8; 64];
+        let n = stream.read(&mut response).await.unwrap();
+        assert_eq!(&response[..n], b"-ERR unknown command 'FOO'\r\n");
+
+        stream.write_all(b"*1\r\n$3\r\nGET\r\n").await.unwrap();
+        let n = stream.read(&mut response).await.unwrap();
+        assert_eq!(&response[..n], b"-ERR wrong number of arguments for 'get' command\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_command_info_reply_shape() {
+        let handle = start("command_info").await;
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+
+        stream.write_all(b"*3\r\n$7\r\nCOMMAND\r\n$4\r\nINFO\r\n$4\r\necho\r\n").await.unwrap();
+        let mut response = [0u8; 256];
+        let n = stream.read(&mut response).await.unwrap();
+
+        let expected = b"*1\r\n*10\r\n$4\r\necho\r\n:2\r\n*1\r\n+fast\r\n:0\r\n:0\r\n:0\r\n*2\r\n+@fast\r\n+@connection\r\n*0\r\n*0\r\n*0\r\n";
+        assert_eq!(&response[..n], &expected[..]);
+    }
+
+    #[tokio::test]
+    async fn test_start_binds_ephemeral_port() {
+        let a = start("ephemeral_a").await;
+        let b = start("ephemeral_b").await;
+
+        assert_ne!(a.addr().port(), 0);
+        assert_ne!(a.addr(), b.addr());
+
+        // each server has its own keyspace
+        let mut stream = TcpStream::connect(a.addr()).await.unwrap();
+        stream.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n").await.unwrap();
+        assert!(stream.read(&mut [0u8; 64]).await.unwrap() > 0);
+
+        assert!(a.server().db().get("foo").is_some());
+        assert!(b.server().db().get("foo").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_listener_and_connections() {
+        let handle = start("shutdown").await;
+        let addr = handle.addr();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        handle.shutdown().await.unwrap();
+
+        // the open connection is closed by the server
+        let n = tokio::time::timeout(Duration::from_secs(1), stream.read(&mut [0u8; 64]))
+            .await
+            .expect("connection should be closed")
+            .unwrap_or(0);
+        assert_eq!(n, 0);
+
+        // and nothing is listening any more
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_drop_stops_server() {
+        let addr = start("drop").await.addr();
+
+        // the accept loop stops on its next poll after the handle is dropped
+        let mut stopped = false;
+        for _ in 0..100 {
+            if TcpStream::connect(addr).await.is_err() {
+                stopped = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(stopped);
+    }
+
+    // a downstream value type and command, registered without touching rs-redis
+    #[derive(Debug, Clone, PartialEq)]
+    struct Tally(Vec<String>);
+
+    impl CustomType for Tally {
+        fn type_name(&self) -> &str {
+            "tally"
+        }
+    }
+
+    struct TallyAdd;
+
+    impl Command for TallyAdd {
+        fn name(&self) -> &str {
+            "tally.add"
+        }
+
+        fn arity(&self) -> i32 {
+            -3
+        }
+
+        fn flags(&self) -> CommandFlags {
+            CommandFlags::WRITE
+        }
+
+        fn key_range(&self) -> (i32, i32, i32) {
+            (1, 1, 1)
+        }
+
+        fn execute(&self, ctx: &mut Context<'_>, args: &[RESPResult]) -> Result<RESPResult, Error> {
+            let mut strings = Vec::new();
+            for arg in args {
+                match arg {
+                    RESPResult::BulkString(Some(b)) => strings.push(String::from_utf8_lossy(b).into_owned()),
+                    _ => return Err(Error::Syntax),
+                }
+            }
+
+            let key = strings.remove(0);
+            let len = ctx.db().update_custom(&key, || Tally(Vec::new()), |t| {
+                t.0.extend(strings);
+                t.0.len()
+            })?;
+
+            Ok(RESPResult::Integer(len as i64))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_registered_command_with_custom_type() {
+        let handle = start("custom_command").await;
+        handle.server().register_command(TallyAdd).unwrap();
+
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+        let mut response = [0u8; 64];
+
+        stream.write_all(b"*4\r\n$9\r\nTALLY.ADD\r\n$1\r\nt\r\n$1\r\na\r\n$1\r\nb\r\n").await.unwrap();
+        let n = stream.read(&mut response).await.unwrap();
+        assert_eq!(&response[..n], b":2\r\n");
+
+        // the built in commands see a value of another type
+        stream.write_all(b"*3\r\n$5\r\nLPUSH\r\n$1\r\nt\r\n$1\r\nx\r\n").await.unwrap();
+        let n = stream.read(&mut response).await.unwrap();
+        assert!(response[..n].starts_with(b"-WRONGTYPE"));
+
+        let tally = handle.server().db().get_custom::<Tally>("t").unwrap();
+        assert_eq!(tally, Some(Tally(vec!["a".to_string(), "b".to_string()])));
+    }
+
+    // builds the sample module once, into its own target dir so it does not
+    // wait on the lock of the build running these tests
+    fn counter_module() -> &'static str {
+        static PATH: OnceLock<String> = OnceLock::new();
+
+        PATH.get_or_init(|| {
+            let root = env!("CARGO_MANIFEST_DIR");
+            let target = format!("{root}/target/modules");
+            let status = std::process::Command::new(env!("CARGO"))
+                .args(["build", "--quiet", "--manifest-path", &format!("{root}/modules/counter/Cargo.toml")])
+                .args(["--target-dir", &target])
+                .status()
+                .expect("cargo should run");
+            assert!(status.success());
+
+            let (prefix, suffix) = (std::env::consts::DLL_PREFIX, std::env::consts::DLL_SUFFIX);
+            format!("{target}/debug/{prefix}counter{suffix}")
+        })
+    }
+
+    async fn call(stream: &mut TcpStream, args: &[&str]) -> String {
+        let mut msg = format!("*{}\r\n", args.len());
+        for a in args {
+            msg.push_str(&format!("${}\r\n{a}\r\n", a.len()));
+        }
+        stream.write_all(msg.as_bytes()).await.unwrap();
+
+        let mut response = [0u8; 512];
+        let n = stream.read(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response[..n]).into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_module_commands_and_data_type() {
+        let path = counter_module();
+        let handle = start("module").await;
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+
+        assert_eq!(call(&mut stream, &["MODULE", "LOAD", path]).await, "+OK\r\n");
+        assert_eq!(call(&mut stream, &["COUNTER.INCRBY", "c", "5"]).await, ":5\r\n");
+        assert_eq!(call(&mut stream, &["counter.incrby", "c", "2"]).await, ":7\r\n");
+        assert_eq!(call(&mut stream, &["COUNTER.GET", "c"]).await, ":7\r\n");
+        assert_eq!(call(&mut stream, &["COUNTER.GET", "missing"]).await, "$-1\r\n");
+        assert!(call(&mut stream, &["COUNTER.GET"]).await.starts_with("-ERR wrong number of arguments"));
+
+        call(&mut stream, &["SET", "s", "x"]).await;
+        assert!(call(&mut stream, &["COUNTER.INCRBY", "s", "1"]).await.starts_with("-WRONGTYPE"));
+
+        let list = call(&mut stream, &["MODULE", "LIST"]).await;
+        assert!(list.starts_with("*1\r\n*8\r\n$4\r\nname\r\n$7\r\ncounter\r\n$3\r\nver\r\n:1\r\n"));
+
+        // one instance per server, and a module with data types stays loaded
+        assert!(call(&mut stream, &["MODULE", "LOAD", path]).await.starts_with("-ERR Error loading the extension"));
+        assert!(call(&mut stream, &["MODULE", "UNLOAD", "counter"]).await.contains("can't unload"));
+
+        // counters survive a snapshot into a server that loaded the module too
+        assert_eq!(call(&mut stream, &["DEL", "s"]).await, ":1\r\n");
+        assert_eq!(call(&mut stream, &["SAVE"]).await, "+OK\r\n");
+
+        let other = start("module_restore").await;
+        let mut other_stream = TcpStream::connect(other.addr()).await.unwrap();
+        let dbfilename = handle.server().config().dbfilename.clone();
+
+        assert!(call(&mut other_stream, &["LOAD", &dbfilename]).await.starts_with("-ERR"));
+        assert_eq!(call(&mut other_stream, &["MODULE", "LOAD", path]).await, "+OK\r\n");
+        assert_eq!(call(&mut other_stream, &["LOAD", &dbfilename]).await, "+OK\r\n");
+        assert_eq!(call(&mut other_stream, &["COUNTER.GET", "c"]).await, ":7\r\n");
+
+        fs::remove_file(dbfilename).ok();
+    }
+
+    #[tokio::test]
+    async fn test_module_unload() {
+        let path = counter_module();
+        let handle = start("module_unload").await;
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+
+        assert!(call(&mut stream, &["MODULE", "LOAD", "/no/such/module.so"]).await.starts_with("-ERR Error loading"));
+
+        assert_eq!(call(&mut stream, &["MODULE", "LOAD", path, "no-type"]).await, "+OK\r\n");
+        assert_eq!(call(&mut stream, &["COUNTER.ADD", "2", "3"]).await, ":5\r\n");
+        assert!(call(&mut stream, &["COUNTER.GET", "c"]).await.starts_with("-ERR unknown command"));
+        assert!(call(&mut stream, &["MODULE", "LIST"]).await.contains("$7\r\nno-type\r\n"));
+
+        assert_eq!(call(&mut stream, &["MODULE", "UNLOAD", "counter"]).await, "+OK\r\n");
+        assert!(call(&mut stream, &["COUNTER.ADD", "2", "3"]).await.starts_with("-ERR unknown command"));
+        assert_eq!(call(&mut stream, &["MODULE", "LIST"]).await, "*0\r\n");
+        assert!(call(&mut stream, &["MODULE", "UNLOAD", "counter"]).await.contains("no such module"));
+    }
+
+    #[tokio::test]
+    async fn test_busy_script_and_script_kill() {
+        let config = Config {
+            busy_reply_threshold: Duration::from_millis(100),
+            ..test_config("busy_script")
+        };
+        let handle = Server::start(config).await.unwrap();
+
+        let mut scripted = TcpStream::connect(handle.addr()).await.unwrap();
+        let mut other = TcpStream::connect(handle.addr()).await.unwrap();
+
+        assert_eq!(call(&mut other, &["SCRIPT", "KILL"]).await, "-NOTBUSY No scripts in execution right now.\r\n");
+
+        let script = "while true do end";
+        let msg = format!("*3\r\n$4\r\nEVAL\r\n${}\r\n{script}\r\n$1\r\n0\r\n", script.len());
+        scripted.write_all(msg.as_bytes()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // other clients wait, then get BUSY once the threshold has passed
+        assert!(call(&mut other, &["SET", "foo", "bar"]).await.starts_with("-BUSY "));
+        assert!(call(&mut other, &["EVAL", "return 1", "0"]).await.starts_with("-BUSY "));
+        assert_eq!(call(&mut other, &["SCRIPT", "KILL"]).await, "+OK\r\n");
+
+        let mut response = [0u8; 128];
+        let n = tokio::time::timeout(Duration::from_secs(1), scripted.read(&mut response)).await.unwrap().unwrap();
+        assert!(response[..n].starts_with(b"-ERR Script killed by user with SCRIPT KILL"));
+
+        assert_eq!(call(&mut other, &["SET", "foo", "bar"]).await, "+OK\r\n");
+        assert!(handle.server().db().get("foo").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_select_is_per_connection() {
+        let handle = start("select").await;
+
+        let mut first = TcpStream::connect(handle.addr()).await.unwrap();
+        let mut second = TcpStream::connect(handle.addr()).await.unwrap();
+
+        assert_eq!(call(&mut first, &["SELECT", "2"]).await, "+OK\r\n");
+        assert_eq!(call(&mut first, &["SET", "k", "v"]).await, "+OK\r\n");
+        assert_eq!(call(&mut first, &["DBSIZE"]).await, ":1\r\n");
+        assert_eq!(call(&mut second, &["DBSIZE"]).await, ":0\r\n");
+
+        // a script starts in its caller's database
+        assert_eq!(call(&mut first, &["EVAL", "return redis.call('DBSIZE')", "0"]).await, ":1\r\n");
+        assert_eq!(call(&mut second, &["EVAL", "return redis.call('DBSIZE')", "0"]).await, ":0\r\n");
+
+        assert!(handle.server().databases()[2].get("k").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_expired_keys_are_removed_without_reads() {
+        let handle = Server::start(Config { hz: 100, ..test_config("active_expire") }).await.unwrap();
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+
+        assert_eq!(call(&mut stream, &["SET", "k", "v", "PX", "20"]).await, "+OK\r\n");
+
+        // nothing reads k, the background cycle has to find it
+        let mut expired = 0;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            expired = handle.server().db().expired_keys();
+            if expired > 0 {
+                break;
+            }
+        }
+        assert_eq!(expired, 1);
+        assert_eq!(handle.server().db().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_binary_values_round_trip() {
+        let handle = start("binary").await;
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+
+        stream.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$4\r\n\xff\x00\r\n\r\n").await.unwrap();
+        let mut response = [0u8; 64];
+        let n = stream.read(&mut response).await.unwrap();
+        assert_eq!(&response[..n], b"+OK\r\n");
+
+        stream.write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nb\r\n").await.unwrap();
+        let n = stream.read(&mut response).await.unwrap();
+        assert_eq!(&response[..n], b"$4\r\n\xff\x00\r\n\r\n");
+
+        // a bitmap is not valid utf-8 either
+        assert_eq!(call(&mut stream, &["SETBIT", "bits", "0", "1"]).await, ":0\r\n");
+        stream.write_all(b"*2\r\n$3\r\nGET\r\n$4\r\nbits\r\n").await.unwrap();
+        let n = stream.read(&mut response).await.unwrap();
+        assert_eq!(&response[..n], b"$1\r\n\x80\r\n");
+        assert_eq!(call(&mut stream, &["BITCOUNT", "bits"]).await, ":1\r\n");
+    }
+
+    // waits until the server has n clients parked by blocking commands
+    async fn blocked_clients(handle: &ServerHandle, n: usize) {
+        for _ in 0..200 {
+            if handle.server().blocking().blocked_clients() == n {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("expected {n} blocked clients");
+    }
+
+    async fn reply(stream: &mut TcpStream) -> String {
+        let mut response = [0u8; 512];
+        let n = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut response)).await.unwrap().unwrap();
+        String::from_utf8_lossy(&response[..n]).into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_blocking_pops_wake_in_order() {
+        let handle = start("blocking").await;
+        let mut first = TcpStream::connect(handle.addr()).await.unwrap();
+        let mut second = TcpStream::connect(handle.addr()).await.unwrap();
+        let mut pusher = TcpStream::connect(handle.addr()).await.unwrap();
+
+        first.write_all(b"*3\r\n$5\r\nBLPOP\r\n$1\r\nq\r\n$1\r\n0\r\n").await.unwrap();
+        blocked_clients(&handle, 1).await;
+        second.write_all(b"*3\r\n$5\r\nBRPOP\r\n$1\r\nq\r\n$1\r\n0\r\n").await.unwrap();
+        blocked_clients(&handle, 2).await;
+
+        // the pusher is not held up, and the first to block is the first served
+        assert_eq!(call(&mut pusher, &["RPUSH", "q", "a", "b"]).await, ":2\r\n");
+        assert_eq!(reply(&mut first).await, "*2\r\n$1\r\nq\r\n$1\r\na\r\n");
+        assert_eq!(reply(&mut second).await, "*2\r\n$1\r\nq\r\n$1\r\nb\r\n");
+        assert_eq!(call(&mut pusher, &["EXISTS", "q"]).await, ":0\r\n");
+
+        // a push from a script wakes a client too
+        first.write_all(b"*6\r\n$6\r\nBLMOVE\r\n$3\r\nsrc\r\n$3\r\ndst\r\n$5\r\nRIGHT\r\n$4\r\nLEFT\r\n$1\r\n0\r\n").await.unwrap();
+        blocked_clients(&handle, 1).await;
+        assert_eq!(call(&mut pusher, &["EVAL", "return redis.call('LPUSH', KEYS[1], 'x')", "1", "src"]).await, ":1\r\n");
+        assert_eq!(reply(&mut first).await, "$1\r\nx\r\n");
+        assert_eq!(call(&mut pusher, &["LRANGE", "dst", "0", "-1"]).await, "*1\r\n$1\r\nx\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_blocking_pop_timeout_and_disconnect() {
+        let handle = start("blocking_timeout").await;
+        let mut stream = TcpStream::connect(handle.addr()).await.unwrap();
+
+        let started = std::time::Instant::now();
+        assert_eq!(call(&mut stream, &["BLPOP", "q", "0.1"]).await, "*-1\r\n");
+        assert_eq!(call(&mut stream, &["BLMOVE", "q", "r", "LEFT", "LEFT", "0.01"]).await, "$-1\r\n");
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(handle.server().blocking().blocked_clients(), 0);
+
+        // commands sent behind a blocking one run once it returns
+        stream.write_all(b"*3\r\n$5\r\nBLPOP\r\n$1\r\nq\r\n$3\r\n0.1\r\n*1\r\n$4\r\nPING\r\n").await.unwrap();
+        let mut replies = reply(&mut stream).await;
+        if replies.len() < "*-1\r\n+PONG\r\n".len() {
+            replies += &reply(&mut stream).await;
+        }
+        assert_eq!(replies, "*-1\r\n+PONG\r\n");
+
+        // a client that goes away while blocked takes nothing
+        let mut gone = TcpStream::connect(handle.addr()).await.unwrap();
+        gone.write_all(b"*3\r\n$5\r\nBLPOP\r\n$1\r\nq\r\n$1\r\n0\r\n").await.unwrap();
+        blocked_clients(&handle, 1).await;
+        drop(gone);
+        blocked_clients(&handle, 0).await;
+
+        // also when it pipelined more commands behind the blocking one
+        let mut gone = TcpStream::connect(handle.addr()).await.unwrap();
+        gone.write_all(b"*3\r\n$5\r\nBLPOP\r\n$1\r\nq\r\n$1\r\n0\r\n*1\r\n$4\r\nPING\r\n").await.unwrap();
+        blocked_clients(&handle, 1).await;
+        gone.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+        drop(gone);
+        blocked_clients(&handle, 0).await;
+
+        assert_eq!(call(&mut stream, &["RPUSH", "q", "x"]).await, ":1\r\n");
+        assert_eq!(call(&mut stream, &["LLEN", "q"]).await, ":1\r\n");
+    }
+}